@@ -0,0 +1,341 @@
+//! Backups of overwritten files, and exporting and importing snapshot
+//! archives.
+
+use crate::config::ProfileConfig;
+use crate::error::profile_not_found;
+use crate::sync::ManifestEntry;
+use crate::util::{
+    confirm, copy_dir, file_mode, format_bytes, format_unix_time, hash_path, host_name,
+    needs_elevation, read_secret, remove_link, state_dir, unix_seconds,
+};
+use crate::Dotty;
+use anyhow::{Context, Result};
+use colored::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use std::{env, fs};
+
+impl Dotty {
+    /// Archives the files as installed at the destinations, for moving them
+    /// without git or keeping a backup elsewhere
+    pub fn export(&self, output: &Path, encrypt: bool, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+
+        let mut manifest = SnapshotManifest {
+            profile: profile.clone(),
+            host: host_name(),
+            created_at: unix_seconds(SystemTime::now()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            entries: BTreeMap::new(),
+        };
+        let mut archive = tar::Builder::new(Vec::new());
+        for (relative_path, entry) in &profile_config.files {
+            let dest = profile_config.destination(&home, relative_path);
+            // Fall back to the source where the destination isn't installed
+            let path = if dest.exists() {
+                dest
+            } else {
+                PathBuf::from(entry.source())
+            };
+            let Some(hash) = hash_path(&path)? else {
+                warn!(
+                    "Skipped {}: neither destination nor source exists",
+                    relative_path
+                );
+                continue;
+            };
+            let name = Path::new(SNAPSHOT_FILES).join(relative_path);
+            if path.is_dir() {
+                archive.append_dir_all(&name, &path)
+            } else {
+                archive.append_path_with_name(&path, &name)
+            }
+            .with_context(|| format!("Failed to archive {}", path.display()))?;
+            manifest.entries.insert(
+                relative_path.clone(),
+                ManifestEntry {
+                    hash,
+                    mode: file_mode(&path),
+                },
+            );
+        }
+        let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest_json.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created_at);
+        archive
+            .append_data(&mut header, SNAPSHOT_MANIFEST, manifest_json.as_slice())
+            .context("Failed to archive manifest")?;
+
+        let tarball = archive.into_inner().context("Failed to finish archive")?;
+        let mut data =
+            zstd::encode_all(tarball.as_slice(), 19).context("Failed to compress archive")?;
+        if encrypt {
+            let passphrase = read_passphrase("Passphrase:")?;
+            if read_passphrase("Repeat passphrase:")? != passphrase {
+                anyhow::bail!("Passphrases don't match");
+            }
+            data = encrypt_snapshot(&data, &passphrase)?;
+        }
+        fs::write(output, &data)
+            .with_context(|| format!("Failed to write {}", output.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(output, fs::Permissions::from_mode(0o600))
+                .context("Failed to restrict archive permissions")?;
+        }
+        println!(
+            "Exported {} file(s) of profile {} to {} ({})",
+            manifest.entries.len(),
+            profile,
+            output.display(),
+            format_bytes(data.len() as u64)
+        );
+        Ok(())
+    }
+
+    /// Checks an exported archive against its manifest and writes its files to
+    /// the destinations, backing up what they replace
+    pub fn import_archive(
+        &self,
+        file: &Path,
+        dry_run: bool,
+        yes: bool,
+        profile: Option<String>,
+    ) -> Result<()> {
+        let mut data =
+            fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+        if data.starts_with(SNAPSHOT_MAGIC) {
+            data = decrypt_snapshot(&data, &read_passphrase("Passphrase:")?)?;
+        }
+        let tarball = zstd::decode_all(data.as_slice()).context("Failed to decompress archive")?;
+        let staging = state_dir()?.join("snapshot");
+        if staging.exists() {
+            fs::remove_dir_all(&staging).context("Failed to clear staging area")?;
+        }
+        let result = self.apply_snapshot(&tarball, &staging, dry_run, yes, profile);
+        if staging.exists() {
+            fs::remove_dir_all(&staging).context("Failed to clear staging area")?;
+        }
+        result
+    }
+
+    pub(crate) fn apply_snapshot(
+        &self,
+        tarball: &[u8],
+        staging: &Path,
+        dry_run: bool,
+        yes: bool,
+        profile: Option<String>,
+    ) -> Result<()> {
+        tar::Archive::new(tarball)
+            .unpack(staging)
+            .context("Failed to unpack archive")?;
+        let manifest: SnapshotManifest = serde_json::from_slice(
+            &fs::read(staging.join(SNAPSHOT_MANIFEST)).context("The archive has no manifest")?,
+        )
+        .context("Failed to parse archive manifest")?;
+        for (relative_path, entry) in &manifest.entries {
+            // Keys come from the archive, so they must stay beneath the home
+            // and staging directories they're joined onto
+            if Path::new(relative_path).components().any(|component| {
+                matches!(
+                    component,
+                    Component::ParentDir | Component::RootDir | Component::Prefix(_)
+                )
+            }) {
+                anyhow::bail!(
+                    "The archive manifest has an unsafe path {:?}; refusing to import it",
+                    relative_path
+                );
+            }
+            let path = staging.join(SNAPSHOT_FILES).join(relative_path);
+            if hash_path(&path)?.as_ref() != Some(&entry.hash) {
+                anyhow::bail!(
+                    "{} in the archive doesn't match its manifest; the archive is damaged or was modified",
+                    relative_path
+                );
+            }
+        }
+
+        let profile = profile.unwrap_or_else(|| manifest.profile.clone());
+        // Untracked keys map to the same paths as on the exporting machine
+        let untracked = ProfileConfig::default();
+        let profile_config = self.config.profiles.get(&profile).unwrap_or(&untracked);
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        println!(
+            "Snapshot of profile {} from {} taken {} (dotty {})",
+            manifest.profile,
+            manifest.host,
+            format_unix_time(manifest.created_at),
+            manifest.version
+        );
+        let mut changes = Vec::new();
+        for (relative_path, entry) in &manifest.entries {
+            let dest = profile_config.destination(&home, relative_path);
+            let status = match hash_path(&dest)? {
+                None => "new".green(),
+                Some(hash) if hash == entry.hash => continue,
+                Some(_) => "changed".yellow(),
+            };
+            println!("  {:>8} {}", status, dest.display());
+            changes.push((relative_path, entry, dest));
+        }
+        if changes.is_empty() {
+            println!("Every destination already matches the snapshot");
+            return Ok(());
+        }
+        if dry_run {
+            return Ok(());
+        }
+        if !yes && !confirm(&format!("Write {} file(s)?", changes.len()))? {
+            println!("Aborted");
+            return Ok(());
+        }
+
+        for (relative_path, entry, dest) in changes {
+            let path = staging.join(SNAPSHOT_FILES).join(relative_path);
+            self.backup_file(&dest)?;
+            let origin = format!("snapshot:{}", relative_path);
+            self.write_destination(&dest, &origin, || {
+                // Never write through a managed symlink into the source
+                if dest.is_symlink() {
+                    remove_link(&dest).context("Failed to remove symlink")?;
+                }
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent).context("Failed to create parent directories")?;
+                }
+                if path.is_dir() {
+                    copy_dir(&path, &dest).context("Failed to copy directory")
+                } else {
+                    fs::copy(&path, &dest)
+                        .map(|_| ())
+                        .context("Failed to copy file")
+                }
+            })?;
+            if let (Some(mode), false) = (entry.mode, path.is_dir()) {
+                self.enforce_permissions(&dest, mode)?;
+            }
+            println!("Restored {}", dest.display());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn backup_file(&self, path: &Path) -> Result<()> {
+        if path.exists() {
+            let backup_path = path.with_extension("bak");
+            let result = if path.is_dir() {
+                copy_dir(path, &backup_path)
+            } else {
+                fs::copy(path, &backup_path).map(|_| ())
+            };
+            match result.context("Failed to create backup") {
+                Err(e) if needs_elevation(path, &e) => self.run_elevated(&[
+                    OsStr::new("cp"),
+                    path.as_os_str(),
+                    backup_path.as_os_str(),
+                ])?,
+                result => {
+                    result?;
+                }
+            }
+            info!("Created backup: {:?}", backup_path);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) const SNAPSHOT_MANIFEST: &str = "manifest.json";
+
+pub(crate) const SNAPSHOT_FILES: &str = "files";
+
+// Starts an encrypted snapshot, followed by the salt, IV, GCM tag and
+// ciphertext
+pub(crate) const SNAPSHOT_MAGIC: &[u8] = b"DOTTYENC1";
+
+pub(crate) const SNAPSHOT_KDF_ITERATIONS: usize = 600_000;
+
+// Describes a `dotty export` archive: each file's hash and mode as exported
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SnapshotManifest {
+    pub(crate) profile: String,
+    pub(crate) host: String,
+    pub(crate) created_at: u64,
+    pub(crate) version: String,
+    pub(crate) entries: BTreeMap<String, ManifestEntry>,
+}
+
+pub(crate) fn snapshot_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0; 32];
+    openssl::pkcs5::pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        SNAPSHOT_KDF_ITERATIONS,
+        openssl::hash::MessageDigest::sha256(),
+        &mut key,
+    )
+    .context("Failed to derive key")?;
+    Ok(key)
+}
+
+pub(crate) fn encrypt_snapshot(data: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let mut salt = [0; 16];
+    let mut iv = [0; 12];
+    openssl::rand::rand_bytes(&mut salt).context("Failed to generate salt")?;
+    openssl::rand::rand_bytes(&mut iv).context("Failed to generate IV")?;
+    let mut tag = [0; 16];
+    let ciphertext = openssl::symm::encrypt_aead(
+        openssl::symm::Cipher::aes_256_gcm(),
+        &snapshot_key(passphrase, &salt)?,
+        Some(&iv),
+        SNAPSHOT_MAGIC,
+        data,
+        &mut tag,
+    )
+    .context("Failed to encrypt archive")?;
+    Ok([SNAPSHOT_MAGIC, &salt, &iv, &tag, &ciphertext].concat())
+}
+
+pub(crate) fn decrypt_snapshot(data: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let rest = &data[SNAPSHOT_MAGIC.len()..];
+    if rest.len() < 16 + 12 + 16 {
+        anyhow::bail!("The encrypted archive is truncated");
+    }
+    let (salt, rest) = rest.split_at(16);
+    let (iv, rest) = rest.split_at(12);
+    let (tag, ciphertext) = rest.split_at(16);
+    openssl::symm::decrypt_aead(
+        openssl::symm::Cipher::aes_256_gcm(),
+        &snapshot_key(passphrase, salt)?,
+        Some(iv),
+        SNAPSHOT_MAGIC,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the archive was modified"))
+}
+
+// Reads a passphrase from DOTTY_PASSPHRASE, or from the terminal without
+// echoing it
+pub(crate) fn read_passphrase(question: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var("DOTTY_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    let passphrase = read_secret(question)?;
+    if passphrase.is_empty() {
+        anyhow::bail!("The passphrase can't be empty");
+    }
+    Ok(passphrase)
+}
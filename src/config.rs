@@ -0,0 +1,1629 @@
+//! The configuration file: profiles, tracked entries, the remote and
+//! daemon settings, and ignore rules.
+
+use crate::daemon::SyncStats;
+use crate::error::{profile_not_found, DottyError, ErrorKind};
+use crate::plugins::{self, StorageBackend};
+use crate::remote::load_keyring_token;
+use crate::template::{is_template, TEMPLATE_SUFFIX};
+use crate::util::{
+    command_exists, expand_env_vars, host_name, parse_permissions, system_destination,
+    SYSTEM_PREFIX,
+};
+use crate::Dotty;
+use anyhow::{Context, Result};
+use colored::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use std::{env, fs};
+
+/// The `[remote]` section: the repository every machine syncs with and how to
+/// authenticate to it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RemoteConfig {
+    /// Clone URL of the repository (HTTPS, SSH or a local path)
+    pub github_repo: String,
+    /// Token for HTTPS remotes; empty when `token_store` is keyring
+    pub github_token: String,
+    /// History depth of the initial clone; 0 clones everything. Older commits
+    /// are fetched on demand.
+    #[serde(default = "default_clone_depth")]
+    pub clone_depth: u32,
+    /// Clone without blobs (`--filter=blob:none`), fetching file contents only
+    /// when they are needed; requires the git CLI
+    #[serde(default)]
+    pub partial_clone: bool,
+    /// Where the repository is cloned, relative to $HOME unless absolute;
+    /// defaults to ~/.dotty_repo. May point at an existing checkout.
+    #[serde(default)]
+    pub local_path: Option<String>,
+    /// Commit to `machines/<hostname>` instead of master; master then only
+    /// receives entries marked `shared` (and tombstones)
+    #[serde(default)]
+    pub branch_per_host: bool,
+    /// The branch every machine shares; defaults to master
+    #[serde(default)]
+    pub branch: Option<String>,
+    /// How clones, fetches and pushes authenticate
+    #[serde(default)]
+    pub auth: RemoteAuth,
+    /// Where github_token is kept; `dotty login` switches this to keyring
+    #[serde(default)]
+    pub token_store: TokenStore,
+    /// The GitHub OAuth app `dotty login` signed in with, needed to renew
+    /// expiring tokens
+    #[serde(default)]
+    pub oauth_client_id: Option<String>,
+    /// Unix time the keyring token expires at; never written to the config
+    #[serde(skip)]
+    pub(crate) token_expires_at: Option<u64>,
+    /// Private key for auth = "deploy_key", relative to $HOME unless absolute
+    #[serde(default)]
+    pub ssh_key: Option<String>,
+    /// HTTP(S) proxy for clone, fetch and push, e.g. http://proxy:3128;
+    /// defaults to https_proxy / all_proxy
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Storage backend plugin that holds the repository instead of a git
+    /// remote; github_repo is then passed to it as is
+    #[serde(default)]
+    pub backend: Option<String>,
+    /// Secret provider plugin that keeps the token when token_store is
+    /// keyring, instead of the system keyring
+    #[serde(default)]
+    pub secret_provider: Option<String>,
+}
+
+impl RemoteConfig {
+    pub(crate) fn shared_branch(&self) -> &str {
+        self.branch.as_deref().unwrap_or("master")
+    }
+
+    // The proxy to reach an HTTPS remote through, if any. SSH remotes
+    // connect directly.
+    pub(crate) fn proxy_url(&self) -> Option<String> {
+        let host = self
+            .github_repo
+            .strip_prefix("https://")
+            .or_else(|| self.github_repo.strip_prefix("http://"))?
+            .split(['/', ':'])
+            .next()?
+            .rsplit('@')
+            .next()?;
+        if let Some(proxy) = &self.proxy {
+            return Some(proxy.clone());
+        }
+        let no_proxy = ["no_proxy", "NO_PROXY"]
+            .iter()
+            .find_map(|name| env::var(name).ok())
+            .unwrap_or_default();
+        let bypassed = no_proxy.split(',').map(str::trim).any(|entry| {
+            let entry = entry.trim_start_matches('.');
+            entry == "*"
+                || (!entry.is_empty() && (host == entry || host.ends_with(&format!(".{}", entry))))
+        });
+        if bypassed {
+            return None;
+        }
+        ["https_proxy", "HTTPS_PROXY", "all_proxy", "ALL_PROXY"]
+            .iter()
+            .filter_map(|name| env::var(name).ok())
+            .find(|proxy| !proxy.is_empty())
+    }
+
+    // libgit2 doesn't look at proxy environment variables by itself
+    pub(crate) fn proxy_options(&self) -> git2::ProxyOptions<'static> {
+        let mut options = git2::ProxyOptions::new();
+        match self.proxy_url() {
+            Some(url) => options.url(&url),
+            // Falls back to git's http.proxy setting
+            None => options.auto(),
+        };
+        options
+    }
+
+    pub(crate) fn storage_backend(&self) -> Result<Option<Arc<dyn StorageBackend>>> {
+        self.backend
+            .as_deref()
+            .map(plugins::storage_backend)
+            .transpose()
+    }
+
+    pub(crate) fn ssh_key_path(&self) -> Result<PathBuf> {
+        let ssh_key = self
+            .ssh_key
+            .as_deref()
+            .context("remote.ssh_key is needed for deploy key authentication")?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let relative = ssh_key
+            .strip_prefix("~/")
+            .or_else(|| ssh_key.strip_prefix("~\\"))
+            .unwrap_or(ssh_key);
+        Ok(home.join(expand_env_vars(relative)))
+    }
+
+    // Enough of the token to tell which one is configured
+    pub(crate) fn redacted_token(&self) -> String {
+        let chars: Vec<char> = self.github_token.chars().collect();
+        match chars.len() {
+            0 => "(not set)".to_string(),
+            len if len <= 8 => "****".to_string(),
+            len => format!("****{}", chars[len - 4..].iter().collect::<String>()),
+        }
+    }
+}
+
+/// Authentication methods for the remote
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, clap::ValueEnum, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteAuth {
+    /// github_token over HTTPS
+    #[default]
+    Token,
+    /// Keys from the running ssh-agent, for git@ URLs
+    Ssh,
+    /// A key file that only has access to the dotfiles repository (ssh_key)
+    DeployKey,
+    /// `git credential fill`, i.e. whatever credential helper git is set up
+    /// with; dotty stores nothing
+    CredentialHelper,
+}
+
+impl RemoteAuth {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            RemoteAuth::Token => "token",
+            RemoteAuth::Ssh => "ssh",
+            RemoteAuth::DeployKey => "deploy key",
+            RemoteAuth::CredentialHelper => "git credential helper",
+        }
+    }
+}
+
+/// Where `github_token` is kept
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStore {
+    /// github_token in config.toml
+    #[default]
+    Config,
+    /// The system keyring; github_token is left empty in the config
+    Keyring,
+}
+
+pub(crate) fn default_clone_depth() -> u32 {
+    1
+}
+
+/// A set of tracked files, keyed by their path relative to $HOME
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ProfileConfig {
+    /// Tracked entries by key
+    pub files: BTreeMap<String, FileEntry>,
+    /// Gitignore-style patterns excluded from directory entries
+    pub ignore_patterns: Vec<String>,
+    /// Applied to the files inside this profile's tracked directories
+    // Left out when empty so `[[exclude]]` tables can be appended by hand
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<ExcludeRule>,
+    /// Link destinations to their sources instead of copying
+    pub use_symlinks: bool,
+    /// Overrides use_symlinks when set
+    #[serde(default)]
+    pub mode: Option<LinkMode>,
+    /// Default line-ending policy for the profile's files
+    #[serde(default)]
+    pub line_endings: Option<LineEnding>,
+    /// Carry extended attributes (and with them ACLs and SELinux labels) over
+    /// to copied destinations
+    #[serde(default)]
+    pub preserve_xattrs: bool,
+    /// Written to env.sh/env.fish/env.ps1 next to config.toml for the shell
+    /// to source
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    /// Environment variables exported by the same scripts
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+impl ProfileConfig {
+    /// Where an entry is written on this machine
+    pub fn destination(&self, home: &Path, relative_path: &str) -> PathBuf {
+        // A template's destination is its key without the .tmpl suffix
+        let relative = self
+            .files
+            .get(relative_path)
+            .and_then(FileEntry::os_destination)
+            .unwrap_or_else(|| {
+                if is_template(Path::new(relative_path)) {
+                    &relative_path[..relative_path.len() - TEMPLATE_SUFFIX.len()]
+                } else {
+                    relative_path
+                }
+            });
+        if let Some(system_path) = relative.strip_prefix(SYSTEM_PREFIX) {
+            return system_destination(system_path);
+        }
+        let relative = relative
+            .strip_prefix("~/")
+            .or_else(|| relative.strip_prefix("~\\"))
+            .unwrap_or(relative);
+        // Absolute results (e.g. from %APPDATA%) replace the home directory
+        home.join(expand_env_vars(relative))
+    }
+
+    pub(crate) fn sources(&self) -> impl Iterator<Item = (&String, &str)> {
+        self.files
+            .iter()
+            .map(|(relative_path, entry)| (relative_path, entry.source()))
+    }
+
+    pub(crate) fn entry_for_source(&self, source: &Path) -> Option<&FileEntry> {
+        self.files
+            .values()
+            .find(|entry| Path::new(entry.source()) == source)
+    }
+
+    pub(crate) fn line_ending(&self, relative_path: &str) -> Option<LineEnding> {
+        self.files
+            .get(relative_path)
+            .and_then(FileEntry::line_ending)
+            .or(self.line_endings)
+    }
+
+    pub(crate) fn link_mode(&self) -> LinkMode {
+        match self.mode {
+            Some(mode) => mode,
+            None if self.use_symlinks => LinkMode::Symlink,
+            None => LinkMode::Copy,
+        }
+    }
+
+    // Entry keys in the order syncs write them: alphabetical, except that an
+    // entry waits for the entries in its `after`. Keys in `after` that aren't
+    // tracked are ignored. On a cycle, returns it, e.g. [a, b, a] for a after
+    // b after a.
+    pub(crate) fn sync_order(&self) -> std::result::Result<Vec<&String>, Vec<&String>> {
+        let mut waiting: BTreeMap<&String, BTreeSet<&String>> = self
+            .files
+            .iter()
+            .map(|(key, entry)| {
+                let after = entry
+                    .after()
+                    .iter()
+                    .filter(|dependency| self.files.contains_key(*dependency))
+                    .collect();
+                (key, after)
+            })
+            .collect();
+        let mut order = Vec::with_capacity(waiting.len());
+        while !waiting.is_empty() {
+            let ready = waiting
+                .iter()
+                .find(|(_, after)| after.is_empty())
+                .map(|(key, _)| *key);
+            let Some(ready) = ready else {
+                // Everything left waits on something left, so following the
+                // first dependency from anywhere must come back around
+                let mut path = vec![*waiting.keys().next().unwrap()];
+                loop {
+                    let next = *waiting[path.last().unwrap()].iter().next().unwrap();
+                    if let Some(start) = path.iter().position(|key| *key == next) {
+                        let mut cycle = path.split_off(start);
+                        cycle.push(next);
+                        return Err(cycle);
+                    }
+                    path.push(next);
+                }
+            };
+            waiting.remove(ready);
+            for after in waiting.values_mut() {
+                after.remove(ready);
+            }
+            order.push(ready);
+        }
+        Ok(order)
+    }
+
+    // sync_order, with a cycle as a config error
+    pub(crate) fn ordered_files(&self) -> Result<Vec<(&String, &FileEntry)>> {
+        let order = self.sync_order().map_err(|cycle| {
+            DottyError::new(ErrorKind::Config, dependency_cycle(&cycle))
+                .hint("remove one of the keys from the `after` lists in config.toml")
+        })?;
+        Ok(order
+            .into_iter()
+            .map(|key| (key, &self.files[key]))
+            .collect())
+    }
+}
+
+// Values `std::env::consts::OS` can take, which OS conditions compare against
+pub(crate) const KNOWN_OS: &[&str] = &[
+    "linux",
+    "macos",
+    "windows",
+    "freebsd",
+    "openbsd",
+    "netbsd",
+    "dragonfly",
+    "solaris",
+    "illumos",
+    "android",
+    "ios",
+];
+
+// Suffixes of the overlays that apply to this machine, most specific first:
+// `<key>.<hostname>`, then `<key>.<os>` (or `<key>.darwin` on macOS)
+pub(crate) fn overlay_suffixes() -> Vec<String> {
+    let mut suffixes = vec![host_name(), env::consts::OS.to_string()];
+    if env::consts::OS == "macos" {
+        suffixes.push("darwin".to_string());
+    }
+    suffixes
+}
+
+pub(crate) fn dependency_cycle(cycle: &[&String]) -> String {
+    let keys: Vec<String> = cycle.iter().map(|key| format!("{:?}", key)).collect();
+    format!(
+        "Entries wait for each other in a cycle: {}",
+        keys.join(" after ")
+    )
+}
+
+/// Either just the source path, or a table with the source and per-file options
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum FileEntry {
+    Source(String),
+    Detailed(Box<FileOptions>),
+}
+
+/// The table form of a tracked entry, for entries that need more than a
+/// source path
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileOptions {
+    /// Absolute path of the file or directory on this machine
+    pub source: String,
+    /// Overrides the profile's mode for this file
+    #[serde(default)]
+    pub mode: Option<LinkMode>,
+    /// Destination per OS (as in std::env::consts::OS), relative to $HOME;
+    /// other systems use the entry's key
+    #[serde(default)]
+    pub destinations: HashMap<String, String>,
+    /// Store LF in the repo and write this ending to copied destinations
+    #[serde(default)]
+    pub line_endings: Option<LineEnding>,
+    /// Octal permission bits, e.g. "0600", set on the destination every sync
+    /// instead of mirroring the source's
+    #[serde(default)]
+    pub permissions: Option<String>,
+    /// User and group (names or numeric ids) to chown the destination to
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// See `owner`
+    #[serde(default)]
+    pub group: Option<String>,
+    /// For directories: only sync files matching one of these (gitignore
+    /// syntax), before ignore_patterns are applied
+    #[serde(default)]
+    pub include_patterns: Vec<String>,
+    /// Exempts the entry from max_file_size
+    #[serde(default)]
+    pub allow_large: bool,
+    /// Store the content in Git LFS rather than as regular git blobs
+    #[serde(default)]
+    pub lfs: bool,
+    /// With the profiles layout, keep one copy under shared/ for every
+    /// profile that tracks this path
+    #[serde(default)]
+    pub shared: bool,
+    /// Keys of entries in the same profile written before this one, e.g. a
+    /// generated theme that this file includes
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Only apply the entry where this program is on $PATH
+    #[serde(default)]
+    pub if_command: Option<String>,
+    /// Only apply the entry where this path exists, relative to $HOME
+    /// unless absolute
+    #[serde(default)]
+    pub if_file: Option<String>,
+    /// Only apply the entry on this OS (as in std::env::consts::OS)
+    #[serde(default)]
+    pub if_os: Option<String>,
+    /// Only apply the entry where this expression, evaluated against the
+    /// repository's script, is true
+    #[serde(default)]
+    pub if_script: Option<String>,
+}
+
+impl FileEntry {
+    pub(crate) fn source(&self) -> &str {
+        match self {
+            FileEntry::Source(source) => source,
+            FileEntry::Detailed(options) => &options.source,
+        }
+    }
+
+    pub(crate) fn mode(&self) -> Option<LinkMode> {
+        match self {
+            FileEntry::Source(_) => None,
+            FileEntry::Detailed(options) => options.mode,
+        }
+    }
+
+    pub(crate) fn line_ending(&self) -> Option<LineEnding> {
+        match self {
+            FileEntry::Source(_) => None,
+            FileEntry::Detailed(options) => options.line_endings,
+        }
+    }
+
+    pub(crate) fn permissions(&self) -> Option<u32> {
+        match self {
+            FileEntry::Source(_) => None,
+            FileEntry::Detailed(options) => options
+                .permissions
+                .as_deref()
+                .and_then(|permissions| parse_permissions(permissions).ok()),
+        }
+    }
+
+    pub(crate) fn allow_large(&self) -> bool {
+        match self {
+            FileEntry::Source(_) => false,
+            FileEntry::Detailed(options) => options.allow_large,
+        }
+    }
+
+    pub(crate) fn lfs(&self) -> bool {
+        match self {
+            FileEntry::Source(_) => false,
+            FileEntry::Detailed(options) => options.lfs,
+        }
+    }
+
+    pub(crate) fn shared(&self) -> bool {
+        match self {
+            FileEntry::Source(_) => false,
+            FileEntry::Detailed(options) => options.shared,
+        }
+    }
+
+    pub(crate) fn after(&self) -> &[String] {
+        match self {
+            FileEntry::Source(_) => &[],
+            FileEntry::Detailed(options) => &options.after,
+        }
+    }
+
+    pub(crate) fn if_script(&self) -> Option<&str> {
+        match self {
+            FileEntry::Source(_) => None,
+            FileEntry::Detailed(options) => options.if_script.as_deref(),
+        }
+    }
+
+    // Why the entry doesn't apply to this machine, if it doesn't
+    pub(crate) fn unmet_condition(&self) -> Option<String> {
+        let FileEntry::Detailed(options) = self else {
+            return None;
+        };
+        if let Some(os) = options.if_os.as_ref().filter(|os| *os != env::consts::OS) {
+            return Some(format!("only for {}", os));
+        }
+        if let Some(command) = options
+            .if_command
+            .as_ref()
+            .filter(|command| !command_exists(command))
+        {
+            return Some(format!("{} isn't installed", command));
+        }
+        if let Some(file) = &options.if_file {
+            let relative = file.strip_prefix("~/").unwrap_or(file);
+            let path = dirs::home_dir()
+                .unwrap_or_default()
+                .join(expand_env_vars(relative));
+            if !path.exists() {
+                return Some(format!("{} doesn't exist", file));
+            }
+        }
+        None
+    }
+
+    pub(crate) fn include_patterns(&self) -> &[String] {
+        match self {
+            FileEntry::Source(_) => &[],
+            FileEntry::Detailed(options) => &options.include_patterns,
+        }
+    }
+
+    pub(crate) fn ownership(&self) -> (Option<&str>, Option<&str>) {
+        match self {
+            FileEntry::Source(_) => (None, None),
+            FileEntry::Detailed(options) => (options.owner.as_deref(), options.group.as_deref()),
+        }
+    }
+
+    pub(crate) fn os_destination(&self) -> Option<&str> {
+        match self {
+            FileEntry::Source(_) => None,
+            FileEntry::Detailed(options) => options
+                .destinations
+                .get(env::consts::OS)
+                .map(String::as_str),
+        }
+    }
+
+    // Keeps any per-file options
+    pub(crate) fn set_source(&mut self, source: String) {
+        match self {
+            FileEntry::Source(old) => *old = source,
+            FileEntry::Detailed(options) => options.source = source,
+        }
+    }
+}
+
+/// How a destination is kept in step with its source
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkMode {
+    Copy,
+    Symlink,
+    Hardlink,
+}
+
+impl LinkMode {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            LinkMode::Copy => "copy",
+            LinkMode::Symlink => "symlink",
+            LinkMode::Hardlink => "hardlink",
+        }
+    }
+}
+
+/// Line endings written to copied destinations
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    /// CRLF on Windows, LF elsewhere
+    Native,
+}
+
+impl LineEnding {
+    pub(crate) fn is_crlf(self) -> bool {
+        match self {
+            LineEnding::Lf => false,
+            LineEnding::Crlf => true,
+            LineEnding::Native => cfg!(windows),
+        }
+    }
+}
+
+/// The contents of config.toml
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Config {
+    /// Profiles by name; `default` is used when no detection rule matches
+    pub profiles: HashMap<String, ProfileConfig>,
+    /// The repository to sync with
+    pub remote: RemoteConfig,
+    /// Seconds between syncs when running as a daemon
+    pub sync_interval: u64,
+    /// Rules picking the profile for this machine
+    pub profile_detection: Option<ProfileDetectionConfig>,
+    /// Webhooks told about sync results
+    pub notifications: Option<NotificationsConfig>,
+    /// Settings for `dotty watch`
+    pub daemon: Option<DaemonConfig>,
+    /// Conditions for scheduled syncs
+    pub schedule: Option<ScheduleConfig>,
+    /// Record every destination write in a hash-chained audit log
+    #[serde(default)]
+    pub audit: bool,
+    /// Lets the repository's script start programs with run(). Never
+    /// shared through the repository, so each machine opts in itself.
+    #[serde(default)]
+    pub script_commands: bool,
+    /// Used to write files outside $HOME that the current user can't, e.g. "pkexec"
+    #[serde(default)]
+    pub elevate_command: Option<String>,
+    /// Applied to every profile before the profile's own ignore_patterns
+    #[serde(default)]
+    pub ignore_patterns: Vec<String>,
+    /// Applied to the files inside every profile's tracked directories
+    // Left out when empty so `[[exclude]]` tables can be appended by hand
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<ExcludeRule>,
+    /// Files larger than this many bytes are skipped unless their entry sets
+    /// allow_large; 0 disables the check
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// How entries are laid out in the repository
+    #[serde(default)]
+    pub repo_layout: RepoLayout,
+    /// What to do when a file's source and destination both changed since
+    /// the last sync
+    #[serde(default)]
+    pub conflict_strategy: ConflictStrategy,
+}
+
+/// How a file changed on both sides since the last sync is resolved
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictStrategy {
+    #[default]
+    PreferSource,
+    PreferDest,
+    /// Whichever of the two was modified last
+    PreferNewest,
+    /// Ask on a terminal; conflicts are skipped when nobody can answer
+    Prompt,
+    /// Fail the sync
+    Abort,
+}
+
+/// Where entries are stored in the repository
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RepoLayout {
+    /// Every profile's entries at the repository root, keyed by path alone
+    #[default]
+    Flat,
+    /// Each profile under `profiles/<name>/`, and entries marked `shared`
+    /// under shared/
+    Profiles,
+}
+
+/// Keeps files inside tracked directories out of the repository, e.g.
+/// `{ patterns = ["*.sqlite", "*.log"] }` for caches and databases or
+/// `{ max_size = 1048576 }` for anything big. A file is excluded when it
+/// matches every condition the rule sets.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ExcludeRule {
+    /// Gitignore-style globs; the rule covers every file when empty
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// Only files larger than this many bytes
+    #[serde(default)]
+    pub max_size: Option<u64>,
+}
+
+impl ExcludeRule {
+    pub(crate) fn describe(&self) -> String {
+        match (self.patterns.is_empty(), self.max_size) {
+            (false, Some(max_size)) => {
+                format!("{} over {} bytes", self.patterns.join(", "), max_size)
+            }
+            (false, None) => self.patterns.join(", "),
+            (true, Some(max_size)) => format!("files over {} bytes", max_size),
+            (true, None) => "every file".to_string(),
+        }
+    }
+}
+
+pub(crate) fn default_max_file_size() -> u64 {
+    5 * 1024 * 1024
+}
+
+/// Conditions an unattended scheduled sync must meet before it runs
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ScheduleConfig {
+    /// Only sync when the remote is reachable
+    #[serde(default)]
+    pub require_network: bool,
+    /// Skip syncs while running on battery
+    #[serde(default)]
+    pub require_ac_power: bool,
+    /// Only sync after the user has been idle this long
+    pub min_idle_minutes: Option<u64>,
+}
+
+/// The `[daemon]` section
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DaemonConfig {
+    /// Opt-in HTTP endpoint serving /health and /metrics, e.g. "127.0.0.1:9464"
+    pub metrics_address: Option<String>,
+    /// File events arriving within this window are coalesced into one sync
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Sync outstanding changes before exiting on Ctrl-C/SIGTERM instead of
+    /// deferring them to the next start
+    #[serde(default)]
+    pub sync_on_shutdown: bool,
+    /// Run `git gc` on the repository after a sync at most this often; 0
+    /// disables automatic maintenance
+    #[serde(default = "default_maintenance_interval_hours")]
+    pub maintenance_interval_hours: u64,
+}
+
+pub(crate) fn default_maintenance_interval_hours() -> u64 {
+    24 * 7
+}
+
+pub(crate) fn default_debounce_ms() -> u64 {
+    2000
+}
+
+/// The `[notifications]` section
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NotificationsConfig {
+    /// Every webhook to notify
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+/// A webhook notified about sync results
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebhookConfig {
+    /// http(s) URL the notification is POSTed to
+    pub url: String,
+    /// Payload format
+    #[serde(default)]
+    pub kind: WebhookKind,
+    /// An empty list means the webhook receives every event
+    #[serde(default)]
+    pub events: Vec<NotificationEvent>,
+}
+
+/// Payload formats for webhooks
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookKind {
+    Slack,
+    Discord,
+    #[default]
+    Generic,
+}
+
+/// Events webhooks can subscribe to
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    SyncSuccess,
+    SyncFailure,
+    RemoteDivergence,
+}
+
+impl NotificationEvent {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            NotificationEvent::SyncSuccess => "sync_success",
+            NotificationEvent::SyncFailure => "sync_failure",
+            NotificationEvent::RemoteDivergence => "remote_divergence",
+        }
+    }
+}
+
+/// The `[profile_detection]` section
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProfileDetectionConfig {
+    /// Checked in order; the first matching rule wins
+    pub rules: Vec<ProfileDetectionRule>,
+}
+
+/// Selects `profile` when every condition holds
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProfileDetectionRule {
+    /// The profile to use
+    pub profile: String,
+    /// All of these must match
+    pub conditions: Vec<DetectionCondition>,
+}
+
+/// A fact about the machine a detection rule can test
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum DetectionCondition {
+    Hostname(String),
+    OS(String),
+    EnvVar {
+        name: String,
+        value: String,
+    },
+    /// true matches inside a WSL distro, false only outside one
+    Wsl(bool),
+    /// true matches inside a Docker/Podman/LXC or dev container
+    Container(bool),
+    /// Asks a detection plugin, passing it `args`
+    Plugin {
+        name: String,
+        #[serde(default)]
+        args: BTreeMap<String, String>,
+    },
+}
+
+impl Config {
+    // Where a profile's entry is stored in the repository
+    pub(crate) fn repo_key(&self, profile: &str, key: &str) -> String {
+        let shared = self
+            .profiles
+            .get(profile)
+            .and_then(|profile_config| profile_config.files.get(key))
+            .is_some_and(FileEntry::shared);
+        self.layout_key(profile, key, shared)
+    }
+
+    pub(crate) fn layout_key(&self, profile: &str, key: &str, shared: bool) -> String {
+        match self.repo_layout {
+            RepoLayout::Flat => key.to_string(),
+            RepoLayout::Profiles if shared => format!("shared/{}", key),
+            RepoLayout::Profiles => format!("profiles/{}/{}", profile, key),
+        }
+    }
+
+    // Every place a key may be stored in the repository, under any layout
+    pub(crate) fn repo_key_candidates(&self, key: &str) -> Vec<String> {
+        let mut candidates = vec![key.to_string(), format!("shared/{}", key)];
+        candidates.extend(
+            self.profiles
+                .keys()
+                .map(|profile| format!("profiles/{}/{}", profile, key)),
+        );
+        candidates
+    }
+
+    // Where this machine reads and writes an entry in the repository: the
+    // most specific of its overlays that `exists`, or its own path
+    pub(crate) fn overlay_key(
+        &self,
+        profile: &str,
+        key: &str,
+        exists: impl Fn(&str) -> bool,
+    ) -> String {
+        let repo_key = self.repo_key(profile, key);
+        overlay_suffixes()
+            .iter()
+            .map(|suffix| format!("{}.{}", repo_key, suffix))
+            .find(|overlay| exists(overlay))
+            .unwrap_or(repo_key)
+    }
+
+    // Looks an entry (or this machine's overlay of it) up in a commit, falling
+    // back to where the flat layout kept it so revisions from before a layout
+    // change still resolve
+    pub(crate) fn tree_entry<'t>(
+        &self,
+        tree: &'t git2::Tree,
+        profile: &str,
+        key: &str,
+    ) -> Option<git2::TreeEntry<'t>> {
+        let repo_key =
+            self.overlay_key(profile, key, |path| tree.get_path(Path::new(path)).is_ok());
+        tree.get_path(Path::new(&repo_key))
+            .or_else(|_| tree.get_path(Path::new(key)))
+            .ok()
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.remote.github_repo.is_empty() {
+            return Err(DottyError::new(
+                ErrorKind::Config,
+                "GitHub repository URL is missing in the configuration",
+            )
+            .hint("set one with `dotty remote set <url>`")
+            .into());
+        }
+        // Backends authenticate themselves
+        if self.remote.auth == RemoteAuth::Token
+            && self.remote.github_token.is_empty()
+            && self.remote.backend.is_none()
+        {
+            return Err(
+                DottyError::new(ErrorKind::Config, "GitHub token is missing in the configuration")
+                    .hint("run `dotty token set` or `dotty login`, or switch to `dotty remote set --auth ssh`")
+                    .into(),
+            );
+        }
+        if self.remote.auth == RemoteAuth::DeployKey && self.remote.ssh_key.is_none() {
+            return Err(DottyError::new(
+                ErrorKind::Config,
+                "remote.ssh_key is needed for deploy key authentication",
+            )
+            .hint("run `dotty remote set --auth deploy-key --key <path>`")
+            .into());
+        }
+        if let Some(problem) = self.problems().into_iter().next() {
+            return Err(DottyError::new(ErrorKind::Config, problem.message)
+                .hint(LINT_HINT)
+                .into());
+        }
+        Ok(())
+    }
+}
+
+const LINT_HINT: &str = "run `dotty config lint` to list every problem with its line";
+
+pub(crate) fn config_path() -> Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .context("Failed to get config directory")?
+        .join("dotty")
+        .join("config.toml"))
+}
+
+impl Config {
+    // Every invalid value, with where it is; validate() fails on the first
+    pub(crate) fn problems(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        if self.sync_interval == 0 {
+            problems.push(Problem::new(
+                field(&["sync_interval"]),
+                "Sync interval must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(notifications) = &self.notifications {
+            for (index, webhook) in notifications.webhooks.iter().enumerate() {
+                if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                    let mut path = field(&["notifications", "webhooks"]);
+                    path.extend([Field::Index(index), Field::key("url")]);
+                    problems.push(Problem::new(
+                        path,
+                        format!("Invalid webhook URL in notifications: {}", webhook.url),
+                    ));
+                }
+            }
+        }
+        let check_pattern = |problems: &mut Vec<Problem>, path: Vec<Field>, pattern: &str| {
+            if let Err(e) = ignore::gitignore::GitignoreBuilder::new("/").add_line(None, pattern) {
+                problems.push(Problem::new(
+                    path,
+                    format!("Invalid ignore pattern: {} ({})", pattern, e),
+                ));
+            }
+        };
+        let check_excludes =
+            |problems: &mut Vec<Problem>, base: Vec<Field>, rules: &[ExcludeRule]| {
+                for (index, rule) in rules.iter().enumerate() {
+                    let mut path = base.clone();
+                    path.push(Field::Index(index));
+                    // A rule without conditions would empty every tracked directory
+                    if rule.patterns.is_empty() && rule.max_size.is_none() {
+                        problems.push(Problem::new(
+                            path.clone(),
+                            "Exclude rule needs patterns, max_size or both".to_string(),
+                        ));
+                    }
+                    for (pattern_index, pattern) in rule.patterns.iter().enumerate() {
+                        let mut path = path.clone();
+                        path.extend([Field::key("patterns"), Field::Index(pattern_index)]);
+                        check_pattern(problems, path, pattern);
+                    }
+                }
+            };
+        for (index, pattern) in self.ignore_patterns.iter().enumerate() {
+            let mut path = field(&["ignore_patterns"]);
+            path.push(Field::Index(index));
+            check_pattern(&mut problems, path, pattern);
+        }
+        check_excludes(&mut problems, field(&["exclude"]), &self.exclude);
+        let mut names: Vec<&String> = self.profiles.keys().collect();
+        names.sort();
+        for name in names {
+            let profile = &self.profiles[name];
+            for (index, pattern) in profile.ignore_patterns.iter().enumerate() {
+                let mut path = field(&["profiles", name, "ignore_patterns"]);
+                path.push(Field::Index(index));
+                check_pattern(&mut problems, path, pattern);
+            }
+            check_excludes(
+                &mut problems,
+                field(&["profiles", name, "exclude"]),
+                &profile.exclude,
+            );
+            if let Err(cycle) = profile.sync_order() {
+                problems.push(Problem::new(
+                    field(&["profiles", name, "files", cycle[0], "after"]),
+                    dependency_cycle(&cycle),
+                ));
+            }
+            for alias in profile.aliases.keys() {
+                if alias.is_empty()
+                    || !alias
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+                {
+                    problems.push(Problem::new(
+                        field(&["profiles", name, "aliases", alias]),
+                        format!("Invalid alias name {:?} in profile {}", alias, name),
+                    ));
+                }
+            }
+            for variable in profile.env.keys() {
+                if variable.is_empty()
+                    || variable.starts_with(|c: char| c.is_ascii_digit())
+                    || !variable
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    problems.push(Problem::new(
+                        field(&["profiles", name, "env", variable]),
+                        format!(
+                            "Invalid environment variable name {:?} in profile {}",
+                            variable, name
+                        ),
+                    ));
+                }
+            }
+            let mut keys: Vec<&String> = profile.files.keys().collect();
+            keys.sort();
+            for relative_path in keys {
+                let FileEntry::Detailed(options) = &profile.files[relative_path] else {
+                    continue;
+                };
+                for (index, pattern) in options.include_patterns.iter().enumerate() {
+                    let mut path = field(&["profiles", name, "files", relative_path]);
+                    path.extend([Field::key("include_patterns"), Field::Index(index)]);
+                    check_pattern(&mut problems, path, pattern);
+                }
+                if let Some(permissions) = &options.permissions {
+                    if let Err(e) = parse_permissions(permissions) {
+                        problems.push(Problem::new(
+                            field(&["profiles", name, "files", relative_path, "permissions"]),
+                            format!(
+                                "Invalid permissions for {} in profile {}: {:#}",
+                                relative_path, name, e
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+        if let Some(address) = self
+            .daemon
+            .as_ref()
+            .and_then(|daemon| daemon.metrics_address.as_ref())
+        {
+            let path = field(&["daemon", "metrics_address"]);
+            match address.parse::<std::net::SocketAddr>() {
+                Err(_) => problems.push(Problem::new(
+                    path,
+                    format!("Invalid daemon metrics address: {}", address),
+                )),
+                Ok(address) if !address.ip().is_loopback() => problems.push(Problem::new(
+                    path,
+                    "Daemon metrics address must be a loopback address".to_string(),
+                )),
+                Ok(_) => {}
+            }
+        }
+        problems
+    }
+}
+
+/// One step of the path to a value in config.toml
+#[derive(Clone, PartialEq, Debug)]
+pub enum Field {
+    Key(String),
+    Index(usize),
+}
+
+impl Field {
+    pub(crate) fn key(key: &str) -> Self {
+        Field::Key(key.to_string())
+    }
+}
+
+pub(crate) fn field(keys: &[&str]) -> Vec<Field> {
+    keys.iter().map(|key| Field::key(key)).collect()
+}
+
+/// Renders a path the way it would be written in TOML, e.g.
+/// `profiles.default.files.".bashrc".permissions`
+pub fn field_path(path: &[Field]) -> String {
+    let mut rendered = String::new();
+    for field in path {
+        match field {
+            Field::Index(index) => rendered.push_str(&format!("[{}]", index)),
+            Field::Key(key) => {
+                if !rendered.is_empty() {
+                    rendered.push('.');
+                }
+                if !key.is_empty()
+                    && key
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+                {
+                    rendered.push_str(key);
+                } else {
+                    rendered.push_str(&format!("{:?}", key));
+                }
+            }
+        }
+    }
+    rendered
+}
+
+/// An invalid value in the config and where it is
+pub struct Problem {
+    /// The value's path from the top of config.toml
+    pub path: Vec<Field>,
+    /// What's wrong with it
+    pub message: String,
+}
+
+impl Problem {
+    pub(crate) fn new(path: Vec<Field>, message: String) -> Self {
+        Problem { path, message }
+    }
+}
+
+impl Dotty {
+    pub(crate) fn new() -> Result<Self> {
+        Self::load(true)
+    }
+
+    /// Reads config.toml, writing a default one on first run, and detects the
+    /// profile. With `validate`, the config must be complete enough to sync;
+    /// tokens kept in the keyring are only read then.
+    pub fn load(validate: bool) -> Result<Self> {
+        let config_path = config_path()?;
+        if let Some(config_dir) = config_path.parent() {
+            fs::create_dir_all(config_dir).context("Failed to create config directory")?;
+        }
+
+        let config = if config_path.exists() {
+            let config_str = fs::read_to_string(&config_path).with_context(|| {
+                DottyError::new(ErrorKind::Config, "Failed to read config file")
+                    .hint(format!("check that {} is readable", config_path.display()))
+            })?;
+            let mut config: Config = toml::from_str(&config_str).with_context(|| {
+                DottyError::new(ErrorKind::Config, "Failed to parse config file").hint(LINT_HINT)
+            })?;
+            if validate {
+                // Commands that don't touch the remote skip the keyring, which
+                // may ask to be unlocked
+                if config.remote.token_store == TokenStore::Keyring {
+                    load_keyring_token(&mut config.remote)?;
+                }
+                config.validate()?;
+            }
+            config
+        } else {
+            let default_config = Config {
+                profiles: HashMap::from([(
+                    "default".to_string(),
+                    ProfileConfig {
+                        files: BTreeMap::new(),
+                        ignore_patterns: vec![".git".to_string(), ".gitignore".to_string()],
+                        exclude: Vec::new(),
+                        use_symlinks: false,
+                        mode: None,
+                        line_endings: None,
+                        preserve_xattrs: false,
+                        aliases: BTreeMap::new(),
+                        env: BTreeMap::new(),
+                    },
+                )]),
+                remote: RemoteConfig {
+                    github_repo: String::new(),
+                    github_token: String::new(),
+                    clone_depth: default_clone_depth(),
+                    partial_clone: false,
+                    local_path: None,
+                    branch_per_host: false,
+                    branch: None,
+                    auth: RemoteAuth::default(),
+                    token_store: TokenStore::default(),
+                    oauth_client_id: None,
+                    token_expires_at: None,
+                    ssh_key: None,
+                    proxy: None,
+                    backend: None,
+                    secret_provider: None,
+                },
+                sync_interval: 300,
+                profile_detection: None,
+                notifications: None,
+                daemon: None,
+                schedule: None,
+                audit: false,
+                script_commands: false,
+                elevate_command: None,
+                ignore_patterns: Vec::new(),
+                exclude: Vec::new(),
+                max_file_size: default_max_file_size(),
+                repo_layout: RepoLayout::default(),
+                conflict_strategy: ConflictStrategy::default(),
+            };
+            let config_str = toml::to_string_pretty(&default_config)
+                .context("Failed to serialize default config")?;
+            fs::write(&config_path, config_str).context("Failed to write default config file")?;
+            default_config
+        };
+
+        let mut dotty = Dotty {
+            config,
+            config_path,
+            current_profile: String::new(), // We'll set this in a moment
+            last_synced: SystemTime::now(),
+            stats: Arc::new(Mutex::new(SyncStats::default())),
+            porcelain: false,
+            force: false,
+            script: OnceLock::new(),
+        };
+
+        // Set the current profile based on automatic detection
+        dotty.current_profile = dotty.detect_profile();
+
+        Ok(dotty)
+    }
+
+    /// Writes the config back to config.toml, keeping tokens out of it when
+    /// they live in the keyring
+    pub fn save_config(&self) -> Result<()> {
+        let mut config = self.config.clone();
+        if config.remote.token_store == TokenStore::Keyring {
+            config.remote.github_token = String::new();
+        }
+        let config_str = toml::to_string_pretty(&config).context("Failed to serialize config")?;
+        fs::write(&self.config_path, config_str).context("Failed to write config file")?;
+        Ok(())
+    }
+
+    pub(crate) fn portable_config(&self) -> Result<String> {
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let mut config = self.config.clone();
+        config.remote.github_token = String::new();
+        config.remote.local_path = None;
+        // Webhook URLs carry their own tokens
+        config.notifications = None;
+        config.script_commands = false;
+        for profile_config in config.profiles.values_mut() {
+            for entry in profile_config.files.values_mut() {
+                if let Ok(rest) = Path::new(entry.source()).strip_prefix(&home) {
+                    entry.set_source(format!("~/{}", rest.to_string_lossy()));
+                }
+            }
+        }
+        toml::to_string_pretty(&config).context("Failed to serialize config")
+    }
+
+    // Global patterns come first so a profile can re-include with `!pattern`,
+    // and a tracked directory's .dottyignore last. `root` is either a tracked
+    // directory (whose include_patterns, .dottyignore and exclude rules
+    // apply) or the parent of a tracked entry.
+    pub(crate) fn ignore_rules(&self, root: &Path, profile_config: &ProfileConfig) -> IgnoreRules {
+        let entry = profile_config.entry_for_source(root);
+        let ignore_file = entry
+            .map(|_| root.join(DOTTYIGNORE))
+            .filter(|path| path.is_file());
+        let rules = IgnoreRules::new(
+            root,
+            self.config
+                .ignore_patterns
+                .iter()
+                .chain(&profile_config.ignore_patterns),
+            ignore_file.as_deref(),
+            entry.map_or(&[], FileEntry::include_patterns),
+        );
+        match entry {
+            Some(_) => {
+                rules.with_excludes(self.config.exclude.iter().chain(&profile_config.exclude))
+            }
+            None => rules,
+        }
+    }
+
+    // Whether a path at or below a tracked source is ignored. A tracked entry
+    // itself is matched by name; paths inside a tracked directory are matched
+    // relative to it, so `/build` only ignores its top-level build directory.
+    pub(crate) fn is_ignored(
+        &self,
+        source: &Path,
+        path: &Path,
+        profile_config: &ProfileConfig,
+    ) -> bool {
+        let root = if path != source && source.is_dir() {
+            source
+        } else {
+            source.parent().unwrap_or(source)
+        };
+        self.ignore_rules(root, profile_config).is_ignored(path)
+    }
+
+    pub(crate) fn ignore_patterns_mut(
+        &mut self,
+        profile: Option<&str>,
+    ) -> Result<&mut Vec<String>> {
+        Ok(match profile {
+            Some(profile) => {
+                &mut self
+                    .config
+                    .profiles
+                    .get_mut(profile)
+                    .with_context(|| profile_not_found(profile))?
+                    .ignore_patterns
+            }
+            None => &mut self.config.ignore_patterns,
+        })
+    }
+
+    /// Adds ignore patterns to a profile, or to the list shared by every profile
+    pub fn add_ignore_patterns(
+        &mut self,
+        patterns: &[String],
+        profile: Option<String>,
+    ) -> Result<()> {
+        for pattern in patterns {
+            ignore::gitignore::GitignoreBuilder::new("/")
+                .add_line(None, pattern)
+                .with_context(|| format!("Invalid ignore pattern: {}", pattern))?;
+        }
+        let scope = ignore_scope(profile.as_deref());
+        let ignore_patterns = self.ignore_patterns_mut(profile.as_deref())?;
+        for pattern in patterns {
+            if ignore_patterns.contains(pattern) {
+                println!("{} is already ignored {}", pattern, scope);
+            } else {
+                ignore_patterns.push(pattern.clone());
+                println!("Ignoring {} {}", pattern, scope);
+            }
+        }
+        self.save_config()
+    }
+
+    /// Removes ignore patterns added with [`Dotty::add_ignore_patterns`]
+    pub fn remove_ignore_patterns(
+        &mut self,
+        patterns: &[String],
+        profile: Option<String>,
+    ) -> Result<()> {
+        let scope = ignore_scope(profile.as_deref());
+        let ignore_patterns = self.ignore_patterns_mut(profile.as_deref())?;
+        for pattern in patterns {
+            match ignore_patterns
+                .iter()
+                .position(|existing| existing == pattern)
+            {
+                Some(position) => {
+                    ignore_patterns.remove(position);
+                    println!("No longer ignoring {} {}", pattern, scope);
+                }
+                None => println!("{} is not in the ignore patterns {}", pattern, scope),
+            }
+        }
+        self.save_config()
+    }
+
+    /// Prints the ignore patterns that apply to a profile and where each comes
+    /// from
+    pub fn list_ignore_patterns(&self, profile: Option<String>) -> Result<()> {
+        let print = |title: String, patterns: &[String], excludes: &[ExcludeRule]| {
+            println!("{}", title.bold());
+            if patterns.is_empty() && excludes.is_empty() {
+                println!("  (none)");
+            }
+            for pattern in patterns {
+                println!("  {}", pattern);
+            }
+            for exclude in excludes {
+                println!("  {} (exclude rule)", exclude.describe());
+            }
+        };
+        print(
+            "All profiles".to_string(),
+            &self.config.ignore_patterns,
+            &self.config.exclude,
+        );
+        let mut profiles: Vec<&String> = match &profile {
+            Some(profile) => vec![
+                self.config
+                    .profiles
+                    .get_key_value(profile)
+                    .with_context(|| profile_not_found(profile))?
+                    .0,
+            ],
+            None => self.config.profiles.keys().collect(),
+        };
+        profiles.sort();
+        for profile in profiles {
+            print(
+                format!("Profile {}", profile),
+                &self.config.profiles[profile].ignore_patterns,
+                &self.config.profiles[profile].exclude,
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks a path the way a sync would: inside a tracked directory it is
+    /// matched relative to that directory's source, otherwise relative to
+    /// its parent, as a newly added file would be
+    pub fn test_ignore(&self, path: &Path, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let absolute = env::current_dir()
+            .context("Failed to get current directory")?
+            .join(path);
+
+        let tracked = profile_config.files.iter().find_map(|(key, entry)| {
+            let source = PathBuf::from(entry.source());
+            let dest = profile_config.destination(&home, key);
+            absolute
+                .strip_prefix(&dest)
+                .or_else(|_| absolute.strip_prefix(&source))
+                .ok()
+                .map(|relative| (key, source.join(relative), source))
+        });
+        let (path, root) = match &tracked {
+            Some((_, path, source)) if path != source => (path.clone(), source.clone()),
+            _ => (
+                absolute.clone(),
+                absolute.parent().unwrap_or(&absolute).to_path_buf(),
+            ),
+        };
+        let rules = self.ignore_rules(&root, profile_config);
+        let ignored = rules.is_ignored(&path);
+        if let Some((key, _, _)) = &tracked {
+            println!("Tracked under {} in profile {}", key, profile);
+        }
+        if !ignored {
+            println!("{} is {}", absolute.display(), "not ignored".green());
+            return Ok(());
+        }
+        println!("{} is {}", absolute.display(), "ignored".yellow());
+
+        let is_dir = path.is_dir();
+        let matching: Vec<String> = self
+            .config
+            .ignore_patterns
+            .iter()
+            .map(|pattern| (pattern, "all profiles".to_string()))
+            .chain(
+                profile_config
+                    .ignore_patterns
+                    .iter()
+                    .map(|pattern| (pattern, format!("profile {}", profile))),
+            )
+            .filter(|(pattern, _)| {
+                build_matcher(&root, std::iter::once(*pattern), None)
+                    .matched_path_or_any_parents(&path, is_dir)
+                    .is_ignore()
+            })
+            .map(|(pattern, scope)| format!("{} ({})", pattern, scope))
+            .chain(
+                rules
+                    .excludes
+                    .iter()
+                    .filter(|exclude| !is_dir && exclude.matches(&path))
+                    .map(|exclude| format!("exclude rule {}", exclude.description)),
+            )
+            .collect();
+        if matching.is_empty() {
+            println!(
+                "  by the directory's {} or its entry's include_patterns",
+                DOTTYIGNORE
+            );
+        }
+        for pattern in matching {
+            println!("  matched by {}", pattern);
+        }
+        Ok(())
+    }
+}
+
+/// Gitignore-style patterns (globs, `/` anchoring, `!` negation) matched
+/// against paths below `root`. When include patterns are given, files they
+/// don't match are ignored before the ignore patterns are consulted.
+pub(crate) struct IgnoreRules {
+    pub(crate) root: PathBuf,
+    pub(crate) matcher: ignore::gitignore::Gitignore,
+    pub(crate) includes: Option<ignore::gitignore::Gitignore>,
+    pub(crate) excludes: Vec<ExcludeMatcher>,
+}
+
+// An ExcludeRule compiled against a tracked directory
+pub(crate) struct ExcludeMatcher {
+    pub(crate) patterns: Option<ignore::gitignore::Gitignore>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) description: String,
+}
+
+impl ExcludeMatcher {
+    pub(crate) fn matches(&self, path: &Path) -> bool {
+        self.patterns.as_ref().is_none_or(|patterns| {
+            patterns
+                .matched_path_or_any_parents(path, false)
+                .is_ignore()
+        }) && self.max_size.is_none_or(|max_size| {
+            fs::metadata(path).is_ok_and(|metadata| metadata.len() > max_size)
+        })
+    }
+}
+
+impl IgnoreRules {
+    pub(crate) fn new<'a>(
+        root: &Path,
+        patterns: impl Iterator<Item = &'a String>,
+        ignore_file: Option<&Path>,
+        include_patterns: &[String],
+    ) -> Self {
+        IgnoreRules {
+            root: root.to_path_buf(),
+            matcher: build_matcher(root, patterns, ignore_file),
+            includes: (!include_patterns.is_empty())
+                .then(|| build_matcher(root, include_patterns.iter(), None)),
+            excludes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_excludes<'a>(
+        mut self,
+        rules: impl Iterator<Item = &'a ExcludeRule>,
+    ) -> Self {
+        for rule in rules {
+            self.excludes.push(ExcludeMatcher {
+                patterns: (!rule.patterns.is_empty())
+                    .then(|| build_matcher(&self.root, rule.patterns.iter(), None)),
+                max_size: rule.max_size,
+                description: rule.describe(),
+            });
+        }
+        self
+    }
+
+    pub(crate) fn is_ignored(&self, path: &Path) -> bool {
+        if path == self.root || !path.starts_with(&self.root) {
+            return false;
+        }
+        let is_dir = path.is_dir();
+        // Directories are always walked into; their files decide for themselves.
+        // The .dottyignore is kept so it travels with the directory.
+        let excluded = self.includes.as_ref().is_some_and(|includes| {
+            !is_dir
+                && path != self.root.join(DOTTYIGNORE)
+                && !includes
+                    .matched_path_or_any_parents(path, false)
+                    .is_ignore()
+        });
+        excluded
+            || self
+                .matcher
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
+            || !is_dir
+                && path != self.root.join(DOTTYIGNORE)
+                && self.excludes.iter().any(|exclude| exclude.matches(path))
+    }
+}
+
+pub(crate) const DOTTYIGNORE: &str = ".dottyignore";
+
+pub(crate) fn ignore_scope(profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("in profile {}", profile),
+        None => "in every profile".to_string(),
+    }
+}
+
+pub(crate) fn build_matcher<'a>(
+    root: &Path,
+    patterns: impl Iterator<Item = &'a String>,
+    ignore_file: Option<&Path>,
+) -> ignore::gitignore::Gitignore {
+    let mut builder = ignore::gitignore::GitignoreBuilder::new(root);
+    for pattern in patterns {
+        if let Err(e) = builder.add_line(None, pattern) {
+            warn!("Skipping invalid pattern {:?}: {}", pattern, e);
+        }
+    }
+    if let Some(e) = ignore_file.and_then(|ignore_file| builder.add(ignore_file)) {
+        warn!("Problem reading {}: {}", DOTTYIGNORE, e);
+    }
+    builder.build().unwrap_or_else(|e| {
+        warn!("Failed to build ignore rules: {}", e);
+        ignore::gitignore::Gitignore::empty()
+    })
+}
@@ -0,0 +1,1164 @@
+//! The watch daemon, its control socket, metrics and schedules.
+
+use crate::config::default_debounce_ms;
+use crate::error::{profile_not_found, DottyError, ErrorKind};
+use crate::sync::{FreezeState, SyncTrigger};
+use crate::util::{state_dir, unix_seconds};
+use crate::Dotty;
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fs, process, thread};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+#[derive(Default)]
+pub(crate) struct SyncStats {
+    pub(crate) started_at: Option<SystemTime>,
+    pub(crate) profile: String,
+    pub(crate) paused: bool,
+    pub(crate) syncs_total: u64,
+    pub(crate) sync_failures_total: u64,
+    pub(crate) files_synced_total: u64,
+    pub(crate) conflicts_total: u64,
+    pub(crate) push_failures_total: u64,
+    pub(crate) last_sync: Option<SystemTime>,
+    pub(crate) last_sync_ok: bool,
+    // Pushes are held back until then after the remote rate-limited one;
+    // the backoff doubles each time it happens again
+    pub(crate) rate_limited_until: Option<SystemTime>,
+    pub(crate) rate_limit_backoff: Duration,
+}
+
+impl SyncStats {
+    pub(crate) fn health_json(&self) -> serde_json::Value {
+        let status = if self.last_sync.is_none() || self.last_sync_ok {
+            "ok"
+        } else {
+            "degraded"
+        };
+        serde_json::json!({
+            "status": status,
+            "profile": self.profile,
+            "paused": self.paused,
+            "uptime_seconds": self.started_at.and_then(|t| t.elapsed().ok()).unwrap_or_default().as_secs(),
+            "last_sync": self.last_sync.map(unix_seconds),
+            "last_sync_ok": self.last_sync_ok,
+            "files_synced_total": self.files_synced_total,
+            "conflicts_total": self.conflicts_total,
+            "push_failures_total": self.push_failures_total,
+            "rate_limited_until": self.rate_limited_until.map(unix_seconds),
+        })
+    }
+
+    pub(crate) fn prometheus(&self) -> String {
+        let metrics = [
+            (
+                "dotty_syncs_total",
+                "counter",
+                "Sync runs started",
+                self.syncs_total,
+            ),
+            (
+                "dotty_sync_failures_total",
+                "counter",
+                "Sync runs that failed",
+                self.sync_failures_total,
+            ),
+            (
+                "dotty_files_synced_total",
+                "counter",
+                "Files written to destinations",
+                self.files_synced_total,
+            ),
+            (
+                "dotty_conflicts_total",
+                "counter",
+                "Sync conflicts detected",
+                self.conflicts_total,
+            ),
+            (
+                "dotty_push_failures_total",
+                "counter",
+                "Failed pushes to the remote",
+                self.push_failures_total,
+            ),
+            (
+                "dotty_last_sync_timestamp_seconds",
+                "gauge",
+                "Unix time of the last sync",
+                self.last_sync.map(unix_seconds).unwrap_or(0),
+            ),
+            (
+                "dotty_last_sync_success",
+                "gauge",
+                "Whether the last sync succeeded",
+                self.last_sync_ok as u64,
+            ),
+            (
+                "dotty_paused",
+                "gauge",
+                "Whether syncing is paused",
+                self.paused as u64,
+            ),
+        ];
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            output.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name, help, name, kind, name, value
+            ));
+        }
+        output
+    }
+}
+
+impl Dotty {
+    pub(crate) fn watch_profile(
+        &self,
+        profile: &str,
+        tx: UnboundedSender<DaemonMessage>,
+    ) -> Result<RecommendedWatcher> {
+        let profile_config = self
+            .config
+            .profiles
+            .get(profile)
+            .with_context(|| profile_not_found(profile))?;
+
+        let (notify_tx, notify_rx) = channel();
+        let mut watcher =
+            watcher(notify_tx, Duration::from_secs(1)).context("Failed to create watcher")?;
+
+        for (_, path) in profile_config.sources() {
+            let mode = if Path::new(path).is_dir() {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            watcher.watch(path, mode).context("Failed to watch path")?;
+        }
+        // Watch the directory rather than the file so editors that replace
+        // config.toml on save don't silently drop the watch
+        if let Some(config_dir) = self.config_path.parent() {
+            watcher
+                .watch(config_dir, RecursiveMode::NonRecursive)
+                .context("Failed to watch config directory")?;
+        }
+
+        // The forwarding thread ends once the watcher (and its sender) is dropped
+        thread::spawn(move || {
+            for event in notify_rx {
+                if tx.send(DaemonMessage::FileEvent(event)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(watcher)
+    }
+
+    pub(crate) fn is_config_event(&self, event: &DebouncedEvent) -> bool {
+        match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Rename(_, path) => path == &self.config_path,
+            _ => false,
+        }
+    }
+
+    // Re-reads config.toml and re-registers watches. On failure the running
+    // configuration is left untouched.
+    pub(crate) fn reload_config(
+        &mut self,
+        profile: &mut String,
+        tx: &UnboundedSender<DaemonMessage>,
+    ) -> Result<RecommendedWatcher> {
+        let mut dotty = Dotty::new()?;
+        dotty.last_synced = self.last_synced;
+        dotty.stats = self.stats.clone();
+        if !dotty.config.profiles.contains_key(profile.as_str()) {
+            *profile = dotty.current_profile.clone();
+        }
+        let watcher = dotty.watch_profile(profile, tx.clone())?;
+        *self = dotty;
+        self.stats.lock().unwrap().profile = profile.clone();
+        info!("Configuration reloaded");
+        Ok(watcher)
+    }
+
+    pub(crate) fn debounce_window(&self) -> Duration {
+        let debounce_ms = self
+            .config
+            .daemon
+            .as_ref()
+            .map_or(default_debounce_ms(), |daemon| daemon.debounce_ms);
+        Duration::from_millis(debounce_ms)
+    }
+
+    /// Watches the profile's sources and syncs on changes until stopped, serving
+    /// the control socket (and metrics, when configured) meanwhile
+    pub fn run_daemon(&mut self, profile: Option<String>) -> Result<()> {
+        runtime()?.block_on(self.daemon_loop(profile))
+    }
+
+    // File events, control requests, metrics scrapes and syncs each run as
+    // their own task, so a slow push never holds up the others
+    async fn daemon_loop(&mut self, profile: Option<String>) -> Result<()> {
+        let mut profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let listener = control_listener()?;
+        let (tx, mut rx) = unbounded_channel();
+
+        // A client that connects and never writes only ties up its own task
+        let control_tx = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Control socket error: {}", e);
+                        continue;
+                    }
+                };
+                let control_tx = control_tx.clone();
+                tokio::spawn(async move {
+                    match read_daemon_request(stream).await {
+                        Ok((stream, request)) => {
+                            let _ = control_tx.send(DaemonMessage::Control(stream, request));
+                        }
+                        Err(e) => warn!("Invalid control request: {:#}", e),
+                    }
+                });
+            }
+        });
+
+        let shutdown = install_shutdown_handler(Some(tx.clone()))?;
+        let mut _watcher = self.watch_profile(&profile, tx.clone())?;
+        let sync_tx = spawn_sync_worker(tx.clone());
+        let queue_sync = |job: SyncJob| {
+            sync_tx
+                .send(job)
+                .map_err(|_| anyhow::anyhow!("The sync worker stopped"))
+        };
+        let mut paused = false;
+        let mut pending = false;
+        // Whether changes are being held because syncing is frozen
+        let mut frozen = false;
+        // Syncs queued on the worker and not finished yet
+        let mut syncing = 0;
+        // How long to wait before trying failed changes again; doubles with
+        // every failure in a row
+        let mut retry_delay = Duration::ZERO;
+
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.started_at = Some(SystemTime::now());
+            stats.profile = profile.clone();
+        }
+        if let Some(address) = self
+            .config
+            .daemon
+            .as_ref()
+            .and_then(|daemon| daemon.metrics_address.clone())
+        {
+            serve_metrics(&address, self.stats.clone()).await?;
+        }
+
+        info!(
+            "Daemon watching for changes in profile {}. Press Ctrl-C to stop.",
+            profile
+        );
+
+        let mut sync_due: Option<Instant> = None;
+        // Entries changed during the current debounce window; a full sync is
+        // used when an event can't be attributed to a single entry.
+        let mut changed_files = HashSet::new();
+        let mut full_sync = false;
+
+        // Pick up changes that were still waiting when the last daemon exited
+        let state = DaemonState::load()?;
+        if state.full_sync || !state.pending_files.is_empty() {
+            info!("Resuming changes left over from the previous run");
+            changed_files.extend(state.pending_files);
+            full_sync = state.full_sync;
+            sync_due = Some(Instant::now());
+        }
+
+        while !shutdown.load(Ordering::SeqCst) {
+            let message = match sync_due {
+                Some(due) => tokio::select! {
+                    message = rx.recv() => Some(message.context("Daemon channel closed")?),
+                    _ = tokio::time::sleep_until(due.into()) => None,
+                },
+                None => Some(rx.recv().await.context("Daemon channel closed")?),
+            };
+
+            let Some(message) = message else {
+                sync_due = None;
+                let freeze = FreezeState::load().unwrap_or_else(|e| {
+                    warn!("Failed to read freeze state: {:#}", e);
+                    None
+                });
+                if paused {
+                    changed_files.clear();
+                    pending = true;
+                } else if let Some(freeze) = freeze {
+                    // Keep the changes and look again until `dotty thaw`
+                    if !std::mem::replace(&mut frozen, true) {
+                        info!("{}; holding changes until it's thawed", freeze.describe());
+                    }
+                    sync_due = Some(Instant::now() + FREEZE_POLL_INTERVAL);
+                } else if syncing == 0 {
+                    if std::mem::take(&mut frozen) {
+                        info!("Syncing thawed, syncing the changes held while frozen");
+                    }
+                    // Otherwise the changes wait for the running sync to finish
+                    let files = std::mem::take(&mut changed_files);
+                    info!("Change detected, syncing...");
+                    queue_sync(SyncJob {
+                        dotty: self.clone(),
+                        profile: profile.clone(),
+                        files: (!std::mem::take(&mut full_sync)).then_some(files),
+                        trigger: SyncTrigger::Watch,
+                        reply: None,
+                    })?;
+                    syncing += 1;
+                }
+                continue;
+            };
+
+            match message {
+                DaemonMessage::Shutdown => break,
+                DaemonMessage::SyncFinished { last_synced, retry } => {
+                    syncing -= 1;
+                    self.last_synced = self.last_synced.max(last_synced);
+                    match retry {
+                        Some(files) => {
+                            // Put the changes back rather than losing them
+                            match files {
+                                Some(files) => changed_files.extend(files),
+                                None => full_sync = true,
+                            }
+                            retry_delay = (retry_delay * 2).clamp(RETRY_MIN_DELAY, RETRY_MAX_DELAY);
+                            info!(
+                                "Trying the failed changes again in {}",
+                                humantime::format_duration(retry_delay)
+                            );
+                            let retry_at = Instant::now() + retry_delay;
+                            sync_due = Some(sync_due.map_or(retry_at, |due| due.max(retry_at)));
+                        }
+                        None => retry_delay = Duration::ZERO,
+                    }
+                    if syncing == 0
+                        && sync_due.is_none()
+                        && (full_sync || !changed_files.is_empty())
+                    {
+                        sync_due = Some(Instant::now());
+                    }
+                }
+                DaemonMessage::FileEvent(event) => {
+                    if let DebouncedEvent::Error(e, path) = event {
+                        error!("Watch error on {:?}: {}", path, e);
+                    } else if self.is_config_event(&event) {
+                        info!("Configuration changed, reloading...");
+                        match self.reload_config(&mut profile, &tx) {
+                            Ok(watcher) => _watcher = watcher,
+                            Err(e) => error!("Failed to reload configuration: {:#}", e),
+                        }
+                    } else if event_path_in(&event, self.config_path.parent()) {
+                        // Other files next to config.toml are not tracked
+                    } else if paused {
+                        pending = true;
+                    } else {
+                        match self.changed_entries(&profile, &event) {
+                            // Only ignored or editor temp files were touched
+                            Some(entries) if entries.is_empty() => continue,
+                            Some(entries) => changed_files.extend(entries),
+                            None => full_sync = true,
+                        }
+                        if sync_due.is_none() {
+                            sync_due = Some(Instant::now() + self.debounce_window());
+                        }
+                    }
+                }
+                DaemonMessage::Control(mut stream, request) => {
+                    let response = match request {
+                        DaemonRequest::Status => DaemonResponse::ok(format!(
+                            "profile: {}\npaused: {}\nsyncing: {}\npending changes: {}\nlast synced: {}s ago",
+                            profile,
+                            paused,
+                            syncing > 0,
+                            pending || full_sync || !changed_files.is_empty(),
+                            self.last_synced.elapsed().unwrap_or_default().as_secs()
+                        )),
+                        DaemonRequest::TriggerSync {
+                            profile: requested,
+                            files,
+                        } => {
+                            match FreezeState::load() {
+                                Ok(Some(freeze)) => DaemonResponse::ok(format!(
+                                    "{}; skipping sync. Run `dotty thaw` to resume.",
+                                    freeze.describe()
+                                )),
+                                _ => {
+                                    // The worker answers once the sync is done
+                                    queue_sync(SyncJob {
+                                        dotty: self.clone(),
+                                        profile: requested.unwrap_or_else(|| profile.clone()),
+                                        files: (!files.is_empty())
+                                            .then(|| files.into_iter().collect()),
+                                        trigger: SyncTrigger::Manual,
+                                        reply: Some(stream),
+                                    })?;
+                                    syncing += 1;
+                                    continue;
+                                }
+                            }
+                        }
+                        DaemonRequest::ReloadConfig => {
+                            match self.reload_config(&mut profile, &tx) {
+                                Ok(watcher) => {
+                                    _watcher = watcher;
+                                    DaemonResponse::ok("Configuration reloaded".to_string())
+                                }
+                                Err(e) => DaemonResponse::error(&e),
+                            }
+                        }
+                        DaemonRequest::Pause => {
+                            paused = true;
+                            self.stats.lock().unwrap().paused = true;
+                            DaemonResponse::ok("Syncing paused".to_string())
+                        }
+                        DaemonRequest::Resume => {
+                            paused = false;
+                            self.stats.lock().unwrap().paused = false;
+                            if std::mem::take(&mut pending) {
+                                queue_sync(SyncJob {
+                                    dotty: self.clone(),
+                                    profile: profile.clone(),
+                                    files: None,
+                                    trigger: SyncTrigger::Watch,
+                                    reply: None,
+                                })?;
+                                syncing += 1;
+                            }
+                            DaemonResponse::ok("Syncing resumed".to_string())
+                        }
+                    };
+                    if let Err(e) = write_daemon_response(&mut stream, &response).await {
+                        warn!("Failed to reply on control socket: {}", e);
+                    }
+                }
+            }
+        }
+
+        info!("Shutting down daemon...");
+        // Queued syncs still run; the final one goes after them
+        while syncing > 0 {
+            match rx.recv().await {
+                Some(DaemonMessage::SyncFinished { retry, .. }) => {
+                    syncing -= 1;
+                    match retry {
+                        Some(Some(files)) => changed_files.extend(files),
+                        Some(None) => full_sync = true,
+                        None => {}
+                    }
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        full_sync |= pending;
+        let has_changes = full_sync || !changed_files.is_empty();
+        let mut state = DaemonState::default();
+        if has_changes && self.sync_on_shutdown() {
+            info!("Running final sync before exiting");
+            let filter = if full_sync {
+                None
+            } else {
+                Some(&changed_files)
+            };
+            let result = tokio::task::block_in_place(|| {
+                self.sync(Some(profile.clone()), filter, SyncTrigger::Watch)
+            });
+            if let Err(e) = result {
+                error!("Final sync failed, keeping changes for the next run: {}", e);
+                state.full_sync = full_sync;
+                state.pending_files = changed_files.into_iter().collect();
+            }
+        } else if has_changes {
+            info!("Deferring unsynced changes to the next run");
+            state.full_sync = full_sync;
+            state.pending_files = changed_files.into_iter().collect();
+        }
+        state.save()?;
+        remove_control_socket();
+        Ok(())
+    }
+
+    pub(crate) fn sync_on_shutdown(&self) -> bool {
+        self.config
+            .daemon
+            .as_ref()
+            .is_some_and(|daemon| daemon.sync_on_shutdown)
+    }
+
+    // Returns why a scheduled sync should not run right now, if anything
+    pub(crate) fn schedule_blocked(&self) -> Option<String> {
+        let schedule = self.config.schedule.as_ref()?;
+        if schedule.require_network && !remote_reachable(&self.config.remote.github_repo) {
+            return Some("remote repository is unreachable".to_string());
+        }
+        if schedule.require_ac_power && !on_ac_power() {
+            return Some("running on battery power".to_string());
+        }
+        if let Some(min_idle) = schedule.min_idle_minutes {
+            match idle_time() {
+                Some(idle) if idle < Duration::from_secs(min_idle * 60) => {
+                    return Some(format!(
+                        "machine has only been idle for {} minutes",
+                        idle.as_secs() / 60
+                    ));
+                }
+                Some(_) => {}
+                None => warn!("Could not determine idle time; ignoring min_idle_minutes"),
+            }
+        }
+        None
+    }
+
+    /// Syncs on a cron schedule until stopped
+    pub fn schedule_sync(&self, schedule: &str, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let cron_schedule = parse_cron(schedule)?;
+        info!("Scheduled sync \"{}\" for profile {}", schedule, profile);
+        runtime()?.block_on(async {
+            let (tx, mut rx) = unbounded_channel();
+            install_shutdown_handler(Some(tx))?;
+            // Syncs run on the blocking pool so a slow push doesn't hold up
+            // the schedule; a run that comes while one is going is skipped
+            let mut running: Option<tokio::task::JoinHandle<()>> = None;
+            while let Some(next_run) = cron_schedule.upcoming(chrono::Utc).next() {
+                info!("Next sync at {}", next_run.format("%Y-%m-%d %H:%M:%S UTC"));
+                let wait = (next_run - chrono::Utc::now()).to_std().unwrap_or_default();
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = rx.recv() => break,
+                }
+                if running.as_ref().is_some_and(|handle| !handle.is_finished()) {
+                    info!("Skipping scheduled sync: the previous one is still running");
+                    continue;
+                }
+                let profile = profile.clone();
+                running = Some(tokio::task::spawn_blocking(move || {
+                    let mut dotty = match Dotty::new() {
+                        Ok(dotty) => dotty,
+                        Err(e) => {
+                            error!("Skipping scheduled sync: {:#}", e);
+                            return;
+                        }
+                    };
+                    if let Some(reason) = dotty.schedule_blocked() {
+                        info!("Skipping scheduled sync: {}", reason);
+                        return;
+                    }
+                    if let Err(e) = dotty.sync(Some(profile), None, SyncTrigger::Schedule) {
+                        error!("Scheduled sync error: {}", e);
+                    }
+                }));
+            }
+
+            info!("Stopping scheduled sync...");
+            // An in-flight sync finishes before the final one
+            if let Some(handle) = running {
+                let _ = handle.await;
+            }
+            if self.sync_on_shutdown() {
+                tokio::task::block_in_place(|| {
+                    Dotty::new()?.sync(Some(profile), None, SyncTrigger::Schedule)
+                })?;
+            }
+            Ok(())
+        })
+    }
+}
+
+pub(crate) enum DaemonMessage {
+    FileEvent(DebouncedEvent),
+    Control(ControlConnection, DaemonRequest),
+    SyncFinished {
+        last_synced: SystemTime,
+        // The changes a failed watch sync was for (None meaning the whole
+        // profile), to be tried again
+        retry: Option<Option<HashSet<String>>>,
+    },
+    Shutdown,
+}
+
+pub(crate) struct SyncJob {
+    pub(crate) dotty: Dotty,
+    pub(crate) profile: String,
+    pub(crate) files: Option<HashSet<String>>,
+    pub(crate) trigger: SyncTrigger,
+    // Answered when the sync is done, for `dotty daemon sync`
+    pub(crate) reply: Option<ControlConnection>,
+}
+
+// Syncs run one at a time on the blocking pool, so file events and control
+// requests are still handled while a slow push is in progress
+pub(crate) fn spawn_sync_worker(tx: UnboundedSender<DaemonMessage>) -> UnboundedSender<SyncJob> {
+    let (job_tx, mut job_rx) = unbounded_channel::<SyncJob>();
+    tokio::spawn(async move {
+        while let Some(job) = job_rx.recv().await {
+            let SyncJob {
+                mut dotty,
+                profile,
+                files,
+                trigger,
+                reply,
+            } = job;
+            let watched = reply.is_none();
+            let sync_profile = profile.clone();
+            let sync_files = files.clone();
+            let outcome = tokio::task::spawn_blocking(move || {
+                let result = dotty.sync(Some(sync_profile), sync_files.as_ref(), trigger);
+                if result.is_ok() && watched {
+                    dotty.maintain_if_due();
+                }
+                (dotty.last_synced, result)
+            })
+            .await;
+            let (last_synced, result) = match outcome {
+                Ok(outcome) => outcome,
+                Err(e) => (
+                    SystemTime::UNIX_EPOCH,
+                    Err(anyhow::anyhow!("The sync stopped unexpectedly: {}", e)),
+                ),
+            };
+            match reply {
+                Some(mut stream) => {
+                    let response = match &result {
+                        Ok(()) => DaemonResponse::ok(format!("Synced profile {}", profile)),
+                        Err(e) => DaemonResponse::error(e),
+                    };
+                    if let Err(e) = write_daemon_response(&mut stream, &response).await {
+                        warn!("Failed to reply on control socket: {}", e);
+                    }
+                }
+                None => {
+                    if let Err(e) = &result {
+                        error!("Error during sync: {}", e);
+                    }
+                }
+            }
+            let finished = DaemonMessage::SyncFinished {
+                last_synced,
+                retry: (watched && result.is_err()).then_some(files),
+            };
+            if tx.send(finished).is_err() {
+                break;
+            }
+        }
+    });
+    job_tx
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct DaemonState {
+    pub(crate) full_sync: bool,
+    pub(crate) pending_files: Vec<String>,
+}
+
+impl DaemonState {
+    pub(crate) fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("daemon_state.toml"))
+    }
+
+    pub(crate) fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(DaemonState::default());
+        }
+        let state_str = fs::read_to_string(&path).context("Failed to read daemon state")?;
+        toml::from_str(&state_str).context("Failed to parse daemon state")
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let state_str = toml::to_string_pretty(self).context("Failed to serialize daemon state")?;
+        fs::write(Self::path()?, state_str).context("Failed to write daemon state")?;
+        Ok(())
+    }
+}
+
+// The first Ctrl-C/SIGTERM asks the current loop to wind down once any
+// in-flight sync has finished; a second one exits immediately.
+pub(crate) fn install_shutdown_handler(
+    tx: Option<UnboundedSender<DaemonMessage>>,
+) -> Result<Arc<AtomicBool>> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let flag = shutdown.clone();
+    ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::SeqCst) {
+            warn!("Forced shutdown");
+            process::exit(130);
+        }
+        info!("Shutdown requested, finishing current work...");
+        if let Some(tx) = &tx {
+            let _ = tx.send(DaemonMessage::Shutdown);
+        }
+    })
+    .context("Failed to install shutdown handler")?;
+    Ok(shutdown)
+}
+
+/// Commands a running daemon accepts on its control socket
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case", tag = "command")]
+pub enum DaemonRequest {
+    Status,
+    TriggerSync {
+        profile: Option<String>,
+        /// Only these entries (config keys); empty syncs the whole profile
+        #[serde(default)]
+        files: Vec<String>,
+    },
+    ReloadConfig,
+    Pause,
+    Resume,
+}
+
+/// A daemon's answer to a [`DaemonRequest`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaemonResponse {
+    pub(crate) ok: bool,
+    pub(crate) message: String,
+    // Passed on so `dotty sync` exits as if it had synced itself
+    #[serde(default)]
+    pub(crate) kind: Option<ErrorKind>,
+    #[serde(default)]
+    pub(crate) hint: Option<String>,
+}
+
+impl DaemonResponse {
+    pub(crate) fn ok(message: String) -> Self {
+        DaemonResponse {
+            ok: true,
+            message,
+            kind: None,
+            hint: None,
+        }
+    }
+
+    pub(crate) fn error(error: &anyhow::Error) -> Self {
+        let typed = error.downcast_ref::<DottyError>();
+        DaemonResponse {
+            ok: false,
+            message: format!("{:#}", error),
+            kind: typed.map(|typed| typed.kind),
+            hint: typed.and_then(|typed| typed.hint.clone()),
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) type ControlStream = std::os::unix::net::UnixStream;
+
+#[cfg(not(unix))]
+pub(crate) type ControlStream = std::net::TcpStream;
+
+// The daemon's end of a control connection
+#[cfg(unix)]
+pub(crate) type ControlConnection = tokio::net::UnixStream;
+
+#[cfg(not(unix))]
+pub(crate) type ControlConnection = tokio::net::TcpStream;
+
+#[cfg(unix)]
+pub(crate) fn control_listener() -> Result<tokio::net::UnixListener> {
+    let listener = bind_control_socket()?;
+    listener
+        .set_nonblocking(true)
+        .context("Failed to configure control socket")?;
+    tokio::net::UnixListener::from_std(listener).context("Failed to listen on control socket")
+}
+
+#[cfg(not(unix))]
+pub(crate) fn control_listener() -> Result<tokio::net::TcpListener> {
+    let listener = bind_control_socket()?;
+    listener
+        .set_nonblocking(true)
+        .context("Failed to configure control socket")?;
+    tokio::net::TcpListener::from_std(listener).context("Failed to listen on control socket")
+}
+
+pub(crate) fn control_socket_path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("daemon.sock"))
+}
+
+#[cfg(unix)]
+pub(crate) fn bind_control_socket() -> Result<std::os::unix::net::UnixListener> {
+    let path = control_socket_path()?;
+    if path.exists() {
+        if ControlStream::connect(&path).is_ok() {
+            anyhow::bail!("A dotty daemon is already running");
+        }
+        fs::remove_file(&path).context("Failed to remove stale control socket")?;
+    }
+    std::os::unix::net::UnixListener::bind(&path).context("Failed to bind control socket")
+}
+
+// Windows has no Unix sockets in std, so the daemon listens on loopback and
+// records the port where the socket would otherwise live.
+#[cfg(not(unix))]
+pub(crate) fn bind_control_socket() -> Result<std::net::TcpListener> {
+    if connect_control_socket().is_some() {
+        anyhow::bail!("A dotty daemon is already running");
+    }
+    let listener =
+        std::net::TcpListener::bind("127.0.0.1:0").context("Failed to bind control socket")?;
+    let port = listener.local_addr()?.port();
+    fs::write(control_socket_path()?, port.to_string()).context("Failed to record control port")?;
+    Ok(listener)
+}
+
+pub(crate) fn runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the async runtime")
+}
+
+pub(crate) fn remove_control_socket() {
+    if let Ok(path) = control_socket_path() {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn connect_control_socket() -> Option<ControlStream> {
+    ControlStream::connect(control_socket_path().ok()?).ok()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn connect_control_socket() -> Option<ControlStream> {
+    let port: u16 = fs::read_to_string(control_socket_path().ok()?)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    ControlStream::connect(("127.0.0.1", port)).ok()
+}
+
+// Clients send their request right after connecting
+pub(crate) const CONTROL_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) async fn read_daemon_request(
+    stream: ControlConnection,
+) -> Result<(ControlConnection, DaemonRequest)> {
+    let mut reader = tokio::io::BufReader::new(stream);
+    let mut line = String::new();
+    tokio::time::timeout(CONTROL_READ_TIMEOUT, reader.read_line(&mut line))
+        .await
+        .context("Timed out waiting for a control request")?
+        .context("Failed to read control request")?;
+    let request = serde_json::from_str(&line).context("Failed to parse control request")?;
+    Ok((reader.into_inner(), request))
+}
+
+pub(crate) async fn write_daemon_response(
+    stream: &mut ControlConnection,
+    response: &DaemonResponse,
+) -> Result<()> {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+/// Returns None when no daemon is listening, so callers can fall back to doing
+/// the work themselves.
+pub fn send_daemon_request(request: &DaemonRequest) -> Result<Option<DaemonResponse>> {
+    let Some(mut stream) = connect_control_socket() else {
+        return Ok(None);
+    };
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .context("Failed to send request to daemon")?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut reply)
+        .context("Failed to read daemon reply")?;
+    let response = serde_json::from_str(&reply).context("Failed to parse daemon reply")?;
+    Ok(Some(response))
+}
+
+/// Prints a daemon's answer, failing if it reported an error
+pub fn print_daemon_response(response: DaemonResponse) -> Result<()> {
+    if response.ok {
+        println!("{}", response.message);
+        Ok(())
+    } else {
+        let message = format!("Daemon error: {}", response.message);
+        match response.kind {
+            Some(kind) => {
+                let mut error = DottyError::new(kind, message);
+                error.hint = response.hint;
+                Err(error.into())
+            }
+            None => anyhow::bail!(message),
+        }
+    }
+}
+
+// Swap, backup and probe files that editors write next to the real file on save
+pub(crate) fn is_editor_temp_file(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    name == "4913"
+        || name.ends_with('~')
+        || name.ends_with(".swp")
+        || name.ends_with(".swo")
+        || name.ends_with(".swx")
+        || name.starts_with(".#")
+        || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+}
+
+pub(crate) fn event_path_in(event: &DebouncedEvent, dir: Option<&Path>) -> bool {
+    let Some(dir) = dir else {
+        return false;
+    };
+    match event {
+        DebouncedEvent::NoticeWrite(path)
+        | DebouncedEvent::NoticeRemove(path)
+        | DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Chmod(path)
+        | DebouncedEvent::Remove(path)
+        | DebouncedEvent::Rename(_, path) => path.parent() == Some(dir),
+        DebouncedEvent::Rescan | DebouncedEvent::Error(..) => false,
+    }
+}
+
+// How often held changes are checked against `dotty thaw` or the freeze
+// running out
+pub(crate) const FREEZE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Bounds for the wait before a failed watch sync is tried again
+pub(crate) const RETRY_MIN_DELAY: Duration = Duration::from_secs(30);
+pub(crate) const RETRY_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+pub(crate) const METRICS_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) async fn serve_metrics(address: &str, stats: Arc<Mutex<SyncStats>>) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind metrics endpoint on {}", address))?;
+    info!("Serving health and metrics on http://{}", address);
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let stats = stats.clone();
+            // Each scrape gets its own task, and one that stalls is dropped
+            tokio::spawn(async move {
+                match tokio::time::timeout(METRICS_TIMEOUT, answer_metrics(stream, &stats)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Failed to write metrics response: {}", e),
+                    Err(_) => warn!("Metrics client timed out"),
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+pub(crate) async fn answer_metrics(
+    stream: tokio::net::TcpStream,
+    stats: &Mutex<SyncStats>,
+) -> std::io::Result<()> {
+    let mut reader = tokio::io::BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, content_type, body) = {
+        let stats = stats.lock().unwrap();
+        match path {
+            "/health" => (
+                "200 OK",
+                "application/json",
+                stats.health_json().to_string(),
+            ),
+            "/metrics" => ("200 OK", "text/plain; version=0.0.4", stats.prometheus()),
+            _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    reader.get_mut().write_all(response.as_bytes()).await
+}
+
+// Extracts the host and port git would connect to. Local paths have none.
+pub(crate) fn remote_host(url: &str) -> Option<(String, u16)> {
+    let (rest, default_port) = if let Some(rest) = url.strip_prefix("https://") {
+        (rest, 443)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (rest, 80)
+    } else if let Some(rest) = url.strip_prefix("ssh://") {
+        (rest, 22)
+    } else if let Some((user_host, _)) = url.split_once(':') {
+        // scp-like syntax: git@github.com:user/repo.git (but not C:\repo)
+        let host = user_host.rsplit('@').next()?;
+        if host.len() < 2 || host.contains('/') || host.contains('\\') {
+            return None;
+        }
+        return Some((host.to_string(), 22));
+    } else {
+        return None;
+    };
+    let authority = rest.split('/').next()?;
+    let authority = authority.rsplit('@').next()?;
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+        None => Some((authority.to_string(), default_port)),
+    }
+}
+
+pub(crate) fn remote_reachable(url: &str) -> bool {
+    let Some((host, port)) = remote_host(url) else {
+        return true;
+    };
+    let Ok(addresses) = std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), port)) else {
+        return false;
+    };
+    addresses.into_iter().any(|address| {
+        std::net::TcpStream::connect_timeout(&address, Duration::from_secs(5)).is_ok()
+    })
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn on_ac_power() -> bool {
+    let Ok(supplies) = fs::read_dir("/sys/class/power_supply") else {
+        return true;
+    };
+    let mut has_mains = false;
+    for supply in supplies.flatten() {
+        let path = supply.path();
+        let kind = fs::read_to_string(path.join("type")).unwrap_or_default();
+        if kind.trim() == "Mains" {
+            has_mains = true;
+            if fs::read_to_string(path.join("online"))
+                .unwrap_or_default()
+                .trim()
+                == "1"
+            {
+                return true;
+            }
+        }
+    }
+    // Desktops and servers usually expose no mains supply at all
+    !has_mains
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn on_ac_power() -> bool {
+    process::Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains("AC Power"))
+        .unwrap_or(true)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn on_ac_power() -> bool {
+    true
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn idle_time() -> Option<Duration> {
+    let output = process::Command::new("ioreg")
+        .args(["-c", "IOHIDSystem", "-d", "4"])
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let line = stdout.lines().find(|line| line.contains("HIDIdleTime"))?;
+    let nanos: u64 = line.rsplit('=').next()?.trim().parse().ok()?;
+    Some(Duration::from_nanos(nanos))
+}
+
+// Relies on xprintidle, which reports X11 idle time in milliseconds
+#[cfg(not(target_os = "macos"))]
+pub(crate) fn idle_time() -> Option<Duration> {
+    let output = process::Command::new("xprintidle").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let millis: u64 = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_millis(millis))
+}
+
+// Cron fields are seconds, minutes, hours, day of month, month, day of week
+// and an optional year. The cron crate only understands steps anchored at an
+// explicit start, so `*/N` is rewritten to start at the field's first value.
+pub(crate) fn parse_cron(expression: &str) -> Result<job_scheduler::Schedule> {
+    let normalized = expression
+        .split_whitespace()
+        .enumerate()
+        .map(|(index, field)| match field.strip_prefix("*/") {
+            Some(step) if index < 3 => format!("0/{}", step),
+            Some(step) => format!("1/{}", step),
+            None => field.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    normalized
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid cron expression \"{}\": {}", expression, e))
+}
+
+// Only intervals that repeat evenly within an hour or a day can be written as
+// cron; anything else would drift at the hour/day boundary.
+pub(crate) fn interval_to_cron(minutes: u64) -> Result<String> {
+    match minutes {
+        0 => anyhow::bail!("Interval must be greater than 0 minutes"),
+        m if m < 60 && 60 % m == 0 => Ok(format!("0 */{} * * * *", m)),
+        m if m % 60 == 0 && m < 1440 && 24 % (m / 60) == 0 => Ok(format!("0 0 */{} * * *", m / 60)),
+        1440 => Ok("0 0 0 * * *".to_string()),
+        m => anyhow::bail!(
+            "An interval of {} minutes can't be scheduled evenly; use --cron instead",
+            m
+        ),
+    }
+}
+
+/// A cron expression from `--interval` minutes or an explicit `--cron`
+pub fn schedule_expression(interval: Option<u64>, cron: Option<String>) -> Result<Option<String>> {
+    let expression = match (interval, cron) {
+        (_, Some(cron)) => cron,
+        (Some(interval), None) => interval_to_cron(interval)?,
+        (None, None) => return Ok(None),
+    };
+    parse_cron(&expression)?;
+    Ok(Some(expression))
+}
@@ -0,0 +1,106 @@
+//! Errors scripts can branch on: each kind has a stable exit code, and
+//! carries a hint on what to do about it.
+//!
+//! | Code | Meaning                                                        |
+//! |------|----------------------------------------------------------------|
+//! | 0    | Success                                                        |
+//! | 1    | Any other failure                                              |
+//! | 2    | Invalid command-line arguments                                 |
+//! | 3    | The config is missing, unreadable or invalid                   |
+//! | 4    | A conflict: files changed in two places, or branches diverged  |
+//! | 5    | The remote couldn't be reached or refused the credentials      |
+//! | 6    | The sync finished but skipped files; see the output            |
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What kind of failure an error is, as far as a wrapper script cares
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Config,
+    Conflict,
+    Network,
+    PartialSync,
+}
+
+impl ErrorKind {
+    /// The process exit code for this kind
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Config => 3,
+            ErrorKind::Conflict => 4,
+            ErrorKind::Network => 5,
+            ErrorKind::PartialSync => 6,
+        }
+    }
+}
+
+/// An error with a kind and a remediation hint. Attach it with `.context()`
+/// or return it with `Err(...into())`; [`exit_code`] and [`hint`] find it
+/// anywhere in an error's context chain.
+#[derive(Debug)]
+pub struct DottyError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl DottyError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        DottyError {
+            kind,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    pub fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+impl fmt::Display for DottyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for DottyError {}
+
+fn find(error: &anyhow::Error) -> Option<&DottyError> {
+    error.downcast_ref::<DottyError>()
+}
+
+/// The exit code for `error`: its kind's, or 1 for untyped errors
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    find(error).map_or(1, |error| error.kind.exit_code())
+}
+
+/// What to do about `error`, if known
+pub fn hint(error: &anyhow::Error) -> Option<&str> {
+    find(error).and_then(|error| error.hint.as_deref())
+}
+
+pub(crate) fn profile_not_found(profile: &str) -> DottyError {
+    DottyError::new(ErrorKind::Config, format!("Profile {} not found", profile)).hint(format!(
+        "add [profiles.{}] to config.toml, or pass an existing profile with --profile",
+        profile
+    ))
+}
+
+pub(crate) const AUTH_HINT: &str = "the remote refused the credentials; check them with `dotty remote show`, then `dotty token set` or `dotty remote set --auth ssh`";
+
+// Auth failures need new credentials; anything else is the connection
+pub(crate) fn network_error(message: impl Into<String>, error: Option<&git2::Error>) -> DottyError {
+    let auth = error.is_some_and(|error| {
+        error.code() == git2::ErrorCode::Auth
+            || error.class() == git2::ErrorClass::Ssh && error.message().contains("auth")
+    });
+    DottyError::new(ErrorKind::Network, message).hint(if auth {
+        AUTH_HINT
+    } else {
+        "check your network connection and remote.proxy; `dotty remote show` prints the URL in use"
+    })
+}
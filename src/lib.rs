@@ -0,0 +1,44 @@
+//! The engine behind the `dotty` CLI: configuration, syncing with the
+//! dotfiles repository, profiles and backups. [`Dotty`] ties them together.
+
+use crate::config::Config;
+use crate::daemon::SyncStats;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+pub mod backup;
+pub mod config;
+pub mod daemon;
+pub mod error;
+pub mod lint;
+pub mod plugins;
+pub mod profiles;
+pub mod remote;
+pub mod repo;
+pub mod script;
+pub mod selftest;
+pub mod service;
+pub mod status;
+pub mod sync;
+pub mod template;
+pub mod util;
+
+/// A loaded configuration and the profile in use on this machine. Every
+/// operation (syncing, tracking files, inspecting the repository) is a method
+/// on it; see the modules for each area.
+#[derive(Clone)]
+pub struct Dotty {
+    /// The parsed config.toml; call [`Dotty::save_config`] after changing it
+    pub config: Config,
+    pub(crate) config_path: PathBuf,
+    pub(crate) current_profile: String,
+    pub(crate) last_synced: SystemTime,
+    pub(crate) stats: Arc<Mutex<SyncStats>>,
+    // Report syncs as porcelain records on stdout instead of human output
+    pub(crate) porcelain: bool,
+    // Overwrite destinations a sync would otherwise leave alone
+    pub(crate) force: bool,
+    // The repository's script, loaded on first use
+    pub(crate) script: OnceLock<Arc<script::Script>>,
+}
@@ -0,0 +1,607 @@
+//! `dotty config lint`: checks config.toml for mistakes that loading it
+//! doesn't catch, pointing at the line of each.
+
+use crate::config::{
+    config_path, field, field_path, Config, DetectionCondition, Field, FileEntry, RemoteAuth,
+    TokenStore, KNOWN_OS,
+};
+use crate::error::{DottyError, ErrorKind};
+use crate::util::SYSTEM_PREFIX;
+use anyhow::{Context, Result};
+use colored::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use toml_edit::{ImDocument, Item};
+
+/// How serious a finding is; only errors fail `dotty config lint`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// One problem found in the config
+pub struct Finding {
+    pub severity: Severity,
+    /// 1-based line in config.toml, when the value could be found
+    pub line: Option<usize>,
+    /// The value's path, e.g. `profile_detection.rules[1].profile`
+    pub field: String,
+    pub message: String,
+}
+
+// The keys each section accepts. Sections not listed (file lists, aliases,
+// env, destinations, plugin args) are maps that take any key.
+fn known_keys(path: &[Field]) -> Option<&'static [&'static str]> {
+    let shape: Vec<&str> = path
+        .iter()
+        .map(|field| match field {
+            Field::Key(key) => key.as_str(),
+            Field::Index(_) => "[]",
+        })
+        .collect();
+    Some(match shape.as_slice() {
+        [] => &[
+            "profiles",
+            "remote",
+            "sync_interval",
+            "profile_detection",
+            "notifications",
+            "daemon",
+            "schedule",
+            "audit",
+            "script_commands",
+            "elevate_command",
+            "ignore_patterns",
+            "exclude",
+            "max_file_size",
+            "repo_layout",
+            "conflict_strategy",
+        ],
+        ["remote"] => &[
+            "github_repo",
+            "github_token",
+            "clone_depth",
+            "partial_clone",
+            "local_path",
+            "branch_per_host",
+            "branch",
+            "auth",
+            "token_store",
+            "oauth_client_id",
+            "ssh_key",
+            "proxy",
+            "backend",
+            "secret_provider",
+        ],
+        ["profiles", _] => &[
+            "files",
+            "ignore_patterns",
+            "exclude",
+            "use_symlinks",
+            "mode",
+            "line_endings",
+            "preserve_xattrs",
+            "aliases",
+            "env",
+        ],
+        ["profiles", _, "files", _] => &[
+            "source",
+            "mode",
+            "destinations",
+            "line_endings",
+            "permissions",
+            "owner",
+            "group",
+            "include_patterns",
+            "allow_large",
+            "lfs",
+            "shared",
+            "after",
+            "if_command",
+            "if_file",
+            "if_os",
+            "if_script",
+        ],
+        ["exclude", "[]"] | ["profiles", _, "exclude", "[]"] => &["patterns", "max_size"],
+        ["profile_detection"] => &["rules"],
+        ["profile_detection", "rules", "[]"] => &["profile", "conditions"],
+        ["profile_detection", "rules", "[]", "conditions", "[]"] => {
+            &["Hostname", "OS", "EnvVar", "Wsl", "Container", "Plugin"]
+        }
+        [.., "conditions", "[]", "EnvVar"] => &["name", "value"],
+        [.., "conditions", "[]", "Plugin"] => &["name", "args"],
+        ["notifications"] => &["webhooks"],
+        ["notifications", "webhooks", "[]"] => &["url", "kind", "events"],
+        ["daemon"] => &[
+            "metrics_address",
+            "debounce_ms",
+            "sync_on_shutdown",
+            "maintenance_interval_hours",
+        ],
+        ["schedule"] => &["require_network", "require_ac_power", "min_idle_minutes"],
+        _ => return None,
+    })
+}
+
+struct Linter<'a> {
+    doc: &'a ImDocument<String>,
+    findings: Vec<Finding>,
+}
+
+impl Linter<'_> {
+    fn line_at(&self, offset: usize) -> usize {
+        self.doc.raw()[..offset.min(self.doc.raw().len())]
+            .matches('\n')
+            .count()
+            + 1
+    }
+
+    // The line of the deepest part of `path` present in the file
+    fn line_of(&self, path: &[Field]) -> Option<usize> {
+        let mut item = self.doc.as_item();
+        let mut line = None;
+        for field in path {
+            let (span, next) = match field {
+                Field::Key(key) => {
+                    let Some((key, next)) = item
+                        .as_table_like()
+                        .and_then(|table| table.get_key_value(key))
+                    else {
+                        break;
+                    };
+                    (key.span(), next)
+                }
+                Field::Index(index) => {
+                    let Some(next) = item.get(*index) else {
+                        break;
+                    };
+                    (next.span(), next)
+                }
+            };
+            line = span.map(|span| self.line_at(span.start)).or(line);
+            item = next;
+        }
+        line
+    }
+
+    fn report(&mut self, severity: Severity, path: &[Field], message: String) {
+        self.findings.push(Finding {
+            severity,
+            line: self.line_of(path),
+            field: field_path(path),
+            message,
+        });
+    }
+
+    fn check_keys(&mut self, item: &Item, path: &mut Vec<Field>) {
+        if let Some(array) = item.as_array_of_tables() {
+            for (index, table) in array.iter().enumerate() {
+                path.push(Field::Index(index));
+                self.check_keys(&Item::Table(table.clone()), path);
+                path.pop();
+            }
+            return;
+        }
+        if let Some(array) = item.as_array() {
+            for (index, value) in array.iter().enumerate() {
+                path.push(Field::Index(index));
+                self.check_keys(&Item::Value(value.clone()), path);
+                path.pop();
+            }
+            return;
+        }
+        let Some(table) = item.as_table_like() else {
+            return;
+        };
+        let known = known_keys(path);
+        for (key, value) in table.iter() {
+            path.push(Field::key(key));
+            if known.is_some_and(|known| !known.contains(&key)) {
+                let line = table
+                    .get_key_value(key)
+                    .and_then(|(key, _)| key.span())
+                    .map(|span| self.line_at(span.start));
+                self.findings.push(Finding {
+                    severity: Severity::Error,
+                    line,
+                    field: field_path(path),
+                    message: format!("unknown key {:?}", key),
+                });
+            } else {
+                self.check_keys(value, path);
+            }
+            path.pop();
+        }
+    }
+
+    fn check_remote(&mut self, config: &Config) {
+        let remote = &config.remote;
+        if remote.github_repo.is_empty() {
+            self.report(
+                Severity::Error,
+                &field(&["remote", "github_repo"]),
+                "no repository URL; set one with `dotty remote set`".to_string(),
+            );
+        }
+        // A keyring token isn't in the file, and backends authenticate themselves
+        if remote.auth == RemoteAuth::Token
+            && remote.token_store == TokenStore::Config
+            && remote.github_token.is_empty()
+            && remote.backend.is_none()
+        {
+            self.report(
+                Severity::Error,
+                &field(&["remote", "github_token"]),
+                "no token for token authentication; set one with `dotty token set`".to_string(),
+            );
+        }
+        if remote.auth == RemoteAuth::DeployKey && remote.ssh_key.is_none() {
+            self.report(
+                Severity::Error,
+                &field(&["remote", "auth"]),
+                "deploy key authentication needs remote.ssh_key".to_string(),
+            );
+        }
+    }
+
+    fn check_detection(&mut self, config: &Config) {
+        let Some(detection) = &config.profile_detection else {
+            return;
+        };
+        for (index, rule) in detection.rules.iter().enumerate() {
+            let mut rule_path = field(&["profile_detection", "rules"]);
+            rule_path.push(Field::Index(index));
+
+            if !config.profiles.contains_key(&rule.profile) {
+                let mut path = rule_path.clone();
+                path.push(Field::key("profile"));
+                self.report(
+                    Severity::Error,
+                    &path,
+                    format!("profile {:?} doesn't exist", rule.profile),
+                );
+            }
+
+            for (position, condition) in rule.conditions.iter().enumerate() {
+                let mut path = rule_path.clone();
+                path.extend([Field::key("conditions"), Field::Index(position)]);
+                if let DetectionCondition::OS(os) = condition {
+                    if !KNOWN_OS.contains(&os.as_str()) {
+                        self.report(
+                            Severity::Warning,
+                            &path,
+                            format!(
+                                "{:?} never matches; OS names are e.g. linux, macos, windows",
+                                os
+                            ),
+                        );
+                    }
+                }
+                let contradicted = rule.conditions[..position]
+                    .iter()
+                    .position(|earlier| contradicts(earlier, condition));
+                if let Some(earlier) = contradicted {
+                    self.report(
+                        Severity::Warning,
+                        &path,
+                        format!(
+                            "the rule never matches: this contradicts conditions[{}]",
+                            earlier
+                        ),
+                    );
+                }
+            }
+
+            // The first matching rule wins, so a rule is shadowed by an earlier
+            // one whose conditions are a subset of its own
+            let shadowed_by = detection.rules[..index].iter().position(|earlier| {
+                earlier
+                    .conditions
+                    .iter()
+                    .all(|condition| rule.conditions.contains(condition))
+            });
+            if let Some(earlier) = shadowed_by {
+                let message = if detection.rules[earlier].conditions.is_empty() {
+                    format!(
+                        "unreachable: rules[{}] has no conditions and always matches first",
+                        earlier
+                    )
+                } else {
+                    format!(
+                        "unreachable: rules[{}] matches first wherever this rule would",
+                        earlier
+                    )
+                };
+                self.report(Severity::Warning, &rule_path, message);
+            }
+        }
+    }
+
+    fn check_files(&mut self, config: &Config, home: &Path) {
+        let mut names: Vec<&String> = config.profiles.keys().collect();
+        names.sort();
+        // Shared by every profile, so a destination claimed twice is found
+        // whichever profiles the two entries are in
+        let mut destinations: HashMap<_, (&String, &String)> = HashMap::new();
+        for name in names {
+            let profile = &config.profiles[name];
+            let mut keys: Vec<&String> = profile.files.keys().collect();
+            keys.sort();
+            for key in keys {
+                let path = field(&["profiles", name, "files", key]);
+                let system = key.starts_with(SYSTEM_PREFIX);
+                // Keys are shared by every OS, so a Windows-style key would
+                // never match the paths dotty derives on any machine
+                let unprefixed = key.strip_prefix(SYSTEM_PREFIX).unwrap_or(key);
+                if key.contains('\\') || unprefixed.as_bytes().get(1) == Some(&b':') {
+                    self.report(
+                        Severity::Error,
+                        &path,
+                        format!(
+                            "keys use forward slashes and no drive colon; files on a drive outside the home directory are tracked as \"{}C/...\"",
+                            SYSTEM_PREFIX
+                        ),
+                    );
+                    continue;
+                }
+                if !system
+                    && (Path::new(key).is_absolute() || key.split('/').any(|part| part == ".."))
+                {
+                    self.report(
+                        Severity::Error,
+                        &path,
+                        format!(
+                            "keys are relative to the home directory; paths elsewhere are tracked as \"{}...\"",
+                            SYSTEM_PREFIX
+                        ),
+                    );
+                    continue;
+                }
+                let source = Path::new(profile.files[key].source());
+                if !system && source.is_absolute() && !source.starts_with(home) {
+                    let mut source_path = path.clone();
+                    source_path.push(Field::key("source"));
+                    self.report(
+                        Severity::Error,
+                        &source_path,
+                        format!(
+                            "{} is outside the home directory, so it must be tracked as a \"{}\" key; re-add it with `dotty add`",
+                            source.display(),
+                            SYSTEM_PREFIX
+                        ),
+                    );
+                }
+                let if_os = match &profile.files[key] {
+                    FileEntry::Detailed(options) => options.if_os.as_ref(),
+                    FileEntry::Source(_) => None,
+                };
+                if let Some(os) = if_os {
+                    if !KNOWN_OS.contains(&os.as_str()) {
+                        let mut os_path = path.clone();
+                        os_path.push(Field::key("if_os"));
+                        self.report(
+                            Severity::Warning,
+                            &os_path,
+                            format!(
+                                "{:?} never matches; OS names are e.g. linux, macos, windows",
+                                os
+                            ),
+                        );
+                    }
+                }
+                for (index, dependency) in profile.files[key].after().iter().enumerate() {
+                    if !profile.files.contains_key(dependency) {
+                        let mut after_path = path.clone();
+                        after_path.extend([Field::key("after"), Field::Index(index)]);
+                        self.report(
+                            Severity::Warning,
+                            &after_path,
+                            format!(
+                                "{:?} isn't tracked in profile {}, so it's ignored",
+                                dependency, name
+                            ),
+                        );
+                    }
+                }
+                let destination = profile.destination(home, key);
+                match destinations.insert(destination.clone(), (name, key)) {
+                    Some((other_name, other)) if other_name == name => self.report(
+                        Severity::Error,
+                        &path,
+                        format!(
+                            "writes to {}, which {:?} in the same profile also writes to",
+                            destination.display(),
+                            other
+                        ),
+                    ),
+                    // Only one profile is applied at a time, so this is
+                    // worth knowing about rather than an error
+                    Some((other_name, other)) => self.report(
+                        Severity::Warning,
+                        &path,
+                        format!(
+                            "writes to {}, which {:?} in profile {} also writes to",
+                            destination.display(),
+                            other,
+                            other_name
+                        ),
+                    ),
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+/// Checks config.toml's text: syntax, unknown keys, invalid values, detection
+/// rules that name missing profiles or can never match, and entries that
+/// collide or point outside the home directory
+pub fn lint(source: &str, home: &Path) -> Vec<Finding> {
+    let doc = match ImDocument::parse(source.to_string()) {
+        Ok(doc) => doc,
+        Err(e) => {
+            let line = e
+                .span()
+                .map(|span| source[..span.start.min(source.len())].matches('\n').count() + 1);
+            return vec![Finding {
+                severity: Severity::Error,
+                line,
+                field: String::new(),
+                message: e.message().trim().replace('\n', ", "),
+            }];
+        }
+    };
+    let mut linter = Linter {
+        doc: &doc,
+        findings: Vec::new(),
+    };
+    linter.check_keys(doc.as_item(), &mut Vec::new());
+
+    let config: Config = match toml::from_str(source) {
+        Ok(config) => config,
+        Err(e) => {
+            // Unknown keys are ignored when loading, so this is a type or a
+            // missing value
+            let line = e.span().map(|span| linter.line_at(span.start));
+            linter.findings.push(Finding {
+                severity: Severity::Error,
+                line,
+                field: String::new(),
+                message: e.message().to_string(),
+            });
+            return linter.findings;
+        }
+    };
+    linter.check_remote(&config);
+    for problem in config.problems() {
+        linter.report(Severity::Error, &problem.path, problem.message);
+    }
+    linter.check_detection(&config);
+    linter.check_files(&config, home);
+    linter.findings
+}
+
+// Whether no machine can satisfy both conditions
+fn contradicts(a: &DetectionCondition, b: &DetectionCondition) -> bool {
+    use DetectionCondition::*;
+    match (a, b) {
+        (Hostname(a), Hostname(b)) | (OS(a), OS(b)) => a != b,
+        (Wsl(a), Wsl(b)) | (Container(a), Container(b)) => a != b,
+        (
+            EnvVar { name, value },
+            EnvVar {
+                name: other_name,
+                value: other_value,
+            },
+        ) => name == other_name && value != other_value,
+        _ => false,
+    }
+}
+
+/// Lints the config file and prints what it finds, failing if there are errors
+pub fn lint_config() -> Result<()> {
+    let config_path = config_path()?;
+    let source = fs::read_to_string(&config_path)
+        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+    let home = dirs::home_dir().context("Failed to get home directory")?;
+    let mut findings = lint(&source, &home);
+    findings.sort_by_key(|finding| finding.line);
+
+    for finding in &findings {
+        let location = match finding.line {
+            Some(line) => format!("{}:{}", config_path.display(), line),
+            None => config_path.display().to_string(),
+        };
+        let severity = match finding.severity {
+            Severity::Error => "error".red().bold(),
+            Severity::Warning => "warning".yellow().bold(),
+        };
+        if finding.field.is_empty() {
+            println!("{}: {}: {}", location, severity, finding.message);
+        } else {
+            println!(
+                "{}: {}: {}: {}",
+                location, severity, finding.field, finding.message
+            );
+        }
+    }
+
+    let errors = findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .count();
+    let warnings = findings.len() - errors;
+    if findings.is_empty() {
+        println!("{}", "No problems found".green());
+    } else if errors > 0 {
+        return Err(DottyError::new(
+            ErrorKind::Config,
+            format!(
+                "{} error(s) and {} warning(s) in {}",
+                errors,
+                warnings,
+                config_path.display()
+            ),
+        )
+        .into());
+    } else {
+        println!("{} warning(s)", warnings);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_errors(key: &str) -> Vec<String> {
+        let source = format!(
+            r#"sync_interval = 300
+
+[remote]
+github_repo = "https://github.com/me/dotfiles.git"
+github_token = "ghp_test"
+
+[profiles.default]
+ignore_patterns = []
+use_symlinks = false
+
+[profiles.default.files]
+'{}' = "/home/me/.dotty/src/file"
+"#,
+            key
+        );
+        lint(&source, Path::new("/home/me"))
+            .into_iter()
+            .filter(|finding| finding.severity == Severity::Error)
+            .map(|finding| finding.message)
+            .collect()
+    }
+
+    #[test]
+    fn windows_keys_are_errors() {
+        for key in [
+            r"AppData\Roaming\app\config.ini",
+            r"C:\ProgramData\app.ini",
+            "C:/ProgramData/app.ini",
+            "@system/C:/ProgramData/app.ini",
+        ] {
+            let errors = key_errors(key);
+            assert_eq!(errors.len(), 1, "{}: {:?}", key, errors);
+            assert!(errors[0].contains("@system/C/..."), "{}", errors[0]);
+        }
+    }
+
+    #[test]
+    fn portable_keys_pass() {
+        for key in [
+            ".zshrc",
+            "AppData/Roaming/app/config.ini",
+            "@system/C/ProgramData/app.ini",
+        ] {
+            assert_eq!(key_errors(key), Vec::<String>::new(), "{}", key);
+        }
+    }
+}
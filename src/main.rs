@@ -1,518 +1,1270 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use clap_complete::engine::{ArgValueCandidates, ArgValueCompleter, CompletionCandidate};
 use colored::*;
-use git2::{Cred, RemoteCallbacks, Repository};
-use ignore::WalkBuilder;
-use job_scheduler::{Job, JobScheduler};
-use log::{error, info, warn};
-use notify::{watcher, RecursiveMode, Watcher};
-use serde::{Deserialize, Serialize};
-use similar::{ChangeTag, TextDiff};
-use std::collections::HashMap;
+use dotty::config::RemoteAuth;
+use dotty::daemon::{
+    print_daemon_response, schedule_expression, send_daemon_request, DaemonRequest,
+};
+use dotty::error::{exit_code, hint};
+use dotty::lint::lint_config;
+use dotty::plugins::list_plugins;
+use dotty::selftest::selftest;
+use dotty::service::{install_service, service_args, service_status, uninstall_service};
+use dotty::status::{show_log, LogFilter};
+use dotty::sync::{freeze, thaw, unpin, SyncTrigger, Take};
+use dotty::util::parse_since;
+use dotty::Dotty;
+use log::warn;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
-use std::time::{Duration, SystemTime};
-use std::{env, fs};
-use symlink::symlink_file;
+use std::time::Duration;
+use std::{env, fs, process};
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    arg_required_else_help = true
+)]
 struct Args {
+    /// Print help for every subcommand and the config file reference
+    #[clap(long)]
+    help_all: bool,
     #[clap(subcommand)]
-    command: Command,
+    command: Option<Command>,
 }
 
 #[derive(clap::Subcommand, Debug)]
 enum Command {
     Add {
-        #[clap(value_parser = clap::value_parser!(PathBuf))]
-        path: PathBuf,
-        #[clap(short, long)]
+        #[clap(
+            value_parser = clap::value_parser!(PathBuf),
+            required_unless_present = "interactive"
+        )]
+        path: Option<PathBuf>,
+        /// Pick files to track with a fuzzy finder
+        #[clap(short, long, conflicts_with = "path")]
+        interactive: bool,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
         profile: Option<String>,
     },
     Remove {
-        #[clap(value_parser = clap::value_parser!(PathBuf))]
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
         path: PathBuf,
-        #[clap(short, long)]
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
         profile: Option<String>,
     },
+    /// Sync the profile, or only the tracked files given
     Sync {
-        #[clap(short, long)]
+        /// e.g. ~/.tmux.conf ~/.config/kitty; other entries are left alone
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        paths: Vec<PathBuf>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+        /// Print one versioned JSON record per action on stdout, for scripts
+        #[clap(long)]
+        porcelain: bool,
+        /// Overwrite destinations with local changes, files dotty didn't
+        /// write and conflicts, keeping a .bak of each
+        #[clap(long)]
+        force: bool,
+    },
+    /// Commit and push sources without touching destinations
+    Push {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        paths: Vec<PathBuf>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Set up this machine from the configuration stored in the repository
+    Apply {
+        #[clap(long)]
+        repo: Option<String>,
+        /// Defaults to DOTTY_GITHUB_TOKEN, which keeps it out of the process list
+        #[clap(long)]
+        token: Option<String>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Print a shell script that installs dotty and runs `dotty apply`
+    BootstrapScript {
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+        /// Download this binary instead of building dotty with cargo
+        #[clap(long)]
+        binary_url: Option<String>,
+    },
+    /// Apply changes from the remote without committing or pushing
+    Pull {
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
         profile: Option<String>,
     },
     Watch {
-        #[clap(short, long)]
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
         profile: Option<String>,
     },
     Schedule {
+        /// Minutes between syncs; must divide an hour or a day evenly
+        #[clap(short, long, conflicts_with = "cron", required_unless_present = "cron")]
+        interval: Option<u64>,
+        /// Cron expression (UTC) with a leading seconds field, e.g. "0 */30 * * * *"
+        #[clap(long)]
+        cron: Option<String>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Daemon {
+        #[clap(subcommand)]
+        control: Option<DaemonControl>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Service {
+        #[clap(subcommand)]
+        action: ServiceAction,
+    },
+    Freeze {
+        /// Thaw automatically after this long, e.g. "2h" or "30m"
+        #[clap(long = "for", value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+        #[clap(short, long)]
+        reason: Option<String>,
+    },
+    Thaw,
+    Checkout {
+        /// Commit, tag or branch to apply to the destination files
+        #[clap(add = ArgValueCandidates::new(tag_candidates))]
+        revision: String,
+        /// Keep destinations at this revision until `dotty unpin`
+        #[clap(long)]
+        pin: bool,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Unpin,
+    Tag {
+        #[clap(subcommand)]
+        action: TagAction,
+    },
+    /// Sign in to GitHub in the browser (OAuth device flow) and keep the
+    /// token in the system keyring
+    Login {
+        /// Client ID of the GitHub OAuth app to sign in with; defaults to
+        /// remote.oauth_client_id or DOTTY_OAUTH_CLIENT_ID
+        #[clap(long)]
+        client_id: Option<String>,
+        /// OAuth scopes to request
+        #[clap(long, default_value = "repo")]
+        scopes: String,
+    },
+    /// Store a GitHub token after checking it can push to the repository
+    Token {
+        #[clap(subcommand)]
+        action: TokenAction,
+    },
+    /// Configure or show the repository dotty syncs with
+    Remote {
+        #[clap(subcommand)]
+        action: RemoteAction,
+    },
+    /// Check the config file
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+    /// Keep a per-machine or per-OS variant of an entry in the repository
+    Overlay {
+        #[clap(subcommand)]
+        action: OverlayAction,
+    },
+    /// List storage backend, secret provider and detection plugins
+    Plugins,
+    /// Try out or check the repository's script (.dotty/script.rhai)
+    Script {
+        #[clap(subcommand)]
+        action: ScriptAction,
+    },
+    /// Check the installation by syncing between two throwaway machines and
+    /// a local repository; your own dotfiles and config aren't touched
+    Selftest {
+        /// Keep the temporary directory for inspection
+        #[clap(long)]
+        keep: bool,
+    },
+    /// Manage ignore patterns, or check whether a path is ignored
+    #[clap(args_conflicts_with_subcommands = true, arg_required_else_help = true)]
+    Ignore {
+        #[clap(subcommand)]
+        action: Option<IgnoreAction>,
+        /// Report whether this path would be ignored, and by which patterns
+        #[clap(long, value_parser = clap::value_parser!(PathBuf))]
+        test: Option<PathBuf>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Machines,
+    Status,
+    /// Print a completion script for a shell, e.g. `dotty completions zsh`
+    Completions {
+        #[clap(value_parser = ["bash", "zsh", "fish", "powershell", "elvish"])]
+        shell: String,
+    },
+    /// Full-screen dashboard of files, diffs, conflicts and sync history
+    Ui,
+    /// Regenerate the profile's alias and environment scripts and show how to
+    /// source them
+    ShellEnv {
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Print a short status line for shell prompts, e.g. "work ~2 5m"
+    PromptInfo {
+        /// Template using {profile}, {dirty}, {conflicts} and {age}
+        #[clap(long)]
+        format: Option<String>,
+        /// Recompute instead of using the cached counts
+        #[clap(long)]
+        refresh: bool,
+    },
+    /// Write man pages for dotty and its subcommands
+    InstallManpages {
+        /// Defaults to the man1 directory under your local data directory
+        #[clap(long, value_parser = clap::value_parser!(PathBuf))]
+        dir: Option<PathBuf>,
+    },
+    /// Diff tracked files: each source against its destination, or with
+    /// --remote, this machine's files against the remote branch
+    Diff {
+        /// Only these tracked files (default: the whole profile)
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        paths: Vec<PathBuf>,
+        /// Fetch and show what `dotty pull` would change
+        #[clap(long)]
+        remote: bool,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Show what changed upstream without touching local files
+    Fetch {
+        #[clap(long)]
+        patch: bool,
+    },
+    /// Open a tracked file in $EDITOR, found by a loose name such as "tmux",
+    /// then offer to sync it
+    Edit {
+        #[clap(add = ArgValueCompleter::new(tracked_path_candidates))]
+        name: String,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Show tracked files as a tree with their profile, mode and status
+    Tree {
+        /// Defaults to every profile
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Report which profile and entry manage a path, and how
+    Which {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+    },
+    /// Summarize tracked files, sync history, machines and repository size
+    Stats {
+        /// How many of the largest files to list
+        #[clap(short = 'n', long, default_value_t = 5)]
+        limit: usize,
+    },
+    /// Search tracked files for a regular expression
+    Grep {
+        pattern: String,
+        #[clap(short, long)]
+        ignore_case: bool,
+        /// Search the repository copies instead of the installed files
+        #[clap(long)]
+        repo: bool,
+        /// Only search this profile's files; defaults to every profile
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Print a tracked file as stored in the repository
+    Cat {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+        /// Commit, tag or branch to read it from, e.g. origin/master
+        #[clap(long = "ref")]
+        revision: Option<String>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Resolve {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+        #[clap(long, value_enum)]
+        take: Take,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Take files from another machine's branch (with `branch_per_host`)
+    MergeFrom {
+        host: String,
+        #[clap(
+            required = true,
+            value_parser = clap::value_parser!(PathBuf),
+            add = ArgValueCompleter::new(tracked_path_candidates)
+        )]
+        paths: Vec<PathBuf>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Repo {
+        #[clap(subcommand)]
+        action: RepoAction,
+    },
+    History {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+        #[clap(long)]
+        patch: bool,
+    },
+    Log {
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+        /// Only runs that changed this tracked file
+        #[clap(short, long, value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        file: Option<PathBuf>,
+        #[clap(short, long, value_enum)]
+        trigger: Option<SyncTrigger>,
+        /// A date (2024-06-01) or a duration ago (3d, 12h)
+        #[clap(long)]
+        since: Option<String>,
+        /// Only failed runs
+        #[clap(long)]
+        errors: bool,
+        #[clap(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    Verify {
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Repair {
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Unlink {
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Adopt {
+        #[clap(value_parser = clap::value_parser!(PathBuf))]
+        path: PathBuf,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Write the profile's files and a manifest to a .tar.zst archive
+    Export {
+        #[clap(short, long, value_parser = clap::value_parser!(PathBuf))]
+        output: PathBuf,
+        /// Encrypt the archive with a passphrase (AES-256-GCM)
+        #[clap(long)]
+        encrypt: bool,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Find well-known dotfiles that aren't tracked yet and pick some to add
+    Discover {
+        /// Add everything found without asking
+        #[clap(long)]
+        all: bool,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Track files managed by another dotfiles tool
+    Import {
+        #[clap(subcommand)]
+        source: ImportSource,
+    },
+    Purge {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+        /// Don't ask for confirmation
         #[clap(short, long)]
-        interval: u64,
+        yes: bool,
+    },
+    Untrack {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Mv {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        old: PathBuf,
+        /// New destination path; omit when moving to another profile with --to
+        #[clap(
+            value_parser = clap::value_parser!(PathBuf),
+            required_unless_present = "to",
+            conflicts_with = "to"
+        )]
+        new: Option<PathBuf>,
+        #[clap(short, long, conflicts_with = "from")]
+        profile: Option<String>,
+        #[clap(long, requires = "to")]
+        from: Option<String>,
+        /// Move the entry to this profile instead of renaming it
+        #[clap(long, add = ArgValueCandidates::new(profile_candidates))]
+        to: Option<String>,
+    },
+    Cp {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+        #[clap(long, add = ArgValueCandidates::new(profile_candidates))]
+        from: Option<String>,
+        #[clap(long, add = ArgValueCandidates::new(profile_candidates))]
+        to: String,
+    },
+    Clean {
+        /// Delete files from the repo that are no longer tracked
+        #[clap(long)]
+        repo: bool,
+        /// Don't ask for confirmation
         #[clap(short, long)]
+        yes: bool,
+    },
+    Audit {
+        #[clap(value_parser = clap::value_parser!(PathBuf))]
+        path: Option<PathBuf>,
+    },
+    Revert {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+        /// Commit or tag to restore the file from
+        #[clap(long, add = ArgValueCandidates::new(tag_candidates))]
+        to: String,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
         profile: Option<String>,
     },
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct RemoteConfig {
-    github_repo: String,
-    github_token: String,
+#[derive(clap::Subcommand, Debug)]
+enum ImportSource {
+    /// A GNU Stow directory of packages
+    Stow {
+        #[clap(value_parser = clap::value_parser!(PathBuf))]
+        dir: PathBuf,
+        /// Where the packages are stowed; defaults to the parent of the
+        /// stow directory, like stow
+        #[clap(long, value_parser = clap::value_parser!(PathBuf))]
+        target: Option<PathBuf>,
+        /// Only import these packages
+        #[clap(long = "package")]
+        packages: Vec<String>,
+        /// Replace stow's symlinks with ones (or copies) managed by dotty
+        #[clap(long)]
+        replace_links: bool,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// An archive written by `dotty export`
+    Archive {
+        #[clap(value_parser = clap::value_parser!(PathBuf))]
+        file: PathBuf,
+        /// Only show what would be written
+        #[clap(long)]
+        dry_run: bool,
+        #[clap(short, long)]
+        yes: bool,
+        /// Where to apply it; defaults to the profile it was exported from
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct ProfileConfig {
-    files: HashMap<String, String>,
-    ignore_patterns: Vec<String>,
-    use_symlinks: bool,
+#[derive(clap::Subcommand, Debug)]
+enum TagAction {
+    Create {
+        name: String,
+        #[clap(short, long)]
+        message: Option<String>,
+    },
+    List,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct Config {
-    profiles: HashMap<String, ProfileConfig>,
-    remote: RemoteConfig,
-    sync_interval: u64,
-    profile_detection: Option<ProfileDetectionConfig>,
+#[derive(clap::Subcommand, Debug)]
+enum TokenAction {
+    /// Prompt for the token, or read it from stdin when piped
+    Set,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct ProfileDetectionConfig {
-    rules: Vec<ProfileDetectionRule>,
+#[derive(clap::Subcommand, Debug)]
+enum RemoteAction {
+    /// Change the remote, checking that it can be reached before saving
+    Set {
+        /// Repository URL; omit to keep the current one
+        url: Option<String>,
+        /// Branch shared by every machine (default master)
+        #[clap(long)]
+        branch: Option<String>,
+        #[clap(long, value_enum)]
+        auth: Option<RemoteAuth>,
+        /// With --auth deploy-key: the private key to use, generated (and
+        /// registered on GitHub/GitLab when a token is available) if missing
+        #[clap(long)]
+        key: Option<String>,
+        #[clap(long)]
+        token: Option<String>,
+        /// Keep the repository in a storage backend plugin instead of a git
+        /// remote; "git" switches back
+        #[clap(long)]
+        backend: Option<String>,
+        /// Make the repository public if dotty offers to create it
+        #[clap(long)]
+        public: bool,
+    },
+    /// Print the remote with the token redacted
+    Show,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct ProfileDetectionRule {
-    profile: String,
-    conditions: Vec<DetectionCondition>,
+#[derive(clap::Subcommand, Debug)]
+enum ConfigAction {
+    /// Report unknown keys, invalid values, detection rules naming missing
+    /// profiles or shadowed by earlier rules, and colliding entries, with
+    /// their line numbers
+    Lint,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-enum DetectionCondition {
-    Hostname(String),
-    OS(String),
-    EnvVar { name: String, value: String },
+// Without --os, the overlay is named after this machine's hostname
+#[derive(clap::Subcommand, Debug)]
+enum OverlayAction {
+    /// Store this machine's copy of the entry separately, starting from its
+    /// current source
+    Add {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+        /// For every machine on this OS rather than only this one
+        #[clap(long)]
+        os: bool,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Go back to the entry's shared copy
+    Remove {
+        #[clap(value_parser = clap::value_parser!(PathBuf), add = ArgValueCompleter::new(tracked_path_candidates))]
+        path: PathBuf,
+        #[clap(long)]
+        os: bool,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
 }
 
-impl Config {
-    fn validate(&self) -> Result<()> {
-        if self.remote.github_repo.is_empty() {
-            anyhow::bail!("GitHub repository URL is missing in the configuration");
-        }
-        if self.remote.github_token.is_empty() {
-            anyhow::bail!("GitHub token is missing in the configuration");
-        }
-        if self.sync_interval == 0 {
-            anyhow::bail!("Sync interval must be greater than 0");
-        }
-        Ok(())
-    }
+#[derive(clap::Subcommand, Debug)]
+enum ScriptAction {
+    /// Print the value of an expression, e.g. "is_work()"
+    Eval {
+        expression: String,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Parse the script and every entry's if_script
+    Check,
 }
 
-#[derive(Clone)]
-struct Dotty {
-    config: Config,
-    config_path: PathBuf,
-    current_profile: String,
-    last_synced: SystemTime,
+// Without --profile, patterns go to the top-level list shared by every profile
+#[derive(clap::Subcommand, Debug)]
+enum IgnoreAction {
+    /// Add patterns after checking their syntax
+    Add {
+        /// Gitignore syntax, e.g. "*.log" or "cache/"
+        #[clap(required = true)]
+        patterns: Vec<String>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    Remove {
+        #[clap(required = true)]
+        patterns: Vec<String>,
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
+    /// Show the shared patterns and each profile's (or only --profile's)
+    List {
+        #[clap(short, long, add = ArgValueCandidates::new(profile_candidates))]
+        profile: Option<String>,
+    },
 }
 
-impl Dotty {
-    fn new() -> Result<Self> {
-        let config_dir = dirs::config_dir()
-            .context("Failed to get config directory")?
-            .join("dotty");
-        fs::create_dir_all(&config_dir).context("Failed to create config directory")?;
-        let config_path = config_dir.join("config.toml");
-
-        let config = if config_path.exists() {
-            let config_str =
-                fs::read_to_string(&config_path).context("Failed to read config file")?;
-            let config: Config =
-                toml::from_str(&config_str).context("Failed to parse config file")?;
-            config.validate()?;
-            config
-        } else {
-            let default_config = Config {
-                profiles: HashMap::from([(
-                    "default".to_string(),
-                    ProfileConfig {
-                        files: HashMap::new(),
-                        ignore_patterns: vec![".git".to_string(), ".gitignore".to_string()],
-                        use_symlinks: false,
-                    },
-                )]),
-                remote: RemoteConfig {
-                    github_repo: String::new(),
-                    github_token: String::new(),
-                },
-                sync_interval: 300,
-                profile_detection: None,
-            };
-            let config_str = toml::to_string_pretty(&default_config)
-                .context("Failed to serialize default config")?;
-            fs::write(&config_path, config_str).context("Failed to write default config file")?;
-            default_config
-        };
-
-        let mut dotty = Dotty {
-            config,
-            config_path,
-            current_profile: String::new(), // We'll set this in a moment
-            last_synced: SystemTime::now(),
-        };
-
-        // Set the current profile based on automatic detection
-        dotty.current_profile = dotty.detect_profile();
-
-        Ok(dotty)
-    }
+#[derive(clap::Subcommand, Debug)]
+enum RepoAction {
+    /// Repack the repository and prune unreachable objects (needs the git CLI)
+    Gc {
+        #[clap(long)]
+        aggressive: bool,
+    },
+    /// Report the repository's size and its largest files
+    Size {
+        #[clap(short = 'n', long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Collapse runs of automatic sync commits into one and force-push
+    Squash {
+        /// A date (2024-06-01) or a duration ago (3d, 12h)
+        #[clap(long)]
+        since: String,
+        /// Don't ask for confirmation
+        #[clap(short, long)]
+        yes: bool,
+    },
+}
 
-    fn detect_profile(&self) -> String {
-        if let Some(profile_detection) = &self.config.profile_detection {
-            for rule in &profile_detection.rules {
-                if rule
-                    .conditions
-                    .iter()
-                    .all(|condition| self.check_condition(condition))
-                {
-                    return rule.profile.clone();
-                }
-            }
-        }
-        "default".to_string()
-    }
+#[derive(clap::Subcommand, Debug)]
+enum ServiceAction {
+    Install {
+        /// Run `dotty schedule` every N minutes instead of the daemon
+        #[clap(short, long, conflicts_with = "cron")]
+        interval: Option<u64>,
+        /// Run `dotty schedule` with this cron expression instead of the daemon
+        #[clap(long)]
+        cron: Option<String>,
+        #[clap(short, long)]
+        profile: Option<String>,
+    },
+    Uninstall,
+    Status,
+}
 
-    fn check_condition(&self, condition: &DetectionCondition) -> bool {
-        match condition {
-            DetectionCondition::Hostname(hostname) => {
-                hostname == &hostname::get().unwrap().to_string_lossy()
-            }
-            DetectionCondition::OS(os) => os == env::consts::OS,
-            DetectionCondition::EnvVar { name, value } => {
-                env::var(name).map(|v| v == *value).unwrap_or(false)
-            }
-        }
-    }
+#[derive(clap::Subcommand, Debug)]
+enum DaemonControl {
+    Status,
+    TriggerSync,
+    ReloadConfig,
+    Pause,
+    Resume,
+}
 
-    fn save_config(&self) -> Result<()> {
-        let config_str =
-            toml::to_string_pretty(&self.config).context("Failed to serialize config")?;
-        fs::write(&self.config_path, config_str).context("Failed to write config file")?;
-        Ok(())
-    }
+const COMPLETE_VAR: &str = "DOTTY_COMPLETE";
 
-    fn add_file(&mut self, path: &Path, profile: Option<String>) -> Result<()> {
-        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
-        let profile_config = self
-            .config
-            .profiles
-            .get_mut(&profile)
-            .context("Profile not found")?;
-
-        let canonical_path = path.canonicalize().context("Failed to canonicalize path")?;
-        let relative_path = canonical_path
-            .strip_prefix(dirs::home_dir().context("Failed to get home directory")?)
-            .context("Path is not in home directory")?;
-        profile_config.files.insert(
-            relative_path.to_string_lossy().into_owned(),
-            canonical_path.to_string_lossy().into_owned(),
-        );
-        self.save_config()?;
-        info!("Added file: {:?} to profile {}", relative_path, profile);
-        Ok(())
-    }
+fn profile_candidates() -> Vec<CompletionCandidate> {
+    let Ok(dotty) = Dotty::load(false) else {
+        return Vec::new();
+    };
+    let mut profiles: Vec<&String> = dotty.config.profiles.keys().collect();
+    profiles.sort();
+    profiles.into_iter().map(CompletionCandidate::new).collect()
+}
 
-    fn remove_file(&mut self, path: &Path, profile: Option<String>) -> Result<()> {
-        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
-        let profile_config = self
-            .config
-            .profiles
-            .get_mut(&profile)
-            .context("Profile not found")?;
-
-        let canonical_path = path.canonicalize().context("Failed to canonicalize path")?;
-        let relative_path = canonical_path
-            .strip_prefix(dirs::home_dir().context("Failed to get home directory")?)
-            .context("Path is not in home directory")?;
-        if profile_config
-            .files
-            .remove(&relative_path.to_string_lossy().into_owned())
-            .is_some()
-        {
-            self.save_config()?;
-            info!("Removed file: {:?} from profile {}", relative_path, profile);
-        } else {
-            warn!("File not found in config: {:?}", relative_path);
-        }
-        Ok(())
-    }
+// Tags of the local clone; commits are too many to be worth offering
+fn tag_candidates() -> Vec<CompletionCandidate> {
+    let Ok(dotty) = Dotty::load(false) else {
+        return Vec::new();
+    };
+    dotty
+        .tag_names()
+        .into_iter()
+        .map(CompletionCandidate::new)
+        .collect()
+}
 
-    fn sync(&mut self, profile: Option<String>) -> Result<()> {
-        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
-        let profile_config = self
-            .config
-            .profiles
-            .get(&profile)
-            .context("Profile not found")?;
-
-        self.show_diff(&profile)?;
-
-        for (relative_path, canonical_path) in &profile_config.files {
-            let source = Path::new(canonical_path);
-            let dest = dirs::home_dir()
-                .context("Failed to get home directory")?
-                .join(relative_path);
-
-            if source.exists() {
-                if self.should_sync(source, profile_config) {
-                    self.backup_file(&dest)?;
-                    if profile_config.use_symlinks {
-                        symlink_file(source, &dest).context("Failed to create symlink")?;
-                        info!("Created symlink: {:?} -> {:?}", dest, source);
-                    } else {
-                        fs::copy(source, &dest).context("Failed to copy file")?;
-                        self.sync_permissions(source, &dest)?;
-                        info!("Synced: {:?}", relative_path);
+// Destinations of tracked files, written the way the user started typing
+// them: ~/..., absolute, or as the config key
+fn tracked_path_candidates(current: &OsStr) -> Vec<CompletionCandidate> {
+    let (Ok(dotty), Some(home)) = (Dotty::load(false), dirs::home_dir()) else {
+        return Vec::new();
+    };
+    let current = current.to_string_lossy();
+    let mut candidates: Vec<String> = dotty
+        .config
+        .profiles
+        .values()
+        .flat_map(|profile_config| {
+            profile_config.files.keys().map(|key| {
+                let dest = profile_config.destination(&home, key);
+                match dest.strip_prefix(&home) {
+                    Ok(relative) if current.starts_with('~') => {
+                        format!("~/{}", relative.display())
                     }
-                } else {
-                    info!("Skipped syncing {:?} (ignored)", relative_path);
+                    _ if current.starts_with('/') || !dest.starts_with(&home) => {
+                        dest.display().to_string()
+                    }
+                    _ => key.clone(),
                 }
-            } else {
-                warn!("Source file missing: {:?}", canonical_path);
-            }
-        }
+            })
+        })
+        .filter(|candidate| candidate.starts_with(current.as_ref()))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates
+        .into_iter()
+        .map(CompletionCandidate::new)
+        .collect()
+}
 
-        self.sync_with_github()?;
-        self.last_synced = SystemTime::now();
-        Ok(())
-    }
+// Reference shown by `--help-all` and appended to dotty(1)
+const HELP_SECTIONS: &[(&str, &str)] = &[
+    (
+        "CONFIGURATION",
+        "dotty reads ~/.config/dotty/config.toml (the platform config directory
+elsewhere). Top-level keys:
 
-    fn show_diff(&self, profile: &str) -> Result<()> {
-        let profile_config = self
-            .config
-            .profiles
-            .get(profile)
-            .context("Profile not found")?;
-
-        for (relative_path, canonical_path) in &profile_config.files {
-            let source = Path::new(canonical_path);
-            let dest = dirs::home_dir()
-                .context("Failed to get home directory")?
-                .join(relative_path);
-
-            if source.exists() && dest.exists() {
-                let source_content =
-                    fs::read_to_string(source).context("Failed to read source file")?;
-                let dest_content =
-                    fs::read_to_string(&dest).context("Failed to read destination file")?;
-
-                let diff = TextDiff::from_lines(&dest_content, &source_content);
-
-                println!("Diff for {}:", relative_path);
-                for change in diff.iter_all_changes() {
-                    let (sign, color) = match change.tag() {
-                        ChangeTag::Delete => ("-", Color::Red),
-                        ChangeTag::Insert => ("+", Color::Green),
-                        ChangeTag::Equal => (" ", Color::White),
-                    };
-                    print!("{}", sign.color(color));
-                    print!("{}", change.value().color(color));
-                }
-                println!();
-            }
-        }
+  sync_interval        Seconds between syncs while the daemon runs
+  max_file_size        Bytes; larger files are skipped unless the entry sets
+                       allow_large. 0 disables the check (default 5 MiB)
+  ignore_patterns      Gitignore patterns applied to every profile
+  [[exclude]]          Rules keeping files inside tracked directories out of
+                       the repository: patterns (gitignore globs) and/or
+                       max_size (bytes); a file matching all is excluded
+  repo_layout          \"flat\" (default) or \"profiles\": each profile under
+                       profiles/<name>/, shared entries under shared/
+  conflict_strategy    prefer-source (default), prefer-dest, prefer-newest,
+                       prompt or abort
+  audit                Record destination writes in a hash-chained audit log
+  script_commands      Let the repository's script start programs with run()
+  elevate_command      Used to write files outside $HOME, e.g. \"pkexec\"
 
-        Ok(())
-    }
+[remote]
+  github_repo          owner/name of the repository
+  github_token         Token used to clone and push
+  auth                 \"token\" (default), \"ssh\" to use the ssh-agent,
+                       \"deploy_key\" to use ssh_key, or \"credential_helper\"
+                       to ask `git credential fill`
+  ssh_key              Deploy key limited to the repository, for
+                       auth = \"deploy_key\"
+  proxy                HTTP(S) proxy for clone/fetch/push (default
+                       https_proxy or all_proxy)
+  backend              Storage backend plugin holding the repository instead
+                       of a git remote
+  secret_provider      Secret provider plugin for token_store = \"keyring\"
+  token_store          \"config\" (default) or \"keyring\", set by `dotty login`
+  oauth_client_id      GitHub OAuth app used by `dotty login`
+  branch               Branch every machine shares (default master)
+  clone_depth          History depth of the first clone; 0 for all (default 1)
+  partial_clone        Clone without blobs; needs the git CLI
+  local_path           Where to clone, relative to $HOME unless absolute
+                       (default ~/.dotty_repo)
+  branch_per_host      Commit to machines/<hostname>; master only receives
+                       entries marked shared
 
-    fn backup_file(&self, path: &Path) -> Result<()> {
-        if path.exists() {
-            let backup_path = path.with_extension("bak");
-            fs::copy(path, &backup_path).context("Failed to create backup")?;
-            info!("Created backup: {:?}", backup_path);
-        }
-        Ok(())
-    }
+[profiles.<name>]
+  files                Map of destination (relative to $HOME, ~/..., or
+                       @system/...) to a source path or a table of options
+  ignore_patterns      Gitignore patterns for this profile
+  exclude              Exclude rules for this profile, as above
+  mode                 copy, symlink or hardlink; overrides use_symlinks
+  use_symlinks         Older switch between copy and symlink
+  line_endings         lf, crlf or native
+  preserve_xattrs      Copy extended attributes to copied destinations
+  aliases              Map of alias name to command; with env, written to
+                       env.sh, env.fish and env.ps1 next to config.toml
+  env                  Map of environment variable to value; $VAR
+                       references expand when the script is sourced
 
-    fn sync_permissions(&self, source: &Path, dest: &Path) -> Result<()> {
-        let metadata = fs::metadata(source).context("Failed to get source file metadata")?;
-        let permissions = metadata.permissions();
-        fs::set_permissions(dest, permissions)
-            .context("Failed to set permissions on destination file")?;
-        Ok(())
-    }
+[profiles.<name>.files.\"<path>\"]
+  source               The file or directory being tracked
+  mode                 Overrides the profile's mode
+  destinations         Destination per OS (linux, macos, windows, ...)
+  line_endings         Overrides the profile's line_endings
+  permissions          Octal bits such as \"0600\" set on every sync
+  owner, group         Names or ids to chown the destination to
+  include_patterns     For directories, only sync files matching these
+  allow_large          Exempt from max_file_size
+  lfs                  Store the content in Git LFS
+  shared               Keep one copy for every profile tracking the path
+  after                Keys of entries in the profile written before this one
+  if_command, if_file, if_os
+                       Only apply the entry where the program is on PATH,
+                       the path exists, or the OS matches
+  if_script            Only apply the entry where this script expression
+                       is true
 
-    fn should_sync(&self, path: &Path, profile_config: &ProfileConfig) -> bool {
-        let walker = WalkBuilder::new(path)
-            .hidden(false)
-            .git_ignore(true)
-            .build();
-
-        for result in walker {
-            match result {
-                Ok(entry) => {
-                    let path = entry.path();
-                    if profile_config
-                        .ignore_patterns
-                        .iter()
-                        .any(|pattern| path.to_str().map_or(false, |s| s.contains(pattern)))
-                    {
-                        return false;
-                    }
-                }
-                Err(e) => {
-                    warn!("Error walking directory: {}", e);
-                }
-            }
-        }
+[daemon]
+  metrics_address      Serve /health and /metrics, e.g. \"127.0.0.1:9464\"
+  debounce_ms          Window in which file events are coalesced (2000)
+  sync_on_shutdown     Sync pending changes on Ctrl-C/SIGTERM
+  maintenance_interval_hours
+                       Run git gc at most this often; 0 disables (168)
 
-        true
-    }
+[schedule]
+  require_network      Skip scheduled syncs while offline
+  require_ac_power     Skip scheduled syncs on battery
+  min_idle_minutes     Only sync after the machine has been idle this long",
+    ),
+    (
+        "PROFILE DETECTION",
+        "Without --profile, dotty picks the first rule whose conditions all match,
+falling back to \"default\".
 
-    fn sync_with_github(&self) -> Result<()> {
-        let repo_path = dirs::home_dir()
-            .context("Failed to get home directory")?
-            .join(".dotty_repo");
-
-        let repo = if repo_path.exists() {
-            Repository::open(&repo_path).context("Failed to open existing repository")?
-        } else {
-            Repository::clone(&self.config.remote.github_repo, &repo_path)
-                .context("Failed to clone repository")?
-        };
-
-        // Copy files to the repo
-        for profile_config in self.config.profiles.values() {
-            for (relative_path, canonical_path) in &profile_config.files {
-                let source = Path::new(canonical_path);
-                let dest = repo_path.join(relative_path);
-
-                if source.exists() {
-                    fs::create_dir_all(dest.parent().unwrap())
-                        .context("Failed to create parent directories")?;
-                    fs::copy(source, &dest).context("Failed to copy file to repo")?;
-                }
-            }
-        }
+[[profile_detection.rules]]
+profile = \"work\"
+conditions = [{ Hostname = \"work-laptop\" }, { OS = \"macos\" }]
 
-        // Commit and push changes
-        let mut index = repo.index().context("Failed to get repo index")?;
-        index
-            .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
-            .context("Failed to add files to index")?;
-        index.write().context("Failed to write index")?;
-
-        let tree_id = index.write_tree().context("Failed to write tree")?;
-        let tree = repo.find_tree(tree_id).context("Failed to find tree")?;
-
-        let signature = repo.signature().context("Failed to get signature")?;
-        let parent_commit = repo
-            .head()
-            .context("Failed to get HEAD")?
-            .peel_to_commit()
-            .context("Failed to peel to commit")?;
-
-        repo.commit(
-            Some("HEAD"),
-            &signature,
-            &signature,
-            "Sync dotfiles",
-            &tree,
-            &[&parent_commit],
-        )
-        .context("Failed to create commit")?;
-
-        let mut remote = repo
-            .find_remote("origin")
-            .context("Failed to find remote 'origin'")?;
-        let mut callbacks = RemoteCallbacks::new();
-        callbacks.credentials(|_, _, _| {
-            Cred::userpass_plaintext("x-access-token", &self.config.remote.github_token)
-        });
-
-        remote
-            .push(
-                &["refs/heads/master:refs/heads/master"],
-                Some(git2::PushOptions::new().remote_callbacks(callbacks)),
-            )
-            .context("Failed to push changes")?;
-
-        info!("Synced with GitHub repository");
-        Ok(())
-    }
+Conditions:
+  Hostname = \"name\"                   The machine's hostname
+  OS = \"linux\"                        std::env::consts::OS of the machine
+  EnvVar = { name = \"..\", value = \"..\" }
+                                      An environment variable's exact value
+  Wsl = true                          Inside (true) or outside (false) WSL
+  Container = true                    Inside Docker, Podman, LXC or a dev
+                                      container (or outside with false)",
+    ),
+    (
+        "NOTIFICATIONS",
+        "[[notifications.webhooks]]
+  url                  Where events are POSTed as JSON
+  kind                 slack, discord or generic (default)
+  events               sync_success, sync_failure, remote_divergence; empty
+                       means every event
 
-    fn watch_and_sync(&mut self, profile: Option<String>) -> Result<()> {
-        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
-        let profile_config = self
-            .config
-            .profiles
-            .get(&profile)
-            .context("Profile not found")?;
-
-        let (tx, rx) = channel();
-        let mut watcher =
-            watcher(tx, Duration::from_secs(1)).context("Failed to create watcher")?;
-
-        for path in profile_config.files.values() {
-            watcher
-                .watch(path, RecursiveMode::NonRecursive)
-                .context("Failed to watch path")?;
+Slack and Discord receive the message as text. Generic webhooks receive:
+  event                One of the events above
+  hostname             The machine that sent it
+  profile              The active profile
+  message              A human-readable summary",
+    ),
+    (
+        "ENVIRONMENT",
+        "  DOTTY_PASSPHRASE     Passphrase for export --encrypt and import archive,
+                       instead of asking on the terminal
+  DOTTY_GITHUB_TOKEN   Token for `dotty apply` when --token isn't given, as
+                       in bootstrap-script output
+  DOTTY_OAUTH_CLIENT_ID  GitHub OAuth app for `dotty login`
+  https_proxy, all_proxy  Proxy for HTTPS remotes unless remote.proxy is set;
+                       hosts in no_proxy connect directly
+  DOTTY_COMPLETE       Set by the completion scripts to request candidates
+  VISUAL, EDITOR       Editor used by `dotty edit` and to resolve conflicts
+  RUST_LOG             Log level, e.g. RUST_LOG=info",
+    ),
+];
+
+fn print_help_all() {
+    let mut cmd = Args::command();
+    cmd.build();
+    println!("{}", cmd.render_long_help());
+    for subcommand in cmd.get_subcommands_mut() {
+        if subcommand.get_name() == "help" {
+            continue;
         }
+        println!("{}", subcommand.get_name().to_uppercase().bold());
+        println!("{}", subcommand.render_long_help());
+    }
+    for (title, body) in HELP_SECTIONS {
+        println!("{}\n\n{}\n", title.bold(), body);
+    }
+}
 
-        info!(
-            "Watching for changes in profile {}. Press Ctrl-C to stop.",
-            profile
-        );
-
-        loop {
-            match rx.recv() {
-                Ok(_event) => {
-                    info!("Change detected, syncing...");
-                    if let Err(e) = self.sync(Some(profile.clone())) {
-                        error!("Error during sync: {}", e);
-                    }
-                }
-                Err(e) => error!("Watch error: {:?}", e),
+fn install_manpages(dir: Option<PathBuf>) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => dirs::data_local_dir()
+            .context("Failed to get local data directory")?
+            .join("man")
+            .join("man1"),
+    };
+    fs::create_dir_all(&dir).context("Failed to create man page directory")?;
+    clap_mangen::generate_to(Args::command(), &dir).context("Failed to write man pages")?;
+
+    // The reference sections go into dotty(1) as preformatted text
+    let mut extra = String::new();
+    for (title, body) in HELP_SECTIONS {
+        extra.push_str(&format!(".SH {}\n.nf\n", title));
+        for line in body.lines() {
+            let line = line.replace('\\', "\\e");
+            if line.starts_with('.') || line.starts_with('\'') {
+                extra.push_str("\\&");
             }
+            extra.push_str(&line);
+            extra.push('\n');
         }
+        extra.push_str(".fi\n");
     }
+    let page = dir.join("dotty.1");
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(&page)
+        .context("Failed to open dotty.1")?;
+    file.write_all(extra.as_bytes())
+        .context("Failed to write dotty.1")?;
 
-    fn schedule_sync(&self, interval: u64, profile: Option<String>) -> Result<()> {
-        let mut scheduler = JobScheduler::new();
-        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
-
-        let profile_clone = profile.clone();
-        scheduler.add(Job::new(
-            format!("1/{} * * * * *", interval).parse().unwrap(),
-            move || {
-                let mut dotty = Dotty::new().expect("Failed to create Dotty instance");
-                if let Err(e) = dotty.sync(Some(profile_clone.clone())) {
-                    error!("Scheduled sync error: {}", e);
-                }
-            },
-        ));
-
-        info!(
-            "Scheduled sync every {} minutes for profile {}",
-            interval, profile
-        );
-        loop {
-            scheduler.tick();
-            std::thread::sleep(Duration::from_secs(1));
+    println!("Man pages written to {}", dir.display());
+    if let Some(man_root) = dir.parent() {
+        let man_path = env::var("MANPATH").unwrap_or_default();
+        if !man_path
+            .split(':')
+            .any(|entry| Path::new(entry) == man_root)
+        {
+            println!(
+                "If `man dotty` can't find them, add {} to MANPATH",
+                man_root.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+// Exit codes are documented in the error module; scripts rely on them
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{} {:#}", "error:".red().bold(), e);
+        if let Some(hint) = hint(&e) {
+            eprintln!("{} {}", "hint:".cyan().bold(), hint);
         }
+        process::exit(exit_code(&e));
     }
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
+    // The script from `dotty completions` calls back in with COMPLETE_VAR set
+    clap_complete::CompleteEnv::with_factory(Args::command)
+        .var(COMPLETE_VAR)
+        .complete();
     env_logger::init();
 
     let args = Args::parse();
-    let mut dotty = Dotty::new()?;
-
-    match args.command {
-        Command::Add { path, profile } => dotty.add_file(&path, profile)?,
-        Command::Remove { path, profile } => dotty.remove_file(&path, profile)?,
-        Command::Sync { profile } => dotty.sync(profile)?,
-        Command::Watch { profile } => dotty.watch_and_sync(profile)?,
-        Command::Schedule { interval, profile } => dotty.schedule_sync(interval, profile)?,
+    // Only reachable with --help-all; a bare `dotty` prints the usual help
+    let Some(command) = args.command else {
+        print_help_all();
+        return Ok(());
+    };
+    if let Command::InstallManpages { dir } = command {
+        return install_manpages(dir);
+    }
+    // Linting reads the file itself, so it works when loading would fail
+    if let Command::Config {
+        action: ConfigAction::Lint,
+    } = command
+    {
+        return lint_config();
+    }
+    if let Command::Plugins = command {
+        return list_plugins();
+    }
+    if let Command::Selftest { keep } = command {
+        return selftest(keep);
+    }
+    if let Command::PromptInfo { format, refresh } = &command {
+        // A broken config shouldn't put an error in every prompt
+        match Dotty::load(false).and_then(|dotty| dotty.prompt_info(format.as_deref(), *refresh)) {
+            Ok(line) => println!("{}", line),
+            Err(e) => warn!("prompt-info failed: {:#}", e),
+        }
+        return Ok(());
+    }
+    // A new machine's config is filled in by `apply`, `remote set`,
+    // `token set` or `login` itself
+    let mut dotty = Dotty::load(!matches!(
+        command,
+        Command::Apply { .. }
+            | Command::Remote { .. }
+            | Command::Token { .. }
+            | Command::Login { .. }
+    ))?;
+
+    match command {
+        Command::Add {
+            path,
+            interactive,
+            profile,
+        } => {
+            match path {
+                Some(path) if !interactive => dotty.add_file(&path, profile)?,
+                _ => dotty.add_interactive(profile)?,
+            }
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Remove { path, profile } => {
+            dotty.remove_file(&path, profile)?;
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Script { action } => match action {
+            ScriptAction::Eval {
+                expression,
+                profile,
+            } => dotty.script_eval(&expression, profile)?,
+            ScriptAction::Check => dotty.script_check()?,
+        },
+        Command::Overlay { action } => match action {
+            OverlayAction::Add { path, os, profile } => dotty.add_overlay(&path, os, profile)?,
+            OverlayAction::Remove { path, os, profile } => {
+                dotty.remove_overlay(&path, os, profile)?
+            }
+        },
+        Command::Sync {
+            paths,
+            profile,
+            porcelain,
+            force,
+        } => {
+            let files = paths
+                .iter()
+                .map(|path| dotty.tracked_key(path))
+                .collect::<Result<HashSet<String>>>()?;
+            // The daemon can't stream records or force, so those syncs run here
+            let response = if porcelain || force {
+                None
+            } else {
+                send_daemon_request(&DaemonRequest::TriggerSync {
+                    profile: profile.clone(),
+                    files: files.iter().cloned().collect(),
+                })?
+            };
+            match response {
+                Some(response) => print_daemon_response(response)?,
+                None => {
+                    dotty.set_porcelain(porcelain);
+                    dotty.set_force(force);
+                    let files = (!files.is_empty()).then_some(&files);
+                    dotty.sync(profile, files, SyncTrigger::Manual)?;
+                }
+            }
+        }
+        Command::Push { paths, profile } => dotty.push(&paths, profile)?,
+        Command::Edit { name, profile } => dotty.edit(&name, profile)?,
+        Command::Tree { profile } => dotty.tree(profile)?,
+        Command::Stats { limit } => dotty.stats(limit)?,
+        Command::Which { path } => dotty.which(&path)?,
+        Command::Grep {
+            pattern,
+            ignore_case,
+            repo,
+            profile,
+        } => dotty.grep(&pattern, ignore_case, repo, profile)?,
+        Command::Cat {
+            path,
+            revision,
+            profile,
+        } => dotty.cat(&path, revision.as_deref(), profile)?,
+        Command::Apply {
+            repo,
+            token,
+            profile,
+        } => dotty.apply(repo, token, profile)?,
+        Command::BootstrapScript {
+            profile,
+            binary_url,
+        } => print!("{}", dotty.bootstrap_script(profile, binary_url)?),
+        Command::Pull { profile } => dotty.pull(profile)?,
+        Command::Watch { profile } => dotty.run_daemon(profile)?,
+        Command::Daemon { control, profile } => match control {
+            None => dotty.run_daemon(profile)?,
+            Some(control) => {
+                let request = match control {
+                    DaemonControl::Status => DaemonRequest::Status,
+                    DaemonControl::TriggerSync => DaemonRequest::TriggerSync {
+                        profile,
+                        files: Vec::new(),
+                    },
+                    DaemonControl::ReloadConfig => DaemonRequest::ReloadConfig,
+                    DaemonControl::Pause => DaemonRequest::Pause,
+                    DaemonControl::Resume => DaemonRequest::Resume,
+                };
+                let response =
+                    send_daemon_request(&request)?.context("No dotty daemon is running")?;
+                print_daemon_response(response)?;
+            }
+        },
+        Command::Freeze { duration, reason } => freeze(duration, reason)?,
+        Command::Thaw => thaw()?,
+        Command::Checkout {
+            revision,
+            pin,
+            profile,
+        } => dotty.checkout(&revision, pin, profile)?,
+        Command::Unpin => unpin()?,
+        Command::History { path, patch } => dotty.history(&path, patch)?,
+        Command::Log {
+            profile,
+            file,
+            trigger,
+            since,
+            errors,
+            limit,
+        } => show_log(
+            &dotty.config,
+            LogFilter {
+                profile,
+                file: file.map(|file| dotty.tracked_key(&file)).transpose()?,
+                trigger,
+                since: since.as_deref().map(parse_since).transpose()?,
+                errors,
+                limit,
+            },
+        )?,
+        Command::Verify { profile } => dotty.verify(profile)?,
+        Command::Repair { profile } => {
+            dotty.repair(profile)?;
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Unlink { profile } => {
+            dotty.unlink_profile(profile)?;
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Adopt { path, profile } => {
+            dotty.adopt_file(&path, profile)?;
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Discover { all, profile } => {
+            dotty.discover(all, profile)?;
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Export {
+            output,
+            encrypt,
+            profile,
+        } => dotty.export(&output, encrypt, profile)?,
+        Command::Import {
+            source:
+                ImportSource::Stow {
+                    dir,
+                    target,
+                    packages,
+                    replace_links,
+                    profile,
+                },
+        } => {
+            dotty.import_stow(&dir, target.as_deref(), &packages, replace_links, profile)?;
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Import {
+            source:
+                ImportSource::Archive {
+                    file,
+                    dry_run,
+                    yes,
+                    profile,
+                },
+        } => dotty.import_archive(&file, dry_run, yes, profile)?,
+        Command::Purge { path, yes } => {
+            dotty.purge_file(&path, yes)?;
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Untrack { path, profile } => {
+            dotty.untrack_file(&path, profile)?;
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Mv {
+            old,
+            new,
+            profile,
+            from,
+            to,
+        } => {
+            match (new, to) {
+                (_, Some(to)) => dotty.transfer_file(&old, from, &to, false)?,
+                (Some(new), None) => dotty.move_file(&old, &new, profile)?,
+                (None, None) => unreachable!("clap requires a new path or --to"),
+            }
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Cp { path, from, to } => {
+            dotty.transfer_file(&path, from, &to, true)?;
+            send_daemon_request(&DaemonRequest::ReloadConfig)?;
+        }
+        Command::Clean { repo, yes } => {
+            if !repo {
+                anyhow::bail!("Nothing to clean; pass --repo to remove orphaned repository files");
+            }
+            dotty.clean_repo(yes)?
+        }
+        Command::Audit { path } => dotty.audit(path.as_deref())?,
+        Command::Revert { path, to, profile } => dotty.revert_file(&path, &to, profile)?,
+        Command::Machines => dotty.list_machines()?,
+        Command::Status => dotty.status()?,
+        Command::Ui => dotty.dashboard()?,
+        Command::ShellEnv { profile } => dotty.shell_env(profile)?,
+        Command::InstallManpages { .. }
+        | Command::PromptInfo { .. }
+        | Command::Config { .. }
+        | Command::Plugins
+        | Command::Selftest { .. } => unreachable!(),
+        Command::Completions { shell } => {
+            let shells = clap_complete::env::Shells::builtins();
+            let completer = shells.completer(&shell).context("Unsupported shell")?;
+            completer
+                .write_registration(COMPLETE_VAR, "dotty", "dotty", "dotty", &mut io::stdout())
+                .context("Failed to write completion script")?;
+        }
+        Command::Fetch { patch } => dotty.fetch_preview(patch)?,
+        Command::Diff {
+            paths,
+            remote,
+            profile,
+        } => dotty.diff(&paths, remote, profile)?,
+        Command::Resolve {
+            path,
+            take,
+            profile,
+        } => dotty.resolve(&path, take, profile)?,
+        Command::MergeFrom {
+            host,
+            paths,
+            profile,
+        } => dotty.merge_from(&host, &paths, profile)?,
+        Command::Login { client_id, scopes } => dotty.login(client_id, scopes)?,
+        Command::Token { action } => match action {
+            TokenAction::Set => dotty.set_token()?,
+        },
+        Command::Remote { action } => match action {
+            RemoteAction::Set {
+                url,
+                branch,
+                auth,
+                key,
+                token,
+                backend,
+                public,
+            } => dotty.set_remote(url, branch, auth, key, token, backend, public)?,
+            RemoteAction::Show => dotty.show_remote()?,
+        },
+        Command::Ignore {
+            action,
+            test,
+            profile,
+        } => match (action, test) {
+            (Some(IgnoreAction::Add { patterns, profile }), _) => {
+                dotty.add_ignore_patterns(&patterns, profile)?;
+                send_daemon_request(&DaemonRequest::ReloadConfig)?;
+            }
+            (Some(IgnoreAction::Remove { patterns, profile }), _) => {
+                dotty.remove_ignore_patterns(&patterns, profile)?;
+                send_daemon_request(&DaemonRequest::ReloadConfig)?;
+            }
+            (Some(IgnoreAction::List { profile }), _) => dotty.list_ignore_patterns(profile)?,
+            (None, Some(path)) => dotty.test_ignore(&path, profile)?,
+            (None, None) => unreachable!(),
+        },
+        Command::Tag { action } => match action {
+            TagAction::Create { name, message } => dotty.create_tag(&name, message)?,
+            TagAction::List => dotty.list_tags()?,
+        },
+        Command::Repo { action } => match action {
+            RepoAction::Gc { aggressive } => dotty.gc_repo(aggressive)?,
+            RepoAction::Size { limit } => dotty.repo_size(limit)?,
+            RepoAction::Squash { since, yes } => dotty.squash_history(parse_since(&since)?, yes)?,
+        },
+        Command::Service { action } => match action {
+            ServiceAction::Install {
+                interval,
+                cron,
+                profile,
+            } => install_service(service_args(schedule_expression(interval, cron)?, profile))?,
+            ServiceAction::Uninstall => uninstall_service()?,
+            ServiceAction::Status => service_status()?,
+        },
+        Command::Schedule {
+            interval,
+            cron,
+            profile,
+        } => {
+            let schedule = schedule_expression(interval, cron)?
+                .context("Either --interval or --cron is required")?;
+            dotty.schedule_sync(&schedule, profile)?
+        }
     }
 
     Ok(())
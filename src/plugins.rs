@@ -0,0 +1,308 @@
+//! Plugins: storage backends, secret providers and profile detection
+//! conditions, either compiled in and registered at startup or provided by
+//! external executables.
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Where the repository lives instead of a git remote, e.g. Keybase or an
+/// object store. dotty still keeps a local git clone; the backend moves refs
+/// between it and the storage. `url` is `remote.github_repo`, whose meaning
+/// is up to the backend.
+pub trait StorageBackend: Send + Sync {
+    /// Branch names in the storage, empty if it holds no repository yet
+    fn list_branches(&self, url: &str) -> Result<Vec<String>>;
+    /// Updates `refs/remotes/origin/*` and `refs/tags/*` in the clone at `repo`
+    fn fetch(&self, repo: &Path, url: &str) -> Result<()>;
+    /// Publishes `refspecs` (git syntax, `+` forcing) from the clone at `repo`
+    fn push(&self, repo: &Path, url: &str, refspecs: &[&str]) -> Result<()>;
+}
+
+/// Keeps secrets such as the remote token in place of the system keyring
+pub trait SecretProvider: Send + Sync {
+    /// The secret stored under `key`, if any
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    /// Stores `value` under `key`, replacing what was there
+    fn set(&self, key: &str, value: &str) -> Result<()>;
+}
+
+/// Decides a `Plugin` profile detection condition
+pub trait DetectionProvider: Send + Sync {
+    /// Whether this machine matches, given the condition's `args`
+    fn matches(&self, args: &BTreeMap<String, String>) -> Result<bool>;
+}
+
+#[derive(Default)]
+struct Registry {
+    backends: HashMap<String, Arc<dyn StorageBackend>>,
+    secrets: HashMap<String, Arc<dyn SecretProvider>>,
+    detections: HashMap<String, Arc<dyn DetectionProvider>>,
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Makes a compiled-in backend available as `remote.backend = "<name>"`,
+/// taking precedence over an executable of the same name
+pub fn register_storage_backend(name: &str, backend: impl StorageBackend + 'static) {
+    let mut registry = registry().lock().unwrap();
+    registry
+        .backends
+        .insert(name.to_string(), Arc::new(backend));
+}
+
+/// Makes a compiled-in provider available as `remote.secret_provider = "<name>"`
+pub fn register_secret_provider(name: &str, provider: impl SecretProvider + 'static) {
+    let mut registry = registry().lock().unwrap();
+    registry
+        .secrets
+        .insert(name.to_string(), Arc::new(provider));
+}
+
+/// Makes a compiled-in detection condition available as
+/// `{ Plugin = { name = "<name>" } }`
+pub fn register_detection(name: &str, provider: impl DetectionProvider + 'static) {
+    let mut registry = registry().lock().unwrap();
+    registry
+        .detections
+        .insert(name.to_string(), Arc::new(provider));
+}
+
+/// The backend called `name`: a registered one, or the `dotty-backend-<name>`
+/// executable
+pub fn storage_backend(name: &str) -> Result<Arc<dyn StorageBackend>> {
+    if let Some(backend) = registry().lock().unwrap().backends.get(name) {
+        return Ok(backend.clone());
+    }
+    Ok(Arc::new(ExternalPlugin::find(PluginKind::Backend, name)?))
+}
+
+/// The secret provider called `name`: a registered one, or the
+/// `dotty-secret-<name>` executable
+pub fn secret_provider(name: &str) -> Result<Arc<dyn SecretProvider>> {
+    if let Some(provider) = registry().lock().unwrap().secrets.get(name) {
+        return Ok(provider.clone());
+    }
+    Ok(Arc::new(ExternalPlugin::find(PluginKind::Secret, name)?))
+}
+
+/// The detection condition called `name`: a registered one, or the
+/// `dotty-detect-<name>` executable
+pub fn detection(name: &str) -> Result<Arc<dyn DetectionProvider>> {
+    if let Some(provider) = registry().lock().unwrap().detections.get(name) {
+        return Ok(provider.clone());
+    }
+    Ok(Arc::new(ExternalPlugin::find(PluginKind::Detect, name)?))
+}
+
+/// The kinds of plugin, and the prefix of their executables' names
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PluginKind {
+    Backend,
+    Secret,
+    Detect,
+}
+
+impl PluginKind {
+    const ALL: [PluginKind; 3] = [PluginKind::Backend, PluginKind::Secret, PluginKind::Detect];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            PluginKind::Backend => "backend",
+            PluginKind::Secret => "secret",
+            PluginKind::Detect => "detect",
+        }
+    }
+
+    fn prefix(&self) -> String {
+        format!("dotty-{}-", self.name())
+    }
+}
+
+// The plugins directory next to config.toml comes first, then $PATH
+pub(crate) fn plugin_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = dirs::config_dir()
+        .map(|dir| dir.join("dotty").join("plugins"))
+        .into_iter()
+        .collect();
+    if let Some(path) = env::var_os("PATH") {
+        dirs.extend(env::split_paths(&path));
+    }
+    dirs
+}
+
+/// A plugin implemented by an executable named `dotty-<kind>-<name>`, looked
+/// up in the plugins directory next to config.toml and then on $PATH. Every
+/// call runs it with one JSON request on stdin,
+/// `{"version": 1, "method": ..., "params": {...}}`, and reads one JSON
+/// response from stdout, `{"result": ...}` or `{"error": "message"}`. Its
+/// stderr goes to the terminal.
+pub struct ExternalPlugin {
+    path: PathBuf,
+}
+
+impl ExternalPlugin {
+    /// Finds the executable for a plugin
+    pub fn find(kind: PluginKind, name: &str) -> Result<Self> {
+        let file_name = format!("{}{}{}", kind.prefix(), name, env::consts::EXE_SUFFIX);
+        plugin_dirs()
+            .into_iter()
+            .map(|dir| dir.join(&file_name))
+            .find(|path| path.is_file())
+            .map(|path| ExternalPlugin { path })
+            .with_context(|| {
+                format!(
+                    "No {} plugin named {}: {} isn't in the plugins directory or on PATH",
+                    kind.name(),
+                    name,
+                    file_name
+                )
+            })
+    }
+
+    /// Sends one request and returns the response's `result`
+    pub fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let request = json!({ "version": 1, "method": method, "params": params });
+        let mut child = process::Command::new(&self.path)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.path.display()))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(format!("{}\n", request).as_bytes())
+                .with_context(|| format!("Failed to write to {}", self.path.display()))?;
+        }
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("Failed to run {}", self.path.display()))?;
+        let response: Value = serde_json::from_slice(&output.stdout).with_context(|| {
+            format!(
+                "{} {} didn't answer with JSON ({})",
+                self.path.display(),
+                method,
+                output.status
+            )
+        })?;
+        if let Some(error) = response.get("error").filter(|error| !error.is_null()) {
+            anyhow::bail!(
+                "{} {} failed: {}",
+                self.path.display(),
+                method,
+                error
+                    .as_str()
+                    .map_or_else(|| error.to_string(), str::to_string)
+            );
+        }
+        if !output.status.success() {
+            anyhow::bail!(
+                "{} {} failed ({})",
+                self.path.display(),
+                method,
+                output.status
+            );
+        }
+        Ok(response.get("result").cloned().unwrap_or(Value::Null))
+    }
+}
+
+impl StorageBackend for ExternalPlugin {
+    fn list_branches(&self, url: &str) -> Result<Vec<String>> {
+        let result = self.call("list_branches", json!({ "url": url }))?;
+        serde_json::from_value(result).context("list_branches didn't return a list of names")
+    }
+
+    fn fetch(&self, repo: &Path, url: &str) -> Result<()> {
+        self.call("fetch", json!({ "repo": repo, "url": url }))?;
+        Ok(())
+    }
+
+    fn push(&self, repo: &Path, url: &str, refspecs: &[&str]) -> Result<()> {
+        self.call(
+            "push",
+            json!({ "repo": repo, "url": url, "refspecs": refspecs }),
+        )?;
+        Ok(())
+    }
+}
+
+impl SecretProvider for ExternalPlugin {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.call("get", json!({ "key": key }))? {
+            Value::Null => Ok(None),
+            Value::String(secret) => Ok(Some(secret)),
+            _ => anyhow::bail!("get didn't return a string"),
+        }
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.call("set", json!({ "key": key, "value": value }))?;
+        Ok(())
+    }
+}
+
+impl DetectionProvider for ExternalPlugin {
+    fn matches(&self, args: &BTreeMap<String, String>) -> Result<bool> {
+        self.call("matches", json!({ "args": args }))?
+            .as_bool()
+            .context("matches didn't return true or false")
+    }
+}
+
+/// Prints the registered plugins and the plugin executables that can be found
+pub fn list_plugins() -> Result<()> {
+    let registry = registry().lock().unwrap();
+    let registered = [
+        (
+            PluginKind::Backend,
+            registry.backends.keys().collect::<Vec<_>>(),
+        ),
+        (PluginKind::Secret, registry.secrets.keys().collect()),
+        (PluginKind::Detect, registry.detections.keys().collect()),
+    ];
+    let mut found = false;
+    for (kind, names) in registered {
+        for name in names {
+            println!("{:<8} {:<16} built in", kind.name(), name);
+            found = true;
+        }
+    }
+    let mut seen = Vec::new();
+    for dir in plugin_dirs() {
+        let Ok(entries) = dir.read_dir() else {
+            continue;
+        };
+        let mut entries: Vec<_> = entries.filter_map(|entry| entry.ok()).collect();
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let stem = file_name
+                .strip_suffix(env::consts::EXE_SUFFIX)
+                .unwrap_or(&file_name);
+            for kind in PluginKind::ALL {
+                let Some(name) = stem.strip_prefix(&kind.prefix()) else {
+                    continue;
+                };
+                // The first match on the search path is the one that runs
+                if name.is_empty() || seen.contains(&(kind, name.to_string())) {
+                    continue;
+                }
+                seen.push((kind, name.to_string()));
+                println!("{:<8} {:<16} {}", kind.name(), name, entry.path().display());
+                found = true;
+            }
+        }
+    }
+    if !found {
+        println!("No plugins found");
+    }
+    Ok(())
+}
@@ -0,0 +1,1488 @@
+//! Profile detection, and adding, moving and removing the files a profile
+//! tracks.
+
+use crate::config::{DetectionCondition, FileEntry, IgnoreRules, LinkMode, ProfileConfig};
+use crate::daemon::is_editor_temp_file;
+use crate::error::profile_not_found;
+use crate::plugins;
+use crate::repo::{Tombstone, Tombstones, TOMBSTONES_PATH};
+use crate::status::print_text_diff;
+use crate::sync::SyncTrigger;
+use crate::util::{
+    confirm, convert_line_endings, create_symlink, dir_size, format_bytes, home_key, host_name,
+    is_secret_path, key_for_path, prompt, remove_link, run_editor, shell_quote, unix_seconds,
+};
+use crate::Dotty;
+use anyhow::{Context, Result};
+use colored::*;
+use ignore::WalkBuilder;
+use log::{info, warn};
+use similar::TextDiff;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{env, fs};
+
+impl Dotty {
+    pub(crate) fn detect_profile(&self) -> String {
+        if let Some(profile_detection) = &self.config.profile_detection {
+            for rule in &profile_detection.rules {
+                if rule
+                    .conditions
+                    .iter()
+                    .all(|condition| self.check_condition(condition))
+                {
+                    return rule.profile.clone();
+                }
+            }
+        }
+        "default".to_string()
+    }
+
+    pub(crate) fn check_condition(&self, condition: &DetectionCondition) -> bool {
+        match condition {
+            DetectionCondition::Hostname(hostname) => {
+                hostname == &hostname::get().unwrap().to_string_lossy()
+            }
+            DetectionCondition::OS(os) => os == env::consts::OS,
+            DetectionCondition::EnvVar { name, value } => {
+                env::var(name).map(|v| v == *value).unwrap_or(false)
+            }
+            DetectionCondition::Wsl(expected) => in_wsl() == *expected,
+            DetectionCondition::Container(expected) => in_container() == *expected,
+            DetectionCondition::Plugin { name, args } => {
+                match plugins::detection(name).and_then(|plugin| plugin.matches(args)) {
+                    Ok(matches) => matches,
+                    Err(e) => {
+                        warn!("Detection plugin {} failed: {:#}", name, e);
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    /// Starts tracking a file or directory in a profile
+    pub fn add_file(&mut self, path: &Path, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get_mut(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+
+        let canonical_path = path.canonicalize().context("Failed to canonicalize path")?;
+        let key = key_for_path(&canonical_path)?;
+        profile_config.files.insert(
+            key.clone(),
+            FileEntry::Source(canonical_path.to_string_lossy().into_owned()),
+        );
+        self.save_config()?;
+        info!("Added file: {:?} to profile {}", key, profile);
+
+        let oversized = WalkBuilder::new(&canonical_path)
+            .hidden(false)
+            .build()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| self.too_large(entry.path(), None))
+            .count();
+        if oversized > 0 {
+            warn!(
+                "{} file(s) in {} exceed max_file_size and will not be synced; set allow_large = true on the entry to sync them",
+                oversized, key
+            );
+        }
+
+        self.clear_tombstone(&profile, &key)
+    }
+
+    /// Lists the dotfiles under $HOME and $XDG_CONFIG_HOME that aren't tracked
+    /// yet in a fuzzy multi-select. App directories directly under the config
+    /// directory can be picked whole.
+    pub fn add_interactive(&mut self, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let config_dir = dirs::config_dir().context("Failed to get config directory")?;
+        let mut excluded: Vec<PathBuf> = profile_config
+            .files
+            .iter()
+            .flat_map(|(key, entry)| {
+                [
+                    profile_config.destination(&home, key),
+                    PathBuf::from(entry.source()),
+                ]
+            })
+            .collect();
+        excluded.push(self.repo_path()?);
+        excluded.push(config_dir.join("dotty"));
+        let patterns: Vec<String> = PICKER_IGNORED
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .chain(self.config.ignore_patterns.iter().cloned())
+            .chain(profile_config.ignore_patterns.iter().cloned())
+            .collect();
+
+        let mut roots = vec![home.clone()];
+        if !config_dir.starts_with(&home) {
+            roots.push(config_dir.clone());
+        }
+        let mut candidates = BTreeSet::new();
+        for root in roots {
+            let rules = IgnoreRules::new(&root, patterns.iter(), None, &[]);
+            let in_home = root == home;
+            let excluded = excluded.clone();
+            let walker = WalkBuilder::new(&root)
+                .hidden(false)
+                .git_ignore(false)
+                .max_depth(Some(4))
+                .filter_entry(move |entry| {
+                    let path = entry.path();
+                    // Only dotfiles at the top of $HOME, not Documents and the like
+                    let dotfile = !in_home
+                        || entry.depth() != 1
+                        || entry.file_name().to_string_lossy().starts_with('.');
+                    dotfile
+                        && !rules.is_ignored(path)
+                        && !excluded.iter().any(|excluded| path.starts_with(excluded))
+                })
+                .build();
+            for entry in walker.filter_map(|entry| entry.ok()) {
+                let path = entry.path();
+                let app_dir = path.parent() == Some(config_dir.as_path()) && path.is_dir();
+                if (path.is_file() && !is_editor_temp_file(path)) || app_dir {
+                    candidates.insert(path.to_path_buf());
+                }
+            }
+        }
+        if candidates.is_empty() {
+            println!("No untracked files found");
+            return Ok(());
+        }
+
+        let options: Vec<String> = candidates
+            .iter()
+            .map(|path| match path.strip_prefix(&home) {
+                Ok(relative) if path.is_dir() => format!("~/{}/", relative.display()),
+                Ok(relative) => format!("~/{}", relative.display()),
+                Err(_) => path.display().to_string(),
+            })
+            .collect();
+        let selected = inquire::MultiSelect::new("Track which files?", options.clone())
+            .with_page_size(15)
+            .with_help_message("type to filter, space to select, enter to confirm")
+            .prompt_skippable()
+            .context("Failed to show file picker")?
+            .unwrap_or_default();
+        for option in &selected {
+            let index = options.iter().position(|o| o == option).unwrap();
+            let path = candidates.iter().nth(index).unwrap();
+            self.add_file(path, Some(profile.clone()))?;
+            println!("Added {}", option);
+        }
+        if selected.is_empty() {
+            println!("Nothing added");
+        }
+        Ok(())
+    }
+
+    // Re-adding a removed file revives it; the next sync commits this
+    pub(crate) fn clear_tombstone(&self, profile: &str, key: &str) -> Result<()> {
+        let tombstones_path = self.repo_path()?.join(TOMBSTONES_PATH);
+        if tombstones_path.exists() {
+            let mut tombstones = Tombstones::load(&tombstones_path)?;
+            let before = tombstones.tombstones.len();
+            tombstones
+                .tombstones
+                .retain(|tombstone| tombstone.profile != profile || tombstone.path != key);
+            if tombstones.tombstones.len() != before {
+                tombstones.save(&tombstones_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops tracking a file and removes it from the repository's history
+    pub fn purge_file(&mut self, path: &Path, yes: bool) -> Result<()> {
+        let key = self.tracked_key(path)?;
+        let repo = self.open_repo()?;
+        let repo_path = self.repo_path()?;
+
+        // A force push must not throw away commits other machines pushed
+        self.fetch_remote(&repo)
+            .context("Failed to fetch the remote before rewriting history")?;
+        let head = repo.head()?.peel_to_commit()?.id();
+        let branch = self.branch();
+        if let Ok(remote_head) = repo.refname_to_id(&format!("refs/remotes/origin/{}", branch)) {
+            if remote_head != head && !repo.graph_descendant_of(head, remote_head)? {
+                anyhow::bail!("The remote has commits this machine does not; sync before purging");
+            }
+        }
+
+        println!(
+            "{}",
+            format!(
+                "WARNING: this permanently removes {} from every commit in {} and force-pushes the rewritten history.",
+                key,
+                repo_path.display()
+            )
+            .red()
+            .bold()
+        );
+        println!(
+            "{}",
+            "All commit ids change, so other machines must re-clone the repository.".red()
+        );
+        println!(
+            "{}",
+            "Clones and forks made before now still contain the file; rotate any secret it held."
+                .red()
+        );
+        if !yes && !confirm(&format!("Purge {} from all history?", key))? {
+            println!("Aborted");
+            return Ok(());
+        }
+
+        let mut revwalk = repo.revwalk().context("Failed to walk history")?;
+        revwalk.push_head().context("Failed to get HEAD")?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+        let mut rewritten: HashMap<git2::Oid, git2::Oid> = HashMap::new();
+        let mut purged_commits = 0;
+        let candidates = self.config.repo_key_candidates(&key);
+        for oid in revwalk {
+            let oid = oid?;
+            let commit = repo.find_commit(oid).context("Failed to find commit")?;
+            let tree = commit.tree()?;
+            let stored: Vec<&String> = candidates
+                .iter()
+                .filter(|path| tree.get_path(Path::new(path)).is_ok())
+                .collect();
+            let tree = if stored.is_empty() {
+                tree
+            } else {
+                purged_commits += 1;
+                let mut update = git2::build::TreeUpdateBuilder::new();
+                for path in stored {
+                    update.remove(path);
+                }
+                let tree_id = update
+                    .create_updated(&repo, &tree)
+                    .context("Failed to rewrite tree")?;
+                repo.find_tree(tree_id)?
+            };
+            let parents = commit
+                .parent_ids()
+                .map(|id| repo.find_commit(rewritten[&id]))
+                .collect::<Result<Vec<_>, _>>()?;
+            let parents: Vec<&git2::Commit> = parents.iter().collect();
+            let new_id = repo
+                .commit(
+                    None,
+                    &commit.author(),
+                    &commit.committer(),
+                    &String::from_utf8_lossy(commit.message_bytes()),
+                    &tree,
+                    &parents,
+                )
+                .context("Failed to rewrite commit")?;
+            rewritten.insert(oid, new_id);
+        }
+        if purged_commits == 0 {
+            anyhow::bail!("{} does not appear in the repository history", key);
+        }
+
+        let new_head = repo.find_object(rewritten[&head], None)?;
+        let branch_ref = format!("refs/heads/{}", branch);
+        repo.reference(&branch_ref, new_head.id(), true, "dotty purge")
+            .context("Failed to update branch")?;
+        repo.reset(&new_head, git2::ResetType::Hard, None)
+            .context("Failed to reset the work tree")?;
+
+        let mut refspecs = vec![format!("+{}:{}", branch_ref, branch_ref)];
+        for name in repo.tag_names(None)?.iter().flatten() {
+            let refname = format!("refs/tags/{}", name);
+            let target = repo.refname_to_id(&refname)?;
+            if let Ok(tag) = repo.find_tag(target) {
+                let Some(&new_target) = rewritten.get(&tag.target_id()) else {
+                    continue;
+                };
+                let tagger = tag.tagger().unwrap_or(repo.signature()?);
+                repo.tag(
+                    name,
+                    &repo.find_object(new_target, None)?,
+                    &tagger,
+                    tag.message().unwrap_or_default(),
+                    true,
+                )?;
+            } else if let Some(&new_target) = rewritten.get(&target) {
+                repo.reference(&refname, new_target, true, "dotty purge")?;
+            } else {
+                continue;
+            }
+            refspecs.push(format!("+{}:{}", refname, refname));
+        }
+        let refspecs: Vec<&str> = refspecs.iter().map(String::as_str).collect();
+        self.push_refspecs(&repo, &refspecs)?;
+        println!("Purged {} from {} commit(s)", key, purged_commits);
+
+        let profiles: Vec<String> = self
+            .config
+            .profiles
+            .iter()
+            .filter(|(_, profile_config)| profile_config.files.contains_key(&key))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for profile in profiles {
+            self.untrack_file(Path::new(&key), Some(profile))?;
+        }
+        Ok(())
+    }
+
+    /// Takes over a destination that is a symlink into another dotfile manager's
+    /// directory, tracking the link's own path rather than its target
+    pub fn adopt_file(&mut self, path: &Path, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        if !path.is_symlink() {
+            anyhow::bail!(
+                "{} is not a symlink; use `dotty add` instead",
+                path.display()
+            );
+        }
+        let target = path
+            .canonicalize()
+            .with_context(|| format!("{} is a broken symlink", path.display()))?;
+        let key = home_key(path)?;
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        if profile_config.files.contains_key(&key) {
+            anyhow::bail!("{} is already tracked in profile {}", key, profile);
+        }
+
+        let dest = dirs::home_dir()
+            .context("Failed to get home directory")?
+            .join(&key);
+        // Symlinked profiles keep linking to the old location; otherwise the
+        // link is replaced by a copy that dotty owns outright
+        let source = if profile_config.link_mode() == LinkMode::Symlink {
+            target.clone()
+        } else {
+            remove_link(&dest).context("Failed to remove symlink")?;
+            self.copy_entry(&target, &dest, profile_config)?;
+            dest
+        };
+
+        self.config
+            .profiles
+            .get_mut(&profile)
+            .unwrap()
+            .files
+            .insert(
+                key.clone(),
+                FileEntry::Source(source.to_string_lossy().into_owned()),
+            );
+        self.save_config()?;
+        self.clear_tombstone(&profile, &key)?;
+        println!(
+            "Adopted {} (was a link to {}) into profile {}",
+            key,
+            target.display(),
+            profile
+        );
+        Ok(())
+    }
+
+    /// Offers common dotfiles that exist on this machine but aren't tracked
+    pub fn discover(&mut self, all: bool, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let tracked: Vec<PathBuf> = profile_config
+            .files
+            .keys()
+            .map(|key| profile_config.destination(&home, key))
+            .collect();
+        let found: Vec<&str> = DISCOVER_CANDIDATES
+            .iter()
+            .copied()
+            .filter(|candidate| {
+                let path = home.join(candidate);
+                path.exists()
+                    && !tracked
+                        .iter()
+                        .any(|dest| path.starts_with(dest) || dest.starts_with(&path))
+            })
+            .collect();
+        if found.is_empty() {
+            println!("No untracked dotfiles found");
+            return Ok(());
+        }
+
+        for (number, candidate) in found.iter().enumerate() {
+            let path = home.join(candidate);
+            let size = if path.is_dir() {
+                dir_size(&path)
+            } else {
+                fs::metadata(&path).map_or(0, |metadata| metadata.len())
+            };
+            println!(
+                "{:>3}. ~/{}  {}{}",
+                number + 1,
+                candidate,
+                format_bytes(size).dimmed(),
+                if is_secret_path(candidate) {
+                    "  (sensitive)".yellow()
+                } else {
+                    "".normal()
+                }
+            );
+        }
+        let selected: Vec<&str> = if all {
+            found
+        } else if io::stdin().is_terminal() {
+            let answer = prompt("Add which? (e.g. 1 3 5-7, \"all\", or nothing to skip)")?;
+            parse_selection(&answer, found.len())?
+                .into_iter()
+                .map(|index| found[index])
+                .collect()
+        } else {
+            println!("Run `dotty discover --all` or `dotty add <path>` to track them");
+            return Ok(());
+        };
+
+        for candidate in &selected {
+            self.add_file(&home.join(candidate), Some(profile.clone()))?;
+        }
+        println!(
+            "Added {} file(s) to profile {}; run `dotty sync` to commit them",
+            selected.len(),
+            profile
+        );
+        Ok(())
+    }
+
+    /// Tracks every file of the given stow packages (all of them by default)
+    /// under the path stow links it to. A directory stow folded into a single
+    /// symlink becomes one entry.
+    pub fn import_stow(
+        &mut self,
+        dir: &Path,
+        target: Option<&Path>,
+        packages: &[String],
+        replace_links: bool,
+        profile: Option<String>,
+    ) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("Failed to find stow directory {}", dir.display()))?;
+        let target = match target {
+            Some(target) => target.to_path_buf(),
+            None => dir
+                .parent()
+                .context("The stow directory has no parent to use as the target")?
+                .to_path_buf(),
+        }
+        .canonicalize()
+        .context("Failed to find the stow target directory")?;
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .context("Failed to read stow directory")?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| !name.starts_with('.'))
+            .collect();
+        names.sort_unstable();
+        if let Some(missing) = packages.iter().find(|package| !names.contains(package)) {
+            anyhow::bail!("No stow package named {} in {}", missing, dir.display());
+        }
+        if !packages.is_empty() {
+            names.retain(|name| packages.contains(name));
+        }
+
+        let mut mappings = Vec::new();
+        for name in &names {
+            stow_mappings(&dir.join(name), &target, &mut mappings)?;
+        }
+        let mut imported = Vec::new();
+        for (source, dest) in mappings {
+            let key = key_for_path(&dest)?;
+            if profile_config.files.contains_key(&key) {
+                warn!("{} is already tracked in profile {}", key, profile);
+                continue;
+            }
+            let stowed = dest.is_symlink() && dest.canonicalize().ok().as_ref() == Some(&source);
+            if replace_links && stowed {
+                remove_link(&dest).context("Failed to remove stow symlink")?;
+                self.install_destination(
+                    &source,
+                    &dest,
+                    &source.to_string_lossy(),
+                    profile_config.link_mode(),
+                    profile_config.line_ending(&key),
+                    profile_config,
+                )?;
+            }
+            println!("Imported {} from {}", key, source.display());
+            imported.push((key, source));
+        }
+
+        let files = &mut self.config.profiles.get_mut(&profile).unwrap().files;
+        for (key, source) in &imported {
+            files.insert(
+                key.clone(),
+                FileEntry::Source(source.to_string_lossy().into_owned()),
+            );
+        }
+        self.save_config()?;
+        for (key, _) in &imported {
+            self.clear_tombstone(&profile, key)?;
+        }
+        println!(
+            "Imported {} file(s) from {} stow package(s) into profile {}",
+            imported.len(),
+            names.len(),
+            profile
+        );
+        Ok(())
+    }
+
+    /// Replaces a profile's symlinked destinations with copies of their sources
+    pub fn unlink_profile(&mut self, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+
+        let mut converted = 0;
+        for (relative_path, entry) in &profile_config.files {
+            let canonical_path = entry.source();
+            let dest = profile_config.destination(&home, relative_path);
+            let source = Path::new(canonical_path);
+            if !dest.is_symlink() || fs::read_link(&dest)? != source {
+                continue;
+            }
+            if !source.exists() {
+                warn!(
+                    "{} links to missing {}; leaving it",
+                    dest.display(),
+                    canonical_path
+                );
+                continue;
+            }
+            remove_link(&dest).context("Failed to remove symlink")?;
+            self.copy_entry(source, &dest, profile_config)?;
+            println!(
+                "Replaced {} with a copy of {}",
+                relative_path, canonical_path
+            );
+            converted += 1;
+        }
+
+        // Otherwise the next sync would put the links straight back
+        if profile_config.link_mode() == LinkMode::Symlink {
+            let profile_config = self.config.profiles.get_mut(&profile).unwrap();
+            profile_config.use_symlinks = false;
+            profile_config.mode = None;
+            self.save_config()?;
+            println!("Switched profile {} to copying files", profile);
+        }
+        println!("Converted {} symlink(s) to regular files", converted);
+        Ok(())
+    }
+
+    /// Restores destinations that are missing or no longer point at their source
+    pub fn repair(&mut self, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let mut broken: Vec<(String, String)> = profile_config
+            .files
+            .iter()
+            .filter(|(relative_path, _)| {
+                let dest = profile_config.destination(&home, relative_path);
+                dest.is_symlink() && !dest.exists()
+            })
+            .map(|(relative_path, entry)| (relative_path.clone(), entry.source().to_string()))
+            .collect();
+        if broken.is_empty() {
+            println!("No broken symlinks in profile {}", profile);
+            return Ok(());
+        }
+        broken.sort();
+
+        let repo_path = self.repo_path()?;
+        for (relative_path, source) in broken {
+            let dest = self.config.profiles[&profile].destination(&home, &relative_path);
+            println!("{} -> {} {}", relative_path, source, "(missing)".red());
+            let repo_copy = repo_path.join(self.repo_copy_key(&profile, &relative_path)?);
+            let answer = prompt(if repo_copy.exists() {
+                "[r]e-point to a new source, [c]opy back from the repo, [u]ntrack or [s]kip?"
+            } else {
+                "[r]e-point to a new source, [u]ntrack or [s]kip?"
+            })?;
+            match answer.as_str() {
+                "r" => {
+                    let new_source = PathBuf::from(prompt("New source path:")?);
+                    let new_source = new_source
+                        .canonicalize()
+                        .with_context(|| format!("{} does not exist", new_source.display()))?;
+                    remove_link(&dest).context("Failed to remove symlink")?;
+                    create_symlink(&new_source, &dest)?;
+                    self.config
+                        .profiles
+                        .get_mut(&profile)
+                        .unwrap()
+                        .files
+                        .get_mut(&relative_path)
+                        .unwrap()
+                        .set_source(new_source.to_string_lossy().into_owned());
+                    println!("Re-pointed {} to {}", relative_path, new_source.display());
+                }
+                // The original source is gone, so the restored copy becomes
+                // its own source
+                "c" if repo_copy.exists() => {
+                    remove_link(&dest).context("Failed to remove symlink")?;
+                    self.copy_entry(&repo_copy, &dest, &self.config.profiles[&profile])?;
+                    self.config
+                        .profiles
+                        .get_mut(&profile)
+                        .unwrap()
+                        .files
+                        .get_mut(&relative_path)
+                        .unwrap()
+                        .set_source(dest.to_string_lossy().into_owned());
+                    println!("Restored {} from the repository", relative_path);
+                }
+                "u" => {
+                    remove_link(&dest).context("Failed to remove symlink")?;
+                    self.config
+                        .profiles
+                        .get_mut(&profile)
+                        .unwrap()
+                        .files
+                        .remove(&relative_path);
+                    println!("Untracked {}", relative_path);
+                }
+                _ => println!("Skipped {}", relative_path),
+            }
+        }
+        self.save_config()
+    }
+
+    /// Moves or copies a tracked entry to another profile
+    pub fn transfer_file(
+        &mut self,
+        path: &Path,
+        from: Option<String>,
+        to: &str,
+        keep: bool,
+    ) -> Result<()> {
+        let from = from.unwrap_or_else(|| self.current_profile.clone());
+        if from == to {
+            anyhow::bail!("Source and target profile are both {}", to);
+        }
+        let key = self.tracked_key(path)?;
+        let entry = self
+            .config
+            .profiles
+            .get(&from)
+            .with_context(|| profile_not_found(&from))?
+            .files
+            .get(&key)
+            .with_context(|| format!("{} is not tracked in profile {}", key, from))?
+            .clone();
+        let from_config = &self.config.profiles[&from];
+        let to_config = self
+            .config
+            .profiles
+            .get(to)
+            .with_context(|| profile_not_found(to))?;
+        match to_config.files.get(&key) {
+            Some(existing) if existing.source() != entry.source() => {
+                anyhow::bail!(
+                    "{} is already tracked in profile {} from {}",
+                    key,
+                    to,
+                    existing.source()
+                )
+            }
+            _ => {}
+        }
+
+        if !self.should_sync(Path::new(entry.source()), to_config) {
+            warn!(
+                "{} matches an ignore pattern of profile {} and will not be synced there",
+                key, to
+            );
+        }
+        if entry.mode().is_none() && from_config.link_mode() != to_config.link_mode() {
+            let how = match to_config.link_mode() {
+                LinkMode::Copy => "copied",
+                LinkMode::Symlink => "symlinked",
+                LinkMode::Hardlink => "hardlinked",
+            };
+            println!("Note: {} will be {} in profile {}", key, how, to);
+        }
+
+        self.config
+            .profiles
+            .get_mut(to)
+            .unwrap()
+            .files
+            .insert(key.clone(), entry);
+        if !keep {
+            self.config
+                .profiles
+                .get_mut(&from)
+                .unwrap()
+                .files
+                .remove(&key);
+        }
+        self.save_config()?;
+        self.clear_tombstone(to, &key)?;
+
+        let verb = if keep { "Copied" } else { "Moved" };
+        println!("{} {} from profile {} to {}", verb, key, from, to);
+        Ok(())
+    }
+
+    /// Stops tracking a file and deletes its copy in the repository
+    pub fn remove_file(&mut self, path: &Path, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get_mut(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+
+        let canonical_path = path.canonicalize().context("Failed to canonicalize path")?;
+        let key = key_for_path(&canonical_path)?;
+        if profile_config.files.remove(&key).is_some() {
+            self.save_config()?;
+            info!("Removed file: {:?} from profile {}", key, profile);
+            self.record_tombstone(&profile, &key)?;
+        } else {
+            warn!("File not found in config: {:?}", key);
+        }
+        Ok(())
+    }
+
+    /// Gives this machine (or with `os`, every machine on this OS) its own
+    /// copy of an entry in the repository, starting from the current source.
+    /// Syncs here then read and write `<key>.<hostname>` (or `<key>.<os>`)
+    /// while other machines keep using the entry's own copy.
+    pub fn add_overlay(&self, path: &Path, os: bool, profile: Option<String>) -> Result<()> {
+        let (profile, key, overlay) = self.overlay_path(path, os, profile)?;
+        let repo_path = self.repo_path()?;
+        let target = repo_path.join(&overlay);
+        if target.exists() {
+            anyhow::bail!("{} already has the overlay {}", key, overlay);
+        }
+        let profile_config = &self.config.profiles[&profile];
+        let source = Path::new(profile_config.files[&key].source());
+        if !source.exists() {
+            anyhow::bail!("Source file missing: {:?}", source);
+        }
+        fs::create_dir_all(target.parent().unwrap())
+            .context("Failed to create parent directories")?;
+        self.copy_entry(source, &target, profile_config)?;
+        if profile_config.line_ending(&key).is_some() {
+            convert_line_endings(&target, false)?;
+        }
+
+        let repo = self.open_repo()?;
+        let mut index = repo.index().context("Failed to get repo index")?;
+        index
+            .add_all([&overlay].iter(), git2::IndexAddOption::DEFAULT, None)
+            .context("Failed to add overlay to index")?;
+        index.write().context("Failed to write index")?;
+        self.commit_index(&repo, &mut index, &format!("Add overlay {}", overlay))?;
+        if let Err(e) = self.push_branch(&repo) {
+            warn!("Failed to push overlay {}: {:#}", overlay, e);
+        }
+        println!("Syncs on this machine now keep {} in {}", key, overlay);
+        Ok(())
+    }
+
+    /// Deletes an overlay made with [`Dotty::add_overlay`]; the machines it
+    /// applied to go back to the entry's own copy on their next sync
+    pub fn remove_overlay(&self, path: &Path, os: bool, profile: Option<String>) -> Result<()> {
+        let (_, key, overlay) = self.overlay_path(path, os, profile)?;
+        let target = self.repo_path()?.join(&overlay);
+        if !target.exists() {
+            anyhow::bail!("{} has no overlay {}", key, overlay);
+        }
+        if target.is_dir() {
+            fs::remove_dir_all(&target)
+        } else {
+            fs::remove_file(&target)
+        }
+        .with_context(|| format!("Failed to delete {}", target.display()))?;
+
+        let repo = self.open_repo()?;
+        let mut index = repo.index().context("Failed to get repo index")?;
+        index
+            .remove_all([&overlay].iter(), None)
+            .context("Failed to remove overlay from index")?;
+        index.write().context("Failed to write index")?;
+        self.commit_index(&repo, &mut index, &format!("Remove overlay {}", overlay))?;
+        if let Err(e) = self.push_branch(&repo) {
+            warn!("Failed to push removal of overlay {}: {:#}", overlay, e);
+        }
+        println!("Removed {}; {} uses the shared copy again", overlay, key);
+        Ok(())
+    }
+
+    // The profile, key and repository path of a tracked path's overlay for
+    // this machine, or its OS
+    fn overlay_path(
+        &self,
+        path: &Path,
+        os: bool,
+        profile: Option<String>,
+    ) -> Result<(String, String, String)> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let key = self.tracked_key(path)?;
+        if !profile_config.files.contains_key(&key) {
+            anyhow::bail!("{} is not tracked in profile {}", key, profile);
+        }
+        let suffix = if os {
+            env::consts::OS.to_string()
+        } else {
+            host_name()
+        };
+        let overlay = format!("{}.{}", self.config.repo_key(&profile, &key), suffix);
+        Ok((profile, key, overlay))
+    }
+
+    // Commits a tombstone so other machines drop the file on their next sync,
+    // and deletes the file from the repo unless another profile still uses it
+    pub(crate) fn record_tombstone(&self, profile: &str, key: &str) -> Result<()> {
+        let repo = self.open_repo()?;
+        let repo_path = self.repo_path()?;
+        let tombstones_path = repo_path.join(TOMBSTONES_PATH);
+        let mut tombstones = Tombstones::load(&tombstones_path)?;
+        tombstones
+            .tombstones
+            .retain(|tombstone| tombstone.profile != profile || tombstone.path != key);
+        tombstones.tombstones.push(Tombstone {
+            profile: profile.to_string(),
+            path: key.to_string(),
+            host: hostname::get()
+                .map(|h| h.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| "unknown".to_string()),
+            removed_at: unix_seconds(SystemTime::now()),
+        });
+        tombstones.save(&tombstones_path)?;
+
+        let mut index = repo.index().context("Failed to get repo index")?;
+        index
+            .add_path(Path::new(TOMBSTONES_PATH))
+            .context("Failed to add tombstones to index")?;
+        self.remove_repo_copy(&mut index, key)?;
+        index.write().context("Failed to write index")?;
+        self.commit_index(
+            &repo,
+            &mut index,
+            &format!("Remove {} from profile {}", key, profile),
+        )?;
+        if let Err(e) = self
+            .push_branch(&repo)
+            .and_then(|()| self.publish_shared(&repo))
+        {
+            warn!("Failed to push removal of {}: {:#}", key, e);
+        }
+        Ok(())
+    }
+
+    // Deletes a key from the repo work tree and index wherever it is stored,
+    // except where a profile still tracks it
+    pub(crate) fn remove_repo_copy(&self, index: &mut git2::Index, key: &str) -> Result<bool> {
+        let in_use = self.tracked_repo_keys(false);
+        let repo_path = self.repo_path()?;
+        let mut removed = false;
+        for repo_key in self.config.repo_key_candidates(key) {
+            let file_path = repo_path.join(&repo_key);
+            if in_use.contains(&repo_key) || !file_path.exists() {
+                continue;
+            }
+            if file_path.is_dir() {
+                fs::remove_dir_all(&file_path)
+            } else {
+                fs::remove_file(&file_path)
+            }
+            .with_context(|| format!("Failed to delete {}", file_path.display()))?;
+            index
+                .remove_all([&repo_key].iter(), None)
+                .context("Failed to remove file from index")?;
+            removed = true;
+        }
+        Ok(removed)
+    }
+
+    // Repository paths of every tracked entry, optionally only those whose
+    // source still exists
+    pub(crate) fn tracked_repo_keys(&self, live: bool) -> HashSet<String> {
+        self.config
+            .profiles
+            .iter()
+            .flat_map(|(profile, profile_config)| {
+                profile_config
+                    .files
+                    .iter()
+                    .filter(move |(_, entry)| !live || Path::new(entry.source()).exists())
+                    .map(move |(key, _)| self.config.repo_key(profile, key))
+            })
+            .collect()
+    }
+
+    /// Stops tracking a file, leaving the file itself in place
+    pub fn untrack_file(&mut self, path: &Path, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let key = self.tracked_key(path)?;
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let source = profile_config
+            .files
+            .get(&key)
+            .with_context(|| format!("{} is not tracked in profile {}", key, profile))?
+            .source();
+
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let dest = profile_config.destination(&home, &key);
+        if dest.is_symlink() {
+            let source = Path::new(source);
+            if !source.exists() {
+                anyhow::bail!(
+                    "{} links to {}, which no longer exists",
+                    dest.display(),
+                    source.display()
+                );
+            }
+            remove_link(&dest).context("Failed to remove symlink")?;
+            self.copy_entry(source, &dest, profile_config)?;
+            println!(
+                "Replaced symlink {} with a copy of its target",
+                dest.display()
+            );
+        }
+
+        self.config
+            .profiles
+            .get_mut(&profile)
+            .unwrap()
+            .files
+            .remove(&key);
+        self.save_config()?;
+
+        let repo = self.open_repo()?;
+        let mut index = repo.index().context("Failed to get repo index")?;
+        if self.remove_repo_copy(&mut index, &key)? {
+            index.write().context("Failed to write index")?;
+            self.commit_index(&repo, &mut index, &format!("Untrack {}", key))?;
+            self.push_branch(&repo)?;
+        }
+        println!("Untracked {}", key);
+        Ok(())
+    }
+
+    // Rewrites the scripts when they changed. Nothing is created until the
+    // profile declares an alias or variable; existing scripts are kept
+    // (possibly emptied) so the rc files sourcing them keep working.
+    pub(crate) fn write_shell_env(&self, profile: &str) -> Result<()> {
+        let profile_config = self
+            .config
+            .profiles
+            .get(profile)
+            .with_context(|| profile_not_found(profile))?;
+        let config_dir = self.config_path.parent().context("Invalid config path")?;
+        let declared = !profile_config.aliases.is_empty() || !profile_config.env.is_empty();
+        for shell in SHELL_ENV_SCRIPTS {
+            let path = config_dir.join(format!("env.{}", shell));
+            if !declared && !path.exists() {
+                continue;
+            }
+            let script = shell_env_script(profile, profile_config, shell);
+            if fs::read_to_string(&path).ok().as_deref() != Some(script.as_str()) {
+                fs::write(&path, script)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints the profile's aliases and environment for the current shell
+    pub fn shell_env(&self, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        if profile_config.aliases.is_empty() && profile_config.env.is_empty() {
+            println!(
+                "Profile {} declares no aliases or environment variables; add them under [profiles.{}.aliases] and [profiles.{}.env]",
+                profile, profile, profile
+            );
+        }
+        self.write_shell_env(&profile)?;
+        let config_dir = self.config_path.parent().context("Invalid config path")?;
+        let script = |shell: &str| config_dir.join(format!("env.{}", shell));
+        if !script("sh").exists() {
+            return Ok(());
+        }
+        println!("Source the generated script from your shell's startup file:");
+        println!(
+            "  bash/zsh    [ -f {0} ] && . {0}",
+            shell_quote(&script("sh").display().to_string())
+        );
+        println!(
+            "  fish        test -f {0}; and source {0}",
+            shell_quote(&script("fish").display().to_string())
+        );
+        println!(
+            "  powershell  if (Test-Path '{0}') {{ . '{0}' }}",
+            script("ps1").display()
+        );
+        Ok(())
+    }
+
+    /// Edits the source, since a sync overwrites destination-only changes,
+    /// then offers to sync just that entry
+    pub fn edit(&mut self, name: &str, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let key = match self.tracked_key(Path::new(name)) {
+            Ok(key) if profile_config.files.contains_key(&key) => key,
+            _ => find_tracked_entry(profile_config, name)?,
+        };
+        let source = PathBuf::from(profile_config.files[&key].source());
+        if source.is_dir() {
+            anyhow::bail!("{} is a directory; open a file inside it instead", key);
+        }
+
+        let before = fs::read_to_string(&source).unwrap_or_default();
+        run_editor(&source)?;
+        let after = fs::read_to_string(&source).unwrap_or_default();
+        if before == after {
+            println!("No changes to {}", key);
+            return Ok(());
+        }
+        print_text_diff(&TextDiff::from_lines(&before, &after));
+
+        if !confirm(&format!("Sync {} now?", key))? {
+            println!("Left {} unsynced; run `dotty sync` later", key);
+            return Ok(());
+        }
+        let files = HashSet::from([key]);
+        self.sync(Some(profile), Some(&files), SyncTrigger::Manual)
+    }
+
+    /// Maps a user-supplied path (absolute, relative to the cwd, or already a
+    /// config key) to the key it is tracked under
+    pub fn tracked_key(&self, path: &Path) -> Result<String> {
+        let as_given = path.to_string_lossy().into_owned();
+        if self
+            .config
+            .profiles
+            .values()
+            .any(|profile| profile.files.contains_key(&as_given))
+        {
+            return Ok(as_given);
+        }
+
+        let absolute = if path.exists() {
+            path.canonicalize().context("Failed to canonicalize path")?
+        } else {
+            env::current_dir()
+                .context("Failed to get current directory")?
+                .join(path)
+        };
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        for profile in self.config.profiles.values() {
+            for (relative_path, source) in profile.sources() {
+                if Path::new(source) == absolute
+                    || profile.destination(&home, relative_path) == absolute
+                {
+                    return Ok(relative_path.clone());
+                }
+            }
+        }
+        key_for_path(&absolute).with_context(|| format!("{:?} is not tracked by dotty", path))
+    }
+
+    /// Moves a tracked file to a new path, updating the config and repository
+    pub fn move_file(&mut self, old: &Path, new: &Path, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let old_key = self.tracked_key(old)?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let new_key = home_key(new)?;
+
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let mut entry = profile_config
+            .files
+            .get(&old_key)
+            .with_context(|| format!("{} is not tracked in profile {}", old_key, profile))?
+            .clone();
+        if profile_config.files.contains_key(&new_key) {
+            anyhow::bail!("{} is already tracked in profile {}", new_key, profile);
+        }
+        if entry.os_destination().is_some() {
+            anyhow::bail!(
+                "{} has a destination for {}; edit its `destinations` in the config instead",
+                old_key,
+                env::consts::OS
+            );
+        }
+
+        let old_dest = home.join(&old_key);
+        let new_dest = home.join(&new_key);
+        if new_dest.exists() || new_dest.is_symlink() {
+            anyhow::bail!("{} already exists", new_dest.display());
+        }
+        let repo = self.open_repo()?;
+        let repo_path = self.repo_path()?;
+        let old_repo_key = self.config.repo_key(&profile, &old_key);
+        let new_repo_key = self.config.layout_key(&profile, &new_key, entry.shared());
+        let old_repo_path = repo_path.join(&old_repo_key);
+        if old_repo_path.exists() {
+            let new_repo_path = repo_path.join(&new_repo_key);
+            fs::create_dir_all(new_repo_path.parent().unwrap())
+                .context("Failed to create parent directories")?;
+            fs::rename(&old_repo_path, &new_repo_path).context("Failed to move repository copy")?;
+
+            let mut index = repo.index().context("Failed to get repo index")?;
+            index
+                .remove_all([&old_repo_key].iter(), None)
+                .context("Failed to remove file from index")?;
+            index
+                .add_all([&new_repo_key].iter(), git2::IndexAddOption::DEFAULT, None)
+                .context("Failed to add files to index")?;
+            index.write().context("Failed to write index")?;
+            self.commit_index(
+                &repo,
+                &mut index,
+                &format!("Move {} to {}", old_key, new_key),
+            )?;
+        }
+
+        if old_dest.exists() || old_dest.is_symlink() {
+            fs::create_dir_all(new_dest.parent().unwrap())
+                .context("Failed to create parent directories")?;
+            fs::rename(&old_dest, &new_dest).context("Failed to move destination")?;
+        }
+        // Files added in place are their own source, so the source moves too
+        if Path::new(entry.source()) == old_dest {
+            entry.set_source(new_dest.to_string_lossy().into_owned());
+        }
+
+        let profile_config = self.config.profiles.get_mut(&profile).unwrap();
+        profile_config.files.remove(&old_key);
+        profile_config.files.insert(new_key.clone(), entry);
+        self.save_config()?;
+        self.push_branch(&repo)?;
+        println!("Moved {} to {}", old_key, new_key);
+        Ok(())
+    }
+}
+
+// Looks an entry up by a loose name: "gitconfig" finds .gitconfig and
+// .config/git/config, "tmux" finds .tmux.conf
+pub(crate) fn find_tracked_entry(profile_config: &ProfileConfig, name: &str) -> Result<String> {
+    let normalize = |value: &str| -> String {
+        value
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_lowercase())
+            .collect()
+    };
+    let query = normalize(name);
+    if query.is_empty() {
+        anyhow::bail!("No tracked file matches {:?}", name);
+    }
+    let file_name = |key: &str| {
+        Path::new(key)
+            .file_name()
+            .map(|file_name| normalize(&file_name.to_string_lossy()))
+            .unwrap_or_default()
+    };
+    let mut keys: Vec<&String> = profile_config.files.keys().collect();
+    keys.sort();
+    // Whole-name matches beat substrings of the file name, which beat
+    // substrings anywhere in the path
+    let tiers: [&dyn Fn(&str) -> bool; 3] = [
+        &|key| file_name(key) == query,
+        &|key| file_name(key).contains(&query),
+        &|key| normalize(key).contains(&query),
+    ];
+    let matches = tiers
+        .iter()
+        .map(|matches| {
+            keys.iter()
+                .filter(|key| matches(key))
+                .map(|key| key.to_string())
+                .collect::<Vec<_>>()
+        })
+        .find(|matches| !matches.is_empty())
+        .with_context(|| format!("No tracked file matches {:?}", name))?;
+
+    match matches.as_slice() {
+        [key] => Ok(key.clone()),
+        _ if io::stdin().is_terminal() => {
+            inquire::Select::new(&format!("Several tracked files match {:?}:", name), matches)
+                .prompt()
+                .context("No file selected")
+        }
+        _ => anyhow::bail!(
+            "{:?} matches several tracked files: {}",
+            name,
+            matches.join(", ")
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn in_wsl() -> bool {
+    if env::var_os("WSL_DISTRO_NAME").is_some() {
+        return true;
+    }
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|release| release.to_lowercase().contains("microsoft"))
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn in_wsl() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn in_container() -> bool {
+    // Docker, Podman, systemd-nspawn/Podman ($container) and VS Code dev containers
+    if Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+        || env::var_os("container").is_some()
+        || env::var_os("REMOTE_CONTAINERS").is_some()
+    {
+        return true;
+    }
+    fs::read_to_string("/proc/1/cgroup")
+        .map(|cgroup| {
+            ["docker", "kubepods", "containerd", "lxc", "libpod"]
+                .iter()
+                .any(|marker| cgroup.contains(marker))
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn in_container() -> bool {
+    false
+}
+
+// Caches and other bulky state left out of `dotty add --interactive`
+pub(crate) const PICKER_IGNORED: &[&str] = &[
+    ".git",
+    "node_modules",
+    "/.cache",
+    "/.local/share",
+    "/.local/state",
+    "/.cargo",
+    "/.rustup",
+    "/.npm",
+    "/.gradle",
+    "/.m2",
+    "/.vscode-server",
+    "/.Trash",
+    "/.var",
+    "*.log",
+    "*.bak",
+];
+
+// Config files and directories `dotty discover` looks for, relative to $HOME
+pub(crate) const DISCOVER_CANDIDATES: &[&str] = &[
+    ".bashrc",
+    ".bash_profile",
+    ".bash_aliases",
+    ".profile",
+    ".zshrc",
+    ".zprofile",
+    ".zshenv",
+    ".config/fish",
+    ".inputrc",
+    ".gitconfig",
+    ".gitignore_global",
+    ".config/git",
+    ".ssh/config",
+    ".tmux.conf",
+    ".config/tmux",
+    ".vimrc",
+    ".config/nvim",
+    ".config/helix",
+    ".emacs",
+    ".config/kitty",
+    ".config/alacritty",
+    ".config/wezterm",
+    ".wezterm.lua",
+    ".config/ghostty",
+    ".config/starship.toml",
+    ".config/zellij",
+    ".config/i3",
+    ".config/sway",
+    ".config/hypr",
+    ".Xresources",
+    ".editorconfig",
+    ".curlrc",
+    ".wgetrc",
+];
+
+// Parses "1 3 5-7" or "all" into zero-based indices below `count`
+pub(crate) fn parse_selection(answer: &str, count: usize) -> Result<Vec<usize>> {
+    if answer.eq_ignore_ascii_case("all") || answer == "a" {
+        return Ok((0..count).collect());
+    }
+    let mut selected = Vec::new();
+    for part in answer.split([' ', ',']).filter(|part| !part.is_empty()) {
+        let (start, end) = part.split_once('-').unwrap_or((part, part));
+        let parse = |number: &str| -> Result<usize> {
+            match number.trim().parse::<usize>() {
+                Ok(number) if (1..=count).contains(&number) => Ok(number - 1),
+                _ => anyhow::bail!("{} is not a number between 1 and {}", number, count),
+            }
+        };
+        for index in parse(start)?..=parse(end)? {
+            if !selected.contains(&index) {
+                selected.push(index);
+            }
+        }
+    }
+    Ok(selected)
+}
+
+// Stow skips these by default
+pub(crate) const STOW_IGNORED: &[&str] =
+    &[".git", ".gitignore", ".gitmodules", ".stow-local-ignore"];
+
+// Pairs each file of a stow package with the path stow links it to,
+// recursing into directories unless stow folded the whole directory into a
+// symlink. `dot-` prefixes (stow --dotfiles) become dots.
+pub(crate) fn stow_mappings(
+    package: &Path,
+    target: &Path,
+    mappings: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(package)
+        .with_context(|| format!("Failed to read {}", package.display()))?
+        .filter_map(|entry| entry.ok())
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if STOW_IGNORED.contains(&name.as_str()) {
+            continue;
+        }
+        let source = entry.path();
+        let dest = target.join(match name.strip_prefix("dot-") {
+            Some(rest) => format!(".{}", rest),
+            None => name,
+        });
+        let folded = dest.is_symlink() && dest.canonicalize().ok() == source.canonicalize().ok();
+        if source.is_dir() && !folded {
+            stow_mappings(&source, &dest, mappings)?;
+        } else {
+            mappings.push((source.canonicalize()?, dest));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) const SHELL_ENV_SCRIPTS: [&str; 3] = ["sh", "fish", "ps1"];
+
+// Variables are double-quoted so values like "$HOME/bin:$PATH" expand when
+// the script is sourced; aliases are kept literal
+pub(crate) fn shell_env_script(
+    profile: &str,
+    profile_config: &ProfileConfig,
+    shell: &str,
+) -> String {
+    let mut script = format!(
+        "# Generated by dotty from profile {}; edit config.toml instead\n",
+        profile
+    );
+    for (name, value) in &profile_config.env {
+        script.push_str(&match shell {
+            "fish" => format!(
+                "set -gx {} \"{}\"\n",
+                name,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+            "ps1" => format!(
+                "$env:{} = \"{}\"\n",
+                name,
+                value.replace('`', "``").replace('"', "`\"")
+            ),
+            _ => format!(
+                "export {}=\"{}\"\n",
+                name,
+                value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('`', "\\`")
+            ),
+        });
+    }
+    for (name, command) in &profile_config.aliases {
+        script.push_str(&match shell {
+            "fish" => format!(
+                "alias {} '{}'\n",
+                name,
+                command.replace('\\', "\\\\").replace('\'', "\\'")
+            ),
+            // PowerShell aliases can't carry arguments, so use a function
+            "ps1" => format!("function {} {{ {} @args }}\n", name, command),
+            _ => format!("alias {}={}\n", name, shell_quote(command)),
+        });
+    }
+    script
+}
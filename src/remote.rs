@@ -0,0 +1,1041 @@
+//! Authentication with the remote, and the GitHub/GitLab APIs.
+
+use crate::config::{RemoteAuth, RemoteConfig, TokenStore};
+use crate::error::{network_error, DottyError, ErrorKind, AUTH_HINT};
+use crate::plugins;
+use crate::util::{confirm, host_name, read_secret, state_dir, unix_seconds};
+use crate::Dotty;
+use anyhow::{Context, Result};
+use colored::*;
+use git2::{Cred, RemoteCallbacks, Repository};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+use std::{env, fs, process, thread};
+
+// A token kept in the keyring, with what's needed to renew it
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredToken {
+    pub(crate) access_token: String,
+    #[serde(default)]
+    pub(crate) refresh_token: Option<String>,
+    // Unix times; None for tokens that don't expire
+    #[serde(default)]
+    pub(crate) expires_at: Option<u64>,
+    #[serde(default)]
+    pub(crate) refresh_expires_at: Option<u64>,
+}
+
+impl StoredToken {
+    pub(crate) fn new(access_token: String) -> Self {
+        StoredToken {
+            access_token,
+            refresh_token: None,
+            expires_at: None,
+            refresh_expires_at: None,
+        }
+    }
+
+    // Tokens are renewed a few minutes early so a sync doesn't start with
+    // one that runs out halfway
+    pub(crate) fn expiring(expires_at: Option<u64>) -> bool {
+        expires_at.is_some_and(|expires_at| expires_at < unix_seconds(SystemTime::now()) + 300)
+    }
+}
+
+impl Dotty {
+    /// Checks a token against the GitHub API (or, for other hosts, by
+    /// connecting to the remote) before storing it in the config
+    pub fn set_token(&mut self) -> Result<()> {
+        let token = if io::stdin().is_terminal() {
+            read_secret("GitHub token:")?
+        } else {
+            let mut token = String::new();
+            io::stdin()
+                .read_line(&mut token)
+                .context("Failed to read token from stdin")?;
+            token.trim().to_string()
+        };
+        if token.is_empty() {
+            anyhow::bail!("The token can't be empty");
+        }
+
+        let mut remote = self.config.remote.clone();
+        remote.github_token = token;
+        remote.auth = RemoteAuth::Token;
+        match forge_repo(&remote.github_repo) {
+            Some((Forge::GitHub, slug)) => verify_github_token(&remote.github_token, &slug)?,
+            _ if remote.github_repo.is_empty() => println!(
+                "{} no remote is configured, so the token could not be checked",
+                "Note:".yellow()
+            ),
+            _ => {
+                remote_branches(&remote)?;
+                println!("Connected to {}", remote.github_repo);
+            }
+        }
+
+        if remote.token_store == TokenStore::Keyring {
+            keyring_write(&remote, &StoredToken::new(remote.github_token.clone()))?;
+        }
+        self.config.remote = remote;
+        self.save_config()?;
+        println!("{}", "Token saved".green());
+        Ok(())
+    }
+
+    /// GitHub's OAuth device flow: the user enters a short code in the
+    /// browser while dotty polls for the token
+    pub fn login(&mut self, client_id: Option<String>, scopes: String) -> Result<()> {
+        let client_id = client_id
+            .or_else(|| env::var("DOTTY_OAUTH_CLIENT_ID").ok())
+            .or_else(|| self.config.remote.oauth_client_id.clone())
+            .context(
+                "No OAuth app configured; register a GitHub OAuth app with device flow enabled and pass its client ID with --client-id",
+            )?;
+        let device = github_oauth_post(
+            GITHUB_DEVICE_CODE_URL,
+            &[("client_id", &client_id), ("scope", &scopes)],
+        )?;
+        if let Some(error) = device["error"].as_str() {
+            anyhow::bail!(
+                "GitHub refused the login: {}",
+                device["error_description"].as_str().unwrap_or(error)
+            );
+        }
+        let device_code = device["device_code"]
+            .as_str()
+            .context("GitHub sent no device code")?;
+        let user_code = device["user_code"].as_str().unwrap_or_default();
+        let verification_uri = device["verification_uri"]
+            .as_str()
+            .unwrap_or("https://github.com/login/device");
+        let mut interval = device["interval"].as_u64().unwrap_or(5);
+        let deadline =
+            Instant::now() + Duration::from_secs(device["expires_in"].as_u64().unwrap_or(900));
+        println!(
+            "Open {} and enter the code {}",
+            verification_uri.cyan(),
+            user_code.bold()
+        );
+
+        let stored = loop {
+            if Instant::now() >= deadline {
+                anyhow::bail!("The code expired before it was entered; run `dotty login` again");
+            }
+            thread::sleep(Duration::from_secs(interval));
+            let response = github_oauth_post(
+                GITHUB_ACCESS_TOKEN_URL,
+                &[
+                    ("client_id", &client_id),
+                    ("device_code", device_code),
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                ],
+            )?;
+            match response["error"].as_str() {
+                None => break oauth_token(&response)?,
+                Some("authorization_pending") => {}
+                Some("slow_down") => {
+                    interval = response["interval"].as_u64().unwrap_or(interval + 5);
+                }
+                Some("access_denied") => anyhow::bail!("The login was cancelled in the browser"),
+                Some("expired_token") => {
+                    anyhow::bail!("The code expired before it was entered; run `dotty login` again")
+                }
+                Some(error) => anyhow::bail!(
+                    "GitHub refused the login: {}",
+                    response["error_description"].as_str().unwrap_or(error)
+                ),
+            }
+        };
+        if let Some((Forge::GitHub, slug)) = forge_repo(&self.config.remote.github_repo) {
+            verify_github_token(&stored.access_token, &slug)?;
+        }
+
+        keyring_write(&self.config.remote, &stored)?;
+        let remote = &mut self.config.remote;
+        remote.github_token = stored.access_token;
+        remote.token_expires_at = stored.expires_at;
+        remote.token_store = TokenStore::Keyring;
+        remote.auth = RemoteAuth::Token;
+        remote.oauth_client_id = Some(client_id);
+        self.save_config()?;
+        println!(
+            "{}",
+            "Logged in; the token is stored in the keyring".green()
+        );
+        Ok(())
+    }
+
+    /// Checks that the new remote can be reached with the new credentials
+    /// before anything is saved
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_remote(
+        &mut self,
+        url: Option<String>,
+        branch: Option<String>,
+        auth: Option<RemoteAuth>,
+        key: Option<String>,
+        token: Option<String>,
+        backend: Option<String>,
+        public: bool,
+    ) -> Result<()> {
+        let mut remote = self.config.remote.clone();
+        if let Some(url) = url {
+            remote.github_repo = url;
+        }
+        if let Some(backend) = backend {
+            remote.backend = Some(backend).filter(|backend| backend != "git");
+        }
+        if let Some(branch) = branch {
+            remote.branch = Some(branch);
+        }
+        if let Some(auth) = auth {
+            remote.auth = auth;
+        }
+        if let Some(token) = token {
+            remote.github_token = token;
+            remote.token_store = TokenStore::Config;
+        } else if remote.token_store == TokenStore::Keyring {
+            load_keyring_token(&mut remote)?;
+        }
+        if remote.github_repo.is_empty() {
+            anyhow::bail!("No remote configured yet; pass its URL");
+        }
+        if remote.auth == RemoteAuth::Token
+            && remote.github_token.is_empty()
+            && remote.backend.is_none()
+        {
+            anyhow::bail!("Token authentication needs a token; pass --token or use --auth ssh");
+        }
+        if let Some(key) = key {
+            remote.ssh_key = Some(key);
+        }
+        if remote.auth == RemoteAuth::DeployKey {
+            setup_deploy_key(&remote)?;
+        }
+        if remote.auth == RemoteAuth::CredentialHelper
+            && !remote.github_repo.starts_with("https://")
+            && !remote.github_repo.starts_with("http://")
+        {
+            anyhow::bail!(
+                "Credential helpers supply HTTPS credentials; use the repository's https:// URL"
+            );
+        }
+
+        println!("Checking {}...", remote.github_repo);
+        let branches = match remote_branches(&remote) {
+            Ok(branches) => branches,
+            Err(e) => {
+                if !offer_to_create_remote(&remote, public)? {
+                    return Err(e);
+                }
+                Vec::new()
+            }
+        };
+        if branches.is_empty() {
+            println!(
+                "The repository is empty; the first sync will create {}",
+                remote.shared_branch()
+            );
+        } else if !branches
+            .iter()
+            .any(|branch| branch == remote.shared_branch())
+        {
+            println!(
+                "{} The remote has no branch {} yet (it has {}); the first sync will create it",
+                "Note:".yellow(),
+                remote.shared_branch(),
+                branches.join(", ")
+            );
+        }
+
+        // A deploy key or git's credential helper replaces the token, which
+        // dotty shouldn't keep around
+        if matches!(
+            remote.auth,
+            RemoteAuth::DeployKey | RemoteAuth::CredentialHelper
+        ) && !remote.github_token.is_empty()
+        {
+            remote.github_token = String::new();
+            remote.token_store = TokenStore::Config;
+            println!("The token is no longer needed and was removed from the config");
+        }
+        let url_changed = remote.github_repo != self.config.remote.github_repo;
+        self.config.remote = remote;
+        self.save_config()?;
+        // An existing clone keeps its history and fetches from the new URL
+        let repo_path = self.repo_path()?;
+        if url_changed && repo_path.join(".git").exists() {
+            Repository::open(&repo_path)
+                .context("Failed to open repository")?
+                .remote_set_url("origin", &self.config.remote.github_repo)
+                .context("Failed to update the clone's remote URL")?;
+        }
+        println!("{}", "Remote updated".green());
+        self.show_remote()
+    }
+
+    /// Prints the remote settings with the token redacted
+    pub fn show_remote(&self) -> Result<()> {
+        let remote = &self.config.remote;
+        if remote.github_repo.is_empty() {
+            println!("No remote configured; run `dotty remote set <url>`");
+            return Ok(());
+        }
+        println!("URL:    {}", remote.github_repo);
+        if let Some(backend) = &remote.backend {
+            println!("Store:  {} backend plugin", backend);
+        }
+        if remote.branch_per_host {
+            println!(
+                "Branch: {} (this machine commits to {})",
+                remote.shared_branch(),
+                self.branch()
+            );
+        } else {
+            println!("Branch: {}", remote.shared_branch());
+        }
+        match remote.auth {
+            RemoteAuth::Token if remote.token_store == TokenStore::Keyring => {
+                println!("Auth:   token (in the keyring)")
+            }
+            RemoteAuth::Token => println!("Auth:   token {}", remote.redacted_token()),
+            RemoteAuth::DeployKey => println!(
+                "Auth:   deploy key {}",
+                remote.ssh_key.as_deref().unwrap_or("(not set)")
+            ),
+            auth => println!("Auth:   {}", auth.name()),
+        }
+        let repo_path = self.repo_path()?;
+        println!(
+            "Clone:  {}{}{}",
+            repo_path.display(),
+            match remote.clone_depth {
+                0 => String::new(),
+                depth => format!(", depth {}", depth),
+            },
+            if remote.partial_clone {
+                ", partial"
+            } else {
+                ""
+            }
+        );
+        let origin_url = Repository::open(&repo_path).ok().and_then(|repo| {
+            repo.find_remote("origin")
+                .ok()
+                .and_then(|origin| origin.url().map(str::to_string))
+        });
+        if let Some(origin_url) = origin_url.filter(|url| *url != remote.github_repo) {
+            println!("{} the clone's origin is {}", "Note:".yellow(), origin_url);
+        }
+        Ok(())
+    }
+}
+
+// Generates the deploy key if it doesn't exist yet and adds it to the
+// repository with write access, using the token when there is one (it's only
+// needed this once). Otherwise prints where to add it.
+pub(crate) fn setup_deploy_key(remote: &RemoteConfig) -> Result<()> {
+    if remote.github_repo.starts_with("http://") || remote.github_repo.starts_with("https://") {
+        anyhow::bail!("Deploy keys authenticate over SSH; use the repository's git@ URL");
+    }
+    let key = remote.ssh_key_path()?;
+    if key.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = key.parent() {
+        fs::create_dir_all(parent).context("Failed to create the key directory")?;
+    }
+    let status = process::Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-C"])
+        .arg(format!("dotty@{}", host_name()))
+        .arg("-f")
+        .arg(&key)
+        .status()
+        .context("Failed to run ssh-keygen")?;
+    if !status.success() {
+        anyhow::bail!("ssh-keygen failed to generate {}", key.display());
+    }
+    println!("Generated {}", key.display());
+
+    let public_key =
+        fs::read_to_string(key.with_extension("pub")).context("Failed to read the public key")?;
+    let public_key = public_key.trim();
+    let title = format!("dotty ({})", host_name());
+    let Some((forge, path)) = forge_repo(&remote.github_repo) else {
+        println!(
+            "Add this key to {} with write access:\n{}",
+            remote.github_repo, public_key
+        );
+        return Ok(());
+    };
+    if remote.github_token.is_empty() {
+        let settings = match forge {
+            Forge::GitHub => format!("https://github.com/{}/settings/keys/new", path),
+            Forge::GitLab => format!("https://gitlab.com/{}/-/settings/repository", path),
+        };
+        println!(
+            "Add this key at {} and allow write access (or pass --token to have dotty add it):\n{}",
+            settings, public_key
+        );
+        return Ok(());
+    }
+    let (endpoint, body) = match forge {
+        Forge::GitHub => (
+            format!("/repos/{}/keys", path),
+            serde_json::json!({"title": title, "key": public_key, "read_only": false}),
+        ),
+        Forge::GitLab => (
+            format!("/projects/{}/deploy_keys", path.replace('/', "%2F")),
+            serde_json::json!({"title": title, "key": public_key, "can_push": true}),
+        ),
+    };
+    forge_request(forge, &remote.github_token, "POST", &endpoint, Some(body))?
+        .with_context(|| format!("{} doesn't exist or the token can't manage its keys", path))?;
+    println!(
+        "Added the key to {} as a deploy key with write access",
+        path
+    );
+    Ok(())
+}
+
+pub(crate) const GITHUB_DEVICE_CODE_URL: &str = "https://github.com/login/device/code";
+
+pub(crate) const GITHUB_ACCESS_TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+
+// GitHub's OAuth endpoints answer 200 with an `error` field for pending and
+// failed requests, so those are left to the caller
+pub(crate) fn github_oauth_post(url: &str, form: &[(&str, &str)]) -> Result<serde_json::Value> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(15))
+        .build();
+    agent
+        .post(url)
+        .set("Accept", "application/json")
+        .set("User-Agent", "dotty")
+        .send_form(form)
+        .context("Failed to reach GitHub")?
+        .into_json()
+        .context("Failed to parse GitHub's response")
+}
+
+pub(crate) fn oauth_token(response: &serde_json::Value) -> Result<StoredToken> {
+    let now = unix_seconds(SystemTime::now());
+    Ok(StoredToken {
+        access_token: response["access_token"]
+            .as_str()
+            .context("GitHub sent no access token")?
+            .to_string(),
+        refresh_token: response["refresh_token"].as_str().map(str::to_string),
+        expires_at: response["expires_in"].as_u64().map(|seconds| now + seconds),
+        refresh_expires_at: response["refresh_token_expires_in"]
+            .as_u64()
+            .map(|seconds| now + seconds),
+    })
+}
+
+// Fills in github_token from the keyring, renewing the token first when it's
+// about to expire
+pub(crate) fn load_keyring_token(remote: &mut RemoteConfig) -> Result<()> {
+    let mut stored = keyring_read(remote)?.context("No token in the keyring; run `dotty login`")?;
+    if StoredToken::expiring(stored.expires_at) {
+        let refresh_token = stored
+            .refresh_token
+            .as_deref()
+            .filter(|_| !StoredToken::expiring(stored.refresh_expires_at))
+            .context("The login has expired; run `dotty login` again")?;
+        let client_id = remote.oauth_client_id.as_deref().context(
+            "remote.oauth_client_id is needed to renew the token; run `dotty login` again",
+        )?;
+        let response = github_oauth_post(
+            GITHUB_ACCESS_TOKEN_URL,
+            &[
+                ("client_id", client_id),
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+            ],
+        )?;
+        if let Some(error) = response["error"].as_str() {
+            anyhow::bail!(
+                "Failed to renew the token ({}); run `dotty login` again",
+                response["error_description"].as_str().unwrap_or(error)
+            );
+        }
+        stored = oauth_token(&response)?;
+        keyring_write(remote, &stored)?;
+        info!("Renewed the GitHub token");
+    }
+    remote.github_token = stored.access_token;
+    remote.token_expires_at = stored.expires_at;
+    Ok(())
+}
+
+pub(crate) const KEYRING_SERVICE: &str = "dotty";
+
+pub(crate) const KEYRING_ACCOUNT: &str = "remote";
+
+// The system keyring is the macOS Keychain (`security`) or the Secret Service
+// (`secret-tool`). Ok(None) means that tool isn't installed.
+pub(crate) fn keyring_command(
+    args: &[&str],
+    input: Option<&str>,
+) -> Result<Option<process::Output>> {
+    let program = if cfg!(target_os = "macos") {
+        "security"
+    } else {
+        "secret-tool"
+    };
+    let mut child = match process::Command::new(program)
+        .args(args)
+        .stdin(if input.is_some() {
+            process::Stdio::piped()
+        } else {
+            process::Stdio::null()
+        })
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to run {}", program)),
+    };
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin
+            .write_all(input.as_bytes())
+            .with_context(|| format!("Failed to write to {}", program))?;
+    }
+    child
+        .wait_with_output()
+        .map(Some)
+        .with_context(|| format!("Failed to run {}", program))
+}
+
+// Stands in for the keyring where there is none, e.g. on a headless server
+pub(crate) fn token_file() -> Result<PathBuf> {
+    Ok(state_dir()?.join("token.json"))
+}
+
+pub(crate) fn keyring_read(remote: &RemoteConfig) -> Result<Option<StoredToken>> {
+    let secret = match &remote.secret_provider {
+        Some(name) => plugins::secret_provider(name)?.get(KEYRING_ACCOUNT)?,
+        None => system_keyring_read()?,
+    };
+    secret
+        .map(|secret| serde_json::from_str(&secret).context("Failed to parse the stored token"))
+        .transpose()
+}
+
+pub(crate) fn system_keyring_read() -> Result<Option<String>> {
+    let args: &[&str] = if cfg!(target_os = "macos") {
+        &[
+            "find-generic-password",
+            "-s",
+            KEYRING_SERVICE,
+            "-a",
+            KEYRING_ACCOUNT,
+            "-w",
+        ]
+    } else {
+        &[
+            "lookup",
+            "service",
+            KEYRING_SERVICE,
+            "account",
+            KEYRING_ACCOUNT,
+        ]
+    };
+    match keyring_command(args, None)? {
+        Some(output) if output.status.success() => Ok(Some(
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        )),
+        _ => {
+            let path = token_file()?;
+            path.exists()
+                .then(|| fs::read_to_string(&path))
+                .transpose()
+                .context("Failed to read the token file")
+        }
+    }
+}
+
+pub(crate) fn keyring_write(remote: &RemoteConfig, stored: &StoredToken) -> Result<()> {
+    let secret = serde_json::to_string(stored).context("Failed to serialize token")?;
+    if let Some(name) = &remote.secret_provider {
+        return plugins::secret_provider(name)?.set(KEYRING_ACCOUNT, &secret);
+    }
+    let output = if cfg!(target_os = "macos") {
+        // A trailing -w makes security prompt for the password (and its
+        // confirmation), which keeps the secret out of argv
+        keyring_command(
+            &[
+                "add-generic-password",
+                "-U",
+                "-s",
+                KEYRING_SERVICE,
+                "-a",
+                KEYRING_ACCOUNT,
+                "-w",
+            ],
+            Some(&format!("{}\n{}\n", secret, secret)),
+        )?
+    } else {
+        keyring_command(
+            &[
+                "store",
+                "--label=dotty",
+                "service",
+                KEYRING_SERVICE,
+                "account",
+                KEYRING_ACCOUNT,
+            ],
+            Some(&secret),
+        )?
+    };
+    match output {
+        Some(output) if output.status.success() => {
+            // A token file left from before the keyring was available
+            let _ = fs::remove_file(token_file()?);
+            return Ok(());
+        }
+        Some(output) => warn!(
+            "The keyring refused the token ({}); storing it in a file instead",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        None => warn!("No keyring available; storing the token in a file instead"),
+    }
+    let path = token_file()?;
+    fs::write(&path, secret).context("Failed to write the token file")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .context("Failed to restrict token file permissions")?;
+    }
+    Ok(())
+}
+
+// Fails unless the token can push to the repository; reports the token's
+// scopes and expiry when GitHub sends them
+pub(crate) fn verify_github_token(token: &str, slug: &str) -> Result<()> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(15))
+        .build();
+    let request = agent
+        .get(&format!("https://api.github.com/repos/{}", slug))
+        .set("Authorization", &format!("Bearer {}", token))
+        .set("Accept", "application/vnd.github+json")
+        .set("User-Agent", "dotty");
+    let response = match call_forge_api(Forge::GitHub, request, None)? {
+        Ok(response) => response,
+        Err(ureq::Error::Status(401, _)) => {
+            return Err(DottyError::new(
+                ErrorKind::Network,
+                "GitHub rejected the token; it may be mistyped, revoked or expired",
+            )
+            .hint(AUTH_HINT)
+            .into())
+        }
+        Err(ureq::Error::Status(404, _)) => anyhow::bail!(
+            "{} doesn't exist or the token can't see it; fine-grained tokens need it among their repositories",
+            slug
+        ),
+        Err(ureq::Error::Status(403, response)) => anyhow::bail!(
+            "GitHub refused the request: {}",
+            response.into_string().unwrap_or_default().trim()
+        ),
+        Err(e) => return Err(e).context(network_error("Failed to reach the GitHub API", None)),
+    };
+
+    // Classic tokens list their scopes; fine-grained ones send none
+    let scopes = response.header("x-oauth-scopes").map(str::to_string);
+    let expiry = response
+        .header("github-authentication-token-expiration")
+        .map(str::to_string);
+    let repository: serde_json::Value = response
+        .into_json()
+        .context("Failed to parse the GitHub API response")?;
+    let can_push = repository["permissions"]["push"].as_bool() == Some(true);
+    if !can_push {
+        anyhow::bail!(
+            "The token can read {} but not push to it; grant it Contents: read and write (or the repo scope)",
+            slug
+        );
+    }
+
+    println!("The token can push to {}", slug);
+    match &scopes {
+        Some(scopes) if scopes.trim().is_empty() => println!("Scopes:  (none)"),
+        Some(scopes) => println!("Scopes:  {}", scopes),
+        None => println!("Type:    fine-grained"),
+    }
+    if scopes.is_some_and(|scopes| scopes.split(',').any(|scope| scope.trim() == "repo")) {
+        println!(
+            "{} this classic token can push to every repository you can. A fine-grained token limited to {} (Contents: read and write), or `dotty remote set --auth deploy-key`, can't.",
+            "Note:".yellow(),
+            slug
+        );
+    }
+    match expiry {
+        Some(expiry) => println!("Expires: {}", expiry),
+        None => println!("Expires: never"),
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Forge {
+    GitHub,
+    GitLab,
+}
+
+impl Forge {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Forge::GitHub => "GitHub",
+            Forge::GitLab => "GitLab",
+        }
+    }
+}
+
+// The forge hosting a repository URL (HTTPS or SSH form) and the
+// repository's path there: owner/name, or group/.../name on GitLab
+pub(crate) fn forge_repo(url: &str) -> Option<(Forge, String)> {
+    let (forge, path) = [(Forge::GitHub, "github.com"), (Forge::GitLab, "gitlab.com")]
+        .into_iter()
+        .find_map(|(forge, host)| {
+            [
+                format!("https://{}/", host),
+                format!("http://{}/", host),
+                format!("git@{}:", host),
+                format!("ssh://git@{}/", host),
+            ]
+            .iter()
+            .find_map(|prefix| url.strip_prefix(prefix.as_str()))
+            .map(|path| (forge, path))
+        })?;
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    let segments = path.split('/').count();
+    let valid = match forge {
+        Forge::GitHub => segments == 2,
+        Forge::GitLab => segments >= 2,
+    };
+    valid.then(|| (forge, path.to_string()))
+}
+
+// Rate limits that reset within this many seconds are waited out
+pub(crate) const RATE_LIMIT_MAX_WAIT: u64 = 60;
+
+// How long a 403/429 response asks to wait, if it is a rate limit (GitHub's
+// primary and secondary limits, GitLab's RateLimit-* headers)
+pub(crate) fn rate_limit_wait(status: u16, response: &ureq::Response) -> Option<u64> {
+    if status != 403 && status != 429 {
+        return None;
+    }
+    if let Some(seconds) = response
+        .header("retry-after")
+        .and_then(|value| value.trim().parse().ok())
+    {
+        return Some(seconds);
+    }
+    let exhausted = ["x-ratelimit-remaining", "ratelimit-remaining"]
+        .iter()
+        .any(|name| response.header(name).map(str::trim) == Some("0"));
+    if exhausted {
+        let reset: u64 = ["x-ratelimit-reset", "ratelimit-reset"]
+            .iter()
+            .find_map(|name| response.header(name)?.trim().parse().ok())?;
+        return Some(reset.saturating_sub(unix_seconds(SystemTime::now())).max(1));
+    }
+    // GitHub documents 429 without headers as "wait at least a minute"
+    (status == 429).then_some(RATE_LIMIT_MAX_WAIT)
+}
+
+// Sends an API request, waiting out short rate limits. A limit that resets
+// later is an error saying when; other failures are left to the caller.
+pub(crate) fn call_forge_api(
+    forge: Forge,
+    request: ureq::Request,
+    body: Option<serde_json::Value>,
+) -> Result<Result<ureq::Response, ureq::Error>> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let result = match &body {
+            Some(body) => request.clone().send_json(body),
+            None => request.clone().call(),
+        };
+        let Err(ureq::Error::Status(status, response)) = result else {
+            return Ok(result);
+        };
+        let Some(wait) = rate_limit_wait(status, &response) else {
+            return Ok(Err(ureq::Error::Status(status, response)));
+        };
+        if wait > RATE_LIMIT_MAX_WAIT || attempts == 3 {
+            anyhow::bail!(
+                "{} is rate limiting requests; try again in {}",
+                forge.name(),
+                humantime::format_duration(Duration::from_secs(wait))
+            );
+        }
+        println!(
+            "{} rate limit reached; retrying in {}s...",
+            forge.name(),
+            wait
+        );
+        thread::sleep(Duration::from_secs(wait));
+    }
+}
+
+// libgit2 only passes on the HTTP status or the server's message
+pub(crate) fn is_rate_limit_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("429") || message.contains("rate limit") || message.contains("abuse")
+}
+
+// Calls the forge's REST API. Ok(None) means 404.
+pub(crate) fn forge_request(
+    forge: Forge,
+    token: &str,
+    method: &str,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> Result<Option<serde_json::Value>> {
+    let (base, header, value) = match forge {
+        Forge::GitHub => (
+            "https://api.github.com",
+            "Authorization",
+            format!("Bearer {}", token),
+        ),
+        Forge::GitLab => (
+            "https://gitlab.com/api/v4",
+            "PRIVATE-TOKEN",
+            token.to_string(),
+        ),
+    };
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(15))
+        .build();
+    let request = agent
+        .request(method, &format!("{}{}", base, path))
+        .set(header, &value)
+        .set("Accept", "application/json")
+        .set("User-Agent", "dotty");
+    match call_forge_api(forge, request, body)? {
+        Ok(response) => Ok(Some(response.into_json().with_context(|| {
+            format!("Failed to parse the {} API response", forge.name())
+        })?)),
+        Err(ureq::Error::Status(404, _)) => Ok(None),
+        Err(ureq::Error::Status(code, response)) => anyhow::bail!(
+            "The {} API returned {}: {}",
+            forge.name(),
+            code,
+            response.into_string().unwrap_or_default().trim()
+        ),
+        Err(e) => Err(e).with_context(|| format!("Failed to reach the {} API", forge.name())),
+    }
+}
+
+// Creates the repository a remote URL points at, under the token's user or
+// the organization/group named in the URL. It is left empty; the first
+// clone makes the initial commit.
+pub(crate) fn create_forge_repo(remote: &RemoteConfig, public: bool) -> Result<()> {
+    let (forge, path) = forge_repo(&remote.github_repo)
+        .with_context(|| format!("Can't create {} automatically", remote.github_repo))?;
+    let token = &remote.github_token;
+    let (owner, name) = path.rsplit_once('/').context("Invalid repository path")?;
+    let description = "Dotfiles synced by dotty";
+    match forge {
+        Forge::GitHub => {
+            let user = forge_request(forge, token, "GET", "/user", None)?
+                .context("Failed to look up the token's user")?;
+            let endpoint = if user["login"].as_str() == Some(owner) {
+                "/user/repos".to_string()
+            } else {
+                format!("/orgs/{}/repos", owner)
+            };
+            let body = serde_json::json!({
+                "name": name,
+                "private": !public,
+                "description": description,
+            });
+            forge_request(forge, token, "POST", &endpoint, Some(body))?.with_context(|| {
+                format!(
+                    "{} doesn't exist or the token can't create repositories in it",
+                    owner
+                )
+            })?;
+        }
+        Forge::GitLab => {
+            let mut body = serde_json::json!({
+                "name": name,
+                "path": name,
+                "visibility": if public { "public" } else { "private" },
+                "description": description,
+            });
+            let user = forge_request(forge, token, "GET", "/user", None)?
+                .context("Failed to look up the token's user")?;
+            if user["username"].as_str() != Some(owner) {
+                let namespace = forge_request(
+                    forge,
+                    token,
+                    "GET",
+                    &format!("/namespaces/{}", owner.replace('/', "%2F")),
+                    None,
+                )?
+                .with_context(|| format!("GitLab group {} not found", owner))?;
+                body["namespace_id"] = namespace["id"].clone();
+            }
+            forge_request(forge, token, "POST", "/projects", Some(body))?
+                .context("GitLab refused to create the project")?;
+        }
+    }
+    println!(
+        "Created {} {} repository {}",
+        if public { "public" } else { "private" },
+        forge.name(),
+        path
+    );
+    Ok(())
+}
+
+// Asks whether to create a remote that can't be reached because it doesn't
+// exist. Only offered on a terminal, for forges dotty knows the API of.
+pub(crate) fn offer_to_create_remote(remote: &RemoteConfig, public: bool) -> Result<bool> {
+    let Some((forge, path)) = forge_repo(&remote.github_repo) else {
+        return Ok(false);
+    };
+    if remote.auth != RemoteAuth::Token
+        || remote.github_token.is_empty()
+        || !io::stdin().is_terminal()
+    {
+        return Ok(false);
+    }
+    let lookup = match forge {
+        Forge::GitHub => format!("/repos/{}", path),
+        Forge::GitLab => format!("/projects/{}", path.replace('/', "%2F")),
+    };
+    if forge_request(forge, &remote.github_token, "GET", &lookup, None)?.is_some() {
+        return Ok(false);
+    }
+    let question = format!(
+        "{} doesn't exist on {}. Create it as a {} repository?",
+        path,
+        forge.name(),
+        if public { "public" } else { "private" }
+    );
+    if !confirm(&question)? {
+        return Ok(false);
+    }
+    create_forge_repo(remote, public)?;
+    Ok(true)
+}
+
+// Gives up after one attempt; libgit2 keeps asking as long as the callback
+// returns credentials, even ones the server already rejected
+pub(crate) fn credential_callbacks(remote: &RemoteConfig) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
+    let mut attempted = false;
+    let mut filled: Option<String> = None;
+    callbacks.credentials(move |url, username, _| {
+        if std::mem::replace(&mut attempted, true) {
+            // Tell the helper, so it doesn't hand out the same credentials
+            if let Some(filled) = filled.take() {
+                let _ = git_credential("reject", &filled);
+            }
+            return Err(git2::Error::from_str("authentication failed"));
+        }
+        match remote.auth {
+            RemoteAuth::Token => Cred::userpass_plaintext("x-access-token", &remote.github_token),
+            RemoteAuth::Ssh => Cred::ssh_key_from_agent(username.unwrap_or("git")),
+            RemoteAuth::DeployKey => {
+                let key = remote
+                    .ssh_key_path()
+                    .map_err(|e| git2::Error::from_str(&format!("{:#}", e)))?;
+                Cred::ssh_key(username.unwrap_or("git"), None, &key, None)
+            }
+            RemoteAuth::CredentialHelper => {
+                let credential = git_credential("fill", &format!("url={}\n", url))
+                    .map_err(|e| git2::Error::from_str(&format!("{:#}", e)))?;
+                let field = |name: &str| {
+                    credential
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                let (username, password) = (field("username"), field("password"));
+                filled = Some(credential);
+                Cred::userpass_plaintext(&username, &password)
+            }
+        }
+    });
+    callbacks
+}
+
+// Runs `git credential <action>` with a credential description in git's
+// key=value format and returns what git prints. fill may prompt on the
+// terminal, as git itself would.
+pub(crate) fn git_credential(action: &str, input: &str) -> Result<String> {
+    let mut child = process::Command::new("git")
+        .args(["credential", action])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .context("Failed to run git credential")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "{}", input.trim_end()).context("Failed to write to git credential")?;
+    }
+    let output = child
+        .wait_with_output()
+        .context("Failed to run git credential")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git credential {} failed; is a credential helper configured?",
+            action
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Branch names on the remote, as `git ls-remote --heads` would list them
+pub(crate) fn remote_branches(remote_config: &RemoteConfig) -> Result<Vec<String>> {
+    if let Some(backend) = remote_config.storage_backend()? {
+        return backend.list_branches(&remote_config.github_repo);
+    }
+    let mut remote = git2::Remote::create_detached(remote_config.github_repo.as_str())
+        .context("Invalid remote URL")?;
+    let connection = remote
+        .connect_auth(
+            git2::Direction::Fetch,
+            Some(credential_callbacks(remote_config)),
+            Some(remote_config.proxy_options()),
+        )
+        .map_err(|e| {
+            let typed = network_error(
+                format!("Failed to connect to {}", remote_config.github_repo),
+                Some(&e),
+            );
+            anyhow::Error::new(e).context(typed)
+        })?;
+    // git2 can't list an empty remote's refs (its list() builds a slice from
+    // a null pointer), and an empty remote advertises no HEAD
+    match connection.default_branch() {
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(Vec::new()),
+        result => {
+            result.context("Failed to read the remote's default branch")?;
+        }
+    }
+    let branches = connection
+        .list()
+        .context("Failed to list remote branches")?
+        .iter()
+        .filter_map(|head| head.name().strip_prefix("refs/heads/"))
+        .map(str::to_string)
+        .collect();
+    Ok(branches)
+}
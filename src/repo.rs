@@ -0,0 +1,1933 @@
+//! The local clone of the dotfiles repository: branches, history, tags and
+//! maintenance.
+
+use crate::config::{
+    default_maintenance_interval_hours, NotificationEvent, ProfileConfig, RemoteAuth, KNOWN_OS,
+};
+use crate::error::{network_error, profile_not_found, DottyError, ErrorKind};
+use crate::remote::{credential_callbacks, is_rate_limit_error, offer_to_create_remote};
+use crate::status::print_diff;
+use crate::sync::PinState;
+use crate::util::{
+    confirm, convert_line_endings, dir_size, expand_env_vars, format_bytes, format_git_time,
+    format_unix_time, host_name, path_key, remove_link, state_dir, unix_seconds,
+};
+use crate::Dotty;
+use anyhow::{Context, Result};
+use colored::*;
+use git2::{RemoteCallbacks, Repository};
+use ignore::WalkBuilder;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{env, fs, process};
+
+// remote.local_path is relative to the home directory, with or without a
+// leading "~/" (or "~\\" on Windows), and may use %VAR% references
+fn local_repo_path(home: &Path, local_path: &str) -> PathBuf {
+    let relative = local_path
+        .strip_prefix("~/")
+        .or_else(|| local_path.strip_prefix("~\\"))
+        .unwrap_or(local_path);
+    home.join(expand_env_vars(relative))
+}
+
+impl Dotty {
+    pub(crate) fn repo_path(&self) -> Result<PathBuf> {
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        Ok(match &self.config.remote.local_path {
+            Some(local_path) => local_repo_path(&home, local_path),
+            None => home.join(".dotty_repo"),
+        })
+    }
+
+    // The repository copy of an entry this machine uses, which is its
+    // overlay when the clone has one
+    pub(crate) fn repo_copy_key(&self, profile: &str, key: &str) -> Result<String> {
+        let repo_path = self.repo_path()?;
+        Ok(self
+            .config
+            .overlay_key(profile, key, |path| repo_path.join(path).exists()))
+    }
+
+    pub(crate) fn open_repo(&self) -> Result<Repository> {
+        let repo = self.open_or_clone_repo()?;
+        self.ensure_branch(&repo)?;
+        Ok(repo)
+    }
+
+    // The branch this machine commits to
+    pub(crate) fn branch(&self) -> String {
+        if self.config.remote.branch_per_host {
+            format!("machines/{}", host_name())
+        } else {
+            self.config.remote.shared_branch().to_string()
+        }
+    }
+
+    // Checks out this machine's branch, creating it from the remote's copy or,
+    // for a new machine, from the shared master branch
+    pub(crate) fn ensure_branch(&self, repo: &Repository) -> Result<()> {
+        let refname = format!("refs/heads/{}", self.branch());
+        if repo
+            .head()
+            .ok()
+            .and_then(|head| head.name().map(str::to_string))
+            == Some(refname.clone())
+        {
+            return Ok(());
+        }
+        if repo.find_reference(&refname).is_err() {
+            let start = repo
+                .refname_to_id(&format!("refs/remotes/origin/{}", self.branch()))
+                .or_else(|_| {
+                    repo.refname_to_id(&format!(
+                        "refs/heads/{}",
+                        self.config.remote.shared_branch()
+                    ))
+                })
+                .or_else(|_| {
+                    repo.head()
+                        .and_then(|head| head.peel_to_commit())
+                        .map(|commit| commit.id())
+                })
+                .context("Failed to find a commit to start the machine branch from")?;
+            repo.reference(&refname, start, false, "dotty: machine branch")
+                .context("Failed to create machine branch")?;
+        }
+        repo.set_head(&refname)
+            .context("Failed to switch to machine branch")?;
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .context("Failed to check out machine branch")?;
+        info!("Switched the repository to {}", self.branch());
+        Ok(())
+    }
+
+    pub(crate) fn push_branch(&self, repo: &Repository) -> Result<()> {
+        let refname = format!("refs/heads/{}", self.branch());
+        self.push_refspecs(repo, &[&format!("{}:{}", refname, refname)])
+    }
+
+    // With branch_per_host, copies the entries marked `shared` and the
+    // tombstones from this machine's latest commit onto master and pushes it
+    pub(crate) fn publish_shared(&self, repo: &Repository) -> Result<()> {
+        if !self.config.remote.branch_per_host {
+            return Ok(());
+        }
+        let mut shared: Vec<String> = self
+            .config
+            .profiles
+            .iter()
+            .flat_map(|(profile, profile_config)| {
+                profile_config
+                    .files
+                    .iter()
+                    .filter(|(_, entry)| entry.shared())
+                    .map(move |(key, _)| self.config.repo_key(profile, key))
+            })
+            .collect();
+        shared.push(TOMBSTONES_PATH.to_string());
+        shared.push(CONFIG_PATH.to_string());
+        shared.sort_unstable();
+        shared.dedup();
+
+        let head = repo.head()?.peel_to_commit()?;
+        let shared_ref = format!("refs/heads/{}", self.config.remote.shared_branch());
+        let parent = repo
+            .refname_to_id(&format!(
+                "refs/remotes/origin/{}",
+                self.config.remote.shared_branch()
+            ))
+            .or_else(|_| repo.refname_to_id(&shared_ref))
+            .and_then(|oid| repo.find_commit(oid))
+            .ok();
+
+        // Build master's new tree in memory from its current tree, with the
+        // shared paths replaced by this machine's versions
+        let mut index = git2::Index::new()?;
+        if let Some(parent) = &parent {
+            index.read_tree(&parent.tree()?)?;
+        }
+        let mut head_index = git2::Index::new()?;
+        head_index.read_tree(&head.tree()?)?;
+        for path in &shared {
+            let prefix = format!("{}/", path);
+            index.remove_all([path].iter(), None)?;
+            for entry in head_index.iter() {
+                let entry_path = String::from_utf8_lossy(&entry.path);
+                if entry_path == *path || entry_path.starts_with(&prefix) {
+                    index.add(&entry)?;
+                }
+            }
+        }
+        let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+        if parent
+            .as_ref()
+            .is_some_and(|parent| parent.tree_id() == tree.id())
+        {
+            return Ok(());
+        }
+
+        let signature = repo.signature().context("Failed to get signature")?;
+        let message = format!(
+            "Share dotfiles from {}\n\nHost: {}",
+            host_name(),
+            host_name()
+        );
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        let oid = repo
+            .commit(None, &signature, &signature, &message, &tree, &parents)
+            .context("Failed to create commit")?;
+        repo.reference(&shared_ref, oid, true, "dotty: share")
+            .with_context(|| format!("Failed to update {}", self.config.remote.shared_branch()))?;
+        self.push_refspecs(repo, &[&format!("{}:{}", shared_ref, shared_ref)])
+    }
+
+    pub(crate) fn open_or_clone_repo(&self) -> Result<Repository> {
+        let repo_path = self.repo_path()?;
+        if repo_path.exists() {
+            return Repository::open(&repo_path).context("Failed to open existing repository");
+        }
+
+        fs::create_dir_all(repo_path.parent().unwrap())
+            .context("Failed to create parent directories")?;
+        let remote = &self.config.remote;
+        if let Some(backend) = remote.storage_backend()? {
+            let repo = Repository::init(&repo_path).context("Failed to create repository")?;
+            repo.remote("origin", &remote.github_repo)
+                .context("Failed to add remote 'origin'")?;
+            backend
+                .fetch(&repo_path, &remote.github_repo)
+                .with_context(|| network_error("Failed to fetch from the storage backend", None))?;
+            let shared = remote.shared_branch();
+            match repo.find_reference(&format!("refs/remotes/origin/{}", shared)) {
+                Ok(reference) => {
+                    let commit = reference
+                        .peel_to_commit()
+                        .context("Failed to find the shared branch's commit")?;
+                    repo.branch(shared, &commit, true)
+                        .context("Failed to create the shared branch")?;
+                    repo.set_head(&format!("refs/heads/{}", shared))
+                        .context("Failed to set HEAD")?;
+                    repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+                        .context("Failed to check out the shared branch")?;
+                }
+                Err(_) => self.initialize_repo(&repo)?,
+            }
+            return Ok(repo);
+        }
+        if remote.partial_clone {
+            let depth = format!("--depth={}", remote.clone_depth);
+            let mut args = vec!["clone", "--filter=blob:none"];
+            if remote.clone_depth > 0 {
+                args.push(&depth);
+            }
+            let target = repo_path.to_string_lossy();
+            args.extend(["--", &remote.github_repo, &target]);
+            let output = self
+                .git_command(&args)?
+                .current_dir(repo_path.parent().unwrap())
+                .output()
+                .context("Failed to run git; partial_clone requires the git CLI")?;
+            if !output.status.success() {
+                return Err(network_error(
+                    format!(
+                        "Failed to clone repository: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ),
+                    None,
+                )
+                .into());
+            }
+            return Repository::open(&repo_path).context("Failed to open cloned repository");
+        }
+
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options
+            .remote_callbacks(self.remote_callbacks())
+            .proxy_options(remote.proxy_options());
+        // libgit2's local transport can't make shallow clones
+        let local =
+            remote.github_repo.starts_with("file://") || Path::new(&remote.github_repo).exists();
+        if remote.clone_depth > 0 && !local {
+            fetch_options.depth(remote.clone_depth as i32);
+        }
+        let clone = |fetch_options| {
+            git2::build::RepoBuilder::new()
+                .fetch_options(fetch_options)
+                .clone(&remote.github_repo, &repo_path)
+        };
+        let repo = match clone(fetch_options) {
+            Ok(repo) => repo,
+            Err(e) => {
+                if !offer_to_create_remote(remote, false)? {
+                    let typed = network_error("Failed to clone repository", Some(&e));
+                    return Err(e).context(typed);
+                }
+                let mut fetch_options = git2::FetchOptions::new();
+                fetch_options
+                    .remote_callbacks(self.remote_callbacks())
+                    .proxy_options(remote.proxy_options());
+                clone(fetch_options).context("Failed to clone the new repository")?
+            }
+        };
+        if repo.is_empty().unwrap_or(false) {
+            self.initialize_repo(&repo)?;
+        }
+        Ok(repo)
+    }
+
+    // Gives a freshly created, empty remote its first commit on the shared
+    // branch, so machine branches and syncs have something to start from
+    pub(crate) fn initialize_repo(&self, repo: &Repository) -> Result<()> {
+        let tree_id = repo
+            .treebuilder(None)
+            .and_then(|builder| builder.write())
+            .context("Failed to write tree")?;
+        let tree = repo.find_tree(tree_id).context("Failed to find tree")?;
+        let signature = repo.signature().context("Failed to get signature")?;
+        let refname = format!("refs/heads/{}", self.config.remote.shared_branch());
+        repo.commit(
+            Some(&refname),
+            &signature,
+            &signature,
+            &format!("Initialize dotfiles repository\n\nHost: {}", host_name()),
+            &tree,
+            &[],
+        )
+        .context("Failed to create the initial commit")?;
+        repo.set_head(&refname).context("Failed to set HEAD")?;
+        self.push_refspecs(repo, &[&format!("{}:{}", refname, refname)])?;
+        info!(
+            "Pushed the initial commit to {}",
+            self.config.remote.shared_branch()
+        );
+        Ok(())
+    }
+
+    // Resolves a revision to a commit, deepening a shallow clone if the
+    // commit is older than its history. In a partial clone the commit's file
+    // contents are fetched up front, since libgit2 can't fetch them lazily.
+    pub(crate) fn resolve_commit<'r>(
+        &self,
+        repo: &'r Repository,
+        revision: &str,
+    ) -> Result<git2::Commit<'r>> {
+        let resolve = || {
+            repo.revparse_single(revision)
+                .and_then(|object| object.peel_to_commit())
+        };
+        let commit = match resolve() {
+            Err(_) if repo.is_shallow() => {
+                info!(
+                    "{} is not in the shallow clone; fetching full history",
+                    revision
+                );
+                self.run_git(&["fetch", "--unshallow", "--tags", "origin"])?;
+                resolve()
+            }
+            result => result,
+        }
+        .with_context(|| format!("Revision not found: {}", revision))?;
+
+        if self.config.remote.partial_clone {
+            // Diffing against the empty tree makes git fetch every missing
+            // blob of the commit in one batch
+            self.run_git(&[
+                "diff",
+                "--numstat",
+                "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+                &commit.id().to_string(),
+            ])?;
+        }
+        Ok(commit)
+    }
+
+    // Runs the git CLI in the repository, for what libgit2 can't do (Git LFS).
+    // The token goes through the environment rather than the command line
+    pub(crate) fn git_command(&self, args: &[&str]) -> Result<process::Command> {
+        let mut command = process::Command::new("git");
+        command
+            .args(args)
+            .current_dir(self.repo_path()?)
+            .env("GIT_TERMINAL_PROMPT", "0");
+        if self.config.remote.auth == RemoteAuth::DeployKey {
+            let key = self.config.remote.ssh_key_path()?;
+            command.env(
+                "GIT_SSH_COMMAND",
+                format!("ssh -i '{}' -o IdentitiesOnly=yes", key.display()),
+            );
+        }
+        // The git CLI reads the proxy variables itself, but not remote.proxy
+        let mut settings = Vec::new();
+        if let Some(proxy) = &self.config.remote.proxy {
+            settings.push(("http.proxy", proxy.clone()));
+        }
+        if self.config.remote.auth == RemoteAuth::Token {
+            let credentials = format!("x-access-token:{}", self.config.remote.github_token);
+            settings.push((
+                "http.extraheader",
+                format!(
+                    "Authorization: Basic {}",
+                    openssl::base64::encode_block(credentials.as_bytes())
+                ),
+            ));
+        }
+        command.env("GIT_CONFIG_COUNT", settings.len().to_string());
+        for (i, (key, value)) in settings.into_iter().enumerate() {
+            command
+                .env(format!("GIT_CONFIG_KEY_{}", i), key)
+                .env(format!("GIT_CONFIG_VALUE_{}", i), value);
+        }
+        Ok(command)
+    }
+
+    pub(crate) fn run_git(&self, args: &[&str]) -> Result<()> {
+        let output = self
+            .git_command(args)?
+            .output()
+            .context("Failed to run git")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    // Updates NESTED_REPOS_PATH with the git checkouts currently inside the
+    // tracked directories being synced
+    pub(crate) fn record_nested_repos(
+        &self,
+        repo_path: &Path,
+        files: Option<&HashSet<String>>,
+    ) -> Result<()> {
+        let path = repo_path.join(NESTED_REPOS_PATH);
+        let mut nested_repos = NestedRepos::load(&path)?;
+        let before = toml::to_string(&nested_repos)?;
+
+        for (profile, profile_config) in &self.config.profiles {
+            for (relative_path, entry) in &profile_config.files {
+                if files.is_some_and(|files| !files.contains(relative_path)) {
+                    continue;
+                }
+                let source = Path::new(entry.source());
+                if !source.exists() {
+                    continue;
+                }
+                let prefix = format!("{}/", self.config.repo_key(profile, relative_path));
+                nested_repos
+                    .repos
+                    .retain(|key, _| !key.starts_with(&prefix));
+                if !source.is_dir() {
+                    continue;
+                }
+                let mut found = Vec::new();
+                self.find_nested_repos(source, source, profile_config, &mut found);
+                for (dir, nested) in found {
+                    let key = format!("{}{}", prefix, path_key(dir.strip_prefix(source)?));
+                    nested_repos.repos.insert(key, nested);
+                }
+            }
+        }
+
+        if toml::to_string(&nested_repos)? != before {
+            nested_repos.save(&path)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn find_nested_repos(
+        &self,
+        source: &Path,
+        dir: &Path,
+        profile_config: &ProfileConfig,
+        found: &mut Vec<(PathBuf, NestedRepo)>,
+    ) {
+        let Ok(children) = fs::read_dir(dir) else {
+            return;
+        };
+        for child in children.flatten() {
+            let path = child.path();
+            if !child.file_type().is_ok_and(|t| t.is_dir())
+                || child.file_name() == ".git"
+                || self.is_ignored(source, &path, profile_config)
+            {
+                continue;
+            }
+            match nested_repo(&path) {
+                Some(nested) => found.push((path, nested)),
+                None => self.find_nested_repos(source, &path, profile_config, found),
+            }
+        }
+    }
+
+    // Clones the nested repositories pinned below a checked-out directory
+    // that are missing at its destination
+    pub(crate) fn restore_nested_repos(&self, nested_repos: &NestedRepos, key: &str, dest: &Path) {
+        for (relative, nested) in nested_repos.under(key) {
+            let target = dest.join(relative);
+            if target.join(".git").exists() {
+                if nested_repo(&target).is_some_and(|current| current.commit != nested.commit) {
+                    info!(
+                        "{:?} is not at the pinned commit {}; leaving it as is",
+                        target,
+                        &nested.commit[..7.min(nested.commit.len())]
+                    );
+                }
+                continue;
+            }
+            let restore = || -> Result<()> {
+                if target.exists() {
+                    fs::remove_dir_all(&target).context("Failed to replace directory")?;
+                }
+                let repo = Repository::clone(&nested.url, &target)
+                    .context("Failed to clone repository")?;
+                let oid = git2::Oid::from_str(&nested.commit)?;
+                repo.set_head_detached(oid)?;
+                repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+                Ok(())
+            };
+            match restore() {
+                Ok(()) => info!("Restored {:?} from {}", target, nested.url),
+                Err(e) => warn!(
+                    "Failed to restore {:?} from {}: {:#}",
+                    target, nested.url, e
+                ),
+            }
+        }
+    }
+
+    // Repository paths of the entries stored in LFS
+    pub(crate) fn lfs_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .config
+            .profiles
+            .iter()
+            .flat_map(|(profile, profile_config)| {
+                profile_config
+                    .files
+                    .iter()
+                    .filter(|(_, entry)| entry.lfs())
+                    .map(move |(key, _)| self.config.repo_key(profile, key))
+            })
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+
+    // Keeps the dotty-managed block of .gitattributes in line with the
+    // entries marked `lfs = true`, and installs the LFS hooks the first time
+    pub(crate) fn configure_lfs(&self, repo_path: &Path) -> Result<()> {
+        let keys = self.lfs_keys();
+        let attributes_path = repo_path.join(".gitattributes");
+        let existing = fs::read_to_string(&attributes_path).unwrap_or_default();
+        if keys.is_empty() && !existing.contains(LFS_ATTRIBUTES_START) {
+            return Ok(());
+        }
+
+        let mut attributes = String::new();
+        let mut lines = existing.lines();
+        while let Some(line) = lines.next() {
+            if line == LFS_ATTRIBUTES_START {
+                lines.by_ref().find(|line| *line == LFS_ATTRIBUTES_END);
+            } else {
+                attributes.push_str(line);
+                attributes.push('\n');
+            }
+        }
+        if !keys.is_empty() {
+            attributes.push_str(LFS_ATTRIBUTES_START);
+            attributes.push('\n');
+            for key in &keys {
+                let pattern = key.replace(' ', "[[:space:]]");
+                let pattern = if repo_path.join(key).is_dir() {
+                    format!("/{}/**", pattern)
+                } else {
+                    format!("/{}", pattern)
+                };
+                attributes.push_str(&format!(
+                    "{} filter=lfs diff=lfs merge=lfs -text\n",
+                    pattern
+                ));
+            }
+            attributes.push_str(LFS_ATTRIBUTES_END);
+            attributes.push('\n');
+        }
+        if attributes == existing {
+            return Ok(());
+        }
+
+        if !keys.is_empty() {
+            let available = self
+                .git_command(&["lfs", "version"])?
+                .output()
+                .is_ok_and(|output| output.status.success());
+            if !available {
+                anyhow::bail!("Entries are marked `lfs = true` but git-lfs is not installed");
+            }
+            self.run_git(&["lfs", "install", "--local"])?;
+        }
+        if attributes.is_empty() {
+            fs::remove_file(&attributes_path).context("Failed to remove .gitattributes")?;
+        } else {
+            fs::write(&attributes_path, attributes).context("Failed to write .gitattributes")?;
+        }
+        Ok(())
+    }
+
+    // Replaces LFS pointer files written from the repository with the
+    // content they refer to, downloading it if needed
+    pub(crate) fn smudge_lfs(&self, path: &Path) -> Result<()> {
+        if path.is_dir() {
+            for entry in WalkBuilder::new(path).standard_filters(false).build() {
+                let entry = entry?;
+                if entry.file_type().is_some_and(|t| t.is_file()) {
+                    self.smudge_lfs(entry.path())?;
+                }
+            }
+            return Ok(());
+        }
+
+        let Ok(content) = fs::read(path) else {
+            return Ok(());
+        };
+        if content.len() > 1024 || !content.starts_with(LFS_POINTER_HEADER) {
+            return Ok(());
+        }
+        let mut child = self
+            .git_command(&["lfs", "smudge", "--", &path.to_string_lossy()])?
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .with_context(|| {
+                format!(
+                    "{} is stored in Git LFS but git-lfs could not be run",
+                    path.display()
+                )
+            })?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(&content)
+            .context("Failed to write LFS pointer")?;
+        let output = child
+            .wait_with_output()
+            .context("Failed to run git lfs smudge")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Failed to fetch LFS content for {}: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        fs::write(path, output.stdout)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    pub(crate) fn remote_callbacks(&self) -> RemoteCallbacks<'_> {
+        credential_callbacks(&self.config.remote)
+    }
+
+    // Updates remote-tracking branches and tags without touching the work tree
+    pub(crate) fn fetch_remote(&self, repo: &Repository) -> Result<()> {
+        if let Some(backend) = self.config.remote.storage_backend()? {
+            return backend
+                .fetch(
+                    repo.workdir().unwrap_or_else(|| repo.path()),
+                    &self.config.remote.github_repo,
+                )
+                .with_context(|| network_error("Failed to fetch from the storage backend", None));
+        }
+        let mut remote = repo
+            .find_remote("origin")
+            .context("Failed to find remote 'origin'")?;
+        remote
+            .fetch(
+                &[
+                    "+refs/heads/*:refs/remotes/origin/*",
+                    "+refs/tags/*:refs/tags/*",
+                ],
+                Some(
+                    git2::FetchOptions::new()
+                        .remote_callbacks(self.remote_callbacks())
+                        .proxy_options(self.config.remote.proxy_options()),
+                ),
+                None,
+            )
+            .map_err(|e| {
+                let typed = network_error("Failed to fetch from remote", Some(&e));
+                anyhow::Error::new(e).context(typed)
+            })?;
+        Ok(())
+    }
+
+    /// Writes the profile's files as they were at a revision
+    pub fn checkout(&self, revision: &str, pin: bool, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+
+        let repo = self.open_repo()?;
+        if let Err(e) = self.fetch_remote(&repo) {
+            warn!("Could not fetch remote, using local history only: {:#}", e);
+        }
+        let commit = self.resolve_commit(&repo, revision)?;
+        let tree = commit.tree().context("Failed to read commit tree")?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let nested_repos = NestedRepos::from_tree(&repo, &tree)?;
+
+        for relative_path in profile_config.files.keys() {
+            let Some(entry) = self.config.tree_entry(&tree, &profile, relative_path) else {
+                warn!("{} does not exist at {}", relative_path, revision);
+                continue;
+            };
+            let dest = profile_config.destination(&home, relative_path);
+            self.backup_file(&dest)?;
+            let object = entry
+                .to_object(&repo)
+                .context("Failed to read repository object")?;
+            let rendered =
+                self.rendered_object(&object, relative_path, &profile, profile_config)?;
+            let origin = format!("{}:{}", commit.id(), relative_path);
+            self.write_destination(&dest, &origin, || {
+                // Never write through a managed symlink into the source
+                if dest.is_symlink() {
+                    remove_link(&dest).context("Failed to remove symlink")?;
+                }
+                match &rendered {
+                    Some(rendered) => self.write_content(rendered, &dest)?,
+                    None => self.write_object(&repo, &object, &dest)?,
+                }
+                match profile_config.line_ending(relative_path) {
+                    Some(line_ending) => convert_line_endings(&dest, line_ending.is_crlf()),
+                    None => Ok(()),
+                }
+            })?;
+            self.restore_nested_repos(
+                &nested_repos,
+                &self.config.repo_key(&profile, relative_path),
+                &dest,
+            );
+            info!("Checked out {} at {}", relative_path, revision);
+        }
+
+        if pin {
+            let pin_state = PinState {
+                commit: commit.id().to_string(),
+                revision: revision.to_string(),
+            };
+            pin_state.save()?;
+            println!(
+                "Pinned to {} ({}); sync will not advance until `dotty unpin`",
+                revision,
+                &pin_state.commit[..7]
+            );
+        }
+        Ok(())
+    }
+
+    // Commits the index on top of HEAD, or returns HEAD when the index
+    // matches it. Every dotty commit carries a Host trailer so history can
+    // show which machine made the change.
+    pub(crate) fn commit_index(
+        &self,
+        repo: &Repository,
+        index: &mut git2::Index,
+        message: &str,
+    ) -> Result<git2::Oid> {
+        let tree_id = index.write_tree().context("Failed to write tree")?;
+        let tree = repo.find_tree(tree_id).context("Failed to find tree")?;
+
+        let signature = repo.signature().context("Failed to get signature")?;
+        let parent_commit = repo
+            .head()
+            .context("Failed to get HEAD")?
+            .peel_to_commit()
+            .context("Failed to peel to commit")?;
+        // An unchanged tree would only make an empty commit
+        if parent_commit.tree_id() == tree_id {
+            return Ok(parent_commit.id());
+        }
+
+        let hostname = hostname::get()
+            .map(|h| h.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let message = format!("{}\n\nHost: {}", message, hostname);
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            &message,
+            &tree,
+            &[&parent_commit],
+        )
+        .context("Failed to create commit")
+    }
+
+    /// Restores one file from a revision
+    pub fn revert_file(&self, path: &Path, revision: &str, profile: Option<String>) -> Result<()> {
+        let (key, short_id) = self.restore_file(path, revision, profile, |key, short_id| {
+            format!("Revert {} to {}", key, short_id)
+        })?;
+        println!("Reverted {} to {}", key, short_id);
+        Ok(())
+    }
+
+    /// Copies selected files from another machine's branch into this
+    /// machine's sources, destinations and branch
+    pub fn merge_from(&self, host: &str, paths: &[PathBuf], profile: Option<String>) -> Result<()> {
+        let branch = format!("machines/{}", host);
+        let repo = self.open_repo()?;
+        self.fetch_remote(&repo)
+            .context("Failed to fetch the remote")?;
+        let revision = format!("refs/remotes/origin/{}", branch);
+        if repo.refname_to_id(&revision).is_err() {
+            anyhow::bail!("The remote has no branch {}", branch);
+        }
+        for path in paths {
+            let (key, short_id) =
+                self.restore_file(path, &revision, profile.clone(), |key, short_id| {
+                    format!("Merge {} from {} ({})", key, host, short_id)
+                })?;
+            println!("Merged {} from {} ({})", key, host, short_id);
+        }
+        Ok(())
+    }
+
+    // Restores a tracked file's source, destination and repository copy to
+    // its content at `revision`, and commits and pushes the result
+    pub(crate) fn restore_file<F>(
+        &self,
+        path: &Path,
+        revision: &str,
+        profile: Option<String>,
+        message: F,
+    ) -> Result<(String, String)>
+    where
+        F: FnOnce(&str, &str) -> String,
+    {
+        let key = self.tracked_key(path)?;
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let source = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?
+            .files
+            .get(&key)
+            .with_context(|| format!("{} is not tracked in profile {}", key, profile))?
+            .source();
+
+        let repo = self.open_repo()?;
+        let commit = self.resolve_commit(&repo, revision)?;
+        let tree = commit.tree()?;
+        let object = self
+            .config
+            .tree_entry(&tree, &profile, &key)
+            .with_context(|| format!("{} does not exist at {}", key, revision))?
+            .to_object(&repo)
+            .context("Failed to read repository object")?;
+
+        let source = Path::new(source);
+        let profile_config = &self.config.profiles[&profile];
+        let dest = profile_config.destination(
+            &dirs::home_dir().context("Failed to get home directory")?,
+            &key,
+        );
+        // A template's destination gets its rendering
+        let rendered = self.rendered_object(&object, &key, &profile, profile_config)?;
+        // The repository stores LF when a policy is set
+        let restore = |path: &Path| -> Result<()> {
+            match &rendered {
+                Some(rendered) if path == dest => self.write_content(rendered, path)?,
+                _ => self.write_object(&repo, &object, path)?,
+            }
+            match profile_config.line_ending(&key) {
+                Some(line_ending) => convert_line_endings(path, line_ending.is_crlf()),
+                None => Ok(()),
+            }
+        };
+        self.backup_file(source)?;
+        restore(source)?;
+        // A symlinked destination already points at the restored source
+        if !dest.is_symlink() && dest != source {
+            self.backup_file(&dest)?;
+            let origin = format!("{}:{}", commit.id(), key);
+            self.write_destination(&dest, &origin, || restore(&dest))?;
+        }
+        let repo_key = self.config.repo_key(&profile, &key);
+        write_git_object(&repo, &object, &self.repo_path()?.join(&repo_key))?;
+
+        let mut index = repo.index().context("Failed to get repo index")?;
+        if object.as_tree().is_some() {
+            index
+                .add_all([&repo_key].iter(), git2::IndexAddOption::DEFAULT, None)
+                .context("Failed to add files to index")?;
+        } else {
+            index
+                .add_path(Path::new(&repo_key))
+                .context("Failed to add file to index")?;
+        }
+        index.write().context("Failed to write index")?;
+        let short_id = commit.id().to_string()[..7].to_string();
+        self.commit_index(&repo, &mut index, &message(&key, &short_id))?;
+        self.push_branch(&repo)?;
+        Ok((key, short_id))
+    }
+
+    // Files committed to the repo that no profile tracks anymore, or whose
+    // source has been deleted
+    pub(crate) fn orphaned_paths(&self, repo: &Repository) -> Result<Vec<String>> {
+        let live_keys = self.tracked_repo_keys(true);
+
+        let tree = repo.head()?.peel_to_tree()?;
+        // Overlays are named after machines that have synced, or an OS
+        let machines = Machines::from_tree(repo, &tree)?;
+        let overlay = |suffix: &str| {
+            machines.machines.contains_key(suffix)
+                || KNOWN_OS.contains(&suffix)
+                || suffix == "darwin"
+        };
+        let mut orphans = Vec::new();
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() != Some(git2::ObjectType::Blob) {
+                return git2::TreeWalkResult::Ok;
+            }
+            let path = format!("{}{}", root, entry.name().unwrap_or_default());
+            let tracked = path.starts_with(".dotty/")
+                || path == ".gitattributes"
+                || live_keys.iter().any(|key| {
+                    let Some(rest) = path.strip_prefix(key.as_str()) else {
+                        return false;
+                    };
+                    let rest = rest.strip_prefix('.').map_or(rest, |overlaid| {
+                        let (suffix, inner) =
+                            overlaid.split_at(overlaid.find('/').unwrap_or(overlaid.len()));
+                        if overlay(suffix) {
+                            inner
+                        } else {
+                            rest
+                        }
+                    });
+                    rest.is_empty() || rest.starts_with('/')
+                });
+            if !tracked {
+                orphans.push(path);
+            }
+            git2::TreeWalkResult::Ok
+        })
+        .context("Failed to walk repository tree")?;
+        Ok(orphans)
+    }
+
+    /// Deletes files in the repository that no entry refers to
+    pub fn clean_repo(&self, yes: bool) -> Result<()> {
+        let repo = self.open_repo()?;
+        let orphans = self.orphaned_paths(&repo)?;
+        if orphans.is_empty() {
+            println!("No orphaned files in the repository");
+            return Ok(());
+        }
+
+        println!("Orphaned files in the repository:");
+        for path in &orphans {
+            println!("  {}", path.red());
+        }
+        if !yes
+            && !confirm(&format!(
+                "Delete {} file(s) from the repository?",
+                orphans.len()
+            ))?
+        {
+            println!("Aborted");
+            return Ok(());
+        }
+
+        let repo_path = self.repo_path()?;
+        let mut index = repo.index().context("Failed to get repo index")?;
+        for path in &orphans {
+            index
+                .remove_path(Path::new(path))
+                .context("Failed to remove file from index")?;
+            let file_path = repo_path.join(path);
+            if file_path.exists() {
+                fs::remove_file(&file_path)
+                    .with_context(|| format!("Failed to delete {}", file_path.display()))?;
+            }
+        }
+        index.write().context("Failed to write index")?;
+        self.commit_index(
+            &repo,
+            &mut index,
+            &format!("Remove orphaned files: {}", orphans.join(", ")),
+        )?;
+        self.push_branch(&repo)?;
+
+        println!("Removed {} orphaned file(s)", orphans.len());
+        Ok(())
+    }
+
+    // The most recent commit that changed `repo_key`
+    pub(crate) fn last_change<'r>(
+        &self,
+        repo: &'r Repository,
+        repo_key: &str,
+    ) -> Result<Option<git2::Commit<'r>>> {
+        let mut revwalk = repo.revwalk().context("Failed to walk history")?;
+        revwalk.push_head().context("Failed to get HEAD")?;
+        let entry_id = |commit: &git2::Commit| {
+            commit
+                .tree()
+                .ok()
+                .and_then(|tree| tree.get_path(Path::new(repo_key)).ok())
+                .map(|entry| entry.id())
+        };
+        for oid in revwalk {
+            let commit = repo.find_commit(oid?).context("Failed to find commit")?;
+            let current = entry_id(&commit);
+            if current.is_some() && current != commit.parents().next().as_ref().and_then(entry_id) {
+                return Ok(Some(commit));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Prints the commits that changed a file
+    pub fn history(&self, path: &Path, patch: bool) -> Result<()> {
+        let key = self.tracked_key(path)?;
+        let profile = std::iter::once(&self.current_profile)
+            .chain(self.config.profiles.keys())
+            .find(|profile| {
+                self.config
+                    .profiles
+                    .get(*profile)
+                    .is_some_and(|profile_config| profile_config.files.contains_key(&key))
+            })
+            .unwrap_or(&self.current_profile);
+        // Switching layouts shows up as a rename, which is followed below
+        let mut key = self.config.repo_key(profile, &key);
+        let repo = self.open_repo()?;
+        let mut revwalk = repo.revwalk().context("Failed to walk history")?;
+        revwalk.push_head().context("Failed to get HEAD")?;
+
+        let mut found = false;
+        for oid in revwalk {
+            let commit = repo.find_commit(oid?).context("Failed to find commit")?;
+            let entry_id = |commit: &git2::Commit| {
+                commit
+                    .tree()
+                    .ok()
+                    .and_then(|tree| tree.get_path(Path::new(&key)).ok())
+                    .map(|entry| entry.id())
+            };
+            let parent = commit.parents().next();
+            let current = entry_id(&commit);
+            let previous = parent.as_ref().and_then(entry_id);
+            if current == previous {
+                continue;
+            }
+            found = true;
+
+            // Follow the file back across `dotty mv`
+            let renamed_from = match &parent {
+                Some(parent) if previous.is_none() => {
+                    let mut diff = repo
+                        .diff_tree_to_tree(Some(&parent.tree()?), Some(&commit.tree()?), None)
+                        .context("Failed to diff commit")?;
+                    diff.find_similar(Some(git2::DiffFindOptions::new().renames(true)))
+                        .context("Failed to detect renames")?;
+                    diff.deltas().find_map(|delta| {
+                        (delta.status() == git2::Delta::Renamed
+                            && delta.new_file().path() == Some(Path::new(&key)))
+                        .then(|| delta.old_file().path())
+                        .flatten()
+                        .map(|path| path.to_string_lossy().into_owned())
+                    })
+                }
+                _ => None,
+            };
+
+            let message = commit.message().unwrap_or_default();
+            let host = message
+                .lines()
+                .find_map(|line| line.strip_prefix("Host: "))
+                .map(str::to_string)
+                .unwrap_or_else(|| commit.author().name().unwrap_or("unknown").to_string());
+            let summary = commit.summary().unwrap_or_default();
+            let action = match &renamed_from {
+                Some(old_key) => format!(" (renamed from {})", old_key),
+                None if current.is_none() => " (deleted)".to_string(),
+                None => String::new(),
+            };
+            println!(
+                "{}  {}  {}  {}{}",
+                commit.id().to_string()[..7].yellow(),
+                format_git_time(commit.time()),
+                host.cyan(),
+                summary,
+                action
+            );
+
+            if patch {
+                let parent_tree = parent.as_ref().map(|p| p.tree()).transpose()?;
+                let mut options = git2::DiffOptions::new();
+                options.pathspec(&key);
+                let diff = repo
+                    .diff_tree_to_tree(
+                        parent_tree.as_ref(),
+                        Some(&commit.tree()?),
+                        Some(&mut options),
+                    )
+                    .context("Failed to diff commit")?;
+                print_diff(&diff)?;
+                println!();
+            }
+            if let Some(old_key) = renamed_from {
+                key = old_key;
+            }
+        }
+
+        if !found {
+            println!("No history for {}", key);
+        }
+        Ok(())
+    }
+
+    /// Reports the commits on the remote branch that aren't local yet and the
+    /// tracked files they change, leaving the work tree alone
+    pub fn fetch_preview(&self, patch: bool) -> Result<()> {
+        let repo = self.open_repo()?;
+        self.fetch_remote(&repo)?;
+        let branch = self.branch();
+        let Ok(remote) = repo.refname_to_id(&format!("refs/remotes/origin/{}", branch)) else {
+            println!("origin/{} doesn't exist yet", branch);
+            return Ok(());
+        };
+        let local = repo.head()?.peel_to_commit()?.id();
+        if remote == local || repo.graph_descendant_of(local, remote)? {
+            println!("Up to date with origin/{}", branch);
+            return Ok(());
+        }
+
+        let tracked = self.tracked_repo_keys(false);
+        let is_tracked = |path: &Path| tracked.iter().any(|key| path.starts_with(key));
+        let changed_paths = |old: Option<&git2::Tree>, new: &git2::Tree| -> Result<Vec<String>> {
+            let diff = repo
+                .diff_tree_to_tree(old, Some(new), None)
+                .context("Failed to diff commit")?;
+            Ok(diff
+                .deltas()
+                .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+                .filter(|path| is_tracked(path))
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect())
+        };
+
+        let mut revwalk = repo.revwalk().context("Failed to walk history")?;
+        revwalk.push(remote)?;
+        revwalk.hide(local)?;
+        let mut count = 0;
+        for oid in revwalk {
+            let commit = repo.find_commit(oid?).context("Failed to find commit")?;
+            let parent_tree = commit.parents().next().map(|p| p.tree()).transpose()?;
+            let paths = changed_paths(parent_tree.as_ref(), &commit.tree()?)?;
+            count += 1;
+            let host = commit
+                .message()
+                .unwrap_or_default()
+                .lines()
+                .find_map(|line| line.strip_prefix("Host: "))
+                .map(str::to_string)
+                .unwrap_or_else(|| commit.author().name().unwrap_or("unknown").to_string());
+            println!(
+                "{}  {}  {}  {}",
+                commit.id().to_string()[..7].yellow(),
+                format_git_time(commit.time()),
+                host.cyan(),
+                commit.summary().unwrap_or_default()
+            );
+            for path in paths {
+                println!("    {}", path);
+            }
+        }
+
+        // Net change per file since the histories diverged
+        let base = repo
+            .merge_base(local, remote)
+            .ok()
+            .map(|base| repo.find_commit(base)?.tree())
+            .transpose()?;
+        let remote_tree = repo.find_commit(remote)?.tree()?;
+        let mut options = git2::DiffOptions::new();
+        options.disable_pathspec_match(true);
+        for key in &tracked {
+            options.pathspec(key);
+        }
+        let diff = repo
+            .diff_tree_to_tree(base.as_ref(), Some(&remote_tree), Some(&mut options))
+            .context("Failed to diff against the remote")?;
+        println!();
+        println!(
+            "{} commit(s) on origin/{} not applied locally",
+            count, branch
+        );
+        for (index, delta) in diff.deltas().enumerate() {
+            let Some(path) = delta.new_file().path().or(delta.old_file().path()) else {
+                continue;
+            };
+            if !is_tracked(path) {
+                continue;
+            }
+            let (added, removed) = git2::Patch::from_diff(&diff, index)?
+                .map(|patch| patch.line_stats())
+                .transpose()?
+                .map_or((0, 0), |(_, added, removed)| (added, removed));
+            let status = match delta.status() {
+                git2::Delta::Added => "A".green(),
+                git2::Delta::Deleted => "D".red(),
+                _ => "M".yellow(),
+            };
+            println!(
+                "  {} {}  {} {}",
+                status,
+                path.display(),
+                format!("+{}", added).green(),
+                format!("-{}", removed).red()
+            );
+        }
+        if patch {
+            println!();
+            print_diff(&diff)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn push_refspecs(&self, repo: &Repository, refspecs: &[&str]) -> Result<()> {
+        if let Some(backend) = self.config.remote.storage_backend()? {
+            let workdir = repo.workdir().unwrap_or_else(|| repo.path());
+            if let Err(e) = backend.push(workdir, &self.config.remote.github_repo, refspecs) {
+                self.stats.lock().unwrap().push_failures_total += 1;
+                return Err(e)
+                    .context(network_error("Failed to push to the storage backend", None));
+            }
+            return Ok(());
+        }
+
+        // LFS objects have to reach the server before the commits pointing
+        // at them; libgit2 doesn't run the pre-push hook that would do it
+        if !self.lfs_keys().is_empty() {
+            for refspec in refspecs {
+                let local = refspec
+                    .trim_start_matches('+')
+                    .split(':')
+                    .next()
+                    .unwrap_or_default();
+                if !local.is_empty() {
+                    self.run_git(&["lfs", "push", "origin", local])?;
+                }
+            }
+        }
+
+        // Retrying right away only extends a rate limit
+        if let Some(until) = self.stats.lock().unwrap().rate_limited_until {
+            if let Ok(remaining) = until.duration_since(SystemTime::now()) {
+                let wait = humantime::format_duration(Duration::from_secs(remaining.as_secs() + 1));
+                return Err(DottyError::new(
+                    ErrorKind::Network,
+                    format!(
+                        "The remote rate-limited the last push; pushing again in {}",
+                        wait
+                    ),
+                )
+                .hint(format!("wait {} before syncing again", wait))
+                .into());
+            }
+        }
+
+        let mut remote = repo
+            .find_remote("origin")
+            .context("Failed to find remote 'origin'")?;
+        let mut callbacks = self.remote_callbacks();
+        // Only a ref that moved on is a divergence; hooks, protected branches
+        // and the like keep their own message
+        callbacks.push_update_reference(|refname, status| match status {
+            Some(message) => Err(git2::Error::new(
+                if message.contains("non-fast-forward") || message.contains("fetch first") {
+                    git2::ErrorCode::NotFastForward
+                } else {
+                    git2::ErrorCode::GenericError
+                },
+                git2::ErrorClass::Reference,
+                format!("Remote rejected {}: {}", refname, message),
+            )),
+            None => Ok(()),
+        });
+
+        if let Err(e) = remote.push(
+            refspecs,
+            Some(
+                git2::PushOptions::new()
+                    .remote_callbacks(callbacks)
+                    .proxy_options(self.config.remote.proxy_options()),
+            ),
+        ) {
+            let mut stats = self.stats.lock().unwrap();
+            stats.push_failures_total += 1;
+            if is_rate_limit_error(e.message()) {
+                stats.rate_limit_backoff = (stats.rate_limit_backoff * 2)
+                    .clamp(Duration::from_secs(60), Duration::from_secs(3600));
+                stats.rate_limited_until = Some(SystemTime::now() + stats.rate_limit_backoff);
+                let wait = humantime::format_duration(stats.rate_limit_backoff);
+                return Err(e).context(
+                    DottyError::new(
+                        ErrorKind::Network,
+                        format!(
+                            "The remote is rate limiting pushes; not pushing again for {}",
+                            wait
+                        ),
+                    )
+                    .hint(format!("wait {} before syncing again", wait)),
+                );
+            }
+            drop(stats);
+            if e.code() == git2::ErrorCode::NotFastForward {
+                self.notify(
+                    NotificationEvent::RemoteDivergence,
+                    None,
+                    &format!("Remote repository has diverged: {}", e.message()),
+                );
+                return Err(e).context(
+                    DottyError::new(ErrorKind::Conflict, "Failed to push changes").hint(
+                        "another machine pushed first; run `dotty sync` again to merge its changes",
+                    ),
+                );
+            }
+            let typed = network_error("Failed to push changes", Some(&e));
+            return Err(e).context(typed);
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.rate_limited_until = None;
+        stats.rate_limit_backoff = Duration::ZERO;
+        Ok(())
+    }
+
+    /// Tags the current commit and pushes the tag
+    pub fn create_tag(&self, name: &str, message: Option<String>) -> Result<()> {
+        let repo = self.open_repo()?;
+        let head = repo
+            .head()
+            .context("Failed to get HEAD")?
+            .peel_to_commit()
+            .context("Failed to peel to commit")?;
+        let signature = repo.signature().context("Failed to get signature")?;
+        let message = message.unwrap_or_else(|| format!("Dotty snapshot {}", name));
+        repo.tag(name, head.as_object(), &signature, &message, false)
+            .with_context(|| format!("Failed to create tag {}", name))?;
+
+        let refspec = format!("refs/tags/{}:refs/tags/{}", name, name);
+        self.push_refspecs(&repo, &[&refspec])?;
+        println!("Created tag {} at {}", name, &head.id().to_string()[..7]);
+        Ok(())
+    }
+
+    /// Runs `git gc` on the local clone
+    pub fn gc_repo(&self, aggressive: bool) -> Result<()> {
+        let repo = self.open_repo()?;
+        let before = dir_size(repo.path());
+        let mut args = vec!["gc", "--quiet"];
+        if aggressive {
+            args.push("--aggressive");
+        }
+        self.run_git(&args)
+            .context("Repository maintenance requires the git CLI")?;
+        fs::write(state_dir()?.join("last_gc"), "").context("Failed to record maintenance")?;
+        println!(
+            "Repository size: {} -> {}",
+            format_bytes(before),
+            format_bytes(dir_size(repo.path()))
+        );
+        Ok(())
+    }
+
+    // Runs `git gc` from the daemon when maintenance_interval_hours have
+    // passed since the last run
+    pub(crate) fn maintain_if_due(&self) {
+        let interval = self
+            .config
+            .daemon
+            .as_ref()
+            .map_or(default_maintenance_interval_hours(), |daemon| {
+                daemon.maintenance_interval_hours
+            });
+        if interval == 0 {
+            return;
+        }
+        let last_gc = state_dir()
+            .and_then(|dir| Ok(fs::metadata(dir.join("last_gc"))?.modified()?))
+            .ok();
+        if last_gc.is_some_and(|time| {
+            time.elapsed().unwrap_or_default() < Duration::from_secs(interval * 3600)
+        }) {
+            return;
+        }
+        info!("Running repository maintenance");
+        if let Err(e) = self.gc_repo(false) {
+            warn!("Repository maintenance failed: {:#}", e);
+        }
+    }
+
+    /// Prints the size of the repository and its largest files
+    pub fn repo_size(&self, limit: usize) -> Result<()> {
+        let repo = self.open_repo()?;
+        let odb = repo.odb().context("Failed to open object database")?;
+
+        // Name each blob after the first path it appears at, newest first
+        let mut blobs: HashMap<git2::Oid, (usize, String)> = HashMap::new();
+        let mut revwalk = repo.revwalk().context("Failed to walk history")?;
+        revwalk.push_head()?;
+        let mut commits = 0;
+        for oid in revwalk {
+            let tree = repo.find_commit(oid?)?.tree()?;
+            commits += 1;
+            tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+                if entry.kind() == Some(git2::ObjectType::Blob) && !blobs.contains_key(&entry.id())
+                {
+                    if let Ok((size, _)) = odb.read_header(entry.id()) {
+                        let path = format!("{}{}", dir, entry.name().unwrap_or_default());
+                        blobs.insert(entry.id(), (size, path));
+                    }
+                }
+                git2::TreeWalkResult::Ok
+            })?;
+        }
+
+        println!(
+            "Repository: {}",
+            repo.path().parent().unwrap_or(repo.path()).display()
+        );
+        println!("On disk:    {}", format_bytes(dir_size(repo.path())));
+        println!(
+            "Commits:    {}{}",
+            commits,
+            if repo.is_shallow() { " (shallow)" } else { "" }
+        );
+        println!(
+            "Content:    {} in {} file versions",
+            format_bytes(blobs.values().map(|(size, _)| *size as u64).sum()),
+            blobs.len()
+        );
+
+        let mut largest: Vec<_> = blobs.into_iter().collect();
+        largest.sort_unstable_by_key(|(_, (size, _))| std::cmp::Reverse(*size));
+        if !largest.is_empty() {
+            println!("\nLargest files:");
+        }
+        for (oid, (size, path)) in largest.into_iter().take(limit) {
+            println!(
+                "{:>10}  {}  {}",
+                format_bytes(size as u64),
+                &oid.to_string()[..7],
+                path
+            );
+        }
+        Ok(())
+    }
+
+    /// Rewrites first-parent history newer than `since`, replacing each run of
+    /// consecutive "Sync dotfiles" commits with a single commit. Tagged commits
+    /// are never rewritten, so the squash stops at the newest one.
+    pub fn squash_history(&self, since: u64, yes: bool) -> Result<()> {
+        let repo = self.open_repo()?;
+        self.fetch_remote(&repo)
+            .context("Failed to fetch before rewriting history")?;
+        let head = repo.head()?.peel_to_commit()?;
+        let branch = self.branch();
+        if let Ok(remote) = repo.refname_to_id(&format!("refs/remotes/origin/{}", branch)) {
+            if remote != head.id() && !repo.graph_descendant_of(head.id(), remote)? {
+                anyhow::bail!("Local history is behind the remote; sync before squashing");
+            }
+        }
+
+        let tagged: HashSet<git2::Oid> = repo
+            .tag_names(None)?
+            .iter()
+            .flatten()
+            .filter_map(|name| repo.revparse_single(&format!("refs/tags/{}", name)).ok())
+            .filter_map(|object| object.peel_to_commit().ok())
+            .map(|commit| commit.id())
+            .collect();
+        let mut commits = Vec::new();
+        let mut base = Some(head.clone());
+        while let Some(commit) = base.take() {
+            if commit.time().seconds() < since as i64 || tagged.contains(&commit.id()) {
+                base = Some(commit);
+                break;
+            }
+            base = commit.parent(0).ok();
+            commits.push(commit);
+        }
+        commits.reverse();
+
+        let is_auto = |commit: &git2::Commit| {
+            commit
+                .summary()
+                .is_some_and(|summary| summary.starts_with("Sync dotfiles"))
+        };
+        let mut runs: Vec<&[git2::Commit]> = Vec::new();
+        let mut rest = commits.as_slice();
+        while !rest.is_empty() {
+            let len = if is_auto(&rest[0]) {
+                rest.iter().take_while(|commit| is_auto(commit)).count()
+            } else {
+                1
+            };
+            let (run, tail) = rest.split_at(len);
+            runs.push(run);
+            rest = tail;
+        }
+        if runs.len() == commits.len() {
+            println!("Nothing to squash");
+            return Ok(());
+        }
+        if !yes
+            && !confirm(&format!(
+                "Rewrite {} commits into {} and force-push? Other machines will need to re-clone",
+                commits.len(),
+                runs.len()
+            ))?
+        {
+            println!("Aborted");
+            return Ok(());
+        }
+
+        let mut parent = base;
+        for run in &runs {
+            let last = run.last().unwrap();
+            let message = if run.len() == 1 {
+                last.message().unwrap_or_default().to_string()
+            } else {
+                let mut hosts: Vec<&str> = run
+                    .iter()
+                    .filter_map(|commit| commit.message())
+                    .flat_map(|message| {
+                        message
+                            .lines()
+                            .filter_map(|line| line.strip_prefix("Host: "))
+                    })
+                    .collect();
+                hosts.sort_unstable();
+                hosts.dedup();
+                let trailers: Vec<String> =
+                    hosts.iter().map(|host| format!("Host: {}", host)).collect();
+                format!(
+                    "Sync dotfiles ({} syncs, {} to {})\n\n{}",
+                    run.len(),
+                    format_git_time(run[0].time()),
+                    format_git_time(last.time()),
+                    trailers.join("\n")
+                )
+            };
+            let parents: Vec<&git2::Commit> = parent.iter().collect();
+            let oid = repo
+                .commit(
+                    None,
+                    &last.author(),
+                    &last.committer(),
+                    &message,
+                    &last.tree()?,
+                    &parents,
+                )
+                .context("Failed to create commit")?;
+            parent = Some(repo.find_commit(oid)?);
+        }
+
+        let new_head = parent.unwrap().id();
+        repo.reference(
+            "refs/dotty/pre-squash",
+            head.id(),
+            true,
+            "dotty repo squash",
+        )
+        .context("Failed to save the previous history")?;
+        let branch_ref = format!("refs/heads/{}", branch);
+        repo.reference(&branch_ref, new_head, true, "dotty repo squash")
+            .context("Failed to update branch")?;
+        self.push_refspecs(&repo, &[&format!("+{}:{}", branch_ref, branch_ref)])?;
+        println!(
+            "Squashed {} commits into {}; the previous history is kept locally at refs/dotty/pre-squash",
+            commits.len(),
+            runs.len()
+        );
+        Ok(())
+    }
+
+    // The local registry merged with the ones on every fetched branch
+    pub(crate) fn known_machines(&self, repo: &Repository) -> Result<Machines> {
+        let mut machines = Machines::load(&self.repo_path()?.join(MACHINES_PATH))?;
+        for reference in repo.references_glob("refs/remotes/origin/*")? {
+            if let Ok(tree) = reference?.peel_to_tree() {
+                machines.merge(Machines::from_tree(repo, &tree)?);
+            }
+        }
+        Ok(machines)
+    }
+
+    /// Prints every machine that has synced, merged across branches
+    pub fn list_machines(&self) -> Result<()> {
+        let repo = self.open_repo()?;
+        if let Err(e) = self.fetch_remote(&repo) {
+            warn!("Could not fetch remote, using local history only: {:#}", e);
+        }
+        let machines = self.known_machines(&repo)?;
+        if machines.machines.is_empty() {
+            println!("No machines have synced yet");
+            return Ok(());
+        }
+
+        let mut machines: Vec<(String, Machine)> = machines.machines.into_iter().collect();
+        machines.sort_unstable_by_key(|(_, machine)| std::cmp::Reverse(machine.last_sync));
+        let now = unix_seconds(SystemTime::now());
+        for (host, machine) in machines {
+            let days = now.saturating_sub(machine.last_sync) / 86400;
+            let last_sync = format!(
+                "{} ({})",
+                format_unix_time(machine.last_sync),
+                match days {
+                    0 => "today".to_string(),
+                    1 => "1 day ago".to_string(),
+                    days => format!("{} days ago", days),
+                }
+            );
+            let last_sync = match days {
+                0..=6 => last_sync.normal(),
+                7..=29 => last_sync.yellow(),
+                _ => last_sync.red(),
+            };
+            let current = if host == host_name() {
+                " (this machine)"
+            } else {
+                ""
+            };
+            println!(
+                "{}{}  {}  profile {}  dotty {}  last sync {}",
+                host.bold(),
+                current,
+                machine.os,
+                machine.profile,
+                machine.version,
+                last_sync
+            );
+        }
+        Ok(())
+    }
+
+    /// Tags in the local clone, for shell completion. Never clones or
+    /// fetches, so it is empty before the first sync.
+    pub fn tag_names(&self) -> Vec<String> {
+        let Ok(repo) = self
+            .repo_path()
+            .and_then(|path| Repository::open(path).context("Failed to open repository"))
+        else {
+            return Vec::new();
+        };
+        repo.tag_names(None)
+            .map(|names| names.iter().flatten().map(String::from).collect())
+            .unwrap_or_default()
+    }
+
+    /// Prints the snapshot tags
+    pub fn list_tags(&self) -> Result<()> {
+        let repo = self.open_repo()?;
+        if let Err(e) = self.fetch_remote(&repo) {
+            warn!("Could not fetch remote tags: {:#}", e);
+        }
+        let names = repo.tag_names(None).context("Failed to list tags")?;
+        if names.is_empty() {
+            println!("No tags");
+            return Ok(());
+        }
+
+        for name in names.iter().flatten() {
+            let object = repo
+                .revparse_single(&format!("refs/tags/{}", name))
+                .with_context(|| format!("Failed to resolve tag {}", name))?;
+            let commit = object
+                .peel_to_commit()
+                .context("Tag does not point to a commit")?;
+            let (when, message) = match object.as_tag() {
+                Some(tag) => (
+                    tag.tagger().map_or(commit.time(), |tagger| tagger.when()),
+                    tag.message().unwrap_or_default().trim().to_string(),
+                ),
+                None => (commit.time(), String::new()),
+            };
+            println!(
+                "{}  {}  {}  {}",
+                name.bold(),
+                &commit.id().to_string()[..7],
+                format_git_time(when),
+                message
+            );
+        }
+        Ok(())
+    }
+}
+
+pub(crate) const TOMBSTONES_PATH: &str = ".dotty/tombstones.toml";
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Tombstone {
+    pub(crate) profile: String,
+    pub(crate) path: String,
+    pub(crate) host: String,
+    pub(crate) removed_at: u64,
+}
+
+// Entries removed from a profile, committed to the repo at TOMBSTONES_PATH
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Tombstones {
+    #[serde(default)]
+    pub(crate) tombstones: Vec<Tombstone>,
+}
+
+impl Tombstones {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let tombstones_str = fs::read_to_string(path).context("Failed to read tombstones")?;
+        toml::from_str(&tombstones_str).context("Failed to parse tombstones")
+    }
+
+    pub(crate) fn from_tree(repo: &Repository, tree: &git2::Tree) -> Result<Self> {
+        let Ok(entry) = tree.get_path(Path::new(TOMBSTONES_PATH)) else {
+            return Ok(Self::default());
+        };
+        let blob = repo
+            .find_blob(entry.id())
+            .context("Failed to read tombstones")?;
+        toml::from_str(&String::from_utf8_lossy(blob.content()))
+            .context("Failed to parse tombstones")
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path.parent().unwrap())
+            .context("Failed to create parent directories")?;
+        let tombstones_str = toml::to_string_pretty(self)?;
+        fs::write(path, tombstones_str).context("Failed to write tombstones")?;
+        Ok(())
+    }
+}
+
+pub(crate) const MACHINES_PATH: &str = ".dotty/machines.toml";
+
+// The configuration committed for `dotty apply`, with sources under $HOME
+// written as ~/ and credentials left out
+pub(crate) const CONFIG_PATH: &str = ".dotty/config.toml";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Machine {
+    pub(crate) os: String,
+    pub(crate) last_sync: u64,
+    pub(crate) profile: String,
+    pub(crate) version: String,
+}
+
+// Every machine that has synced, keyed by hostname, committed at
+// MACHINES_PATH
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Machines {
+    #[serde(default)]
+    pub(crate) machines: BTreeMap<String, Machine>,
+}
+
+impl Machines {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let machines_str = fs::read_to_string(path).context("Failed to read machine registry")?;
+        toml::from_str(&machines_str).context("Failed to parse machine registry")
+    }
+
+    pub(crate) fn from_tree(repo: &Repository, tree: &git2::Tree) -> Result<Self> {
+        let Ok(entry) = tree.get_path(Path::new(MACHINES_PATH)) else {
+            return Ok(Self::default());
+        };
+        let blob = repo
+            .find_blob(entry.id())
+            .context("Failed to read machine registry")?;
+        toml::from_str(&String::from_utf8_lossy(blob.content()))
+            .context("Failed to parse machine registry")
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path.parent().unwrap())
+            .context("Failed to create parent directories")?;
+        let machines_str = toml::to_string_pretty(self)?;
+        fs::write(path, machines_str).context("Failed to write machine registry")?;
+        Ok(())
+    }
+
+    // Updates this machine's entry ahead of a sync commit
+    pub(crate) fn record(path: &Path, profile: &str) -> Result<()> {
+        let mut machines = Self::load(path)?;
+        machines.machines.insert(
+            host_name(),
+            Machine {
+                os: env::consts::OS.to_string(),
+                last_sync: unix_seconds(SystemTime::now()),
+                profile: profile.to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+        );
+        machines.save(path)
+    }
+
+    // Keeps the most recent entry for each machine
+    pub(crate) fn merge(&mut self, other: Self) {
+        for (host, machine) in other.machines {
+            match self.machines.get(&host) {
+                Some(existing) if existing.last_sync >= machine.last_sync => {}
+                _ => {
+                    self.machines.insert(host, machine);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) const NESTED_REPOS_PATH: &str = ".dotty/repos.toml";
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct NestedRepo {
+    pub(crate) url: String,
+    pub(crate) commit: String,
+}
+
+// Git repositories found inside tracked directories (e.g. plugin checkouts),
+// keyed by their path in the repo. They are pinned here rather than copied,
+// and cloned back at the pinned commit on checkout.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct NestedRepos {
+    #[serde(default)]
+    pub(crate) repos: BTreeMap<String, NestedRepo>,
+}
+
+impl NestedRepos {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let repos_str = fs::read_to_string(path).context("Failed to read nested repos")?;
+        toml::from_str(&repos_str).context("Failed to parse nested repos")
+    }
+
+    pub(crate) fn from_tree(repo: &Repository, tree: &git2::Tree) -> Result<Self> {
+        let Ok(entry) = tree.get_path(Path::new(NESTED_REPOS_PATH)) else {
+            return Ok(Self::default());
+        };
+        let blob = repo
+            .find_blob(entry.id())
+            .context("Failed to read nested repos")?;
+        toml::from_str(&String::from_utf8_lossy(blob.content()))
+            .context("Failed to parse nested repos")
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path.parent().unwrap())
+            .context("Failed to create parent directories")?;
+        let repos_str = toml::to_string_pretty(self)?;
+        fs::write(path, repos_str).context("Failed to write nested repos")?;
+        Ok(())
+    }
+
+    // The repos below a tracked entry, relative to it
+    pub(crate) fn under<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a NestedRepo)> {
+        self.repos.iter().filter_map(move |(path, nested)| {
+            path.strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix('/'))
+                .map(|rest| (rest, nested))
+        })
+    }
+}
+
+// A directory that is a git checkout with an origin to restore it from
+pub(crate) fn nested_repo(path: &Path) -> Option<NestedRepo> {
+    if !path.join(".git").exists() {
+        return None;
+    }
+    let repo = Repository::open(path).ok()?;
+    let url = repo.find_remote("origin").ok()?.url()?.to_string();
+    let commit = repo.head().ok()?.peel_to_commit().ok()?.id().to_string();
+    Some(NestedRepo { url, commit })
+}
+
+// Writes a blob to `dest`, or recreates a tree beneath it
+pub(crate) fn write_git_object(
+    repo: &Repository,
+    object: &git2::Object,
+    dest: &Path,
+) -> Result<()> {
+    if let Some(blob) = object.as_blob() {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).context("Failed to create parent directories")?;
+        }
+        fs::write(dest, blob.content()).context("Failed to write file")?;
+    } else if let Some(tree) = object.as_tree() {
+        fs::create_dir_all(dest).context("Failed to create directory")?;
+        for entry in tree.iter() {
+            let name = entry.name().context("Invalid file name in repository")?;
+            let child = entry
+                .to_object(repo)
+                .context("Failed to read repository object")?;
+            write_git_object(repo, &child, &dest.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+// Per-directory ignore file, in gitignore syntax, synced along with the directory
+pub(crate) const LFS_POINTER_HEADER: &[u8] = b"version https://git-lfs.github.com/spec/v1";
+
+pub(crate) const LFS_ATTRIBUTES_START: &str = "# BEGIN dotty lfs";
+
+pub(crate) const LFS_ATTRIBUTES_END: &str = "# END dotty lfs";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_path_is_relative_to_home() {
+        let home = Path::new("/home/me");
+        for local_path in ["~/dots", r"~\dots", "dots"] {
+            assert_eq!(local_repo_path(home, local_path), home.join("dots"));
+        }
+    }
+
+    #[test]
+    fn local_path_expands_env_vars() {
+        env::set_var("DOTTY_TEST_REPO_DIR", "repos");
+        assert_eq!(
+            local_repo_path(Path::new("/home/me"), "~/%DOTTY_TEST_REPO_DIR%/dots"),
+            Path::new("/home/me/repos/dots")
+        );
+    }
+}
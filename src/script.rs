@@ -0,0 +1,398 @@
+//! Scripts: [Rhai](https://rhai.rs) functions kept in the repository at
+//! `.dotty/script.rhai` that serve as hooks, template helpers and entry
+//! conditions.
+//!
+//! Only the script's `fn` definitions are used; top-level statements never
+//! run. Besides Rhai's own functions and string methods, scripts can call
+//! `hostname()`, `os()`, `arch()`, `profile()`, `user()`, `home()`,
+//! `env(name)`, `exists(path)`, `command_exists(name)` and
+//! `run(program, [args...])`, which returns the program's trimmed stdout.
+//! `run` only works on machines whose config sets `script_commands = true`.
+//!
+//! Functions named `pre_sync`, `post_sync`, `post_apply` and `post_pull` run
+//! as hooks; a failing `pre_sync` stops the sync.
+
+use crate::config::{FileEntry, ProfileConfig};
+use crate::template::TemplateContext;
+use crate::util::command_exists;
+use crate::Dotty;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+use std::path::Path;
+use std::sync::Arc;
+use std::{fmt, fs, process};
+
+/// Where the script lives in the repository
+pub const SCRIPT_PATH: &str = ".dotty/script.rhai";
+
+/// The functions dotty calls as hooks, if the script defines them
+pub const HOOKS: [&str; 4] = ["pre_sync", "post_sync", "post_apply", "post_pull"];
+
+// Calls nested deeper than this are taken for runaway recursion
+const MAX_CALL_LEVELS: usize = 64;
+
+// Enough for any reasonable helper; stops loops that never end
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// A script value
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+impl Value {
+    /// Whether the value counts as true in a condition: non-empty strings
+    /// and non-zero integers do
+    pub fn truthy(&self) -> bool {
+        match self {
+            Value::Unit => false,
+            Value::Bool(value) => *value,
+            Value::Int(value) => *value != 0,
+            Value::Str(value) => !value.is_empty(),
+        }
+    }
+}
+
+impl From<Dynamic> for Value {
+    fn from(value: Dynamic) -> Self {
+        if value.is_unit() {
+            Value::Unit
+        } else if let Ok(value) = value.as_bool() {
+            Value::Bool(value)
+        } else if let Ok(value) = value.as_int() {
+            Value::Int(value)
+        } else {
+            Value::Str(value.to_string())
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Unit => Ok(()),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Str(value) => f.write_str(value),
+        }
+    }
+}
+
+/// A compiled script
+#[derive(Debug)]
+pub struct Script {
+    ast: AST,
+    commands: bool,
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Script {
+            ast: AST::empty(),
+            commands: false,
+        }
+    }
+}
+
+impl Script {
+    /// Compiles a script, naming the line of the first error
+    pub fn parse(source: &str) -> Result<Self> {
+        let ast = Engine::new()
+            .compile(source)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        Ok(Script {
+            ast,
+            commands: false,
+        })
+    }
+
+    /// The script at `path`, or an empty one when there's no file
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Script::default());
+        }
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read script {}", path.display()))?;
+        Script::parse(&source).with_context(|| format!("Invalid script {}", path.display()))
+    }
+
+    /// Lets the script's `run()` start programs
+    pub fn with_commands(mut self, commands: bool) -> Self {
+        self.commands = commands;
+        self
+    }
+
+    /// The names of the functions the script defines, sorted
+    pub fn functions(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.ast.iter_functions().map(|f| f.name).collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// Whether the script defines a function called `name`
+    pub fn defines(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == name)
+    }
+
+    /// Evaluates a single expression, e.g. an entry's `if_script`
+    pub fn eval(&self, expression: &str, context: &TemplateContext) -> Result<Value> {
+        let engine = self.engine(context);
+        let expression = engine
+            .compile_expression(expression)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let ast = self.ast.clone_functions_only().merge(&expression);
+        engine
+            .eval_ast_with_scope::<Dynamic>(&mut Scope::new(), &ast)
+            .map(Value::from)
+            .map_err(|e| anyhow::anyhow!("{}", e))
+    }
+
+    /// Calls one of the script's functions without arguments
+    pub fn call(&self, name: &str, context: &TemplateContext) -> Result<Value> {
+        self.engine(context)
+            .call_fn_with_options::<Dynamic>(
+                CallFnOptions::new().eval_ast(false),
+                &mut Scope::new(),
+                &self.ast,
+                name,
+                (),
+            )
+            .map(Value::from)
+            .map_err(|e| anyhow::anyhow!("in fn {}: {}", name, e))
+    }
+
+    // An engine with dotty's functions bound to `context`
+    fn engine(&self, context: &TemplateContext) -> Engine {
+        let mut engine = Engine::new();
+        engine
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_operations(MAX_OPERATIONS);
+        for name in ["hostname", "os", "arch", "profile", "user", "home"] {
+            let value = context.get(name).unwrap_or_default();
+            engine.register_fn(name, move || value.clone());
+        }
+        let context = context.clone();
+        engine.register_fn("env", move |name: &str| {
+            context.get(&format!("env.{}", name)).unwrap_or_default()
+        });
+        engine.register_fn("exists", |path: &str| {
+            let relative = path.strip_prefix("~/").unwrap_or(path);
+            dirs::home_dir().unwrap_or_default().join(relative).exists()
+        });
+        engine.register_fn("command_exists", command_exists);
+        let commands = self.commands;
+        engine.register_fn("run", move |program: &str| {
+            run_program(commands, program, Array::new())
+        });
+        engine.register_fn("run", move |program: &str, args: Array| {
+            run_program(commands, program, args)
+        });
+        engine
+    }
+}
+
+fn run_program(commands: bool, program: &str, args: Array) -> Result<String, Box<EvalAltResult>> {
+    // The script comes from the repository, so starting programs is up to
+    // each machine
+    if !commands {
+        return Err(format!(
+            "run({:?}) is disabled; set script_commands = true in this machine's config to allow it",
+            program
+        )
+        .into());
+    }
+    let args: Vec<String> = args.into_iter().map(|arg| arg.to_string()).collect();
+    let output = process::Command::new(program)
+        .args(&args)
+        .stdin(process::Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl Dotty {
+    // The repository's script, compiled once per Dotty
+    pub(crate) fn script(&self) -> Result<Arc<Script>> {
+        if let Some(script) = self.script.get() {
+            return Ok(script.clone());
+        }
+        let script = Script::load(&self.repo_path()?.join(SCRIPT_PATH))?
+            .with_commands(self.config.script_commands);
+        Ok(self.script.get_or_init(|| Arc::new(script)).clone())
+    }
+
+    // The values scripts and templates see for a profile
+    pub(crate) fn script_context(&self, profile: &str) -> Result<TemplateContext> {
+        let default = ProfileConfig::default();
+        let profile_config = self.config.profiles.get(profile).unwrap_or(&default);
+        Ok(TemplateContext::new(profile, profile_config).with_script(self.script()?))
+    }
+
+    // Why an entry doesn't apply on this machine: its if_* conditions, then
+    // its if_script expression
+    pub(crate) fn unmet_condition(&self, profile: &str, entry: &FileEntry) -> Option<String> {
+        if let Some(reason) = entry.unmet_condition() {
+            return Some(reason);
+        }
+        let expression = entry.if_script()?;
+        let result = self.script_context(profile).and_then(|context| {
+            self.script()
+                .and_then(|script| script.eval(expression, &context))
+        });
+        match result {
+            Ok(value) if value.truthy() => None,
+            Ok(_) => Some(format!("{} is false", expression)),
+            Err(e) => {
+                warn!("if_script {:?} failed: {:#}", expression, e);
+                Some(format!("{} failed", expression))
+            }
+        }
+    }
+
+    // Runs a hook if the script defines it
+    pub(crate) fn run_hook(&self, hook: &str, profile: &str) -> Result<()> {
+        let script = self.script()?;
+        if !script.defines(hook) {
+            return Ok(());
+        }
+        script
+            .call(hook, &self.script_context(profile)?)
+            .with_context(|| format!("The {} hook failed", hook))?;
+        info!("Ran the {} hook", hook);
+        Ok(())
+    }
+
+    /// Evaluates an expression against the repository's script and prints
+    /// its value
+    pub fn script_eval(&self, expression: &str, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let value = self
+            .script()?
+            .eval(expression, &self.script_context(&profile)?)?;
+        println!("{}", value);
+        Ok(())
+    }
+
+    /// Compiles the repository's script and every entry's `if_script`, and
+    /// lists the functions and hooks found
+    pub fn script_check(&self) -> Result<()> {
+        let path = self.repo_path()?.join(SCRIPT_PATH);
+        let script = Script::load(&path)?;
+        if path.exists() {
+            println!("{}", path.display());
+            let functions = script.functions();
+            if functions.is_empty() {
+                println!("  functions: none");
+            } else {
+                println!("  functions: {}", functions.join(", "));
+            }
+            let hooks: Vec<&str> = HOOKS
+                .into_iter()
+                .filter(|hook| script.defines(hook))
+                .collect();
+            if !hooks.is_empty() {
+                println!("  hooks: {}", hooks.join(", "));
+            }
+            if !self.config.script_commands {
+                println!("  run(): disabled on this machine (script_commands = false)");
+            }
+        } else {
+            println!("No script at {}", path.display());
+        }
+        let engine = Engine::new();
+        let mut invalid = 0;
+        for (profile, profile_config) in &self.config.profiles {
+            for (key, entry) in &profile_config.files {
+                let Some(expression) = entry.if_script() else {
+                    continue;
+                };
+                if let Err(e) = engine.compile_expression(expression) {
+                    println!("profiles.{}.files.{:?}.if_script: {}", profile, key, e);
+                    invalid += 1;
+                }
+            }
+        }
+        if invalid > 0 {
+            anyhow::bail!("{} if_script expression(s) don't compile", invalid);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> TemplateContext {
+        TemplateContext::new("work", &ProfileConfig::default())
+    }
+
+    #[test]
+    fn eval_calls_script_functions() {
+        let script = Script::parse(
+            r#"fn greeting(name) { "hi " + name } fn is_work() { profile() == "work" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            script.eval(r#"greeting("bob")"#, &context()).unwrap(),
+            Value::Str("hi bob".to_string())
+        );
+        assert!(script.eval("is_work()", &context()).unwrap().truthy());
+    }
+
+    #[test]
+    fn hooks_are_found_by_name() {
+        let script = Script::parse("fn post_sync() { 1 } fn helper() { 2 }").unwrap();
+        assert!(script.defines("post_sync"));
+        assert!(!script.defines("pre_sync"));
+        assert_eq!(script.functions(), vec!["helper", "post_sync"]);
+        assert_eq!(script.call("post_sync", &context()).unwrap(), Value::Int(1));
+    }
+
+    #[test]
+    fn top_level_statements_never_run() {
+        let script = Script::parse(r#"throw "ran"; fn ok() { true }"#).unwrap();
+        assert_eq!(script.eval("ok()", &context()).unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn run_needs_script_commands() {
+        let script = Script::parse(r#"fn hello() { run("echo", ["hello"]) }"#).unwrap();
+        let error = script.call("hello", &context()).unwrap_err();
+        assert!(error.to_string().contains("script_commands"));
+        if cfg!(unix) {
+            let script = script.with_commands(true);
+            assert_eq!(
+                script.call("hello", &context()).unwrap(),
+                Value::Str("hello".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped() {
+        let script = Script::parse("fn spin() { loop {} } fn down(n) { down(n + 1) }").unwrap();
+        assert!(script.call("spin", &context()).is_err());
+        assert!(script.eval("down(0)", &context()).is_err());
+    }
+
+    #[test]
+    fn syntax_errors_name_the_line() {
+        let error = Script::parse("fn ok() { 1 }\nfn broken( {").unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{}", error);
+    }
+}
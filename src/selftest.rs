@@ -0,0 +1,240 @@
+//! `dotty selftest`: a full add/sync/pull/conflict cycle between two
+//! throwaway machines and a local bare repository.
+
+use crate::error::ErrorKind;
+use anyhow::{Context, Result};
+use colored::*;
+use git2::Repository;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+const FILE_NAME: &str = ".selftestrc";
+
+type Step<'a> = (&'static str, Box<dyn Fn() -> Result<()> + 'a>);
+
+// A home directory standing in for one machine. Every command runs the dotty
+// executable with HOME and the XDG directories pointed into it, so the real
+// config, clone, state and daemon are never touched.
+struct Machine {
+    home: PathBuf,
+}
+
+impl Machine {
+    fn new(root: &Path, name: &str) -> Result<Self> {
+        let home = root.join(name);
+        fs::create_dir_all(&home).context("Failed to create test home directory")?;
+        // libgit2 needs an identity to commit with
+        fs::write(
+            home.join(".gitconfig"),
+            format!(
+                "[user]\n\tname = dotty selftest ({})\n\temail = selftest@localhost\n",
+                name
+            ),
+        )
+        .context("Failed to write test git config")?;
+        Ok(Machine { home })
+    }
+
+    fn file(&self) -> PathBuf {
+        self.home.join(FILE_NAME)
+    }
+
+    fn write(&self, content: &str) -> Result<()> {
+        fs::write(self.file(), content).context("Failed to write test file")
+    }
+
+    fn expect(&self, content: &str) -> Result<()> {
+        let actual = fs::read_to_string(self.file()).context("Test file is missing")?;
+        if actual != content {
+            anyhow::bail!(
+                "expected {:?} in {}, found {:?}",
+                content,
+                FILE_NAME,
+                actual
+            );
+        }
+        Ok(())
+    }
+
+    fn dotty(&self, args: &[&str]) -> Result<()> {
+        self.dotty_exiting(args, 0)
+    }
+
+    // Runs dotty and checks its exit code
+    fn dotty_exiting(&self, args: &[&str], expected: i32) -> Result<()> {
+        let exe = env::current_exe().context("Failed to locate the dotty executable")?;
+        let mut command = process::Command::new(exe);
+        command
+            .args(args)
+            .env("HOME", &self.home)
+            .env("XDG_CONFIG_HOME", self.home.join(".config"))
+            .env("XDG_DATA_HOME", self.home.join(".local/share"))
+            .env("XDG_CACHE_HOME", self.home.join(".cache"))
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env_remove("GIT_CONFIG_GLOBAL")
+            .stdin(process::Stdio::null());
+        for (name, _) in env::vars_os() {
+            if name.to_string_lossy().starts_with("DOTTY_") {
+                command.env_remove(name);
+            }
+        }
+        let output = command.output().context("Failed to run dotty")?;
+        if output.status.code() != Some(expected) {
+            anyhow::bail!(
+                "`dotty {}` exited with {} instead of {}: {}",
+                args.join(" "),
+                output.status,
+                expected,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn expect_remote(remote: &Path, content: &str) -> Result<()> {
+    let repo = Repository::open_bare(remote).context("Failed to open test repository")?;
+    let blob = repo
+        .revparse_single(&format!("master:{}", FILE_NAME))
+        .context("The file wasn't pushed")?
+        .peel_to_blob()?;
+    if blob.content() != content.as_bytes() {
+        anyhow::bail!(
+            "expected {:?} in the repository, found {:?}",
+            content,
+            String::from_utf8_lossy(blob.content())
+        );
+    }
+    Ok(())
+}
+
+/// Runs the self-test in a temporary directory and prints each step's result.
+/// The directory is removed afterwards unless `keep` is set or a step failed.
+pub fn selftest(keep: bool) -> Result<()> {
+    if !cfg!(unix) {
+        anyhow::bail!("The self-test needs HOME-based config directories, i.e. Linux or macOS");
+    }
+    let root = env::temp_dir().join(format!("dotty-selftest-{}", process::id()));
+    if root.exists() {
+        fs::remove_dir_all(&root).context("Failed to clear the test directory")?;
+    }
+    fs::create_dir_all(&root).context("Failed to create the test directory")?;
+    println!("Running the self-test in {}", root.display());
+
+    let remote = root.join("remote.git");
+    let remote_url = remote.to_string_lossy().into_owned();
+    let a = Machine::new(&root, "a")?;
+    let b = Machine::new(&root, "b")?;
+    let a_file = a.file().to_string_lossy().into_owned();
+
+    let steps: Vec<Step> = vec![
+        (
+            "Create a bare repository",
+            Box::new(|| {
+                Repository::init_bare(&remote).context("Failed to create repository")?;
+                Ok(())
+            }),
+        ),
+        (
+            "Configure machine A",
+            Box::new(|| a.dotty(&["remote", "set", &remote_url, "--auth", "ssh"])),
+        ),
+        (
+            "Add a file on machine A",
+            Box::new(|| {
+                a.write("one\n")?;
+                a.dotty(&["add", &a_file])
+            }),
+        ),
+        (
+            "Sync machine A",
+            Box::new(|| {
+                a.dotty(&["sync"])?;
+                expect_remote(&remote, "one\n")
+            }),
+        ),
+        (
+            "Set up machine B from the repository",
+            Box::new(|| {
+                b.dotty(&["apply", "--repo", &remote_url])?;
+                b.dotty(&["remote", "set", &remote_url, "--auth", "ssh"])?;
+                b.expect("one\n")
+            }),
+        ),
+        (
+            "Modify and sync on machine A",
+            Box::new(|| {
+                a.write("two\n")?;
+                a.dotty(&["sync"])?;
+                expect_remote(&remote, "two\n")
+            }),
+        ),
+        (
+            "Pull on machine B",
+            Box::new(|| {
+                b.dotty(&["pull"])?;
+                b.expect("two\n")
+            }),
+        ),
+        (
+            "Detect conflicting edits",
+            Box::new(|| {
+                a.write("three from A\n")?;
+                a.dotty(&["sync"])?;
+                b.write("three from B\n")?;
+                // The conflicting file is skipped, which is a partial sync
+                b.dotty_exiting(&["pull"], ErrorKind::PartialSync.exit_code())?;
+                // The local edit stays and upstream's is set aside
+                b.expect("three from B\n")?;
+                let conflict_file = b.home.join(format!("{}.conflict", FILE_NAME));
+                if !conflict_file.exists() {
+                    anyhow::bail!("no conflict file was written");
+                }
+                Ok(())
+            }),
+        ),
+        (
+            "Sync the resolution from machine B to A",
+            Box::new(|| {
+                b.dotty(&["sync"])?;
+                a.dotty(&["pull"])?;
+                a.expect("three from B\n")
+            }),
+        ),
+    ];
+
+    let total = steps.len();
+    let mut passed = 0;
+    for (name, step) in &steps {
+        match step() {
+            Ok(()) => {
+                println!("{} {}", "✓".green(), name);
+                passed += 1;
+            }
+            Err(e) => {
+                println!("{} {}: {:#}", "✗".red(), name, e);
+                break;
+            }
+        }
+    }
+    // Later steps depend on earlier ones, so the rest are skipped
+    for (name, _) in steps.iter().skip(passed + 1) {
+        println!("{} {} (skipped)", "-".yellow(), name);
+    }
+
+    if passed < total {
+        println!("Test files kept in {}", root.display());
+        anyhow::bail!(
+            "The self-test failed ({} of {} steps passed)",
+            passed,
+            total
+        );
+    }
+    if keep {
+        println!("Test files kept in {}", root.display());
+    } else {
+        fs::remove_dir_all(&root).context("Failed to remove the test directory")?;
+    }
+    println!("{}", format!("All {} steps passed", total).green());
+    Ok(())
+}
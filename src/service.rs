@@ -0,0 +1,231 @@
+//! Running dotty as a system service (systemd, launchd or Task
+//! Scheduler).
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::path::PathBuf;
+use std::{env, fs, process};
+
+pub(crate) const SERVICE_NAME: &str = "dotty";
+
+#[cfg(target_os = "macos")]
+pub(crate) const LAUNCHD_LABEL: &str = "com.github.crazywolf132.dotty";
+
+/// Arguments the service runs dotty with
+pub fn service_args(schedule: Option<String>, profile: Option<String>) -> Vec<String> {
+    let mut args = match schedule {
+        Some(schedule) => vec!["schedule".to_string(), "--cron".to_string(), schedule],
+        None => vec!["daemon".to_string()],
+    };
+    if let Some(profile) = profile {
+        args.push("--profile".to_string());
+        args.push(profile);
+    }
+    args
+}
+
+pub(crate) fn quote_service_args(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            if arg.contains(' ') {
+                format!("\"{}\"", arg)
+            } else {
+                arg.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn run_service_command(program: &str, args: &[&str]) -> Result<()> {
+    let status = process::Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !status.success() {
+        anyhow::bail!("{} {} exited with {}", program, args.join(" "), status);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn systemd_unit_path() -> Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .context("Failed to get config directory")?
+        .join("systemd/user")
+        .join(format!("{}.service", SERVICE_NAME)))
+}
+
+/// Installs and starts the service for the current user
+#[cfg(target_os = "linux")]
+pub fn install_service(args: Vec<String>) -> Result<()> {
+    let exe = env::current_exe().context("Failed to locate the dotty executable")?;
+    let unit = format!(
+        "[Unit]\nDescription=Dotty dotfile synchronization\nAfter=network-online.target\n\n\
+         [Service]\nExecStart={} {}\nRestart=on-failure\nRestartSec=30\n\n\
+         [Install]\nWantedBy=default.target\n",
+        exe.display(),
+        quote_service_args(&args)
+    );
+    let unit_path = systemd_unit_path()?;
+    fs::create_dir_all(unit_path.parent().unwrap())
+        .context("Failed to create systemd user directory")?;
+    fs::write(&unit_path, unit).context("Failed to write systemd unit")?;
+    run_service_command("systemctl", &["--user", "daemon-reload"])?;
+    run_service_command("systemctl", &["--user", "enable", "--now", SERVICE_NAME])?;
+    info!("Installed systemd user unit: {:?}", unit_path);
+    Ok(())
+}
+
+/// Stops and removes the service
+#[cfg(target_os = "linux")]
+pub fn uninstall_service() -> Result<()> {
+    let unit_path = systemd_unit_path()?;
+    if !unit_path.exists() {
+        warn!("Service is not installed");
+        return Ok(());
+    }
+    run_service_command("systemctl", &["--user", "disable", "--now", SERVICE_NAME])?;
+    fs::remove_file(&unit_path).context("Failed to remove systemd unit")?;
+    run_service_command("systemctl", &["--user", "daemon-reload"])?;
+    info!("Removed systemd user unit: {:?}", unit_path);
+    Ok(())
+}
+
+/// Prints whether the service is installed and running
+#[cfg(target_os = "linux")]
+pub fn service_status() -> Result<()> {
+    if !systemd_unit_path()?.exists() {
+        println!("Service is not installed");
+        return Ok(());
+    }
+    // systemctl exits non-zero for inactive units, which is still a valid answer
+    let _ = process::Command::new("systemctl")
+        .args(["--user", "status", SERVICE_NAME])
+        .status()
+        .context("Failed to run systemctl")?;
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn launchd_plist_path() -> Result<PathBuf> {
+    Ok(dirs::home_dir()
+        .context("Failed to get home directory")?
+        .join("Library/LaunchAgents")
+        .join(format!("{}.plist", LAUNCHD_LABEL)))
+}
+
+/// Installs and starts the service for the current user
+#[cfg(target_os = "macos")]
+pub fn install_service(args: Vec<String>) -> Result<()> {
+    let exe = env::current_exe().context("Failed to locate the dotty executable")?;
+    let program_arguments: String = std::iter::once(exe.display().to_string())
+        .chain(args)
+        .map(|arg| format!("        <string>{}</string>\n", arg))
+        .collect();
+    let plist = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n<dict>\n\
+         \x20   <key>Label</key>\n    <string>{}</string>\n\
+         \x20   <key>ProgramArguments</key>\n    <array>\n{}    </array>\n\
+         \x20   <key>RunAtLoad</key>\n    <true/>\n\
+         \x20   <key>KeepAlive</key>\n    <true/>\n\
+         </dict>\n</plist>\n",
+        LAUNCHD_LABEL, program_arguments
+    );
+    let plist_path = launchd_plist_path()?;
+    fs::create_dir_all(plist_path.parent().unwrap())
+        .context("Failed to create LaunchAgents directory")?;
+    fs::write(&plist_path, plist).context("Failed to write launchd plist")?;
+    run_service_command("launchctl", &["load", "-w", &plist_path.to_string_lossy()])?;
+    info!("Installed launchd agent: {:?}", plist_path);
+    Ok(())
+}
+
+/// Stops and removes the service
+#[cfg(target_os = "macos")]
+pub fn uninstall_service() -> Result<()> {
+    let plist_path = launchd_plist_path()?;
+    if !plist_path.exists() {
+        warn!("Service is not installed");
+        return Ok(());
+    }
+    run_service_command(
+        "launchctl",
+        &["unload", "-w", &plist_path.to_string_lossy()],
+    )?;
+    fs::remove_file(&plist_path).context("Failed to remove launchd plist")?;
+    info!("Removed launchd agent: {:?}", plist_path);
+    Ok(())
+}
+
+/// Prints whether the service is installed and running
+#[cfg(target_os = "macos")]
+pub fn service_status() -> Result<()> {
+    if !launchd_plist_path()?.exists() {
+        println!("Service is not installed");
+        return Ok(());
+    }
+    let _ = process::Command::new("launchctl")
+        .args(["list", LAUNCHD_LABEL])
+        .status()
+        .context("Failed to run launchctl")?;
+    Ok(())
+}
+
+/// Installs and starts the service for the current user
+#[cfg(windows)]
+pub fn install_service(args: Vec<String>) -> Result<()> {
+    let exe = env::current_exe().context("Failed to locate the dotty executable")?;
+    let task = format!("\"{}\" {}", exe.display(), quote_service_args(&args));
+    run_service_command(
+        "schtasks",
+        &[
+            "/Create",
+            "/F",
+            "/TN",
+            SERVICE_NAME,
+            "/TR",
+            &task,
+            "/SC",
+            "ONLOGON",
+        ],
+    )?;
+    run_service_command("schtasks", &["/Run", "/TN", SERVICE_NAME])?;
+    info!("Installed scheduled task: {}", SERVICE_NAME);
+    Ok(())
+}
+
+/// Stops and removes the service
+#[cfg(windows)]
+pub fn uninstall_service() -> Result<()> {
+    run_service_command("schtasks", &["/End", "/TN", SERVICE_NAME]).ok();
+    run_service_command("schtasks", &["/Delete", "/F", "/TN", SERVICE_NAME])?;
+    info!("Removed scheduled task: {}", SERVICE_NAME);
+    Ok(())
+}
+
+/// Prints whether the service is installed and running
+#[cfg(windows)]
+pub fn service_status() -> Result<()> {
+    run_service_command("schtasks", &["/Query", "/TN", SERVICE_NAME])
+}
+
+/// Installs and starts the service for the current user
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn install_service(_args: Vec<String>) -> Result<()> {
+    anyhow::bail!("Service installation is not supported on this platform")
+}
+
+/// Stops and removes the service
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn uninstall_service() -> Result<()> {
+    anyhow::bail!("Service installation is not supported on this platform")
+}
+
+/// Prints whether the service is installed and running
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn service_status() -> Result<()> {
+    anyhow::bail!("Service installation is not supported on this platform")
+}
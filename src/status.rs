@@ -0,0 +1,1568 @@
+//! Reporting on tracked files: status, diffs, the dashboard, history
+//! logs and lookups.
+
+use crate::config::{overlay_suffixes, Config, FileEntry, ProfileConfig};
+use crate::daemon::is_editor_temp_file;
+use crate::error::profile_not_found;
+use crate::repo::Machine;
+use crate::sync::{
+    AuditEntry, Conflict, Conflicts, FreezeState, JournalEntry, Manifest, PinState, SyncTrigger,
+    Take,
+};
+use crate::template::is_template;
+use crate::util::{
+    dir_size, file_mode, format_age, format_bytes, format_unix_time, hash_path, hex, host_name,
+    is_same_file, ownership_mismatch, path_key, state_dir, unix_seconds,
+};
+use crate::Dotty;
+use anyhow::{Context, Result};
+use colored::*;
+use ignore::WalkBuilder;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{env, fs};
+
+impl Dotty {
+    /// Prints every tracked file's state
+    pub fn status(&self) -> Result<()> {
+        println!("Profile: {}", self.current_profile);
+        if let Some(freeze) = FreezeState::load()? {
+            println!("{}", freeze.describe().yellow());
+        }
+        if let Some(pin) = PinState::load()? {
+            println!(
+                "{}",
+                format!("Pinned to {} ({})", pin.revision, &pin.commit[..7]).yellow()
+            );
+        }
+
+        // A template's destination is compared with its rendering
+        let changed: Vec<FileStatus> = self
+            .file_statuses(&self.current_profile)?
+            .into_iter()
+            .filter(|status| status.state == FileState::Changed)
+            .collect();
+        if !changed.is_empty() {
+            println!("Destinations that differ from their source (see `dotty diff`):");
+            for status in &changed {
+                println!("  {} {}", "changed:".yellow(), status.key);
+            }
+        }
+
+        let conflicts = Conflicts::load()?;
+        if conflicts.conflicts.is_empty() {
+            println!("No unresolved conflicts");
+            return Ok(());
+        }
+        println!("Unresolved conflicts (run `dotty resolve <file> --take local|remote|edit`):");
+        for conflict in &conflicts.conflicts {
+            println!(
+                "  {} {} (profile {}, since {}){}",
+                "conflict:".red(),
+                conflict.path,
+                conflict.profile,
+                format_unix_time(conflict.detected_at),
+                conflict
+                    .conflict_file
+                    .as_ref()
+                    .map_or(String::new(), |path| format!(
+                        ", markers in {}",
+                        path.display()
+                    ))
+            );
+        }
+        Ok(())
+    }
+
+    /// Every tracked destination under $HOME (and / for system files),
+    /// annotated with its profile, mode and status
+    pub fn tree(&self, profile: Option<String>) -> Result<()> {
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let mut profiles: Vec<String> = match profile {
+            Some(profile) => vec![profile],
+            None => self.config.profiles.keys().cloned().collect(),
+        };
+        profiles.sort();
+
+        let (mut home_tree, mut system_tree) = (PathTree::default(), PathTree::default());
+        for profile in &profiles {
+            let profile_config = self
+                .config
+                .profiles
+                .get(profile)
+                .with_context(|| profile_not_found(profile))?;
+            for status in self.file_statuses(profile)? {
+                let mode = profile_config.files[&status.key]
+                    .mode()
+                    .unwrap_or(profile_config.link_mode());
+                let annotation =
+                    format!("{}: {}, {}", profile, mode.name(), status.state.label().0)
+                        .color(status.state.color())
+                        .to_string();
+                match status.dest.strip_prefix(&home) {
+                    Ok(relative) => home_tree.insert(relative, annotation),
+                    Err(_) => system_tree.insert(
+                        status.dest.strip_prefix("/").unwrap_or(&status.dest),
+                        annotation,
+                    ),
+                }
+            }
+        }
+
+        if home_tree.children.is_empty() && system_tree.children.is_empty() {
+            println!("No tracked files");
+            return Ok(());
+        }
+        if !home_tree.children.is_empty() {
+            println!("{}", "~".blue().bold());
+            home_tree.print("");
+        }
+        if !system_tree.children.is_empty() {
+            println!("{}", "/".blue().bold());
+            system_tree.print("");
+        }
+        Ok(())
+    }
+
+    // Where each file of a profile stands, for the dashboard
+    pub(crate) fn file_statuses(&self, profile: &str) -> Result<Vec<FileStatus>> {
+        let profile_config = self
+            .config
+            .profiles
+            .get(profile)
+            .with_context(|| profile_not_found(profile))?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let repo_path = self.repo_path()?;
+        let conflicts = Conflicts::load()?;
+        let mut statuses: Vec<FileStatus> = profile_config
+            .files
+            .iter()
+            .map(|(key, entry)| {
+                let source = PathBuf::from(entry.source());
+                let dest = profile_config.destination(&home, key);
+                let repo_copy = repo_path.join(
+                    self.config
+                        .overlay_key(profile, key, |path| repo_path.join(path).exists()),
+                );
+                let hash = |path: &Path| hash_path(path).ok().flatten();
+                // A template's destination holds its rendering
+                let installed = match self.rendered(&source, profile_config) {
+                    Ok(Some(rendered)) => Some(hex(&openssl::sha::sha256(&rendered))),
+                    _ => hash(&source),
+                };
+                let state = if conflicts.contains(profile, key) {
+                    FileState::Conflict
+                } else if self.unmet_condition(profile, entry).is_some() {
+                    FileState::Inactive
+                } else if !source.exists() {
+                    FileState::Missing
+                } else if !dest.exists() {
+                    FileState::NotInstalled
+                } else if installed != hash(&dest) {
+                    FileState::Changed
+                } else if hash(&source) != hash(&repo_copy) {
+                    FileState::Unpushed
+                } else {
+                    FileState::Synced
+                };
+                FileStatus {
+                    key: key.clone(),
+                    source,
+                    dest,
+                    repo_copy,
+                    state,
+                }
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(statuses)
+    }
+
+    /// Hashing every file is too slow for a prompt, so the counts are cached
+    /// for a short while and recomputed once the config or journal changes
+    pub fn prompt_info(&self, format: Option<&str>, refresh: bool) -> Result<String> {
+        let profile = &self.current_profile;
+        let now = unix_seconds(SystemTime::now());
+        let modified = |path: &Path| {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .map(unix_seconds)
+                .unwrap_or(0)
+        };
+        let changed_since = |computed_at: u64| {
+            [
+                self.config_path.clone(),
+                JournalEntry::path()?,
+                Conflicts::path()?,
+            ]
+            .iter()
+            .try_fold(false, |changed, path| {
+                Ok::<_, anyhow::Error>(changed || modified(path) >= computed_at)
+            })
+        };
+
+        let cache = match PromptCache::load()? {
+            Some(cache)
+                if !refresh
+                    && cache.profile == *profile
+                    && now.saturating_sub(cache.computed_at) < PROMPT_CACHE_SECONDS
+                    && !changed_since(cache.computed_at)? =>
+            {
+                cache
+            }
+            _ => {
+                let statuses = self.file_statuses(profile)?;
+                let count = |states: &[FileState]| {
+                    statuses
+                        .iter()
+                        .filter(|status| states.contains(&status.state))
+                        .count()
+                };
+                let last_sync = JournalEntry::load_all()?
+                    .into_iter()
+                    .filter(|entry| entry.profile == *profile && entry.error.is_none())
+                    .map(|entry| entry.timestamp)
+                    .max();
+                let cache = PromptCache {
+                    profile: profile.clone(),
+                    dirty: count(&[FileState::Changed, FileState::Unpushed]),
+                    conflicts: count(&[FileState::Conflict]),
+                    last_sync,
+                    computed_at: now,
+                };
+                cache.save()?;
+                cache
+            }
+        };
+
+        // The age is worked out on every call so it never goes stale
+        let age = match cache.last_sync {
+            Some(last_sync) => format_age(now.saturating_sub(last_sync)),
+            None => "never".to_string(),
+        };
+        Ok(match format {
+            Some(format) => format
+                .replace("{profile}", profile)
+                .replace("{dirty}", &cache.dirty.to_string())
+                .replace("{conflicts}", &cache.conflicts.to_string())
+                .replace("{age}", &age),
+            None => {
+                let mut line = profile.clone();
+                if cache.dirty > 0 {
+                    line.push_str(&format!(" ~{}", cache.dirty));
+                }
+                if cache.conflicts > 0 {
+                    line.push_str(&format!(" !{}", cache.conflicts));
+                }
+                line.push(' ');
+                line.push_str(&age);
+                line
+            }
+        })
+    }
+
+    /// Opens the full-screen status dashboard
+    pub fn dashboard(&mut self) -> Result<()> {
+        use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+
+        if !io::stdout().is_terminal() {
+            anyhow::bail!("dotty ui needs a terminal");
+        }
+        let mut profiles: Vec<String> = self.config.profiles.keys().cloned().collect();
+        profiles.sort();
+        let mut state = DashboardState {
+            profile: self.current_profile.clone(),
+            files: self.file_statuses(&self.current_profile)?,
+            selected: ratatui::widgets::ListState::default().with_selected(Some(0)),
+            marked: HashSet::new(),
+            conflicts: Conflicts::load()?.conflicts,
+            history: JournalEntry::load_all()?,
+            message: String::new(),
+            resolving: false,
+        };
+
+        let mut terminal = ratatui::init();
+        let result = loop {
+            if let Err(e) = terminal.draw(|frame| draw_dashboard(frame, &mut state)) {
+                break Err(e.into());
+            }
+            let key = match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+                Ok(_) => continue,
+                Err(e) => break Err(e.into()),
+            };
+            let current = state
+                .selected
+                .selected()
+                .and_then(|index| state.files.get(index))
+                .map(|file| file.key.clone());
+
+            if state.resolving {
+                state.resolving = false;
+                let take = match key.code {
+                    KeyCode::Char('l') => Take::Local,
+                    KeyCode::Char('r') => Take::Remote,
+                    KeyCode::Char('e') => Take::Edit,
+                    _ => {
+                        state.message = "Resolve cancelled".to_string();
+                        continue;
+                    }
+                };
+                let Some(key) = current else { continue };
+                let dest = state.files[state.selected.selected().unwrap()].dest.clone();
+                let profile = state.profile.clone();
+                ratatui::restore();
+                let outcome = self.resolve(&dest, take, Some(profile));
+                terminal = ratatui::init();
+                state.message = match outcome {
+                    Ok(()) => format!("Resolved {}", key),
+                    Err(e) => format!("Failed to resolve {}: {:#}", key, e),
+                };
+            } else {
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => break Ok(()),
+                    KeyCode::Down | KeyCode::Char('j') => state.selected.select_next(),
+                    KeyCode::Up | KeyCode::Char('k') => state.selected.select_previous(),
+                    KeyCode::Char(' ') => {
+                        if let Some(key) = current {
+                            if !state.marked.remove(&key) {
+                                state.marked.insert(key);
+                            }
+                        }
+                        state.selected.select_next();
+                        continue;
+                    }
+                    KeyCode::Tab | KeyCode::Char('p') => {
+                        let index = profiles
+                            .iter()
+                            .position(|profile| *profile == state.profile)
+                            .map_or(0, |index| (index + 1) % profiles.len());
+                        state.profile = profiles[index].clone();
+                        state.marked.clear();
+                        state.selected.select(Some(0));
+                        state.message = format!("Switched to profile {}", state.profile);
+                    }
+                    KeyCode::Char('s') | KeyCode::Char('a') => {
+                        let files: Option<HashSet<String>> = match key.code {
+                            KeyCode::Char('a') => None,
+                            _ if !state.marked.is_empty() => Some(state.marked.clone()),
+                            _ => current.map(|key| HashSet::from([key])),
+                        };
+                        let profile = state.profile.clone();
+                        // Sync prints its progress, so hand the terminal back
+                        ratatui::restore();
+                        let outcome = self.sync(Some(profile), files.as_ref(), SyncTrigger::Manual);
+                        terminal = ratatui::init();
+                        state.message = match outcome {
+                            Ok(()) => format!(
+                                "Synced {}",
+                                files.map_or("the profile".to_string(), |files| format!(
+                                    "{} file(s)",
+                                    files.len()
+                                ))
+                            ),
+                            Err(e) => format!("Sync failed: {:#}", e),
+                        };
+                        state.marked.clear();
+                    }
+                    KeyCode::Char('r') => {
+                        let conflicted = state
+                            .selected
+                            .selected()
+                            .and_then(|index| state.files.get(index))
+                            .is_some_and(|file| file.state == FileState::Conflict);
+                        if conflicted {
+                            state.resolving = true;
+                            state.message =
+                                "Take [l]ocal, [r]emote, or [e]dit the conflict file?".to_string();
+                        } else {
+                            state.message = "The selected file has no conflict".to_string();
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                }
+            }
+            match self.file_statuses(&state.profile) {
+                Ok(files) => state.files = files,
+                Err(e) => state.message = format!("{:#}", e),
+            }
+            state.conflicts = Conflicts::load().map(|c| c.conflicts).unwrap_or_default();
+            state.history = JournalEntry::load_all().unwrap_or_default();
+        };
+        ratatui::restore();
+        result
+    }
+
+    /// Checks destinations against the signed manifest of the last sync
+    pub fn verify(&self, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let manifest = Manifest::load(&profile)?.with_context(|| {
+            format!(
+                "No manifest for profile {}; run `dotty sync` first",
+                profile
+            )
+        })?;
+        if !manifest.signature_valid()? {
+            anyhow::bail!("Manifest signature is invalid; the manifest has been modified");
+        }
+
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let profile_config = self.config.profiles.get(&profile);
+        let mut problems = 0;
+        let mut relative_paths: Vec<&String> = manifest.entries.keys().collect();
+        relative_paths.sort();
+        for relative_path in relative_paths {
+            let expected = &manifest.entries[relative_path];
+            let dest = match profile_config {
+                Some(profile_config) => profile_config.destination(&home, relative_path),
+                None => home.join(relative_path),
+            };
+            let (owner, group) = profile_config
+                .and_then(|profile_config| profile_config.files.get(relative_path))
+                .map_or((None, None), FileEntry::ownership);
+            let ownership = ownership_mismatch(&dest, owner, group)?;
+            let status = match hash_path(&dest)? {
+                None => Some("missing".red()),
+                Some(hash) if hash != expected.hash => Some("drifted".yellow()),
+                Some(_) if ownership.is_some() => ownership.map(|ownership| ownership.yellow()),
+                Some(_) if expected.mode.is_some() && file_mode(&dest) != expected.mode => Some(
+                    format!(
+                        "permissions {:o}, expected {:o}",
+                        file_mode(&dest).unwrap_or_default(),
+                        expected.mode.unwrap_or_default()
+                    )
+                    .yellow(),
+                ),
+                Some(_) => None,
+            };
+            match status {
+                Some(status) => {
+                    problems += 1;
+                    println!("{}: {}", relative_path, status);
+                }
+                None => println!("{}: {}", relative_path, "ok".green()),
+            }
+        }
+
+        println!(
+            "Manifest generated {} for profile {}",
+            format_unix_time(manifest.generated_at),
+            profile
+        );
+        if problems > 0 {
+            anyhow::bail!("{} file(s) failed verification", problems);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn show_diff(&self, profile: &str, files: Option<&HashSet<String>>) -> Result<()> {
+        let profile_config = self
+            .config
+            .profiles
+            .get(profile)
+            .with_context(|| profile_not_found(profile))?;
+        if self.porcelain {
+            return Ok(());
+        }
+
+        for (relative_path, canonical_path) in profile_config.sources() {
+            if files.is_some_and(|files| !files.contains(relative_path)) {
+                continue;
+            }
+            let source = Path::new(canonical_path);
+            let dest = profile_config.destination(
+                &dirs::home_dir().context("Failed to get home directory")?,
+                relative_path,
+            );
+
+            if source.is_file() && dest.is_file() {
+                let source_content = match self.rendered(source, profile_config)? {
+                    Some(rendered) => rendered,
+                    None => fs::read(source).context("Failed to read source file")?,
+                };
+                let dest_content = fs::read(&dest).context("Failed to read destination file")?;
+
+                // Binary files get a one-line summary rather than a diff
+                let (Ok(source_text), Ok(dest_text)) = (
+                    std::str::from_utf8(&source_content),
+                    std::str::from_utf8(&dest_content),
+                ) else {
+                    if source_content != dest_content {
+                        println!("Diff for {}:", relative_path);
+                        println!("Binary files differ");
+                        println!();
+                    }
+                    continue;
+                };
+
+                // Compare lines without their endings so a CRLF copy of an LF
+                // file doesn't show up as entirely changed
+                let dest_content = dest_text.replace("\r\n", "\n");
+                let source_content = source_text.replace("\r\n", "\n");
+                let diff = TextDiff::from_lines(&dest_content, &source_content);
+
+                println!("Diff for {}:", relative_path);
+                print_text_diff(&diff);
+                println!();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Diffs the given tracked paths (all when empty): sources against their
+    /// destinations, or with `remote`, against the remote branch tip
+    pub fn diff(&self, paths: &[PathBuf], remote: bool, profile: Option<String>) -> Result<()> {
+        let files = paths
+            .iter()
+            .map(|path| self.tracked_key(path))
+            .collect::<Result<HashSet<String>>>()?;
+        let files = (!files.is_empty()).then_some(&files);
+        if remote {
+            return self.diff_remote(files, profile);
+        }
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        self.show_diff(&profile, files)
+    }
+
+    /// Shows what pulling would change: fetches the remote and diffs each
+    /// tracked entry on this machine against the remote branch tip, the local
+    /// side first. Copied destinations that differ from their source are
+    /// compared too. `files` limits it to those keys.
+    pub fn diff_remote(
+        &self,
+        files: Option<&HashSet<String>>,
+        profile: Option<String>,
+    ) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let repo = self.open_repo()?;
+        self.fetch_remote(&repo)?;
+        let branch = self.branch();
+        let Ok(remote) = repo.refname_to_id(&format!("refs/remotes/origin/{}", branch)) else {
+            println!("origin/{} doesn't exist yet", branch);
+            return Ok(());
+        };
+        let tree = repo.find_commit(remote)?.tree()?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+
+        let mut keys: Vec<&String> = profile_config
+            .files
+            .keys()
+            .filter(|key| files.is_none_or(|files| files.contains(*key)))
+            .collect();
+        keys.sort();
+        let mut differing = 0;
+        for key in keys {
+            let upstream = match self.config.tree_entry(&tree, &profile, key) {
+                Some(entry) => tree_files(&repo, &entry)?,
+                None => BTreeMap::new(),
+            };
+            let source = Path::new(profile_config.files[key].source());
+            let normalize = profile_config.line_ending(key).is_some();
+            let local = self.local_files(source, profile_config, normalize)?;
+            let mut sides = vec![(source.to_path_buf(), local)];
+            let dest = profile_config.destination(&home, key);
+            if dest.exists()
+                && !dest.is_symlink()
+                && !is_same_file(source, &dest)
+                && !is_template(source)
+            {
+                let copied = self.local_files(&dest, profile_config, normalize)?;
+                if copied != sides[0].1 {
+                    sides.push((dest, copied));
+                }
+            }
+
+            for (root, local) in &sides {
+                let names: BTreeSet<&String> = local.keys().chain(upstream.keys()).collect();
+                for name in names {
+                    let (ours, theirs) = (local.get(name), upstream.get(name));
+                    if ours == theirs {
+                        continue;
+                    }
+                    differing += 1;
+                    let (local_path, remote_path) = if name.is_empty() {
+                        (root.clone(), key.clone())
+                    } else {
+                        (root.join(name), format!("{}/{}", key, name))
+                    };
+                    println!("{}", format!("--- {}", local_path.display()).bold());
+                    println!(
+                        "{}",
+                        format!("+++ origin/{}:{}", branch, remote_path).bold()
+                    );
+                    print_content_diff(ours.map(Vec::as_slice), theirs.map(Vec::as_slice));
+                    println!();
+                }
+            }
+        }
+        if differing == 0 {
+            println!(
+                "{}",
+                format!("Everything matches origin/{}", branch).green()
+            );
+        } else {
+            println!("{} file(s) differ from origin/{}", differing, branch);
+        }
+        Ok(())
+    }
+
+    // The files a source or destination would put in the repository, keyed by
+    // path below it ("" for a single file)
+    fn local_files(
+        &self,
+        root: &Path,
+        profile_config: &ProfileConfig,
+        normalize: bool,
+    ) -> Result<BTreeMap<String, Vec<u8>>> {
+        let read = |path: &Path| -> Result<Vec<u8>> {
+            let content =
+                fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            Ok(if normalize {
+                String::from_utf8_lossy(&content)
+                    .replace("\r\n", "\n")
+                    .into_bytes()
+            } else {
+                content
+            })
+        };
+        let mut files = BTreeMap::new();
+        if root.is_file() {
+            files.insert(String::new(), read(root)?);
+        } else if root.is_dir() {
+            let ignore_rules = self.ignore_rules(root, profile_config);
+            let walker = WalkBuilder::new(root)
+                .hidden(false)
+                .git_ignore(true)
+                .filter_entry(|entry| entry.file_name() != ".git")
+                .build();
+            for entry in walker.filter_map(|entry| entry.ok()) {
+                let path = entry.path();
+                if !path.is_file() || is_editor_temp_file(path) || ignore_rules.is_ignored(path) {
+                    continue;
+                }
+                if let Ok(relative) = path.strip_prefix(root) {
+                    files.insert(path_key(relative), read(path)?);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Prints the audit log, checking its hash chain
+    pub fn audit(&self, path: Option<&Path>) -> Result<()> {
+        let entries = AuditEntry::load_all()?;
+        match AuditEntry::verify_chain(&entries)? {
+            Ok(()) => println!(
+                "{} ({} entries)",
+                "Audit chain intact".green(),
+                entries.len()
+            ),
+            Err(seq) => println!(
+                "{}",
+                format!(
+                    "Audit chain broken at entry {}; the log has been tampered with",
+                    seq
+                )
+                .red()
+                .bold()
+            ),
+        }
+
+        let dest = match path {
+            Some(path) => {
+                let key = self.tracked_key(path)?;
+                let home = dirs::home_dir().context("Failed to get home directory")?;
+                Some(match self.config.profiles.get(&self.current_profile) {
+                    Some(profile_config) => profile_config.destination(&home, &key),
+                    None => home.join(key),
+                })
+            }
+            None => None,
+        };
+        for entry in &entries {
+            if dest
+                .as_ref()
+                .is_some_and(|dest| Path::new(&entry.path) != dest)
+            {
+                continue;
+            }
+            let short = |hash: &Option<String>| {
+                hash.as_deref()
+                    .map_or("(none)".to_string(), |hash| hash[..12].to_string())
+            };
+            println!(
+                "#{} {}  {}  {} -> {}  from {}  by {} ({})",
+                entry.seq,
+                format_unix_time(entry.timestamp),
+                entry.path.bold(),
+                short(&entry.old_hash),
+                short(&entry.new_hash),
+                entry.origin,
+                entry.user,
+                entry.command
+            );
+        }
+        Ok(())
+    }
+
+    /// Searches tracked files where they are installed (falling back to the
+    /// source), or as the repository stores them with `in_repo`
+    pub fn grep(
+        &self,
+        pattern: &str,
+        ignore_case: bool,
+        in_repo: bool,
+        profile: Option<String>,
+    ) -> Result<()> {
+        let regex = regex::RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .with_context(|| format!("Invalid pattern: {}", pattern))?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let repo_path = self.repo_path()?;
+        let mut profiles: Vec<&String> = match &profile {
+            Some(profile) => vec![
+                self.config
+                    .profiles
+                    .get_key_value(profile)
+                    .with_context(|| profile_not_found(profile))?
+                    .0,
+            ],
+            None => self.config.profiles.keys().collect(),
+        };
+        profiles.sort();
+
+        // Entries shared between profiles are searched once
+        let mut roots: BTreeMap<PathBuf, (String, &ProfileConfig)> = BTreeMap::new();
+        for profile in profiles {
+            let profile_config = &self.config.profiles[profile];
+            for (key, entry) in &profile_config.files {
+                let (root, label) = if in_repo {
+                    let repo_key = self.repo_copy_key(profile, key)?;
+                    (repo_path.join(&repo_key), repo_key)
+                } else {
+                    let dest = profile_config.destination(&home, key);
+                    let root = if dest.exists() {
+                        dest
+                    } else {
+                        PathBuf::from(entry.source())
+                    };
+                    let label = match root.strip_prefix(&home) {
+                        Ok(relative) => format!("~/{}", path_key(relative)),
+                        Err(_) => root.display().to_string(),
+                    };
+                    (root, label)
+                };
+                roots.entry(root).or_insert((label, profile_config));
+            }
+        }
+
+        let mut matches = 0;
+        for (root, (label, profile_config)) in &roots {
+            let files: Vec<(PathBuf, String)> = if root.is_dir() {
+                let rules = self.ignore_rules(root, profile_config);
+                let mut files: Vec<(PathBuf, String)> = WalkBuilder::new(root)
+                    .hidden(false)
+                    .git_ignore(false)
+                    .filter_entry(|entry| entry.file_name() != ".git")
+                    .build()
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.into_path())
+                    .filter(|path| path.is_file() && !rules.is_ignored(path))
+                    .filter_map(|path| {
+                        let relative = path.strip_prefix(root).ok()?;
+                        let label = format!("{}/{}", label, path_key(relative));
+                        Some((path, label))
+                    })
+                    .collect();
+                files.sort();
+                files
+            } else if root.is_file() {
+                vec![(root.clone(), label.clone())]
+            } else {
+                Vec::new()
+            };
+
+            for (path, label) in files {
+                let Ok(content) = fs::read(&path) else {
+                    warn!("Failed to read {}", path.display());
+                    continue;
+                };
+                // Like grep, don't print lines of binary files
+                if content.iter().take(8000).any(|&byte| byte == 0) {
+                    continue;
+                }
+                let content = String::from_utf8_lossy(&content);
+                for (number, line) in content.lines().enumerate() {
+                    if !regex.is_match(line) {
+                        continue;
+                    }
+                    matches += 1;
+                    let mut highlighted = String::new();
+                    let mut last = 0;
+                    for found in regex.find_iter(line) {
+                        highlighted.push_str(&line[last..found.start()]);
+                        highlighted.push_str(&found.as_str().red().bold().to_string());
+                        last = found.end();
+                    }
+                    highlighted.push_str(&line[last..]);
+                    println!(
+                        "{}:{}: {}",
+                        label.magenta(),
+                        (number + 1).to_string().green(),
+                        highlighted
+                    );
+                }
+            }
+        }
+        if matches == 0 {
+            println!("No tracked file matches {:?}", pattern);
+        }
+        Ok(())
+    }
+
+    /// Prints a tracked file as the repository has it: the copy written by the
+    /// last sync, or its content at `revision`
+    pub fn cat(&self, path: &Path, revision: Option<&str>, profile: Option<String>) -> Result<()> {
+        let key = self.tracked_key(path)?;
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        if !self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?
+            .files
+            .contains_key(&key)
+        {
+            anyhow::bail!("{} is not tracked in profile {}", key, profile);
+        }
+
+        let content = match revision {
+            Some(revision) => {
+                let repo = self.open_repo()?;
+                let commit = self.resolve_commit(&repo, revision)?;
+                let tree = commit.tree()?;
+                let object = self
+                    .config
+                    .tree_entry(&tree, &profile, &key)
+                    .with_context(|| format!("{} does not exist at {}", key, revision))?
+                    .to_object(&repo)
+                    .context("Failed to read repository object")?;
+                let blob = object
+                    .as_blob()
+                    .with_context(|| format!("{} is a directory", key))?;
+                blob.content().to_vec()
+            }
+            None => {
+                let repo_copy = self.repo_path()?.join(self.repo_copy_key(&profile, &key)?);
+                if repo_copy.is_dir() {
+                    anyhow::bail!("{} is a directory", key);
+                }
+                fs::read(&repo_copy).with_context(|| {
+                    format!("{} is not in the repository yet; run `dotty sync`", key)
+                })?
+            }
+        };
+        io::stdout()
+            .write_all(&content)
+            .context("Failed to write to stdout")?;
+        Ok(())
+    }
+
+    /// Reports which entries, in any profile, a path belongs to: as their
+    /// destination or source, or as a file inside a tracked directory
+    pub fn which(&self, path: &Path) -> Result<()> {
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        // Destinations may be symlinks to their sources, so compare the path
+        // both as given and resolved
+        let absolute = env::current_dir()
+            .context("Failed to get current directory")?
+            .join(path);
+        let resolved = absolute.canonicalize().ok();
+        let display = |path: &Path| match path.strip_prefix(&home) {
+            Ok(relative) => format!("~/{}", path_key(relative)),
+            Err(_) => path.display().to_string(),
+        };
+        let repo = self.open_repo().ok();
+
+        let mut profiles: Vec<&String> = self.config.profiles.keys().collect();
+        profiles.sort();
+        let mut found = false;
+        for profile in profiles {
+            let profile_config = &self.config.profiles[profile];
+            let mut keys: Vec<&String> = profile_config.files.keys().collect();
+            keys.sort();
+            for key in keys {
+                let entry = &profile_config.files[key];
+                let source = PathBuf::from(entry.source());
+                let dest = profile_config.destination(&home, key);
+                let inside = |root: &Path| {
+                    [Some(&absolute), resolved.as_ref()]
+                        .into_iter()
+                        .flatten()
+                        .find_map(|path| path.strip_prefix(root).ok().map(Path::to_path_buf))
+                };
+                let (role, relative) = match (inside(&dest), inside(&source)) {
+                    (Some(relative), _) => ("destination", relative),
+                    (None, Some(relative)) => ("source", relative),
+                    (None, None) => continue,
+                };
+                if found {
+                    println!();
+                }
+                found = true;
+
+                if relative.as_os_str().is_empty() {
+                    println!("{} is the {} of {}", display(&absolute), role, key.bold());
+                } else {
+                    println!(
+                        "{} is inside the {} of {}",
+                        display(&absolute),
+                        role,
+                        key.bold()
+                    );
+                    let rules = self.ignore_rules(&source, profile_config);
+                    if rules.is_ignored(&source.join(&relative)) {
+                        println!(
+                            "  {}",
+                            "ignored by the ignore patterns, so it is not synced".yellow()
+                        );
+                    }
+                }
+                println!("  profile:     {}", profile);
+                println!("  source:      {}", display(&source));
+                println!("  destination: {}", display(&dest));
+                println!(
+                    "  mode:        {}",
+                    entry.mode().unwrap_or(profile_config.link_mode()).name()
+                );
+                if let Some(status) = self
+                    .file_statuses(profile)?
+                    .into_iter()
+                    .find(|status| status.key == *key)
+                {
+                    println!(
+                        "  status:      {}",
+                        status.state.label().0.color(status.state.color())
+                    );
+                }
+                let repo_key = self.repo_copy_key(profile, key)?;
+                let last_change = match &repo {
+                    Some(repo) => self.last_change(repo, &repo_key)?,
+                    None => None,
+                };
+                match last_change {
+                    Some(commit) => println!(
+                        "  last synced: {} {} {}",
+                        &commit.id().to_string()[..7],
+                        format_unix_time(commit.time().seconds().max(0) as u64),
+                        commit.summary().unwrap_or_default()
+                    ),
+                    None => println!("  last synced: never"),
+                }
+            }
+        }
+        if !found {
+            println!("{} is not managed by dotty", display(&absolute));
+        }
+        Ok(())
+    }
+
+    /// A quick overview from local state only; nothing is fetched
+    pub fn stats(&self, limit: usize) -> Result<()> {
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let display = |path: &Path| match path.strip_prefix(&home) {
+            Ok(relative) => format!("~/{}", path_key(relative)),
+            Err(_) => path.display().to_string(),
+        };
+        let size = |path: &Path| {
+            if path.is_dir() {
+                dir_size(path)
+            } else {
+                fs::metadata(path)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0)
+            }
+        };
+
+        let mut profiles: Vec<&String> = self.config.profiles.keys().collect();
+        profiles.sort();
+        // Sources shared between profiles are only counted once in the total
+        let mut sources: BTreeMap<PathBuf, (u64, String)> = BTreeMap::new();
+        println!("{}", "Profiles".bold());
+        for profile in profiles {
+            let profile_config = &self.config.profiles[profile];
+            let mut bytes = 0;
+            for (_, source) in profile_config.sources() {
+                let source = PathBuf::from(source);
+                let source_size = size(&source);
+                bytes += source_size;
+                sources
+                    .entry(source)
+                    .or_insert((source_size, profile.clone()));
+            }
+            println!(
+                "  {:<16} {:>4} file(s)  {:>10}{}",
+                profile,
+                profile_config.files.len(),
+                format_bytes(bytes),
+                if *profile == self.current_profile {
+                    "  (active)"
+                } else {
+                    ""
+                }
+            );
+        }
+        println!(
+            "  {:<16} {:>4} file(s)  {:>10}",
+            "total",
+            sources.len(),
+            format_bytes(sources.values().map(|(size, _)| size).sum())
+        );
+
+        if !sources.is_empty() {
+            println!("\n{}", "Largest tracked files".bold());
+            let mut largest: Vec<(&PathBuf, &(u64, String))> = sources.iter().collect();
+            largest.sort_by_key(|(_, (size, _))| std::cmp::Reverse(*size));
+            for (source, (size, profile)) in largest.into_iter().take(limit) {
+                println!(
+                    "  {:>10}  {} ({})",
+                    format_bytes(*size),
+                    display(source),
+                    profile
+                );
+            }
+        }
+
+        let journal = JournalEntry::load_all()?;
+        let failed = journal.iter().filter(|entry| entry.error.is_some()).count();
+        println!("\n{}", "Syncs".bold());
+        match journal.iter().map(|entry| entry.timestamp).max() {
+            Some(last) => println!(
+                "  {} in the journal, {} failed, last at {}",
+                journal.len(),
+                failed,
+                format_unix_time(last)
+            ),
+            None => println!("  None recorded yet"),
+        }
+
+        let repo = match self.open_repo() {
+            Ok(repo) => repo,
+            Err(e) => {
+                println!("\n{} {:#}", "Repository unavailable:".yellow(), e);
+                return Ok(());
+            }
+        };
+        let mut machines: Vec<(String, Machine)> =
+            self.known_machines(&repo)?.machines.into_iter().collect();
+        machines.sort_unstable_by_key(|(_, machine)| std::cmp::Reverse(machine.last_sync));
+        println!("\n{}", "Last push per machine".bold());
+        if machines.is_empty() {
+            println!("  No machines have synced yet");
+        }
+        for (host, machine) in machines {
+            println!(
+                "  {:<24} {}{}",
+                host,
+                format_unix_time(machine.last_sync),
+                if host == host_name() {
+                    "  (this machine)"
+                } else {
+                    ""
+                }
+            );
+        }
+
+        let mut revwalk = repo.revwalk().context("Failed to walk history")?;
+        let commits = match revwalk.push_head() {
+            Ok(()) => revwalk.count(),
+            Err(_) => 0,
+        };
+        println!("\n{}", "Repository".bold());
+        println!(
+            "  {}  {} on disk, {} commit(s){}",
+            display(&self.repo_path()?),
+            format_bytes(dir_size(repo.path())),
+            commits,
+            if repo.is_shallow() { " (shallow)" } else { "" }
+        );
+        Ok(())
+    }
+}
+
+pub(crate) fn print_text_diff<'a>(diff: &TextDiff<'a, 'a, 'a, str>) {
+    for change in diff.iter_all_changes() {
+        let (sign, color) = match change.tag() {
+            ChangeTag::Delete => ("-", Color::Red),
+            ChangeTag::Insert => ("+", Color::Green),
+            ChangeTag::Equal => (" ", Color::White),
+        };
+        print!("{}", sign.color(color));
+        print!("{}", change.value().color(color));
+    }
+}
+
+// Blobs under a tree entry, keyed like Dotty::local_files
+fn tree_files(
+    repo: &git2::Repository,
+    entry: &git2::TreeEntry,
+) -> Result<BTreeMap<String, Vec<u8>>> {
+    let object = entry
+        .to_object(repo)
+        .context("Failed to read repository object")?;
+    let mut files = BTreeMap::new();
+    if let Some(blob) = object.as_blob() {
+        files.insert(String::new(), blob.content().to_vec());
+    } else if let Some(tree) = object.as_tree() {
+        tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+            if let Some(blob) = entry
+                .name()
+                .and_then(|_| entry.to_object(repo).ok())
+                .and_then(|object| object.into_blob().ok())
+            {
+                files.insert(
+                    format!("{}{}", dir, entry.name().unwrap_or_default()),
+                    blob.content().to_vec(),
+                );
+            }
+            git2::TreeWalkResult::Ok
+        })
+        .context("Failed to walk repository tree")?;
+    }
+    Ok(files)
+}
+
+// A unified diff of two versions of a file, either of which may be missing
+fn print_content_diff(old: Option<&[u8]>, new: Option<&[u8]>) {
+    if [old, new]
+        .iter()
+        .flatten()
+        .any(|content| content.contains(&0))
+    {
+        println!("Binary files differ");
+        return;
+    }
+    let old = String::from_utf8_lossy(old.unwrap_or_default());
+    let new = String::from_utf8_lossy(new.unwrap_or_default());
+    let diff = TextDiff::from_lines(old.as_ref(), new.as_ref());
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        println!("{}", hunk.header().to_string().cyan());
+        for change in hunk.iter_changes() {
+            let line = format!(
+                "{}{}",
+                match change.tag() {
+                    ChangeTag::Delete => "-",
+                    ChangeTag::Insert => "+",
+                    ChangeTag::Equal => " ",
+                },
+                change.value()
+            );
+            let line = line.trim_end_matches('\n');
+            match change.tag() {
+                ChangeTag::Delete => println!("{}", line.red()),
+                ChangeTag::Insert => println!("{}", line.green()),
+                ChangeTag::Equal => println!("{}", line),
+            }
+        }
+    }
+}
+
+/// Which journal entries [`show_log`] prints
+pub struct LogFilter {
+    /// Only syncs of this profile
+    pub profile: Option<String>,
+    /// Only syncs that changed this key
+    pub file: Option<String>,
+    /// Only syncs started this way
+    pub trigger: Option<SyncTrigger>,
+    /// Only syncs after this Unix time
+    pub since: Option<u64>,
+    /// Only failed syncs
+    pub errors: bool,
+    /// At most this many entries, newest first
+    pub limit: usize,
+}
+
+/// Prints the sync journal
+pub fn show_log(config: &Config, filter: LogFilter) -> Result<()> {
+    let entries: Vec<JournalEntry> = JournalEntry::load_all()?
+        .into_iter()
+        .filter(|entry| filter.profile.as_ref().is_none_or(|p| &entry.profile == p))
+        .filter(|entry| filter.trigger.is_none_or(|t| entry.trigger == t))
+        .filter(|entry| filter.since.is_none_or(|since| entry.timestamp >= since))
+        .filter(|entry| !filter.errors || entry.error.is_some())
+        .filter(|entry| {
+            filter.file.as_ref().is_none_or(|file| {
+                // The journal records repository paths, which differ from the
+                // key under the profiles layout and for overlays; the key
+                // itself matches runs from before a layout change
+                let repo_key = config.repo_key(&entry.profile, file);
+                let mut paths = vec![file.clone(), repo_key.clone()];
+                paths.extend(
+                    overlay_suffixes()
+                        .iter()
+                        .map(|suffix| format!("{}.{}", repo_key, suffix)),
+                );
+                entry.files_changed.iter().any(|changed| {
+                    paths
+                        .iter()
+                        .any(|path| changed == path || Path::new(changed).starts_with(path))
+                })
+            })
+        })
+        .collect();
+    if entries.is_empty() {
+        println!("No matching sync runs");
+        return Ok(());
+    }
+
+    let skip = entries.len().saturating_sub(filter.limit);
+    for entry in entries.iter().skip(skip) {
+        let result = match &entry.error {
+            Some(error) => format!("failed: {}", error).red(),
+            None => "ok".green(),
+        };
+        println!(
+            "{}  {:<8}  {}  {} file(s)  {:.1}s  {}  {}",
+            format_unix_time(entry.timestamp),
+            entry.trigger.name(),
+            entry.profile.bold(),
+            entry.files_changed.len(),
+            entry.duration_ms as f64 / 1000.0,
+            entry
+                .commit
+                .as_deref()
+                .map_or("-------", |commit| &commit[..7])
+                .yellow(),
+            result
+        );
+        for file in &entry.files_changed {
+            println!("    {}", file);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn print_diff(diff: &git2::Diff) -> Result<()> {
+    diff.print(git2::DiffFormat::Patch, |_, _, line| {
+        let content = String::from_utf8_lossy(line.content());
+        match line.origin() {
+            '+' => print!("{}", format!("+{}", content).green()),
+            '-' => print!("{}", format!("-{}", content).red()),
+            ' ' => print!(" {}", content),
+            _ => print!("{}", content),
+        }
+        true
+    })
+    .context("Failed to print diff")
+}
+
+pub(crate) const PROMPT_CACHE_SECONDS: u64 = 60;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PromptCache {
+    pub(crate) profile: String,
+    pub(crate) dirty: usize,
+    pub(crate) conflicts: usize,
+    pub(crate) last_sync: Option<u64>,
+    pub(crate) computed_at: u64,
+}
+
+impl PromptCache {
+    pub(crate) fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("prompt.toml"))
+    }
+
+    pub(crate) fn load() -> Result<Option<Self>> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let cache_str = fs::read_to_string(&path).context("Failed to read prompt cache")?;
+        // A cache from an older version is simply rebuilt
+        Ok(toml::from_str(&cache_str).ok())
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let cache_str = toml::to_string_pretty(self).context("Failed to serialize prompt cache")?;
+        fs::write(Self::path()?, cache_str).context("Failed to write prompt cache")?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum FileState {
+    Synced,
+    // The source differs from the destination
+    Changed,
+    // The source differs from the repository copy
+    Unpushed,
+    NotInstalled,
+    Missing,
+    Conflict,
+    // The entry's if_* conditions don't hold on this machine
+    Inactive,
+}
+
+impl FileState {
+    pub(crate) fn label(self) -> (&'static str, ratatui::style::Color) {
+        use ratatui::style::Color;
+        match self {
+            FileState::Synced => ("synced", Color::Green),
+            FileState::Changed => ("changed", Color::Yellow),
+            FileState::Unpushed => ("unpushed", Color::Cyan),
+            FileState::NotInstalled => ("not installed", Color::Magenta),
+            FileState::Missing => ("missing", Color::Red),
+            FileState::Conflict => ("conflict", Color::Red),
+            FileState::Inactive => ("not for this machine", Color::DarkGray),
+        }
+    }
+
+    // The label's color for plain terminal output
+    pub(crate) fn color(self) -> Color {
+        match self.label().1 {
+            ratatui::style::Color::Green => Color::Green,
+            ratatui::style::Color::Yellow => Color::Yellow,
+            ratatui::style::Color::Cyan => Color::Cyan,
+            ratatui::style::Color::Magenta => Color::Magenta,
+            ratatui::style::Color::DarkGray => Color::BrightBlack,
+            _ => Color::Red,
+        }
+    }
+}
+
+// Destinations of tracked files by path component, for `dotty tree`
+#[derive(Default)]
+pub(crate) struct PathTree {
+    pub(crate) children: BTreeMap<String, PathTree>,
+    // One per profile tracking this exact path
+    pub(crate) annotations: Vec<String>,
+}
+
+impl PathTree {
+    pub(crate) fn insert(&mut self, path: &Path, annotation: String) {
+        let node = path.components().fold(self, |node, component| {
+            node.children
+                .entry(component.as_os_str().to_string_lossy().into_owned())
+                .or_default()
+        });
+        node.annotations.push(annotation);
+    }
+
+    pub(crate) fn print(&self, prefix: &str) {
+        for (index, (name, child)) in self.children.iter().enumerate() {
+            let last = index + 1 == self.children.len();
+            let name = if child.annotations.is_empty() {
+                name.blue().bold().to_string()
+            } else {
+                name.clone()
+            };
+            let annotations = if child.annotations.is_empty() {
+                String::new()
+            } else {
+                format!("  {}", child.annotations.join("; "))
+            };
+            println!(
+                "{}{}{}{}",
+                prefix,
+                if last { "└── " } else { "├── " },
+                name,
+                annotations
+            );
+            child.print(&format!("{}{}", prefix, if last { "    " } else { "│   " }));
+        }
+    }
+}
+
+pub(crate) struct FileStatus {
+    pub(crate) key: String,
+    pub(crate) source: PathBuf,
+    pub(crate) dest: PathBuf,
+    pub(crate) repo_copy: PathBuf,
+    pub(crate) state: FileState,
+}
+
+pub(crate) struct DashboardState {
+    pub(crate) profile: String,
+    pub(crate) files: Vec<FileStatus>,
+    pub(crate) selected: ratatui::widgets::ListState,
+    pub(crate) marked: HashSet<String>,
+    pub(crate) conflicts: Vec<Conflict>,
+    pub(crate) history: Vec<JournalEntry>,
+    pub(crate) message: String,
+    // Waiting for the l/r/e answer to a resolve
+    pub(crate) resolving: bool,
+}
+
+// Lines of the change a sync would make: the destination against the source,
+// or the repository copy against the source when only that differs
+pub(crate) fn pending_diff(file: &FileStatus) -> Vec<ratatui::text::Line<'static>> {
+    use ratatui::style::{Color, Style};
+    use ratatui::text::Line;
+
+    let old = match file.state {
+        FileState::Changed => &file.dest,
+        FileState::Unpushed => &file.repo_copy,
+        FileState::Synced => return vec![Line::from("No pending changes")],
+        _ => return Vec::new(),
+    };
+    if !file.source.is_file() || !old.is_file() {
+        return vec![Line::from("Directory; no preview")];
+    }
+    let (Ok(old), Ok(new)) = (fs::read_to_string(old), fs::read_to_string(&file.source)) else {
+        return vec![Line::from("Binary file; no preview")];
+    };
+    let (old, new) = (old.replace("\r\n", "\n"), new.replace("\r\n", "\n"));
+    TextDiff::from_lines(&old, &new)
+        .iter_all_changes()
+        .map(|change| {
+            let (sign, color) = match change.tag() {
+                ChangeTag::Delete => ("-", Color::Red),
+                ChangeTag::Insert => ("+", Color::Green),
+                ChangeTag::Equal => (" ", Color::Reset),
+            };
+            Line::styled(
+                format!("{}{}", sign, change.value().trim_end_matches('\n')),
+                Style::default().fg(color),
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn draw_dashboard(frame: &mut ratatui::Frame, state: &mut DashboardState) {
+    use ratatui::layout::{Constraint, Layout};
+    use ratatui::style::{Color, Modifier, Style};
+    use ratatui::text::{Line, Span};
+    use ratatui::widgets::{Block, List, ListItem, Paragraph};
+
+    let [header, body, footer] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(0),
+        Constraint::Length(2),
+    ])
+    .areas(frame.area());
+    let [files_area, right] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(body);
+    let [diff_area, conflicts_area, history_area] = Layout::vertical([
+        Constraint::Min(0),
+        Constraint::Length(state.conflicts.len().clamp(1, 5) as u16 + 2),
+        Constraint::Length(7),
+    ])
+    .areas(right);
+
+    frame.render_widget(
+        Line::from(vec![
+            Span::styled(" dotty ", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(format!("profile {} ", state.profile)),
+        ]),
+        header,
+    );
+
+    let items: Vec<ListItem> = state
+        .files
+        .iter()
+        .map(|file| {
+            let (label, color) = file.state.label();
+            let mark = if state.marked.contains(&file.key) {
+                "● "
+            } else {
+                "  "
+            };
+            ListItem::new(Line::from(vec![
+                Span::raw(mark),
+                Span::styled(format!("{:<14}", label), Style::default().fg(color)),
+                Span::raw(file.key.clone()),
+            ]))
+        })
+        .collect();
+    frame.render_stateful_widget(
+        List::new(items)
+            .block(Block::bordered().title(" Files "))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+        files_area,
+        &mut state.selected,
+    );
+
+    let diff = state
+        .selected
+        .selected()
+        .and_then(|index| state.files.get(index))
+        .map(pending_diff)
+        .unwrap_or_default();
+    frame.render_widget(
+        Paragraph::new(diff).block(Block::bordered().title(" Pending changes ")),
+        diff_area,
+    );
+
+    let conflicts: Vec<Line> = if state.conflicts.is_empty() {
+        vec![Line::from("None")]
+    } else {
+        state
+            .conflicts
+            .iter()
+            .map(|conflict| {
+                Line::from(format!(
+                    "{} (profile {}, since {})",
+                    conflict.path,
+                    conflict.profile,
+                    format_unix_time(conflict.detected_at)
+                ))
+            })
+            .collect()
+    };
+    frame.render_widget(
+        Paragraph::new(conflicts).block(Block::bordered().title(" Conflicts ")),
+        conflicts_area,
+    );
+
+    let history: Vec<Line> = state
+        .history
+        .iter()
+        .rev()
+        .take(5)
+        .map(|entry| {
+            let (result, color) = match &entry.error {
+                Some(_) => ("failed", Color::Red),
+                None => ("ok", Color::Green),
+            };
+            Line::from(vec![
+                Span::raw(format!(
+                    "{}  {:<8} {:<10} {} file(s)  ",
+                    format_unix_time(entry.timestamp),
+                    entry.trigger.name(),
+                    entry.profile,
+                    entry.files_changed.len()
+                )),
+                Span::styled(result, Style::default().fg(color)),
+            ])
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(history).block(Block::bordered().title(" Recent syncs ")),
+        history_area,
+    );
+
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(state.message.clone()),
+            Line::styled(
+                "↑↓ move  space mark  s sync marked/selected  a sync all  r resolve  tab switch profile  q quit",
+                Style::default().fg(Color::DarkGray),
+            ),
+        ]),
+        footer,
+    );
+}
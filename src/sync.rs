@@ -0,0 +1,2493 @@
+//! Syncing tracked files between their sources, destinations and the
+//! repository, and resolving conflicts.
+
+use crate::config::{
+    Config, ConflictStrategy, FileEntry, LineEnding, LinkMode, NotificationEvent, ProfileConfig,
+    RemoteAuth, WebhookKind,
+};
+use crate::daemon::is_editor_temp_file;
+use crate::error::{profile_not_found, DottyError, ErrorKind};
+use crate::remote::{load_keyring_token, StoredToken};
+use crate::repo::{
+    nested_repo, write_git_object, Machines, NestedRepos, Tombstones, CONFIG_PATH, MACHINES_PATH,
+    NESTED_REPOS_PATH, TOMBSTONES_PATH,
+};
+use crate::template::is_template;
+use crate::util::{
+    chown, convert_line_endings, copy_xattrs, create_symlink, file_mode, hash_path, hex,
+    is_same_file, needs_elevation, ownership_mismatch, prompt, remove_link, run_editor,
+    shell_quote, state_dir, unix_seconds, warn_if_exposed,
+};
+use crate::Dotty;
+use anyhow::{Context, Result};
+use colored::*;
+use git2::Repository;
+use ignore::WalkBuilder;
+use log::{info, warn};
+use notify::DebouncedEvent;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+use std::{env, fs, process};
+
+/// Which side of a conflict to keep
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Take {
+    /// Keep the destination on this machine
+    Local,
+    /// Keep the source, as synced to the repository
+    Remote,
+    /// Edit the file with conflict markers in $EDITOR
+    Edit,
+}
+
+pub(crate) enum Resolution {
+    UseSource,
+    UseDest,
+    // Leave both as they are until `dotty resolve`
+    Skip,
+    Abort,
+}
+
+pub(crate) enum TextMerge {
+    Clean(Vec<u8>),
+    // The merge with conflict markers around the overlapping hunks
+    Conflict(Vec<u8>),
+    Binary,
+}
+
+impl Dotty {
+    // Drops entries that another machine removed from this profile (only
+    // `files`, if given), backing up and deleting the local destination
+    pub(crate) fn apply_tombstones(
+        &mut self,
+        profile: &str,
+        files: Option<&HashSet<String>>,
+    ) -> Result<()> {
+        let repo = self.open_repo()?;
+        if let Err(e) = self.fetch_remote(&repo) {
+            warn!("Could not fetch remote tombstones: {:#}", e);
+        }
+        let mut tombstones = Tombstones::load(&self.repo_path()?.join(TOMBSTONES_PATH))?;
+        if let Ok(remote_tree) = repo
+            .revparse_single(&format!(
+                "refs/remotes/origin/{}",
+                self.config.remote.shared_branch()
+            ))
+            .and_then(|object| object.peel_to_tree())
+        {
+            tombstones
+                .tombstones
+                .extend(Tombstones::from_tree(&repo, &remote_tree)?.tombstones);
+        }
+
+        let hostname = hostname::get()
+            .map(|h| h.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let mut removed = Vec::new();
+        for tombstone in &tombstones.tombstones {
+            if tombstone.profile != profile
+                || tombstone.host == hostname
+                || files.is_some_and(|files| !files.contains(&tombstone.path))
+            {
+                continue;
+            }
+            let tracked = self
+                .config
+                .profiles
+                .get(profile)
+                .is_some_and(|profile_config| profile_config.files.contains_key(&tombstone.path));
+            if !tracked || removed.contains(&tombstone.path) {
+                continue;
+            }
+
+            let dest = self.config.profiles[profile].destination(&home, &tombstone.path);
+            if dest.is_symlink() {
+                remove_link(&dest)
+            } else if dest.is_dir() {
+                self.backup_file(&dest)?;
+                fs::remove_dir_all(&dest)
+            } else if dest.exists() {
+                self.backup_file(&dest)?;
+                fs::remove_file(&dest)
+            } else {
+                Ok(())
+            }
+            .with_context(|| format!("Failed to delete {}", dest.display()))?;
+            if self.porcelain {
+                self.event(
+                    "remove",
+                    Some(&tombstone.path),
+                    "ok",
+                    Some(tombstone.host.clone()),
+                );
+            } else {
+                println!(
+                    "Removed {} (deleted from profile {} on {})",
+                    tombstone.path, profile, tombstone.host
+                );
+            }
+            removed.push(tombstone.path.clone());
+        }
+
+        if !removed.is_empty() {
+            if let Some(profile_config) = self.config.profiles.get_mut(profile) {
+                for path in &removed {
+                    profile_config.files.remove(path);
+                }
+            }
+            self.save_config()?;
+        }
+        Ok(())
+    }
+
+    // `profile` is the profile the event is about; None for events that
+    // concern the whole branch, such as a diverged remote
+    pub(crate) fn notify(&self, event: NotificationEvent, profile: Option<&str>, message: &str) {
+        let Some(notifications) = &self.config.notifications else {
+            return;
+        };
+        let hostname = hostname::get()
+            .map(|h| h.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+
+        for webhook in &notifications.webhooks {
+            if !webhook.events.is_empty() && !webhook.events.contains(&event) {
+                continue;
+            }
+            let text = format!("[dotty@{}] {}", hostname, message);
+            let payload = match webhook.kind {
+                WebhookKind::Slack => serde_json::json!({ "text": text }),
+                WebhookKind::Discord => serde_json::json!({ "content": text }),
+                WebhookKind::Generic => serde_json::json!({
+                    "event": event.name(),
+                    "hostname": hostname,
+                    "profile": profile,
+                    "message": message,
+                }),
+            };
+            if let Err(e) = agent.post(&webhook.url).send_json(payload) {
+                warn!("Failed to send {} notification: {}", event.name(), e);
+            }
+        }
+    }
+
+    /// `files` restricts the sync to the given tracked entries (config keys);
+    /// None syncs the whole profile.
+    pub fn sync(
+        &mut self,
+        profile: Option<String>,
+        files: Option<&HashSet<String>>,
+        trigger: SyncTrigger,
+    ) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        if let Some(files) = files {
+            let profile_config = self
+                .config
+                .profiles
+                .get(&profile)
+                .with_context(|| profile_not_found(&profile))?;
+            if let Some(key) = files
+                .iter()
+                .find(|key| !profile_config.files.contains_key(*key))
+            {
+                anyhow::bail!("{} isn't tracked in profile {}", key, profile);
+            }
+        }
+        // The daemon outlives tokens that expire
+        if StoredToken::expiring(self.config.remote.token_expires_at) {
+            load_keyring_token(&mut self.config.remote)?;
+        }
+        if let Some(freeze) = FreezeState::load()? {
+            if self.porcelain {
+                self.event("sync", None, "skipped", Some(freeze.describe()));
+            } else {
+                println!(
+                    "{}; skipping sync. Run `dotty thaw` to resume.",
+                    freeze.describe()
+                );
+            }
+            return Ok(());
+        }
+        let _lock = SyncLock::acquire()?;
+        if let Err(e) = self.write_shell_env(&profile) {
+            warn!("Failed to write shell environment: {:#}", e);
+        }
+        self.run_hook("pre_sync", &profile)?;
+        let started = Instant::now();
+        let result = self.sync_profile(&profile, files);
+        let entry = JournalEntry {
+            timestamp: unix_seconds(SystemTime::now()),
+            trigger,
+            profile: profile.clone(),
+            files_changed: result
+                .as_ref()
+                .map(|outcome| outcome.changed_files.clone())
+                .unwrap_or_default(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            commit: result
+                .as_ref()
+                .ok()
+                .and_then(|outcome| outcome.commit.clone()),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+        if let Err(e) = entry.append() {
+            warn!("Failed to write sync journal: {:#}", e);
+        }
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.syncs_total += 1;
+            stats.last_sync = Some(SystemTime::now());
+            stats.last_sync_ok = result.is_ok();
+            if result.is_err() {
+                stats.sync_failures_total += 1;
+            }
+        }
+        match result {
+            Ok(outcome) => {
+                // The sync may have brought in a new script
+                self.script = OnceLock::new();
+                if let Err(e) = self.run_hook("post_sync", &profile) {
+                    warn!("{:#}", e);
+                }
+                self.notify(
+                    NotificationEvent::SyncSuccess,
+                    Some(&profile),
+                    &format!("Synced profile {}", profile),
+                );
+                if !outcome.overwritten.is_empty() && !self.porcelain {
+                    println!(
+                        "Forcibly overwrote {} destination(s):",
+                        outcome.overwritten.len()
+                    );
+                    for (relative_path, backup) in &outcome.overwritten {
+                        println!(
+                            "  {} (previous version in {})",
+                            relative_path,
+                            backup.display()
+                        );
+                    }
+                }
+                let result = partial_sync(&outcome.skipped, &outcome.refused);
+                match &result {
+                    Ok(()) => self.event("sync", None, "ok", None),
+                    Err(e) => self.event("sync", None, "partial", Some(format!("{:#}", e))),
+                }
+                result
+            }
+            Err(e) => {
+                self.notify(
+                    NotificationEvent::SyncFailure,
+                    Some(&profile),
+                    &format!("Sync of profile {} failed: {:#}", profile, e),
+                );
+                self.event("sync", None, "error", Some(format!("{:#}", e)));
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) fn sync_profile(
+        &mut self,
+        profile: &str,
+        files: Option<&HashSet<String>>,
+    ) -> Result<SyncOutcome> {
+        // A pin holds the destinations at the pinned commit, but edits to the
+        // sources are still committed and pushed
+        if let Some(pin) = PinState::load()? {
+            if !self.porcelain {
+                println!(
+                    "Destinations are pinned to {} ({}); pushing without applying. Run `dotty unpin` to resume.",
+                    pin.revision,
+                    &pin.commit[..7]
+                );
+            }
+            let outcome = self.sync_with_github(profile, files)?;
+            self.last_synced = SystemTime::now();
+            return Ok(outcome);
+        }
+        self.apply_tombstones(profile, files)?;
+        let profile_config = self
+            .config
+            .profiles
+            .get(profile)
+            .with_context(|| profile_not_found(profile))?;
+
+        self.show_diff(profile, files)?;
+        let mut conflicts = Conflicts::load()?;
+        let manifest = Manifest::load(profile)?;
+        let mut skipped = Vec::new();
+        let mut refused = Vec::new();
+        let mut overwritten = Vec::new();
+
+        for (relative_path, entry) in profile_config.ordered_files()? {
+            if files.is_some_and(|files| !files.contains(relative_path)) {
+                continue;
+            }
+            let canonical_path = entry.source();
+            let source = Path::new(canonical_path);
+            let dest = profile_config.destination(
+                &dirs::home_dir().context("Failed to get home directory")?,
+                relative_path,
+            );
+            if let Some(reason) = self.unmet_condition(profile, entry) {
+                info!("Skipped syncing {:?}: {}", relative_path, reason);
+                self.event("skip", Some(relative_path), "condition", Some(reason));
+                continue;
+            }
+            let mut forced = false;
+            if conflicts.contains(profile, relative_path) {
+                if !self.force {
+                    warn!(
+                        "Skipped syncing {:?}: unresolved conflict; run `dotty resolve`",
+                        relative_path
+                    );
+                    self.event("skip", Some(relative_path), "conflict", None);
+                    skipped.push(relative_path.clone());
+                    continue;
+                }
+                // Forcing drops the recorded conflict and the source wins
+                conflicts.remove(profile, relative_path)?;
+                forced = source.exists();
+            }
+
+            if source.exists() {
+                if self.too_large(source, Some(entry)) {
+                    warn!(
+                        "Skipped syncing {:?}: larger than max_file_size ({} bytes)",
+                        relative_path, self.config.max_file_size
+                    );
+                    self.event("skip", Some(relative_path), "too_large", None);
+                } else if self.should_sync(source, profile_config) {
+                    let mode = entry.mode().unwrap_or(profile_config.link_mode());
+                    let conflict = if mode == LinkMode::Symlink {
+                        None
+                    } else {
+                        self.conflict(profile, relative_path, source, &dest)?
+                    };
+                    if let Some([base, ours, theirs]) = conflict {
+                        let merged = merge_text(&self.open_repo()?, &base, &ours, &theirs)?;
+                        let mut marked = None;
+                        let resolution = match merged {
+                            TextMerge::Clean(merged) => {
+                                info!(
+                                    "Merged changes to {:?} from source and destination",
+                                    relative_path
+                                );
+                                self.backup_file(source)?;
+                                fs::write(source, merged).context("Failed to write merged file")?;
+                                self.event("merge", Some(relative_path), "ok", None);
+                                Resolution::UseSource
+                            }
+                            TextMerge::Conflict(_) | TextMerge::Binary if self.force => {
+                                forced = true;
+                                Resolution::UseSource
+                            }
+                            TextMerge::Conflict(content) => {
+                                marked = Some(content);
+                                self.resolve_conflict(relative_path, source, &dest)?
+                            }
+                            TextMerge::Binary => {
+                                self.resolve_conflict(relative_path, source, &dest)?
+                            }
+                        };
+                        match resolution {
+                            Resolution::UseSource => {}
+                            Resolution::UseDest => {
+                                self.backup_file(source)?;
+                                fs::copy(&dest, source).context("Failed to copy destination")?;
+                                self.event("conflict", Some(relative_path), "destination", None);
+                            }
+                            Resolution::Skip | Resolution::Abort => {
+                                let conflict_file = self.record_conflict(
+                                    profile,
+                                    relative_path,
+                                    &dest,
+                                    marked.as_deref(),
+                                )?;
+                                let message = format!(
+                                    "{} changed in both {:?} and {:?} since the last sync{}",
+                                    relative_path,
+                                    source,
+                                    dest,
+                                    conflict_file.as_ref().map_or(String::new(), |path| {
+                                        format!(" (conflict markers in {:?})", path)
+                                    })
+                                );
+                                if matches!(resolution, Resolution::Abort) {
+                                    self.event("conflict", Some(relative_path), "aborted", None);
+                                    return Err(DottyError::new(ErrorKind::Conflict, message)
+                                        .hint("run `dotty resolve`")
+                                        .into());
+                                }
+                                warn!("{}; run `dotty resolve`", message);
+                                self.event(
+                                    "conflict",
+                                    Some(relative_path),
+                                    "skipped",
+                                    conflict_file.map(|path| path.display().to_string()),
+                                );
+                                skipped.push(relative_path.clone());
+                                continue;
+                            }
+                        }
+                    } else if let Some(reason) = unsafe_overwrite(
+                        manifest.as_ref(),
+                        relative_path,
+                        source,
+                        &dest,
+                        mode,
+                        self.rendered(source, profile_config)?.as_deref(),
+                    )? {
+                        if !self.force {
+                            warn!(
+                                "Skipped syncing {:?}: {:?} {}; run `dotty sync --force` to overwrite it",
+                                relative_path, dest, reason
+                            );
+                            self.event("skip", Some(relative_path), "unsafe", Some(reason.into()));
+                            refused.push(relative_path.clone());
+                            continue;
+                        }
+                        forced = true;
+                    }
+                    self.backup_file(&dest)?;
+                    self.install_destination(
+                        source,
+                        &dest,
+                        canonical_path,
+                        entry.mode().unwrap_or(profile_config.link_mode()),
+                        profile_config.line_ending(relative_path),
+                        profile_config,
+                    )?;
+                    if let Some(permissions) = entry.permissions() {
+                        self.enforce_permissions(&dest, permissions)?;
+                    }
+                    self.enforce_ownership(&dest, entry)?;
+                    warn_if_exposed(relative_path, &dest);
+                    self.stats.lock().unwrap().files_synced_total += 1;
+                    if forced {
+                        overwritten.push((relative_path.clone(), dest.with_extension("bak")));
+                        self.event("install", Some(relative_path), "forced", None);
+                    } else {
+                        self.event("install", Some(relative_path), "ok", None);
+                    }
+                } else {
+                    info!("Skipped syncing {:?} (ignored)", relative_path);
+                    self.event("skip", Some(relative_path), "ignored", None);
+                }
+            } else if dest.is_symlink() && !dest.exists() {
+                warn!(
+                    "{:?} is a dangling symlink to missing {:?}; run `dotty repair` to fix it",
+                    dest, canonical_path
+                );
+                self.event("skip", Some(relative_path), "dangling", None);
+            } else {
+                warn!("Source file missing: {:?}", canonical_path);
+                self.event("skip", Some(relative_path), "missing", None);
+            }
+        }
+
+        if let Err(e) = self.update_manifest(profile, files, &refused) {
+            warn!("Failed to update manifest: {:#}", e);
+        }
+        let mut outcome = self.sync_with_github(profile, files)?;
+        outcome.skipped = skipped;
+        outcome.refused = refused;
+        outcome.overwritten = overwritten;
+        self.last_synced = SystemTime::now();
+        Ok(outcome)
+    }
+
+    /// Provisions a new machine: adopts the configuration stored in the
+    /// repository and writes every file of the profile to its source and
+    /// destination
+    pub fn apply(
+        &mut self,
+        repo_url: Option<String>,
+        token: Option<String>,
+        profile: Option<String>,
+    ) -> Result<()> {
+        if let Some(repo_url) = repo_url {
+            self.config.remote.github_repo = repo_url;
+        }
+        // The environment keeps the token out of argv, where ps would show it
+        if let Some(token) = token.or_else(|| {
+            env::var("DOTTY_GITHUB_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty())
+        }) {
+            self.config.remote.github_token = token;
+        }
+        if self.config.remote.github_repo.is_empty() {
+            anyhow::bail!("No repository configured; pass --repo <url>");
+        }
+
+        // The stored config decides the branch, so don't switch yet
+        let repo = self.open_or_clone_repo()?;
+        let tree = repo.head()?.peel_to_tree()?;
+        let stored = tree
+            .get_path(Path::new(CONFIG_PATH))
+            .ok()
+            .and_then(|entry| repo.find_blob(entry.id()).ok())
+            .context(
+                "The repository has no stored config; run `dotty sync` on a configured machine first",
+            )?;
+        let mut config: Config = toml::from_str(&String::from_utf8_lossy(stored.content()))
+            .context("Failed to parse stored config")?;
+        config.remote.github_repo = self.config.remote.github_repo.clone();
+        config.remote.github_token = self.config.remote.github_token.clone();
+        config.remote.auth = self.config.remote.auth;
+        config.remote.token_store = self.config.remote.token_store;
+        config.remote.local_path = self.config.remote.local_path.clone();
+        config.notifications = self.config.notifications.take();
+        config.script_commands = self.config.script_commands;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        for profile_config in config.profiles.values_mut() {
+            for entry in profile_config.files.values_mut() {
+                if let Some(rest) = entry.source().strip_prefix("~/") {
+                    let source = home.join(rest).to_string_lossy().into_owned();
+                    entry.set_source(source);
+                }
+            }
+        }
+        self.config = config;
+        self.save_config()?;
+        self.current_profile = profile.unwrap_or_else(|| self.detect_profile());
+        let profile = self.current_profile.clone();
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| format!("The stored config has no profile {}", profile))?;
+
+        let repo = self.open_repo()?;
+        let commit = repo.head()?.peel_to_commit()?;
+        let tree = commit.tree()?;
+        let nested_repos = NestedRepos::from_tree(&repo, &tree)?;
+        for (relative_path, entry) in profile_config.ordered_files()? {
+            if let Some(reason) = self.unmet_condition(&profile, entry) {
+                info!("Skipped {}: {}", relative_path, reason);
+                continue;
+            }
+            let Some(tree_entry) = self.config.tree_entry(&tree, &profile, relative_path) else {
+                warn!("{} is not in the repository yet", relative_path);
+                continue;
+            };
+            let object = tree_entry
+                .to_object(&repo)
+                .context("Failed to read repository object")?;
+            let source = Path::new(entry.source());
+            let dest = profile_config.destination(&home, relative_path);
+            let line_ending = profile_config.line_ending(relative_path);
+            if let Some(parent) = source.parent() {
+                fs::create_dir_all(parent).context("Failed to create parent directories")?;
+            }
+            self.backup_file(source)?;
+            self.write_object(&repo, &object, source)?;
+            if let Some(line_ending) = line_ending {
+                convert_line_endings(source, line_ending.is_crlf())?;
+            }
+            self.restore_nested_repos(
+                &nested_repos,
+                &self.config.repo_key(&profile, relative_path),
+                source,
+            );
+            if dest != source {
+                self.backup_file(&dest)?;
+            }
+            self.install_destination(
+                source,
+                &dest,
+                entry.source(),
+                entry.mode().unwrap_or(profile_config.link_mode()),
+                line_ending,
+                profile_config,
+            )?;
+            if let Some(permissions) = entry.permissions() {
+                self.enforce_permissions(&dest, permissions)?;
+            }
+            self.enforce_ownership(&dest, entry)?;
+            println!("Applied {}", relative_path);
+        }
+        if let Err(e) = self.update_manifest(&profile, None, &[]) {
+            warn!("Failed to update manifest: {:#}", e);
+        }
+        self.write_shell_env(&profile)?;
+
+        if let Err(e) = self.run_hook("post_apply", &profile) {
+            warn!("{:#}", e);
+        }
+        println!(
+            "Applied profile {} from {}",
+            profile,
+            &commit.id().to_string()[..7]
+        );
+        if self.config.remote.auth == RemoteAuth::Token
+            && self.config.remote.github_token.is_empty()
+        {
+            println!(
+                "Set remote.github_token in {} (or pass --token) before syncing",
+                self.config_path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// A POSIX sh script for `curl | sh` onboarding. The token is never
+    /// embedded; `dotty apply` reads it from DOTTY_GITHUB_TOKEN.
+    pub fn bootstrap_script(
+        &self,
+        profile: Option<String>,
+        binary_url: Option<String>,
+    ) -> Result<String> {
+        if self.config.remote.github_repo.is_empty() {
+            anyhow::bail!("No repository configured to bootstrap from");
+        }
+        let install = match binary_url {
+            Some(url) => format!(
+                r#"mkdir -p "$HOME/.local/bin"
+    curl -fsSL {} -o "$HOME/.local/bin/dotty"
+    chmod +x "$HOME/.local/bin/dotty"
+    PATH="$HOME/.local/bin:$PATH""#,
+                shell_quote(&url)
+            ),
+            None => format!(
+                r#"if ! command -v cargo >/dev/null 2>&1; then
+        curl -fsSL https://sh.rustup.rs | sh -s -- -y
+        . "$HOME/.cargo/env"
+    fi
+    cargo install --locked --git {}
+    PATH="$HOME/.cargo/bin:$PATH""#,
+                shell_quote(env!("CARGO_PKG_REPOSITORY"))
+            ),
+        };
+        let mut apply = format!(
+            "dotty apply --repo {}",
+            shell_quote(&self.config.remote.github_repo)
+        );
+        if let Some(profile) = profile {
+            apply.push_str(&format!(" --profile {}", shell_quote(&profile)));
+        }
+        Ok(format!(
+            r#"#!/bin/sh
+# Sets up this machine with dotty. Generated by `dotty bootstrap-script`.
+# For a private repository, export DOTTY_GITHUB_TOKEN first.
+set -eu
+
+if ! command -v dotty >/dev/null 2>&1; then
+    {}
+fi
+
+{}
+"#,
+            install, apply
+        ))
+    }
+
+    /// Publishes the current sources (or just `paths`) without installing
+    /// anything at the destinations
+    pub fn push(&self, paths: &[PathBuf], profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        if let Some(freeze) = FreezeState::load()? {
+            println!(
+                "{}; skipping push. Run `dotty thaw` to resume.",
+                freeze.describe()
+            );
+            return Ok(());
+        }
+        let files = paths
+            .iter()
+            .map(|path| self.tracked_key(path))
+            .collect::<Result<HashSet<String>>>()?;
+        let _lock = SyncLock::acquire()?;
+        let outcome = self.sync_with_github(&profile, (!files.is_empty()).then_some(&files))?;
+        match outcome.commit {
+            Some(commit) => println!(
+                "Pushed {} ({} file(s) changed)",
+                &commit[..7],
+                outcome.changed_files.len()
+            ),
+            None => println!("Nothing to push"),
+        }
+        Ok(())
+    }
+
+    /// Fast-forwards the repository to the remote and applies the files that
+    /// changed upstream to sources and destinations. Local edits made since the
+    /// last sync are merged; nothing is committed or pushed.
+    pub fn pull(&mut self, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        if let Some(freeze) = FreezeState::load()? {
+            println!(
+                "{}; skipping pull. Run `dotty thaw` to resume.",
+                freeze.describe()
+            );
+            return Ok(());
+        }
+        if let Some(pin) = PinState::load()? {
+            anyhow::bail!(
+                "Destinations are pinned to {} ({}); run `dotty unpin` before pulling",
+                pin.revision,
+                pin.commit
+            );
+        }
+        let _lock = SyncLock::acquire()?;
+        self.apply_tombstones(&profile, None)?;
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+
+        let repo = self.open_repo()?;
+        self.fetch_remote(&repo)?;
+        let branch = self.branch();
+        let Ok(remote) = repo.refname_to_id(&format!("refs/remotes/origin/{}", branch)) else {
+            println!("origin/{} doesn't exist yet; nothing to pull", branch);
+            return Ok(());
+        };
+        let local = repo.head()?.peel_to_commit()?.id();
+        if remote == local || repo.graph_descendant_of(local, remote)? {
+            println!("Already up to date with origin/{}", branch);
+            return Ok(());
+        }
+        if !repo.graph_descendant_of(remote, local)? {
+            return Err(DottyError::new(
+                ErrorKind::Conflict,
+                format!("{} has diverged from origin/{}", branch, branch),
+            )
+            .hint("run `dotty sync` to reconcile them")
+            .into());
+        }
+        let old_tree = repo.find_commit(local)?.tree()?;
+        let new_commit = repo.find_commit(remote)?;
+        let new_tree = new_commit.tree()?;
+
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+        let conflicts = Conflicts::load()?;
+        let mut applied = 0;
+        let mut skipped = Vec::new();
+        for (relative_path, entry) in profile_config.ordered_files()? {
+            if self.unmet_condition(&profile, entry).is_some() {
+                continue;
+            }
+            let old = self.config.tree_entry(&old_tree, &profile, relative_path);
+            let Some(new) = self.config.tree_entry(&new_tree, &profile, relative_path) else {
+                continue;
+            };
+            if old.as_ref().map(|old| old.id()) == Some(new.id()) {
+                continue;
+            }
+            if conflicts.contains(&profile, relative_path) {
+                warn!(
+                    "Skipped pulling {:?}: unresolved conflict; run `dotty resolve`",
+                    relative_path
+                );
+                skipped.push(relative_path.clone());
+                continue;
+            }
+            let source = Path::new(entry.source());
+            let dest = profile_config.destination(&home, relative_path);
+            let mode = entry.mode().unwrap_or(profile_config.link_mode());
+            let line_ending = profile_config.line_ending(relative_path);
+            let object = new
+                .to_object(&repo)
+                .context("Failed to read repository object")?;
+            let write = |path: &Path, content: Option<&[u8]>| -> Result<()> {
+                match content {
+                    Some(content) => fs::write(path, content)
+                        .with_context(|| format!("Failed to write {:?}", path))?,
+                    None => self.write_object(&repo, &object, path)?,
+                }
+                match line_ending {
+                    Some(line_ending) => convert_line_endings(path, line_ending.is_crlf()),
+                    None => Ok(()),
+                }
+            };
+
+            // A local edit lives in the source, or in a copied destination
+            let base = old
+                .and_then(|old| repo.find_blob(old.id()).ok())
+                .map(|blob| blob.content().to_vec());
+            let local_edit = base.as_ref().and_then(|base| {
+                let read = |path: &Path| {
+                    fs::read(path).ok().map(|content| {
+                        if line_ending.is_some() {
+                            String::from_utf8_lossy(&content)
+                                .replace("\r\n", "\n")
+                                .into_bytes()
+                        } else {
+                            content
+                        }
+                    })
+                };
+                let source_content = read(source).filter(|content| content != base);
+                let dest_content =
+                    (mode == LinkMode::Copy && !dest.is_symlink() && !is_template(source))
+                        .then(|| read(&dest))
+                        .flatten()
+                        .filter(|content| content != base);
+                source_content.or(dest_content)
+            });
+            let mut merged = None;
+            if let (Some(base), Some(local_edit), Some(blob)) =
+                (&base, local_edit, object.as_blob())
+            {
+                let result = if entry.lfs() {
+                    TextMerge::Binary
+                } else {
+                    merge_text(&repo, base, blob.content(), &local_edit)?
+                };
+                match result {
+                    TextMerge::Clean(content) => {
+                        info!(
+                            "Merged upstream changes to {:?} with local edits",
+                            relative_path
+                        );
+                        merged = Some(content);
+                    }
+                    result => {
+                        let marked = match result {
+                            TextMerge::Conflict(content) => Some(content),
+                            _ => None,
+                        };
+                        if mode != LinkMode::Copy
+                            || dest.is_symlink()
+                            || is_same_file(source, &dest)
+                        {
+                            // The destination is the source, so the local
+                            // edit stays and the remote version is set aside
+                            let mut name = dest.file_name().unwrap_or_default().to_os_string();
+                            name.push(".conflict");
+                            let conflict_file = dest.with_file_name(name);
+                            match &marked {
+                                Some(content) => fs::write(&conflict_file, content),
+                                None => fs::write(&conflict_file, blob.content()),
+                            }
+                            .context("Failed to write conflict file")?;
+                            warn!(
+                                "{} changed both upstream and locally; kept the local edit, upstream changes are in {:?}",
+                                relative_path, conflict_file
+                            );
+                            skipped.push(relative_path.clone());
+                            continue;
+                        }
+                        // Stage it as a source/destination conflict: the
+                        // upstream version in the source, the local one in
+                        // the destination
+                        self.backup_file(&dest)?;
+                        fs::write(&dest, &local_edit).context("Failed to write destination")?;
+                        self.backup_file(source)?;
+                        write(source, None)?;
+                        let conflict_file = self.record_conflict(
+                            &profile,
+                            relative_path,
+                            &dest,
+                            marked.as_deref(),
+                        )?;
+                        warn!(
+                            "{} changed both upstream and locally{}; run `dotty resolve`",
+                            relative_path,
+                            conflict_file.map_or(String::new(), |path| format!(
+                                " (conflict markers in {:?})",
+                                path
+                            ))
+                        );
+                        skipped.push(relative_path.clone());
+                        continue;
+                    }
+                }
+            }
+
+            self.backup_file(source)?;
+            write(source, merged.as_deref())?;
+            self.backup_file(&dest)?;
+            self.install_destination(
+                source,
+                &dest,
+                entry.source(),
+                mode,
+                line_ending,
+                profile_config,
+            )?;
+            if let Some(permissions) = entry.permissions() {
+                self.enforce_permissions(&dest, permissions)?;
+            }
+            self.enforce_ownership(&dest, entry)?;
+            println!("Pulled {}", relative_path);
+            applied += 1;
+        }
+
+        let refname = format!("refs/heads/{}", branch);
+        repo.reference(&refname, remote, true, "dotty: pull")
+            .context("Failed to fast-forward")?;
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .context("Failed to check out the pulled commit")?;
+        if let Err(e) = self.update_manifest(&profile, None, &[]) {
+            warn!("Failed to update manifest: {:#}", e);
+        }
+        println!(
+            "Fast-forwarded to {} ({} file(s) updated)",
+            &remote.to_string()[..7],
+            applied
+        );
+        self.script = OnceLock::new();
+        if let Err(e) = self.run_hook("post_pull", &profile) {
+            warn!("{:#}", e);
+        }
+        partial_sync(&skipped, &[])
+    }
+
+    // The last-synced, source and destination contents of a file whose source
+    // and destination were both changed, differently, since the last sync.
+    // The repository copy is the last-synced version.
+    pub(crate) fn conflict(
+        &self,
+        profile: &str,
+        relative_path: &str,
+        source: &Path,
+        dest: &Path,
+    ) -> Result<Option<[Vec<u8>; 3]>> {
+        // A template's destination is generated, so there's nothing to merge
+        if is_template(source) {
+            return Ok(None);
+        }
+        let base = self
+            .repo_path()?
+            .join(self.repo_copy_key(profile, relative_path)?);
+        if !source.is_file() || !dest.is_file() || dest.is_symlink() || !base.is_file() {
+            return Ok(None);
+        }
+        // The repository stores LF when a line-ending policy is set
+        let normalize = self.config.profiles[profile]
+            .line_ending(relative_path)
+            .is_some();
+        let read = |path: &Path| -> Result<Vec<u8>> {
+            let content = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+            Ok(if normalize {
+                String::from_utf8_lossy(&content)
+                    .replace("\r\n", "\n")
+                    .into_bytes()
+            } else {
+                content
+            })
+        };
+        let (source, dest, base) = (read(source)?, read(dest)?, read(&base)?);
+        Ok((source != base && dest != base && source != dest).then_some([base, source, dest]))
+    }
+
+    pub(crate) fn resolve_conflict(
+        &self,
+        relative_path: &str,
+        source: &Path,
+        dest: &Path,
+    ) -> Result<Resolution> {
+        self.stats.lock().unwrap().conflicts_total += 1;
+        let strategy = self.config.conflict_strategy;
+        let resolution = match strategy {
+            ConflictStrategy::PreferSource => Resolution::UseSource,
+            ConflictStrategy::PreferDest => Resolution::UseDest,
+            ConflictStrategy::PreferNewest => {
+                let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+                if modified(dest) > modified(source) {
+                    Resolution::UseDest
+                } else {
+                    Resolution::UseSource
+                }
+            }
+            ConflictStrategy::Prompt if io::stdin().is_terminal() && !self.porcelain => {
+                println!(
+                    "{} changed in both {} and {} since the last sync",
+                    relative_path.bold(),
+                    source.display(),
+                    dest.display()
+                );
+                match prompt("Keep the [s]ource, the [d]estination, or [r]esolve later?")?.as_str()
+                {
+                    "s" => Resolution::UseSource,
+                    "d" => Resolution::UseDest,
+                    _ => Resolution::Skip,
+                }
+            }
+            ConflictStrategy::Prompt => Resolution::Skip,
+            ConflictStrategy::Abort => Resolution::Abort,
+        };
+        match resolution {
+            Resolution::UseSource if strategy != ConflictStrategy::Prompt => warn!(
+                "{} changed in both source and destination; keeping the source",
+                relative_path
+            ),
+            Resolution::UseDest if strategy != ConflictStrategy::Prompt => warn!(
+                "{} changed in both source and destination; keeping the destination",
+                relative_path
+            ),
+            _ => {}
+        }
+        Ok(resolution)
+    }
+
+    // Writes the merge with conflict markers next to the destination (never
+    // over it) and holds the entry back from syncing until `dotty resolve`
+    pub(crate) fn record_conflict(
+        &self,
+        profile: &str,
+        relative_path: &str,
+        dest: &Path,
+        marked: Option<&[u8]>,
+    ) -> Result<Option<PathBuf>> {
+        let conflict_file = match marked {
+            Some(content) => {
+                let mut name = dest.file_name().unwrap_or_default().to_os_string();
+                name.push(".conflict");
+                let path = dest.with_file_name(name);
+                fs::write(&path, content).context("Failed to write conflict file")?;
+                Some(path)
+            }
+            None => None,
+        };
+        let mut conflicts = Conflicts::load()?;
+        conflicts
+            .conflicts
+            .retain(|conflict| conflict.profile != profile || conflict.path != relative_path);
+        conflicts.conflicts.push(Conflict {
+            profile: profile.to_string(),
+            path: relative_path.to_string(),
+            conflict_file: conflict_file.clone(),
+            detected_at: unix_seconds(SystemTime::now()),
+        });
+        conflicts.save()?;
+        Ok(conflict_file)
+    }
+
+    /// Resolves a recorded conflict by keeping one side or editing the merge
+    pub fn resolve(&mut self, path: &Path, take: Take, profile: Option<String>) -> Result<()> {
+        let profile = profile.unwrap_or_else(|| self.current_profile.clone());
+        let key = self.tracked_key(path)?;
+        let mut conflicts = Conflicts::load()?;
+        let Some(position) = conflicts
+            .conflicts
+            .iter()
+            .position(|conflict| conflict.profile == profile && conflict.path == key)
+        else {
+            anyhow::bail!("{} has no unresolved conflict in profile {}", key, profile);
+        };
+        let profile_config = self
+            .config
+            .profiles
+            .get(&profile)
+            .with_context(|| profile_not_found(&profile))?;
+        let entry = profile_config
+            .files
+            .get(&key)
+            .with_context(|| format!("{} is not tracked in profile {}", key, profile))?;
+        let source = Path::new(entry.source());
+        let dest = profile_config.destination(
+            &dirs::home_dir().context("Failed to get home directory")?,
+            &key,
+        );
+        let conflict_file = conflicts.conflicts[position].conflict_file.clone();
+
+        match take {
+            Take::Remote => {}
+            // Copying a file onto itself would truncate it
+            Take::Local if is_same_file(source, &dest) => {}
+            Take::Local => {
+                self.backup_file(source)?;
+                fs::copy(&dest, source).context("Failed to copy destination")?;
+            }
+            Take::Edit => {
+                let conflict_file = conflict_file
+                    .as_deref()
+                    .filter(|path| path.exists())
+                    .context("No conflict file to edit; take local or remote instead")?;
+                run_editor(conflict_file)?;
+                let content = fs::read(conflict_file).context("Failed to read conflict file")?;
+                let has_markers = String::from_utf8_lossy(&content).lines().any(|line| {
+                    line.starts_with("<<<<<<<") || line.starts_with(">>>>>>>") || line == "======="
+                });
+                if has_markers {
+                    anyhow::bail!(
+                        "{} still contains conflict markers",
+                        conflict_file.display()
+                    );
+                }
+                self.backup_file(source)?;
+                fs::write(source, content).context("Failed to write resolved file")?;
+            }
+        }
+
+        // With both sides equal, the resumed sync no longer sees a conflict
+        if !matches!(take, Take::Local) && !is_same_file(source, &dest) {
+            self.backup_file(&dest)?;
+            fs::copy(source, &dest).context("Failed to update destination")?;
+        }
+        if let Some(conflict_file) = conflict_file.filter(|path| path.exists()) {
+            fs::remove_file(conflict_file).context("Failed to remove conflict file")?;
+        }
+        conflicts.conflicts.remove(position);
+        conflicts.save()?;
+        println!("Resolved {}", key);
+
+        let files = HashSet::from([key]);
+        self.sync(Some(profile), Some(&files), SyncTrigger::Manual)
+    }
+
+    pub(crate) fn install_destination(
+        &self,
+        source: &Path,
+        dest: &Path,
+        origin: &str,
+        mode: LinkMode,
+        line_ending: Option<LineEnding>,
+        profile_config: &ProfileConfig,
+    ) -> Result<()> {
+        if dest == source {
+            return Ok(());
+        }
+        match self.install_destination_as_user(
+            source,
+            dest,
+            origin,
+            mode,
+            line_ending,
+            profile_config,
+        ) {
+            Err(e) if needs_elevation(dest, &e) => {
+                info!(
+                    "{:?} is not writable; retrying with elevated permissions",
+                    dest
+                );
+                // cp can't render a template, so it copies the rendering
+                let staged = match self.rendered(source, profile_config)? {
+                    Some(rendered) => {
+                        let path = state_dir()?.join("rendered.tmp");
+                        fs::write(&path, rendered).context("Failed to write rendered template")?;
+                        Some(path)
+                    }
+                    None => None,
+                };
+                let result = match &staged {
+                    Some(staged) => self.write_destination(dest, origin, || {
+                        self.install_elevated(staged, dest, LinkMode::Copy)
+                    }),
+                    None => self.write_destination(dest, origin, || {
+                        self.install_elevated(source, dest, mode)
+                    }),
+                };
+                if let Some(staged) = staged {
+                    let _ = fs::remove_file(staged);
+                }
+                result
+            }
+            result => result,
+        }
+    }
+
+    pub(crate) fn install_destination_as_user(
+        &self,
+        source: &Path,
+        dest: &Path,
+        origin: &str,
+        mode: LinkMode,
+        line_ending: Option<LineEnding>,
+        profile_config: &ProfileConfig,
+    ) -> Result<()> {
+        fs::create_dir_all(dest.parent().unwrap())
+            .context("Failed to create parent directories")?;
+        let replace_dest = || -> Result<()> {
+            if dest.is_symlink() {
+                remove_link(dest).context("Failed to replace destination")?;
+            } else if dest.exists() {
+                fs::remove_file(dest).context("Failed to replace destination")?;
+            }
+            Ok(())
+        };
+        // Templates are rendered, so they're written out whatever the mode
+        if let Some(rendered) = self.rendered(source, profile_config)? {
+            self.write_destination(dest, origin, || {
+                replace_dest()?;
+                fs::write(dest, &rendered).context("Failed to write destination")?;
+                if let Some(line_ending) = line_ending {
+                    convert_line_endings(dest, line_ending.is_crlf())?;
+                }
+                self.sync_permissions(source, dest)
+            })?;
+            info!("Rendered: {:?}", dest);
+            return Ok(());
+        }
+        match mode {
+            LinkMode::Symlink => {
+                if dest.is_symlink() && fs::read_link(dest)? == source {
+                    return Ok(());
+                }
+                self.write_destination(dest, origin, || {
+                    replace_dest()?;
+                    create_symlink(source, dest)
+                })?;
+                info!("Created symlink: {:?} -> {:?}", dest, source);
+            }
+            // Directories can't be hardlinked, so they are copied
+            LinkMode::Hardlink if source.is_file() => {
+                if is_same_file(source, dest) {
+                    return Ok(());
+                }
+                self.write_destination(dest, origin, || {
+                    replace_dest()?;
+                    match fs::hard_link(source, dest) {
+                        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                            info!("{:?} is on another filesystem; copying instead", dest);
+                            self.copy_entry(source, dest, profile_config)?;
+                            self.sync_permissions(source, dest)
+                        }
+                        result => result.context("Failed to create hardlink"),
+                    }
+                })?;
+                info!("Created hardlink: {:?} -> {:?}", dest, source);
+            }
+            // Links share the source's bytes, so only copies can be translated
+            LinkMode::Copy | LinkMode::Hardlink => {
+                self.write_destination(dest, origin, || {
+                    self.copy_entry(source, dest, profile_config)?;
+                    if let Some(line_ending) = line_ending {
+                        convert_line_endings(dest, line_ending.is_crlf())?;
+                    }
+                    self.sync_permissions(source, dest)
+                })?;
+                info!("Synced: {:?}", dest);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn install_elevated(
+        &self,
+        source: &Path,
+        dest: &Path,
+        mode: LinkMode,
+    ) -> Result<()> {
+        let parent = dest.parent().unwrap();
+        self.run_elevated(&[OsStr::new("mkdir"), OsStr::new("-p"), parent.as_os_str()])?;
+        match mode {
+            LinkMode::Symlink => self.run_elevated(&[
+                OsStr::new("ln"),
+                OsStr::new("-sfn"),
+                source.as_os_str(),
+                dest.as_os_str(),
+            ]),
+            LinkMode::Hardlink if source.is_file() => self.run_elevated(&[
+                OsStr::new("ln"),
+                OsStr::new("-f"),
+                source.as_os_str(),
+                dest.as_os_str(),
+            ]),
+            LinkMode::Copy | LinkMode::Hardlink => {
+                if source.is_dir() {
+                    self.run_elevated(&[OsStr::new("rm"), OsStr::new("-rf"), dest.as_os_str()])?;
+                }
+                self.run_elevated(&[
+                    OsStr::new("cp"),
+                    OsStr::new("-R"),
+                    source.as_os_str(),
+                    dest.as_os_str(),
+                ])
+            }
+        }
+    }
+
+    pub(crate) fn run_elevated(&self, args: &[&OsStr]) -> Result<()> {
+        let program = self.config.elevate_command.as_deref().unwrap_or("sudo");
+        let status = process::Command::new(program)
+            .args(args)
+            .status()
+            .with_context(|| format!("Failed to run {}", program))?;
+        if !status.success() {
+            let command: Vec<_> = args.iter().map(|arg| arg.to_string_lossy()).collect();
+            anyhow::bail!("`{} {}` failed", program, command.join(" "));
+        }
+        Ok(())
+    }
+
+    // Like write_git_object, but stages the content and installs it with
+    // elevated permissions when the target is an unwritable system file
+    pub(crate) fn write_object(
+        &self,
+        repo: &Repository,
+        object: &git2::Object,
+        dest: &Path,
+    ) -> Result<()> {
+        match write_git_object(repo, object, dest).and_then(|()| self.smudge_lfs(dest)) {
+            Err(e) if needs_elevation(dest, &e) => {
+                let staging = state_dir()?.join("staging");
+                if staging.is_dir() {
+                    fs::remove_dir_all(&staging).context("Failed to clear staging area")?;
+                }
+                write_git_object(repo, object, &staging)?;
+                self.smudge_lfs(&staging)?;
+                let result = self.install_elevated(&staging, dest, LinkMode::Copy);
+                if staging.is_dir() {
+                    fs::remove_dir_all(&staging)
+                } else {
+                    fs::remove_file(&staging)
+                }
+                .context("Failed to clear staging area")?;
+                result
+            }
+            result => result,
+        }
+    }
+
+    // Like write_object, for content dotty generated such as a template's
+    // rendering
+    pub(crate) fn write_content(&self, content: &[u8], dest: &Path) -> Result<()> {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).context("Failed to create parent directories")?;
+        }
+        match fs::write(dest, content).with_context(|| format!("Failed to write {:?}", dest)) {
+            Err(e) if needs_elevation(dest, &e) => {
+                let staging = state_dir()?.join("staging");
+                fs::write(&staging, content).context("Failed to write staging file")?;
+                let result = self.install_elevated(&staging, dest, LinkMode::Copy);
+                fs::remove_file(&staging).context("Failed to clear staging area")?;
+                result
+            }
+            result => result,
+        }
+    }
+
+    // Records the expected state of each destination just written, so that
+    // `dotty verify` can later detect drift. Entries in `held` weren't
+    // written and keep their previous state.
+    pub(crate) fn update_manifest(
+        &self,
+        profile: &str,
+        files: Option<&HashSet<String>>,
+        held: &[String],
+    ) -> Result<()> {
+        let profile_config = self
+            .config
+            .profiles
+            .get(profile)
+            .with_context(|| profile_not_found(profile))?;
+        let home = dirs::home_dir().context("Failed to get home directory")?;
+
+        let previous = Manifest::load(profile)?.unwrap_or_default();
+        let mut manifest = Manifest::default();
+        if files.is_some() {
+            manifest.entries = previous.entries.clone();
+        }
+        manifest.profile = profile.to_string();
+        manifest.generated_at = unix_seconds(SystemTime::now());
+        for relative_path in profile_config.files.keys() {
+            if files.is_some_and(|files| !files.contains(relative_path)) {
+                continue;
+            }
+            if held.contains(relative_path) {
+                match previous.entries.get(relative_path) {
+                    Some(entry) => manifest
+                        .entries
+                        .insert(relative_path.clone(), entry.clone()),
+                    None => manifest.entries.remove(relative_path),
+                };
+                continue;
+            }
+            let dest = profile_config.destination(&home, relative_path);
+            match hash_path(&dest)? {
+                Some(hash) => {
+                    manifest.entries.insert(
+                        relative_path.clone(),
+                        ManifestEntry {
+                            hash,
+                            mode: file_mode(&dest),
+                        },
+                    );
+                }
+                None => {
+                    manifest.entries.remove(relative_path);
+                }
+            }
+        }
+        manifest
+            .entries
+            .retain(|relative_path, _| profile_config.files.contains_key(relative_path));
+        manifest.save()
+    }
+
+    // Performs a destination write, recording it in the audit log when the
+    // content actually changed and auditing is enabled
+    pub(crate) fn write_destination<F>(&self, dest: &Path, origin: &str, write: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        if !self.config.audit {
+            return write();
+        }
+        let old_hash = hash_path(dest)?;
+        write()?;
+        let new_hash = hash_path(dest)?;
+        if old_hash != new_hash {
+            AuditEntry::append(dest, origin, old_hash, new_hash)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn enforce_permissions(&self, dest: &Path, permissions: u32) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        if file_mode(dest) == Some(permissions) {
+            return Ok(());
+        }
+        match fs::set_permissions(dest, fs::Permissions::from_mode(permissions))
+            .with_context(|| format!("Failed to set permissions on {}", dest.display()))
+        {
+            Err(e) if needs_elevation(dest, &e) => self.run_elevated(&[
+                OsStr::new("chmod"),
+                OsStr::new(&format!("{:o}", permissions)),
+                dest.as_os_str(),
+            ]),
+            result => result,
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn enforce_permissions(&self, _dest: &Path, _permissions: u32) -> Result<()> {
+        Ok(())
+    }
+
+    pub(crate) fn enforce_ownership(&self, dest: &Path, entry: &FileEntry) -> Result<()> {
+        let (owner, group) = entry.ownership();
+        if owner.is_none() && group.is_none() {
+            return Ok(());
+        }
+        if ownership_mismatch(dest, owner, group)?.is_none() {
+            return Ok(());
+        }
+        match chown(dest, owner, group) {
+            Err(e) if needs_elevation(dest, &e) => {
+                let spec = match (owner, group) {
+                    (Some(owner), Some(group)) => format!("{}:{}", owner, group),
+                    (Some(owner), None) => owner.to_string(),
+                    (None, group) => format!(":{}", group.unwrap_or_default()),
+                };
+                self.run_elevated(&[OsStr::new("chown"), OsStr::new(&spec), dest.as_os_str()])
+            }
+            result => result,
+        }
+    }
+
+    // Only Unix modes are carried over; Windows has nothing but a read-only
+    // flag, which would just stop the next sync from overwriting the file
+    #[cfg(not(unix))]
+    pub(crate) fn sync_permissions(&self, _source: &Path, _dest: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn sync_permissions(&self, source: &Path, dest: &Path) -> Result<()> {
+        let metadata = fs::metadata(source).context("Failed to get source file metadata")?;
+        let permissions = metadata.permissions();
+        fs::set_permissions(dest, permissions)
+            .context("Failed to set permissions on destination file")?;
+        Ok(())
+    }
+
+    // Copies a tracked file, or the non-ignored contents of a tracked directory
+    pub(crate) fn copy_entry(
+        &self,
+        source: &Path,
+        dest: &Path,
+        profile_config: &ProfileConfig,
+    ) -> Result<()> {
+        // Copying a file onto itself truncates it
+        if dest.exists() && source.canonicalize()? == dest.canonicalize()? {
+            return Ok(());
+        }
+        if !source.is_dir() {
+            fs::copy(source, dest).context("Failed to copy file")?;
+            if profile_config.preserve_xattrs {
+                copy_xattrs(source, dest)?;
+            }
+            return Ok(());
+        }
+
+        let ignore_rules = self.ignore_rules(source, profile_config);
+        let into_repo = dest.starts_with(self.repo_path()?);
+        let walker = WalkBuilder::new(source)
+            .hidden(false)
+            .git_ignore(true)
+            // Nested repositories are pinned in NESTED_REPOS_PATH rather than
+            // copied, and their git internals are never copied anywhere
+            .filter_entry(move |entry| {
+                entry.file_name() != ".git"
+                    && !(into_repo && entry.depth() > 0 && nested_repo(entry.path()).is_some())
+            })
+            .build();
+        for result in walker {
+            let entry = match result {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Error walking directory: {}", e);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            if !path.is_file() || is_editor_temp_file(path) || ignore_rules.is_ignored(path) {
+                continue;
+            }
+            if self.too_large(path, profile_config.entry_for_source(source)) {
+                warn!("Skipped {:?}: larger than max_file_size", path);
+                continue;
+            }
+            let target = dest.join(path.strip_prefix(source)?);
+            fs::create_dir_all(target.parent().unwrap())
+                .context("Failed to create parent directories")?;
+            fs::copy(path, &target).context("Failed to copy file")?;
+            if profile_config.preserve_xattrs {
+                copy_xattrs(path, &target)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn too_large(&self, path: &Path, entry: Option<&FileEntry>) -> bool {
+        let limit = self.config.max_file_size;
+        if limit == 0 || entry.is_some_and(FileEntry::allow_large) {
+            return false;
+        }
+        fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.len() > limit)
+    }
+
+    pub(crate) fn should_sync(&self, source: &Path, profile_config: &ProfileConfig) -> bool {
+        !self.is_ignored(source, source, profile_config)
+    }
+
+    pub(crate) fn sync_with_github(
+        &self,
+        profile: &str,
+        files: Option<&HashSet<String>>,
+    ) -> Result<SyncOutcome> {
+        let repo_path = self.repo_path()?;
+        let repo = self.open_repo()?;
+
+        // Copy files to the repo
+        let conflicts = Conflicts::load()?;
+        let in_use = self.tracked_repo_keys(false);
+        let mut migrated = Vec::new();
+        let mut selected = Vec::new();
+        for (profile, profile_config) in &self.config.profiles {
+            for (relative_path, entry) in &profile_config.files {
+                if files.is_some_and(|files| !files.contains(relative_path))
+                    || conflicts.contains(profile, relative_path)
+                {
+                    continue;
+                }
+                let source = Path::new(entry.source());
+                let repo_key = self
+                    .config
+                    .overlay_key(profile, relative_path, |path| repo_path.join(path).exists());
+                let dest = repo_path.join(&repo_key);
+                // Moving a copy left by the flat layout keeps its history
+                // connected
+                let flat = repo_path.join(relative_path);
+                if !dest.exists() && flat.exists() && !in_use.contains(relative_path) {
+                    fs::create_dir_all(dest.parent().unwrap())
+                        .context("Failed to create parent directories")?;
+                    fs::rename(&flat, &dest).context("Failed to move repository copy")?;
+                    migrated.push(relative_path.clone());
+                }
+                selected.push(repo_key);
+
+                if source.exists() && !self.too_large(source, Some(entry)) {
+                    fs::create_dir_all(dest.parent().unwrap())
+                        .context("Failed to create parent directories")?;
+                    self.copy_entry(source, &dest, profile_config)?;
+                    if profile_config.line_ending(relative_path).is_some() {
+                        convert_line_endings(&dest, false)?;
+                    }
+                }
+            }
+        }
+
+        self.record_nested_repos(&repo_path, files)?;
+        self.configure_lfs(&repo_path)?;
+        let config_path = repo_path.join(CONFIG_PATH);
+        fs::create_dir_all(config_path.parent().unwrap())
+            .context("Failed to create parent directories")?;
+        fs::write(config_path, self.portable_config()?).context("Failed to write stored config")?;
+
+        // Commit and push changes
+        let lfs_keys = self.lfs_keys();
+        let mut index = repo.index().context("Failed to get repo index")?;
+        if !migrated.is_empty() {
+            index
+                .remove_all(migrated.iter(), None)
+                .context("Failed to remove file from index")?;
+        }
+        match files {
+            Some(_) => {
+                index
+                    .add_path(Path::new(CONFIG_PATH))
+                    .context("Failed to add stored config to index")?;
+                if repo_path.join(NESTED_REPOS_PATH).exists() {
+                    index
+                        .add_path(Path::new(NESTED_REPOS_PATH))
+                        .context("Failed to add nested repos to index")?;
+                }
+                for repo_key in &selected {
+                    if lfs_keys.contains(repo_key) {
+                        continue;
+                    }
+                    // e.g. a file over max_file_size that was never committed
+                    if !repo_path.join(repo_key).exists() {
+                        continue;
+                    }
+                    if repo_path.join(repo_key).is_dir() {
+                        index
+                            .add_all([repo_key].iter(), git2::IndexAddOption::DEFAULT, None)
+                            .context("Failed to add files to index")?;
+                    } else {
+                        index
+                            .add_path(Path::new(repo_key))
+                            .context("Failed to add file to index")?;
+                    }
+                }
+            }
+            None => index
+                .add_all(
+                    ["*"].iter(),
+                    git2::IndexAddOption::DEFAULT,
+                    Some(&mut |path: &Path, _: &[u8]| {
+                        let lfs = lfs_keys.iter().any(|key| path.starts_with(key));
+                        i32::from(lfs)
+                    }),
+                )
+                .context("Failed to add files to index")?,
+        }
+        index.write().context("Failed to write index")?;
+
+        // libgit2 doesn't run the LFS clean filter, so let git stage the
+        // entries stored in LFS (and their attributes) as pointers
+        if !lfs_keys.is_empty() {
+            let mut args = vec!["add", "--", ".gitattributes"];
+            args.extend(
+                lfs_keys
+                    .iter()
+                    .filter(|key| {
+                        (files.is_none() || selected.contains(key)) && repo_path.join(key).exists()
+                    })
+                    .map(String::as_str),
+            );
+            self.run_git(&args)?;
+            index.read(true).context("Failed to reload index")?;
+        }
+
+        let message = match files {
+            Some(files) => {
+                let mut names: Vec<&str> = files.iter().map(String::as_str).collect();
+                names.sort_unstable();
+                format!("Sync dotfiles: {}", names.join(", "))
+            }
+            None => "Sync dotfiles".to_string(),
+        };
+        let head_tree = repo.head()?.peel_to_tree()?;
+        let changed_files = repo
+            .diff_tree_to_index(Some(&head_tree), Some(&index), None)
+            .context("Failed to diff index")?
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        // A sync that changes nothing commits nothing; it only pushes a
+        // commit an earlier sync couldn't
+        if changed_files.is_empty() {
+            let upstream = repo
+                .refname_to_id(&format!("refs/remotes/origin/{}", self.branch()))
+                .ok();
+            if upstream != Some(repo.head()?.peel_to_commit()?.id()) {
+                self.push_branch(&repo)?;
+                self.event("push", None, "ok", Some(self.branch()));
+            }
+            info!("Nothing to sync");
+            return Ok(SyncOutcome {
+                commit: None,
+                changed_files,
+                skipped: Vec::new(),
+                refused: Vec::new(),
+                overwritten: Vec::new(),
+            });
+        }
+        // The registry only moves along with a change worth committing
+        Machines::record(&repo_path.join(MACHINES_PATH), profile)?;
+        index
+            .add_path(Path::new(MACHINES_PATH))
+            .context("Failed to add machine registry to index")?;
+        index.write().context("Failed to write index")?;
+        let commit = self.commit_index(&repo, &mut index, &message)?;
+        self.event("commit", None, "ok", Some(commit.to_string()));
+
+        self.push_branch(&repo)?;
+        self.event("push", None, "ok", Some(self.branch()));
+        if let Err(e) = self.publish_shared(&repo) {
+            warn!("Failed to update the shared branch: {:#}", e);
+        }
+
+        let orphans = self.orphaned_paths(&repo)?;
+        if !orphans.is_empty() {
+            warn!(
+                "{} orphaned file(s) in the repository; run `dotty clean --repo` to remove them",
+                orphans.len()
+            );
+        }
+
+        info!("Synced with GitHub repository");
+        Ok(SyncOutcome {
+            commit: Some(commit.to_string()),
+            changed_files,
+            skipped: Vec::new(),
+            refused: Vec::new(),
+            overwritten: Vec::new(),
+        })
+    }
+
+    // Maps a watcher event to the tracked entries it touches, or None when the
+    // event can't be attributed (e.g. a rescan) and a full sync is needed.
+    pub(crate) fn changed_entries(
+        &self,
+        profile: &str,
+        event: &DebouncedEvent,
+    ) -> Option<Vec<String>> {
+        let paths = match event {
+            DebouncedEvent::NoticeWrite(path)
+            | DebouncedEvent::NoticeRemove(path)
+            | DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Chmod(path)
+            | DebouncedEvent::Remove(path) => vec![path],
+            DebouncedEvent::Rename(from, to) => vec![from, to],
+            DebouncedEvent::Rescan | DebouncedEvent::Error(..) => return None,
+        };
+        let profile_config = self.config.profiles.get(profile)?;
+
+        let mut entries = Vec::new();
+        for path in paths {
+            if is_editor_temp_file(path) {
+                continue;
+            }
+            let (relative_path, source) = profile_config
+                .sources()
+                .find(|(_, source)| path.starts_with(source))?;
+            if !self.is_ignored(Path::new(source), path, profile_config) {
+                entries.push(relative_path.clone());
+            }
+        }
+        Some(entries)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Conflict {
+    pub(crate) profile: String,
+    pub(crate) path: String,
+    pub(crate) conflict_file: Option<PathBuf>,
+    pub(crate) detected_at: u64,
+}
+
+// Entries held back from syncing until `dotty resolve`
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Conflicts {
+    #[serde(default)]
+    pub(crate) conflicts: Vec<Conflict>,
+}
+
+impl Conflicts {
+    pub(crate) fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("conflicts.toml"))
+    }
+
+    pub(crate) fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let conflicts_str = fs::read_to_string(&path).context("Failed to read conflicts")?;
+        toml::from_str(&conflicts_str).context("Failed to parse conflicts")
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let conflicts_str =
+            toml::to_string_pretty(self).context("Failed to serialize conflicts")?;
+        fs::write(Self::path()?, conflicts_str).context("Failed to write conflicts")?;
+        Ok(())
+    }
+
+    pub(crate) fn contains(&self, profile: &str, path: &str) -> bool {
+        self.conflicts
+            .iter()
+            .any(|conflict| conflict.profile == profile && conflict.path == path)
+    }
+
+    // Forgets a conflict without resolving it, along with its conflict file
+    pub(crate) fn remove(&mut self, profile: &str, path: &str) -> Result<()> {
+        for conflict in &self.conflicts {
+            if conflict.profile == profile && conflict.path == path {
+                if let Some(conflict_file) = conflict.conflict_file.as_ref().filter(|f| f.exists())
+                {
+                    fs::remove_file(conflict_file).context("Failed to remove conflict file")?;
+                }
+            }
+        }
+        self.conflicts
+            .retain(|conflict| conflict.profile != profile || conflict.path != path);
+        self.save()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct ManifestEntry {
+    pub(crate) hash: String,
+    pub(crate) mode: Option<u32>,
+}
+
+// Expected destination state per profile, signed with a machine-local HMAC key
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Manifest {
+    pub(crate) profile: String,
+    pub(crate) generated_at: u64,
+    pub(crate) entries: HashMap<String, ManifestEntry>,
+    #[serde(default)]
+    pub(crate) signature: String,
+}
+
+impl Manifest {
+    pub(crate) fn path(profile: &str) -> Result<PathBuf> {
+        Ok(state_dir()?.join(format!("manifest-{}.json", profile)))
+    }
+
+    pub(crate) fn key() -> Result<Vec<u8>> {
+        let path = state_dir()?.join("manifest.key");
+        if path.exists() {
+            return fs::read(&path).context("Failed to read manifest key");
+        }
+        let mut key = vec![0; 32];
+        openssl::rand::rand_bytes(&mut key).context("Failed to generate manifest key")?;
+        fs::write(&path, &key).context("Failed to write manifest key")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+                .context("Failed to restrict manifest key permissions")?;
+        }
+        Ok(key)
+    }
+
+    pub(crate) fn compute_signature(&self) -> Result<String> {
+        let mut relative_paths: Vec<&String> = self.entries.keys().collect();
+        relative_paths.sort();
+        let mut payload = format!("{}|{}", self.profile, self.generated_at);
+        for relative_path in relative_paths {
+            let entry = &self.entries[relative_path];
+            payload.push_str(&format!(
+                "|{}={}:{}",
+                relative_path,
+                entry.hash,
+                entry.mode.map(|mode| mode.to_string()).unwrap_or_default()
+            ));
+        }
+        let key = openssl::pkey::PKey::hmac(&Self::key()?)?;
+        let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)?;
+        signer.update(payload.as_bytes())?;
+        Ok(hex(&signer.sign_to_vec()?))
+    }
+
+    pub(crate) fn signature_valid(&self) -> Result<bool> {
+        let expected = self.compute_signature()?;
+        // memcmp::eq panics on inputs of different lengths
+        Ok(expected.len() == self.signature.len()
+            && openssl::memcmp::eq(expected.as_bytes(), self.signature.as_bytes()))
+    }
+
+    pub(crate) fn load(profile: &str) -> Result<Option<Self>> {
+        let path = Self::path(profile)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let manifest_str = fs::read_to_string(&path).context("Failed to read manifest")?;
+        Ok(Some(
+            serde_json::from_str(&manifest_str).context("Failed to parse manifest")?,
+        ))
+    }
+
+    pub(crate) fn save(&mut self) -> Result<()> {
+        self.signature = self.compute_signature()?;
+        let manifest_str = serde_json::to_string_pretty(self)?;
+        fs::write(Self::path(&self.profile)?, manifest_str).context("Failed to write manifest")?;
+        Ok(())
+    }
+}
+
+// One line of audit.jsonl. Each entry's hash is an HMAC, keyed like the
+// manifest signature, over its own fields and the previous entry's hash, so
+// editing or dropping a line breaks the chain and can't be re-hashed without
+// the key.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AuditEntry {
+    pub(crate) seq: u64,
+    pub(crate) timestamp: u64,
+    pub(crate) path: String,
+    pub(crate) origin: String,
+    pub(crate) old_hash: Option<String>,
+    pub(crate) new_hash: Option<String>,
+    pub(crate) command: String,
+    pub(crate) user: String,
+    pub(crate) prev_hash: String,
+    pub(crate) hash: String,
+}
+
+impl AuditEntry {
+    pub(crate) fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("audit.jsonl"))
+    }
+
+    pub(crate) fn compute_hash(&self, key: &[u8]) -> Result<String> {
+        let payload = format!(
+            "{}|{}|{}|{}|{}|{}|{}|{}|{}",
+            self.seq,
+            self.timestamp,
+            self.path,
+            self.origin,
+            self.old_hash.as_deref().unwrap_or_default(),
+            self.new_hash.as_deref().unwrap_or_default(),
+            self.command,
+            self.user,
+            self.prev_hash
+        );
+        let key = openssl::pkey::PKey::hmac(key)?;
+        let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)?;
+        signer.update(payload.as_bytes())?;
+        Ok(hex(&signer.sign_to_vec()?))
+    }
+
+    pub(crate) fn load_all() -> Result<Vec<Self>> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let log = fs::read_to_string(&path).context("Failed to read audit log")?;
+        log.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).context("Failed to parse audit log"))
+            .collect()
+    }
+
+    pub(crate) fn append(
+        dest: &Path,
+        origin: &str,
+        old_hash: Option<String>,
+        new_hash: Option<String>,
+    ) -> Result<()> {
+        let entries = Self::load_all()?;
+        let last = entries.last();
+        let mut entry = AuditEntry {
+            seq: last.map_or(1, |last| last.seq + 1),
+            timestamp: unix_seconds(SystemTime::now()),
+            path: dest.to_string_lossy().into_owned(),
+            origin: origin.to_string(),
+            old_hash,
+            new_hash,
+            command: env::args().collect::<Vec<_>>().join(" "),
+            user: env::var("USER")
+                .or_else(|_| env::var("USERNAME"))
+                .unwrap_or_else(|_| "unknown".to_string()),
+            prev_hash: last.map_or_else(String::new, |last| last.hash.clone()),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash(&Manifest::key()?)?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::path()?)
+            .context("Failed to open audit log")?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)
+            .context("Failed to write audit log")?;
+        Ok(())
+    }
+
+    // Returns the sequence number of the first entry that fails verification
+    pub(crate) fn verify_chain(entries: &[Self]) -> Result<std::result::Result<(), u64>> {
+        let key = Manifest::key()?;
+        let mut prev_hash = String::new();
+        for entry in entries {
+            let expected = entry.compute_hash(&key)?;
+            // memcmp::eq panics on inputs of different lengths
+            if entry.prev_hash != prev_hash
+                || expected.len() != entry.hash.len()
+                || !openssl::memcmp::eq(expected.as_bytes(), entry.hash.as_bytes())
+            {
+                return Ok(Err(entry.seq));
+            }
+            prev_hash = entry.hash.clone();
+        }
+        Ok(Ok(()))
+    }
+}
+
+/// Version of the `--porcelain` record format, raised on incompatible changes
+pub const PORCELAIN_VERSION: u32 = 1;
+
+/// One line of `dotty sync --porcelain` output. `action` is one of install,
+/// merge, conflict, skip, remove, commit, push and finally sync; `result`
+/// depends on the action (ok, skipped, conflict, error, ...).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PorcelainRecord {
+    pub version: u32,
+    pub action: String,
+    /// The entry's key, for per-file actions
+    pub path: Option<String>,
+    pub result: String,
+    /// A commit id, branch, conflict file or error message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Dotty {
+    /// Makes syncs print a [`PorcelainRecord`] per line on stdout for each
+    /// thing they do, and nothing else there; diffs and prompts are skipped
+    pub fn set_porcelain(&mut self, porcelain: bool) {
+        self.porcelain = porcelain;
+    }
+
+    /// Makes syncs overwrite destinations instead of skipping them: local
+    /// edits, files dotty didn't write and conflicts all give way to the
+    /// source, after a backup
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+
+    pub(crate) fn event(
+        &self,
+        action: &str,
+        path: Option<&str>,
+        result: &str,
+        detail: Option<String>,
+    ) {
+        if !self.porcelain {
+            return;
+        }
+        let record = PorcelainRecord {
+            version: PORCELAIN_VERSION,
+            action: action.to_string(),
+            path: path.map(str::to_string),
+            result: result.to_string(),
+            detail,
+        };
+        if let Ok(line) = serde_json::to_string(&record) {
+            let mut stdout = io::stdout().lock();
+            // Scripts read records as they happen
+            let _ = writeln!(stdout, "{}", line).and_then(|()| stdout.flush());
+        }
+    }
+}
+
+// Why writing `source` over `dest` would lose something the repository
+// doesn't have: an edit to the destination since the last sync, or a file
+// dotty never wrote. None when the destination is missing or already current.
+fn unsafe_overwrite(
+    manifest: Option<&Manifest>,
+    relative_path: &str,
+    source: &Path,
+    dest: &Path,
+    mode: LinkMode,
+    rendered: Option<&[u8]>,
+) -> Result<Option<&'static str>> {
+    if dest == source || !dest.exists() && !dest.is_symlink() {
+        return Ok(None);
+    }
+    let linked = match mode {
+        LinkMode::Symlink => fs::read_link(dest).is_ok_and(|target| target == source),
+        _ => !dest.is_symlink() && is_same_file(source, dest),
+    };
+    if linked {
+        return Ok(None);
+    }
+    let hash = hash_path(dest)?;
+    Ok(
+        match manifest.and_then(|manifest| manifest.entries.get(relative_path)) {
+            Some(entry) if hash.as_ref() == Some(&entry.hash) => None,
+            Some(_) => Some("changed since the last sync"),
+            None if hash.is_some()
+                && hash
+                    == match rendered {
+                        Some(rendered) => Some(hex(&openssl::sha::sha256(rendered))),
+                        None => hash_path(source)?,
+                    } =>
+            {
+                None
+            }
+            None => Some("exists but wasn't written by dotty"),
+        },
+    )
+}
+
+// Succeeds unless entries were skipped, which scripts see as a partial sync
+fn partial_sync(skipped: &[String], refused: &[String]) -> Result<()> {
+    if skipped.is_empty() && refused.is_empty() {
+        return Ok(());
+    }
+    let list = |names: &[String]| {
+        let mut names = names.to_vec();
+        names.sort_unstable();
+        format!("{} ({})", names.join(", "), names.len())
+    };
+    let mut reasons = Vec::new();
+    let mut hints = Vec::new();
+    if !skipped.is_empty() {
+        reasons.push(format!("conflicts in {}", list(skipped)));
+        hints.push("run `dotty resolve` (or merge the .conflict files by hand)");
+    }
+    if !refused.is_empty() {
+        reasons.push(format!("local changes in {}", list(refused)));
+        hints.push("copy changes worth keeping into the source, or overwrite with `--force`");
+    }
+    Err(DottyError::new(
+        ErrorKind::PartialSync,
+        format!(
+            "Some files weren't synced because of {}",
+            reasons.join(" and ")
+        ),
+    )
+    .hint(format!("{}, then sync again", hints.join("; ")))
+    .into())
+}
+
+pub(crate) struct SyncOutcome {
+    pub(crate) commit: Option<String>,
+    pub(crate) changed_files: Vec<String>,
+    // Entries left alone because of conflicts
+    pub(crate) skipped: Vec<String>,
+    // Entries whose destinations had changes a sync would have overwritten
+    pub(crate) refused: Vec<String>,
+    // Entries written with --force, and where the previous destination went
+    pub(crate) overwritten: Vec<(String, PathBuf)>,
+}
+
+/// What started a sync, as recorded in the journal
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncTrigger {
+    Manual,
+    Watch,
+    Schedule,
+}
+
+impl SyncTrigger {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            SyncTrigger::Manual => "manual",
+            SyncTrigger::Watch => "watch",
+            SyncTrigger::Schedule => "schedule",
+        }
+    }
+}
+
+// One line of journal.jsonl, appended after every sync run
+#[derive(Serialize, Deserialize)]
+pub(crate) struct JournalEntry {
+    pub(crate) timestamp: u64,
+    pub(crate) trigger: SyncTrigger,
+    pub(crate) profile: String,
+    pub(crate) files_changed: Vec<String>,
+    pub(crate) duration_ms: u64,
+    pub(crate) commit: Option<String>,
+    pub(crate) error: Option<String>,
+}
+
+impl JournalEntry {
+    pub(crate) fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("journal.jsonl"))
+    }
+
+    pub(crate) fn append(&self) -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::path()?)
+            .context("Failed to open sync journal")?;
+        writeln!(file, "{}", serde_json::to_string(self)?)
+            .context("Failed to write sync journal")?;
+        Ok(())
+    }
+
+    pub(crate) fn load_all() -> Result<Vec<Self>> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let journal = fs::read_to_string(&path).context("Failed to read sync journal")?;
+        let mut entries = Vec::new();
+        for line in journal.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => warn!("Skipping malformed journal entry: {}", e),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// Written by `dotty checkout --pin`; while present, sync only commits and
+// pushes the sources and pull refuses to run, so the destinations stay at the
+// pinned commit.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PinState {
+    pub(crate) commit: String,
+    pub(crate) revision: String,
+}
+
+impl PinState {
+    pub(crate) fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("pin.toml"))
+    }
+
+    pub(crate) fn load() -> Result<Option<Self>> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let state_str = fs::read_to_string(&path).context("Failed to read pin state")?;
+        Ok(Some(
+            toml::from_str(&state_str).context("Failed to parse pin state")?,
+        ))
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let state_str = toml::to_string_pretty(self).context("Failed to serialize pin state")?;
+        fs::write(Self::path()?, state_str).context("Failed to write pin state")?;
+        Ok(())
+    }
+}
+
+/// Lets syncs update destinations again after `dotty checkout --pin`
+pub fn unpin() -> Result<()> {
+    match PinState::load()? {
+        Some(pin) => {
+            fs::remove_file(PinState::path()?).context("Failed to remove pin state")?;
+            println!("Unpinned from {} ({})", pin.revision, pin.commit);
+        }
+        None => println!("Not pinned"),
+    }
+    Ok(())
+}
+
+// Three-way merges text with libgit2. `ours` is the source (labelled
+// remote in conflict markers) and `theirs` the destination (local).
+pub(crate) fn merge_text(
+    repo: &Repository,
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+) -> Result<TextMerge> {
+    if [base, ours, theirs]
+        .iter()
+        .any(|content| content.contains(&0))
+    {
+        return Ok(TextMerge::Binary);
+    }
+    let tree = |content: &[u8]| -> Result<git2::Tree> {
+        let blob = repo.blob(content)?;
+        let mut builder = repo.treebuilder(None)?;
+        builder.insert("file", blob, 0o100644)?;
+        Ok(repo.find_tree(builder.write()?)?)
+    };
+    let mut index = repo
+        .merge_trees(&tree(base)?, &tree(ours)?, &tree(theirs)?, None)
+        .context("Failed to merge")?;
+    if !index.has_conflicts() {
+        let entry = index
+            .get_path(Path::new("file"), 0)
+            .context("Merge produced no result")?;
+        return Ok(TextMerge::Clean(
+            repo.find_blob(entry.id)?.content().to_vec(),
+        ));
+    }
+
+    // Checking out the conflicted index is how libgit2 renders the markers
+    let target = state_dir()?.join("merge");
+    if target.exists() {
+        fs::remove_dir_all(&target).context("Failed to clear merge directory")?;
+    }
+    repo.checkout_index(
+        Some(&mut index),
+        Some(
+            git2::build::CheckoutBuilder::new()
+                .target_dir(&target)
+                .force()
+                .allow_conflicts(true)
+                .conflict_style_merge(true)
+                .our_label("remote")
+                .their_label("local"),
+        ),
+    )
+    .context("Failed to write conflict markers")?;
+    let content = fs::read(target.join("file")).context("Failed to read conflict markers")?;
+    fs::remove_dir_all(&target).context("Failed to clear merge directory")?;
+    Ok(TextMerge::Conflict(content))
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct FreezeState {
+    pub(crate) frozen_at: u64,
+    pub(crate) until: Option<u64>,
+    pub(crate) reason: Option<String>,
+}
+
+impl FreezeState {
+    pub(crate) fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("freeze.toml"))
+    }
+
+    // Returns the active freeze, clearing it first if it has expired
+    pub(crate) fn load() -> Result<Option<Self>> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let state_str = fs::read_to_string(&path).context("Failed to read freeze state")?;
+        let state: FreezeState =
+            toml::from_str(&state_str).context("Failed to parse freeze state")?;
+        if state
+            .until
+            .is_some_and(|until| until <= unix_seconds(SystemTime::now()))
+        {
+            fs::remove_file(&path).context("Failed to remove expired freeze state")?;
+            info!("Freeze expired, syncing resumed");
+            return Ok(None);
+        }
+        Ok(Some(state))
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let state_str = toml::to_string_pretty(self).context("Failed to serialize freeze state")?;
+        fs::write(Self::path()?, state_str).context("Failed to write freeze state")?;
+        Ok(())
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        let mut description = "Syncing is frozen".to_string();
+        if let Some(until) = self.until {
+            let remaining = until.saturating_sub(unix_seconds(SystemTime::now()));
+            description.push_str(&format!(
+                " for another {}",
+                humantime::format_duration(Duration::from_secs(remaining))
+            ));
+        }
+        if let Some(reason) = &self.reason {
+            description.push_str(&format!(" ({})", reason));
+        }
+        description
+    }
+}
+
+/// Pauses syncing, optionally for a limited time
+pub fn freeze(duration: Option<Duration>, reason: Option<String>) -> Result<()> {
+    let now = unix_seconds(SystemTime::now());
+    let state = FreezeState {
+        frozen_at: now,
+        until: duration.map(|duration| now + duration.as_secs()),
+        reason,
+    };
+    state.save()?;
+    println!("{}", state.describe());
+    Ok(())
+}
+
+/// Resumes syncing after [`freeze`]
+pub fn thaw() -> Result<()> {
+    let path = FreezeState::path()?;
+    if path.exists() {
+        fs::remove_file(&path).context("Failed to remove freeze state")?;
+        println!("Syncing resumed");
+    } else {
+        println!("Syncing is not frozen");
+    }
+    Ok(())
+}
+
+pub(crate) const SYNC_LOCK_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Held for the duration of a sync so a watcher, a scheduled job and a manual
+// run never write the same files at once. Released on drop.
+pub(crate) struct SyncLock {
+    pub(crate) path: PathBuf,
+}
+
+impl SyncLock {
+    pub(crate) fn acquire() -> Result<Self> {
+        let path = state_dir()?.join("sync.lock");
+        if let Ok(contents) = fs::read_to_string(&path) {
+            // A lock this old outlived its sync, even if its pid was reused
+            let expired = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > SYNC_LOCK_MAX_AGE);
+            match contents.trim().parse::<u32>() {
+                Ok(pid) if !expired && process_alive(pid) => {
+                    anyhow::bail!("Another sync is already running (pid {})", pid)
+                }
+                _ => {
+                    warn!("Removing stale sync lock: {:?}", path);
+                    fs::remove_file(&path).context("Failed to remove stale sync lock")?;
+                }
+            }
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .context("Failed to create sync lock")?;
+        write!(file, "{}", process::id()).context("Failed to write sync lock")?;
+        Ok(SyncLock { path })
+    }
+}
+
+impl Drop for SyncLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to release sync lock: {}", e);
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn process_alive(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists; EPERM means it does
+    // but belongs to another user
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+pub(crate) fn process_alive(pid: u32) -> bool {
+    // tasklist prints a CSV row for the pid, or an INFO line when no
+    // process has it. If it can't run, the lock's age decides.
+    match process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/FO", "CSV", "/NH"])
+        .output()
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).contains(&format!("\"{}\"", pid)),
+        Err(_) => true,
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn process_alive(_pid: u32) -> bool {
+    true
+}
@@ -0,0 +1,235 @@
+//! Templates: tracked files whose name ends in `.tmpl` are rendered into
+//! their destination, which drops the suffix.
+//!
+//! `{{ name }}` inserts a value and `{{ if name }}`, `{{ if name == "x" }}`
+//! or `{{ if name != "x" }}` with an optional `{{ else }}` and a closing
+//! `{{ end }}` keep text on some machines only. The values are `hostname`,
+//! `os`, `arch`, `profile`, `user`, `home` and `env.NAME`, which is the
+//! profile's `env` entry or else the environment variable (empty if unset).
+//! A tag or condition with a call in it, e.g. `{{ git_email() }}` or
+//! `{{ if is_work() && os() == "macos" }}`, is a script expression (see
+//! [`crate::script`]).
+
+use crate::config::ProfileConfig;
+use crate::script::{Script, Value};
+use crate::util::host_name;
+use crate::Dotty;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::{env, fs};
+
+pub(crate) const TEMPLATE_SUFFIX: &str = ".tmpl";
+
+// Whether a path names a template, i.e. ends in .tmpl after something else
+pub(crate) fn is_template(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.len() > TEMPLATE_SUFFIX.len() && name.ends_with(TEMPLATE_SUFFIX))
+}
+
+/// The values a template can refer to
+#[derive(Clone)]
+pub struct TemplateContext {
+    values: BTreeMap<String, String>,
+    env: BTreeMap<String, String>,
+    script: Option<Arc<Script>>,
+}
+
+impl TemplateContext {
+    /// This machine's values, with `profile` and `env.*` from the given profile
+    pub fn new(profile: &str, profile_config: &ProfileConfig) -> Self {
+        let values = [
+            ("hostname", host_name()),
+            ("os", env::consts::OS.to_string()),
+            ("arch", env::consts::ARCH.to_string()),
+            ("profile", profile.to_string()),
+            (
+                "user",
+                env::var("USER")
+                    .or_else(|_| env::var("USERNAME"))
+                    .unwrap_or_default(),
+            ),
+            (
+                "home",
+                dirs::home_dir()
+                    .map(|home| home.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+        TemplateContext {
+            values,
+            env: profile_config.env.clone(),
+            script: None,
+        }
+    }
+
+    /// Lets the template call the script's functions
+    pub fn with_script(mut self, script: Arc<Script>) -> Self {
+        self.script = Some(script);
+        self
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<String> {
+        match name.strip_prefix("env.") {
+            Some(variable) => Some(
+                self.env
+                    .get(variable)
+                    .cloned()
+                    .or_else(|| env::var(variable).ok())
+                    .unwrap_or_default(),
+            ),
+            None => self.values.get(name).cloned(),
+        }
+    }
+
+    // Tags with a call in them are script expressions
+    fn eval(&self, expression: &str) -> Option<Result<Value>> {
+        expression.contains('(').then(|| match &self.script {
+            Some(script) => script.eval(expression, self),
+            None => Script::default().eval(expression, self),
+        })
+    }
+
+    fn condition(&self, expression: &str) -> Result<bool> {
+        if let Some(value) = self.eval(expression) {
+            return Ok(value?.truthy());
+        }
+        let (name, expected, equal) = match expression.split_once("==") {
+            Some((name, value)) => (name, Some(value), true),
+            None => match expression.split_once("!=") {
+                Some((name, value)) => (name, Some(value), false),
+                None => (expression, None, true),
+            },
+        };
+        let name = name.trim();
+        let value = self
+            .get(name)
+            .with_context(|| format!("unknown value {:?}", name))?;
+        Ok(match expected {
+            Some(expected) => {
+                let expected = expected.trim();
+                let expected = expected
+                    .strip_prefix('"')
+                    .and_then(|expected| expected.strip_suffix('"'))
+                    .with_context(|| format!("{} isn't a quoted string", expected))?;
+                (value == expected) == equal
+            }
+            None => !value.is_empty(),
+        })
+    }
+}
+
+/// Renders a template, naming the line of the first tag it can't handle
+pub fn render(template: &str, context: &TemplateContext) -> Result<String> {
+    let mut output = String::new();
+    // Per open `if`: whether its branch being read is kept, and whether it
+    // reached its `else`
+    let mut blocks: Vec<(bool, bool)> = Vec::new();
+    let kept = |blocks: &[(bool, bool)]| blocks.iter().all(|(kept, _)| *kept);
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let before = &template[..template.len() - rest.len() + start];
+        let line = before.matches('\n').count() + 1;
+        if kept(&blocks) {
+            output.push_str(&rest[..start]);
+        }
+        let end = rest[start..]
+            .find("}}")
+            .with_context(|| format!("line {}: {{{{ is never closed", line))?;
+        let tag = rest[start + 2..start + end].trim();
+        rest = &rest[start + end + 2..];
+        // Tags alone on their line don't leave an empty line behind
+        let block_tag = tag == "else" || tag == "end" || tag.starts_with("if ");
+        if block_tag && (before.is_empty() || before.ends_with('\n')) {
+            if let Some(stripped) = rest.strip_prefix('\n') {
+                rest = stripped;
+            }
+        }
+
+        if let Some(expression) = tag.strip_prefix("if ") {
+            // Conditions in dropped branches aren't evaluated
+            let condition = !kept(&blocks)
+                || context
+                    .condition(expression)
+                    .with_context(|| format!("line {}", line))?;
+            blocks.push((condition, false));
+        } else if tag == "else" {
+            match blocks.last_mut() {
+                Some((kept, in_else @ false)) => {
+                    *kept = !*kept;
+                    *in_else = true;
+                }
+                _ => anyhow::bail!("line {}: else without an open if", line),
+            }
+        } else if tag == "end" {
+            if blocks.pop().is_none() {
+                anyhow::bail!("line {}: end without an open if", line);
+            }
+        } else if kept(&blocks) {
+            let value = match context.eval(tag) {
+                Some(value) => value.with_context(|| format!("line {}", line))?.to_string(),
+                None => context
+                    .get(tag)
+                    .with_context(|| format!("line {}: unknown value {:?}", line, tag))?,
+            };
+            output.push_str(&value);
+        }
+    }
+    if !blocks.is_empty() {
+        anyhow::bail!("an if is never closed with end");
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+impl Dotty {
+    // A template source's rendering for its destination, or None when the
+    // source isn't a template
+    pub(crate) fn rendered(
+        &self,
+        source: &Path,
+        profile_config: &ProfileConfig,
+    ) -> Result<Option<Vec<u8>>> {
+        if !is_template(source) || !source.is_file() {
+            return Ok(None);
+        }
+        // Entries are installed from a profile's config, so find its name
+        let profile = self
+            .config
+            .profiles
+            .iter()
+            .find(|(_, config)| std::ptr::eq(*config, profile_config))
+            .map_or(self.current_profile.as_str(), |(name, _)| name.as_str());
+        let template = fs::read_to_string(source)
+            .with_context(|| format!("Failed to read template {:?}", source))?;
+        let context = TemplateContext::new(profile, profile_config).with_script(self.script()?);
+        let rendered = render(&template, &context)
+            .with_context(|| format!("Failed to render {:?}", source))?;
+        Ok(Some(rendered.into_bytes()))
+    }
+
+    // The rendering of a template entry as stored in a commit, or None when
+    // the entry isn't a template
+    pub(crate) fn rendered_object(
+        &self,
+        object: &git2::Object,
+        key: &str,
+        profile: &str,
+        profile_config: &ProfileConfig,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(blob) = object.as_blob().filter(|_| is_template(Path::new(key))) else {
+            return Ok(None);
+        };
+        let template = std::str::from_utf8(blob.content())
+            .with_context(|| format!("Template {} isn't valid UTF-8", key))?;
+        let context = TemplateContext::new(profile, profile_config).with_script(self.script()?);
+        let rendered =
+            render(template, &context).with_context(|| format!("Failed to render {}", key))?;
+        Ok(Some(rendered.into_bytes()))
+    }
+}
@@ -0,0 +1,740 @@
+//! Path, file and formatting helpers shared by the other modules.
+
+use anyhow::{Context, Result};
+use ignore::WalkBuilder;
+#[cfg(windows)]
+use log::info;
+use log::warn;
+#[cfg(windows)]
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{env, fs, process};
+use symlink::symlink_auto;
+
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// SHA-256 of a file's content, or of every file beneath a directory (paths
+// included, in sorted order). None when the path doesn't exist.
+pub(crate) fn hash_path(path: &Path) -> Result<Option<String>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    if !path.is_dir() {
+        let content = fs::read(path).context("Failed to read file for hashing")?;
+        return Ok(Some(hex(&openssl::sha::sha256(&content))));
+    }
+
+    let mut files: Vec<PathBuf> = WalkBuilder::new(path)
+        .hidden(false)
+        .git_ignore(false)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|entry| entry.is_file())
+        .collect();
+    files.sort();
+    let mut hasher = openssl::sha::Sha256::new();
+    for file in files {
+        hasher.update(file.strip_prefix(path)?.to_string_lossy().as_bytes());
+        hasher.update(&fs::read(&file).context("Failed to read file for hashing")?);
+    }
+    Ok(Some(hex(&hasher.finish())))
+}
+
+// Config key for a path under $HOME that may not exist yet or may be a
+// symlink; only the parent directory is resolved
+pub(crate) fn home_key(path: &Path) -> Result<String> {
+    let absolute = env::current_dir()
+        .context("Failed to get current directory")?
+        .join(path);
+    let absolute = match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) if parent.exists() => parent
+            .canonicalize()
+            .context("Failed to canonicalize path")?
+            .join(name),
+        _ => absolute,
+    };
+    key_for_path(&absolute)
+}
+
+// Paths outside $HOME are stored under this prefix in the config and the repo
+pub(crate) const SYSTEM_PREFIX: &str = "@system/";
+
+pub(crate) fn key_for_path(path: &Path) -> Result<String> {
+    let home = dirs::home_dir().context("Failed to get home directory")?;
+    let path = strip_verbatim_prefix(path);
+    if let Ok(relative_path) = path.strip_prefix(&home) {
+        return Ok(path_key(relative_path));
+    }
+    Ok(format!("{}{}", SYSTEM_PREFIX, system_key(&path)?))
+}
+
+// Keys use forward slashes on every OS so the repository layout is shared
+pub(crate) fn path_key(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(not(windows))]
+pub(crate) fn system_key(path: &Path) -> Result<String> {
+    let system_path = path
+        .strip_prefix("/")
+        .with_context(|| format!("{} is not an absolute path", path.display()))?;
+    Ok(path_key(system_path))
+}
+
+// Windows system keys start with the drive letter, e.g. "@system/C/ProgramData/..."
+#[cfg(windows)]
+pub(crate) fn system_key(path: &Path) -> Result<String> {
+    drive_key(&path.to_string_lossy())
+        .with_context(|| format!("{} is not a path on a lettered drive", path.display()))
+}
+
+// "C:\ProgramData\app" (or its \\?\ form) as "C/ProgramData/app". Works on
+// the string rather than Path so it behaves the same on every OS.
+#[cfg(any(windows, test))]
+fn drive_key(path: &str) -> Option<String> {
+    let path = path.strip_prefix(r"\\?\").unwrap_or(path);
+    let mut chars = path.chars();
+    let (Some(letter), Some(':')) = (chars.next(), chars.next()) else {
+        return None;
+    };
+    if !letter.is_ascii_alphabetic() {
+        return None;
+    }
+    let parts: Vec<&str> = chars
+        .as_str()
+        .split(['\\', '/'])
+        .filter(|part| !matches!(*part, "" | "." | ".."))
+        .collect();
+    Some(format!(
+        "{}/{}",
+        letter.to_ascii_uppercase(),
+        parts.join("/")
+    ))
+}
+
+#[cfg(not(windows))]
+pub(crate) fn system_destination(system_path: &str) -> PathBuf {
+    Path::new("/").join(system_path)
+}
+
+#[cfg(windows)]
+pub(crate) fn system_destination(system_path: &str) -> PathBuf {
+    PathBuf::from(drive_path(system_path))
+}
+
+// The reverse of drive_key: "C/ProgramData/app" as "C:\ProgramData\app"
+#[cfg(any(windows, test))]
+fn drive_path(system_path: &str) -> String {
+    let (drive, rest) = system_path.split_once('/').unwrap_or((system_path, ""));
+    format!("{}:\\{}", drive, rest.replace('/', "\\"))
+}
+
+// canonicalize() on Windows returns \\?\C:\... paths, which never start with
+// the home directory as dirs reports it
+#[cfg(windows)]
+pub(crate) fn strip_verbatim_prefix(path: &Path) -> PathBuf {
+    match path.to_str().and_then(|s| s.strip_prefix(r"\\?\")) {
+        Some(rest) if rest.as_bytes().get(1) == Some(&b':') => PathBuf::from(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn strip_verbatim_prefix(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+// Whether `command` names a file on $PATH, or is a path to a file
+pub(crate) fn command_exists(command: &str) -> bool {
+    if command.contains(['/', '\\']) {
+        return Path::new(command).is_file();
+    }
+    let file_name = format!("{}{}", command, env::consts::EXE_SUFFIX);
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(&file_name).is_file()))
+}
+
+// Expands %NAME% references such as %APPDATA%; unknown names are kept as written
+pub(crate) fn expand_env_vars(path: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = path;
+    while let Some(start) = rest.find('%') {
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('%') else {
+            break;
+        };
+        match env::var(&after[..end]) {
+            Ok(value) if end > 0 => {
+                expanded.push_str(&rest[..start]);
+                expanded.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            _ => {
+                expanded.push_str(&rest[..=start]);
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+// Elevation shells out to sudo-style commands, which Windows doesn't have
+pub(crate) fn needs_elevation(path: &Path, error: &anyhow::Error) -> bool {
+    if !cfg!(unix) {
+        return false;
+    }
+    let outside_home = dirs::home_dir().is_some_and(|home| !path.starts_with(home));
+    outside_home
+        && error.chain().any(|cause| {
+            cause
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+        })
+}
+
+pub(crate) fn prompt(question: &str) -> Result<String> {
+    print!("{} ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read answer")?;
+    Ok(answer.trim().to_string())
+}
+
+pub(crate) fn run_editor(path: &Path) -> Result<()> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // EDITOR may carry arguments, e.g. "code --wait"
+    let mut words = editor.split_whitespace();
+    let status = process::Command::new(words.next().unwrap_or("vi"))
+        .args(words)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run {}", editor))?;
+    if !status.success() {
+        anyhow::bail!("{} exited with {}", editor, status);
+    }
+    Ok(())
+}
+
+pub(crate) fn confirm(question: &str) -> Result<bool> {
+    let answer = prompt(&format!("{} [y/N]", question))?;
+    Ok(matches!(answer.as_str(), "y" | "Y" | "yes"))
+}
+
+pub(crate) fn create_symlink(source: &Path, dest: &Path) -> Result<()> {
+    match symlink_auto(source, dest) {
+        // Without Developer Mode or admin rights Windows refuses to create
+        // symlinks, so directories get a junction and files a hardlink
+        #[cfg(windows)]
+        Err(e) if e.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD) => {
+            if source.is_dir() {
+                let status = process::Command::new("cmd")
+                    .args([OsStr::new("/C"), OsStr::new("mklink"), OsStr::new("/J")])
+                    .arg(dest)
+                    .arg(source)
+                    .stdout(process::Stdio::null())
+                    .status()
+                    .context("Failed to run mklink")?;
+                if !status.success() {
+                    anyhow::bail!("Failed to create junction {}", dest.display());
+                }
+                info!("Symlinks need Developer Mode; created a junction instead");
+            } else {
+                fs::hard_link(source, dest).context("Failed to create hardlink")?;
+                info!("Symlinks need Developer Mode; created a hardlink instead");
+            }
+            Ok(())
+        }
+        result => result.context("Failed to create symlink"),
+    }
+}
+
+#[cfg(windows)]
+pub(crate) const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+// Directory symlinks and junctions on Windows must be removed as directories;
+// this never touches the link's target
+pub(crate) fn remove_link(path: &Path) -> io::Result<()> {
+    #[cfg(windows)]
+    if path.is_dir() {
+        return fs::remove_dir(path);
+    }
+    fs::remove_file(path)
+}
+
+// Copies every extended attribute the current user can read. On Linux this
+// includes POSIX ACLs (system.posix_acl_*) and SELinux contexts; attributes
+// the destination's filesystem or the current user can't set are skipped.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn copy_xattrs(source: &Path, dest: &Path) -> Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let c_source = CString::new(source.as_os_str().as_bytes())?;
+    let c_dest = CString::new(dest.as_os_str().as_bytes())?;
+
+    let size = unsafe { xattr::list(&c_source, &mut []) };
+    if size < 0 {
+        let e = io::Error::last_os_error();
+        // Filesystems without xattr support have nothing to preserve
+        if e.raw_os_error() == Some(libc::ENOTSUP) {
+            return Ok(());
+        }
+        return Err(e).with_context(|| format!("Failed to list attributes of {:?}", source));
+    }
+    let mut names = vec![0; size as usize];
+    let size = unsafe { xattr::list(&c_source, &mut names) };
+    if size < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to list attributes of {:?}", source));
+    }
+    names.truncate(size as usize);
+
+    for name in names
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let c_name = CString::new(name)?;
+        let size = unsafe { xattr::get(&c_source, &c_name, &mut []) };
+        if size < 0 {
+            continue;
+        }
+        let mut value = vec![0; size as usize];
+        let size = unsafe { xattr::get(&c_source, &c_name, &mut value) };
+        if size < 0 {
+            continue;
+        }
+        value.truncate(size as usize);
+        if unsafe { xattr::set(&c_dest, &c_name, &value) } != 0 {
+            warn!(
+                "Could not copy attribute {} to {:?}: {}",
+                String::from_utf8_lossy(name),
+                dest,
+                io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn copy_xattrs(_source: &Path, _dest: &Path) -> Result<()> {
+    Ok(())
+}
+
+// The xattr calls take extra position/option arguments on macOS
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) mod xattr {
+    use std::ffi::CStr;
+
+    pub unsafe fn list(path: &CStr, names: &mut [u8]) -> isize {
+        #[cfg(target_os = "linux")]
+        return libc::listxattr(path.as_ptr(), names.as_mut_ptr().cast(), names.len());
+        #[cfg(target_os = "macos")]
+        return libc::listxattr(path.as_ptr(), names.as_mut_ptr().cast(), names.len(), 0);
+    }
+
+    pub unsafe fn get(path: &CStr, name: &CStr, value: &mut [u8]) -> isize {
+        #[cfg(target_os = "linux")]
+        return libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        );
+        #[cfg(target_os = "macos")]
+        return libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+            0,
+            0,
+        );
+    }
+
+    pub unsafe fn set(path: &CStr, name: &CStr, value: &[u8]) -> i32 {
+        #[cfg(target_os = "linux")]
+        return libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        );
+        #[cfg(target_os = "macos")]
+        return libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+            0,
+        );
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+pub(crate) fn file_mode(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+// Resolves a user or group name, or a numeric id, with getpwnam/getgrnam
+#[cfg(unix)]
+pub(crate) fn lookup_id(name: &str, group: bool) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = std::ffi::CString::new(name).context("Invalid user or group name")?;
+    let id = unsafe {
+        if group {
+            let entry = libc::getgrnam(c_name.as_ptr());
+            (!entry.is_null()).then(|| (*entry).gr_gid)
+        } else {
+            let entry = libc::getpwnam(c_name.as_ptr());
+            (!entry.is_null()).then(|| (*entry).pw_uid)
+        }
+    };
+    let kind = if group { "group" } else { "user" };
+    id.with_context(|| format!("Unknown {}: {}", kind, name))
+}
+
+// Describes how a destination's owner differs from the declared one, if it does
+#[cfg(unix)]
+pub(crate) fn ownership_mismatch(
+    dest: &Path,
+    owner: Option<&str>,
+    group: Option<&str>,
+) -> Result<Option<String>> {
+    use std::os::unix::fs::MetadataExt;
+    if owner.is_none() && group.is_none() {
+        return Ok(None);
+    }
+    let Ok(metadata) = fs::metadata(dest) else {
+        return Ok(None);
+    };
+    let owner_differs = match owner {
+        Some(owner) => lookup_id(owner, false)? != metadata.uid(),
+        None => false,
+    };
+    let group_differs = match group {
+        Some(group) => lookup_id(group, true)? != metadata.gid(),
+        None => false,
+    };
+    if !owner_differs && !group_differs {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "owned by {}:{}, expected {}:{}",
+        metadata.uid(),
+        metadata.gid(),
+        owner.unwrap_or("*"),
+        group.unwrap_or("*")
+    )))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn ownership_mismatch(
+    _dest: &Path,
+    _owner: Option<&str>,
+    _group: Option<&str>,
+) -> Result<Option<String>> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+pub(crate) fn chown(dest: &Path, owner: Option<&str>, group: Option<&str>) -> Result<()> {
+    let uid = owner.map(|owner| lookup_id(owner, false)).transpose()?;
+    let gid = group.map(|group| lookup_id(group, true)).transpose()?;
+    std::os::unix::fs::chown(dest, uid, gid)
+        .with_context(|| format!("Failed to change owner of {}", dest.display()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn chown(_dest: &Path, _owner: Option<&str>, _group: Option<&str>) -> Result<()> {
+    Ok(())
+}
+
+// Accepts "600", "0600" or "0o600"
+pub(crate) fn parse_permissions(permissions: &str) -> Result<u32> {
+    let digits = permissions.strip_prefix("0o").unwrap_or(permissions);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => anyhow::bail!("{:?} is not an octal file mode", permissions),
+    }
+}
+
+// Paths that commonly hold keys or credentials
+pub(crate) fn is_secret_path(relative_path: &str) -> bool {
+    let first = relative_path.split('/').next().unwrap_or(relative_path);
+    let name = relative_path.rsplit('/').next().unwrap_or(relative_path);
+    if name.ends_with(".pub") || name == "known_hosts" {
+        return false;
+    }
+    [".ssh", ".gnupg", ".aws", ".kube"].contains(&first)
+        || name.starts_with("id_")
+        || [".netrc", ".pgpass", ".git-credentials", ".npmrc", ".pypirc"].contains(&name)
+}
+
+pub(crate) fn warn_if_exposed(relative_path: &str, dest: &Path) {
+    if !is_secret_path(relative_path) {
+        return;
+    }
+    if let Some(mode) = file_mode(dest).filter(|mode| mode & 0o077 != 0) {
+        warn!(
+            "{} looks like a secret but is {:o} (readable by others); set `permissions = \"0600\"` for it",
+            dest.display(),
+            mode
+        );
+    }
+}
+
+// Prompts with echo turned off
+pub(crate) fn read_secret(question: &str) -> Result<String> {
+    #[cfg(unix)]
+    let restore = unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        (libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0).then(|| {
+            let mut silent = termios;
+            silent.c_lflag &= !libc::ECHO;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent);
+            termios
+        })
+    };
+    let secret = prompt(question);
+    #[cfg(unix)]
+    if let Some(termios) = restore {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+        println!();
+    }
+    secret
+}
+
+/// Accepts either a date ("2024-06-01") or a duration ago ("3d", "12h")
+pub fn parse_since(since: &str) -> Result<u64> {
+    if let Ok(duration) = humantime::parse_duration(since) {
+        return Ok(unix_seconds(SystemTime::now()).saturating_sub(duration.as_secs()));
+    }
+    let date = chrono::NaiveDate::parse_from_str(since, "%Y-%m-%d")
+        .with_context(|| format!("Invalid --since value: {}", since))?;
+    let midnight = date
+        .and_hms_opt(0, 0, 0)
+        .and_then(|time| time.and_local_timezone(chrono::Local).earliest())
+        .context("Invalid local date")?;
+    Ok(midnight.timestamp().max(0) as u64)
+}
+
+pub(crate) fn format_unix_time(seconds: u64) -> String {
+    chrono::DateTime::from_timestamp(seconds as i64, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) fn host_name() -> String {
+    hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+pub(crate) fn dir_size(path: &Path) -> u64 {
+    WalkBuilder::new(path)
+        .standard_filters(false)
+        .build()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+pub(crate) fn format_git_time(time: git2::Time) -> String {
+    chrono::DateTime::from_timestamp(time.seconds(), 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+// Rewrites the line endings of a text file, or of every text file beneath a
+// directory, as CRLF or LF. Files containing NUL bytes are treated as binary
+// and left alone.
+pub(crate) fn convert_line_endings(path: &Path, crlf: bool) -> Result<()> {
+    if path.is_dir() {
+        for entry in WalkBuilder::new(path)
+            .hidden(false)
+            .git_ignore(false)
+            .build()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+        {
+            convert_line_endings(entry.path(), crlf)?;
+        }
+        return Ok(());
+    }
+    let content = fs::read(path).context("Failed to read file")?;
+    if content.contains(&0) {
+        return Ok(());
+    }
+    let mut converted = Vec::with_capacity(content.len());
+    for (index, &byte) in content.iter().enumerate() {
+        if byte == b'\r' && content.get(index + 1) == Some(&b'\n') {
+            continue;
+        }
+        if byte == b'\n' && crlf {
+            converted.push(b'\r');
+        }
+        converted.push(byte);
+    }
+    if converted != content {
+        fs::write(path, converted).context("Failed to write file")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn copy_dir(source: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let dest = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest)?;
+        } else {
+            fs::copy(entry.path(), dest)?;
+        }
+    }
+    Ok(())
+}
+
+// "42s", "5m", "3h", "2d"
+pub(crate) fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+pub(crate) fn state_dir() -> Result<PathBuf> {
+    let state_dir = dirs::data_local_dir()
+        .context("Failed to get local data directory")?
+        .join("dotty");
+    fs::create_dir_all(&state_dir).context("Failed to create state directory")?;
+    Ok(state_dir)
+}
+
+pub(crate) fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drive_keys_start_with_the_letter() {
+        assert_eq!(
+            drive_key(r"C:\ProgramData\app\config.ini").as_deref(),
+            Some("C/ProgramData/app/config.ini")
+        );
+        assert_eq!(
+            drive_key(r"\\?\d:\tools\.\bin\").as_deref(),
+            Some("D/tools/bin")
+        );
+        assert_eq!(drive_key(r"\\server\share\file"), None);
+        assert_eq!(drive_key("/etc/hosts"), None);
+    }
+
+    #[test]
+    fn drive_paths_round_trip() {
+        let key = drive_key(r"C:\ProgramData\app\config.ini").unwrap();
+        assert_eq!(drive_path(&key), r"C:\ProgramData\app\config.ini");
+        assert_eq!(drive_path("D"), r"D:\");
+    }
+
+    #[test]
+    fn env_vars_are_expanded() {
+        env::set_var("DOTTY_TEST_APPDATA", r"C:\Users\me\AppData\Roaming");
+        assert_eq!(
+            expand_env_vars(r"%DOTTY_TEST_APPDATA%\dotty"),
+            r"C:\Users\me\AppData\Roaming\dotty"
+        );
+        assert_eq!(
+            expand_env_vars("%DOTTY_TEST_APPDATA%/a/%DOTTY_TEST_APPDATA%"),
+            r"C:\Users\me\AppData\Roaming/a/C:\Users\me\AppData\Roaming"
+        );
+    }
+
+    #[test]
+    fn unknown_env_vars_are_kept() {
+        env::remove_var("DOTTY_TEST_UNSET");
+        assert_eq!(
+            expand_env_vars(r"%DOTTY_TEST_UNSET%\x"),
+            r"%DOTTY_TEST_UNSET%\x"
+        );
+        assert_eq!(expand_env_vars("100%"), "100%");
+        assert_eq!(expand_env_vars("%%repo"), "%%repo");
+    }
+}